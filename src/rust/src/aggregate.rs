@@ -0,0 +1,767 @@
+//! This module summarises groups of rows across RecordBatches, like
+//! `dplyr::group_by()` followed by `dplyr::summarise()`.
+//!
+//! Rows are hashed into groups by the values of their key columns. The rows
+//! are split into chunks of [CHUNK_ROWS] that are aggregated in parallel with
+//! [rayon], each into its own hash table, and the tables are then merged. Each
+//! [Aggregate] is written like an R function call, `function(column)`, where
+//! the function is one of:
+//!
+//! - `sum` and `mean` of a numeric or logical column, as doubles
+//! - `min` and `max` of any column that can be a key, keeping its type
+//!   (factors compare by the order of their levels)
+//! - `count` (or `n`) of the rows, or `count(x)` of the non-NA values of `x`
+//! - `n_distinct` of the values of a column, counting NA as a value
+//!
+//! Like R, `sum`, `mean`, `min`, and `max` are NA for a group with any NA's,
+//! unless `na_rm` is set, in which case NA's are skipped (and not counted by
+//! `n_distinct`). Key columns can be of any integer, floating point, string,
+//! logical, dictionary, date, time, or timestamp type, and NA keys form a
+//! group of their own. The result has one row per group, sorted by the keys
+//! with NA's last, the way `dplyr::summarise()` orders groups.
+
+use crate::relational::{
+    column_index, combine_batches, dictionary_keys, sort_record_batches_fn, without_row_names,
+    SortKey,
+};
+use crate::structs::{RecordBatches, RecordBatchesError};
+use arrow::{
+    array::{
+        Array, ArrayRef, BooleanArray, Float64Array, Int32Array, Int64Array, StringArray,
+        UInt32Array,
+    },
+    compute::{cast, take},
+    datatypes::{DataType, Field, Schema},
+    record_batch::RecordBatch,
+};
+use rayon::prelude::*;
+use std::cmp::Ordering;
+use std::collections::{hash_map::Entry, HashMap, HashSet};
+use std::ops::Range;
+use std::sync::Arc;
+
+type Result<T> = std::result::Result<T, RecordBatchesError>;
+
+/// The number of rows aggregated together by each parallel task
+pub(crate) const CHUNK_ROWS: usize = 1 << 16;
+
+/// A function summarising the rows of a group
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Function {
+    Sum,
+    Mean,
+    Min,
+    Max,
+    Count,
+    NDistinct,
+}
+
+/// One summary column, computed by a function of a column (or just of the
+/// rows, for `count()`)
+#[derive(Debug, Clone)]
+pub(crate) struct Aggregate {
+    pub name: String,
+    pub function: Function,
+    pub column: Option<String>,
+}
+
+impl Aggregate {
+    /// Parses an aggregate written like `mean(mpg)` or `n()`, naming its
+    /// column `name`. Column names can be quoted with backticks.
+    pub(crate) fn parse(name: &str, spec: &str) -> Result<Self> {
+        let error = || {
+            RecordBatchesError::schema(
+                "Could not parse aggregate",
+                Some("a function call like `mean(x)`".to_string()),
+                Some(format!("'{}'", spec)),
+            )
+        };
+        let call = spec.trim();
+        let open = call.find('(').ok_or_else(error)?;
+        if !call.ends_with(')') {
+            return Err(error());
+        }
+        let function = match call[..open].trim() {
+            "sum" => Function::Sum,
+            "mean" => Function::Mean,
+            "min" => Function::Min,
+            "max" => Function::Max,
+            "count" | "n" => Function::Count,
+            "n_distinct" => Function::NDistinct,
+            other => {
+                return Err(RecordBatchesError::schema(
+                    "Unknown aggregate function",
+                    Some("sum, mean, min, max, count, n, or n_distinct".to_string()),
+                    Some(format!("'{}'", other)),
+                ))
+            }
+        };
+        let column = call[open + 1..call.len() - 1].trim();
+        let column = column
+            .strip_prefix('`')
+            .and_then(|x| x.strip_suffix('`'))
+            .unwrap_or(column);
+        let column = Some(column.to_string()).filter(|x| !x.is_empty());
+        if column.is_none() && function != Function::Count {
+            return Err(error());
+        }
+        Ok(Aggregate {
+            name: name.to_string(),
+            function,
+            column,
+        })
+    }
+}
+
+/// Summarises each group of rows with the same values in the `keys` columns
+/// (or all rows, if there are no keys), combining all RecordBatches into one
+pub(crate) fn group_aggregate_fn(
+    batches: RecordBatches,
+    keys: &[String],
+    aggregates: &[Aggregate],
+    na_rm: bool,
+) -> Result<RecordBatches> {
+    let batch = match combine_batches(batches)? {
+        Some(batch) => batch,
+        None => return Ok(Vec::<RecordBatch>::new().into_iter().collect()),
+    };
+    let schema = batch.schema();
+    let key_indices = keys
+        .iter()
+        .map(|name| column_index(&schema, name))
+        .collect::<Result<Vec<_>>>()?;
+    let key_values = key_indices
+        .iter()
+        .zip(keys)
+        .map(|(&i, name)| Values::try_new(batch.column(i)).map_err(|e| e.for_field(name.as_str())))
+        .collect::<Result<Vec<_>>>()?;
+    let aggregator = Aggregator::try_new(&batch, aggregates, na_rm)?;
+
+    let num_rows = batch.num_rows();
+    let chunks: Vec<Range<usize>> = (0..num_rows)
+        .step_by(CHUNK_ROWS)
+        .map(|start| start..num_rows.min(start + CHUNK_ROWS))
+        .collect();
+    let groups = chunks
+        .into_par_iter()
+        .map(|rows| aggregator.aggregate_rows(&key_values, rows))
+        .reduce(HashMap::new, |mut groups, other| {
+            aggregator.merge_groups(&mut groups, other);
+            groups
+        });
+
+    let mut groups: Vec<Group> = groups.into_values().collect();
+    if groups.is_empty() && keys.is_empty() {
+        // Like summarise(), no keys always gives one row
+        groups.push(Group {
+            first_row: 0,
+            states: aggregator.initial_states(),
+        });
+    }
+    groups.sort_by_key(|group| group.first_row);
+
+    let first_rows = UInt32Array::from(
+        groups
+            .iter()
+            .map(|group| group.first_row as u32)
+            .collect::<Vec<_>>(),
+    );
+    let mut fields = Vec::new();
+    let mut columns = Vec::new();
+    for (&i, name) in key_indices.iter().zip(keys) {
+        let column = take(batch.column(i).as_ref(), &first_rows, None).map_err(|e| {
+            RecordBatchesError::arrow("Error taking group keys")(e).for_field(name.as_str())
+        })?;
+        fields.push(schema.field(i).clone());
+        columns.push(column);
+    }
+    let mut next_dict_id = fields
+        .iter()
+        .chain(schema.fields())
+        .filter_map(|field| field.dict_id())
+        .max()
+        .map_or(0, |id| id + 1);
+    for (j, aggregate) in aggregates.iter().enumerate() {
+        let column = aggregator
+            .finish(j, &groups)
+            .map_err(|e| e.for_field(aggregate.name.as_str()))?;
+        let field = match column.data_type() {
+            DataType::Dictionary(_, _) => {
+                let source =
+                    schema.field(column_index(&schema, aggregate.column.as_ref().unwrap())?);
+                next_dict_id += 1;
+                Field::new_dict(
+                    &aggregate.name,
+                    column.data_type().clone(),
+                    true,
+                    next_dict_id - 1,
+                    source.dict_is_ordered().unwrap_or(false),
+                )
+            }
+            data_type => Field::new(&aggregate.name, data_type.clone(), true),
+        };
+        fields.push(field);
+        columns.push(column);
+    }
+
+    let metadata = without_row_names(&schema)?.metadata().clone();
+    let summary = RecordBatch::try_new(
+        Arc::new(Schema::new_with_metadata(fields, metadata)),
+        columns,
+    )
+    .map_err(RecordBatchesError::arrow(
+        "Error creating aggregated RecordBatch",
+    ))?;
+    let sort_keys: Vec<SortKey> = keys
+        .iter()
+        .map(|column| SortKey {
+            column: column.clone(),
+            descending: false,
+        })
+        .collect();
+    sort_record_batches_fn(vec![summary].into_iter().collect(), &sort_keys, true)
+}
+
+// -=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-
+
+/// A value of a key column, which can be hashed
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum KeyValue {
+    Null,
+    Int(i64),
+    Float(u64),
+    Text(String),
+    Bool(bool),
+}
+
+/// The values of a column in a form that can be hashed and compared
+pub(crate) enum Values {
+    Int(Int64Array),
+    Float(Float64Array),
+    Text(StringArray),
+    Bool(BooleanArray),
+}
+
+impl Values {
+    pub(crate) fn try_new(array: &ArrayRef) -> Result<Self> {
+        let cast_to = |array: &ArrayRef, data_type: DataType| {
+            cast(array, &data_type).map_err(RecordBatchesError::arrow("Error casting column"))
+        };
+        let values = match array.data_type() {
+            DataType::Boolean => Values::Bool(BooleanArray::from(array.data())),
+            DataType::Float16 | DataType::Float32 | DataType::Float64 => Values::Float(
+                Float64Array::from(cast_to(array, DataType::Float64)?.data()),
+            ),
+            DataType::Utf8 | DataType::LargeUtf8 => {
+                Values::Text(StringArray::from(cast_to(array, DataType::Utf8)?.data()))
+            }
+            DataType::Dictionary(_, _) => {
+                let keys = cast_to(&dictionary_keys(array), DataType::Int64)?;
+                Values::Int(Int64Array::from(keys.data()))
+            }
+            DataType::Date32(_) | DataType::Time32(_) => {
+                let values = cast_to(&cast_to(array, DataType::Int32)?, DataType::Int64)?;
+                Values::Int(Int64Array::from(values.data()))
+            }
+            DataType::Int8
+            | DataType::Int16
+            | DataType::Int32
+            | DataType::Int64
+            | DataType::UInt8
+            | DataType::UInt16
+            | DataType::UInt32
+            | DataType::UInt64
+            | DataType::Date64(_)
+            | DataType::Time64(_)
+            | DataType::Timestamp(_, _) => {
+                Values::Int(Int64Array::from(cast_to(array, DataType::Int64)?.data()))
+            }
+            data_type => {
+                return Err(RecordBatchesError::schema(
+                    "Unsupported column type for grouping or comparing",
+                    Some("a number, string, logical, dictionary, or date/time".to_string()),
+                    Some(format!("{:?}", data_type)),
+                ))
+            }
+        };
+        Ok(values)
+    }
+
+    fn is_valid(&self, i: usize) -> bool {
+        match self {
+            Values::Int(a) => a.is_valid(i),
+            Values::Float(a) => a.is_valid(i),
+            Values::Text(a) => a.is_valid(i),
+            Values::Bool(a) => a.is_valid(i),
+        }
+    }
+
+    pub(crate) fn value(&self, i: usize) -> KeyValue {
+        if !self.is_valid(i) {
+            return KeyValue::Null;
+        }
+        match self {
+            Values::Int(a) => KeyValue::Int(a.value(i)),
+            Values::Float(a) => KeyValue::Float(float_bits(a.value(i))),
+            Values::Text(a) => KeyValue::Text(a.value(i).to_string()),
+            Values::Bool(a) => KeyValue::Bool(a.value(i)),
+        }
+    }
+
+    /// Compares two non-null values
+    fn compare(&self, i: usize, j: usize) -> Ordering {
+        match self {
+            Values::Int(a) => a.value(i).cmp(&a.value(j)),
+            Values::Float(a) => a
+                .value(i)
+                .partial_cmp(&a.value(j))
+                .unwrap_or(Ordering::Equal),
+            Values::Text(a) => a.value(i).cmp(a.value(j)),
+            Values::Bool(a) => a.value(i).cmp(&a.value(j)),
+        }
+    }
+}
+
+/// The bits of a float, with every zero and every NaN hashed the same
+fn float_bits(x: f64) -> u64 {
+    if x == 0.0 {
+        0
+    } else if x.is_nan() {
+        f64::NAN.to_bits()
+    } else {
+        x.to_bits()
+    }
+}
+
+// -=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-
+
+/// What an aggregate reads from each row
+enum Input {
+    Rows,
+    Validity(ArrayRef),
+    Numbers(Float64Array),
+    Values(Values, ArrayRef),
+}
+
+/// The running result of an aggregate for one group
+#[derive(Debug, Clone)]
+enum State {
+    Count(usize),
+    Sum {
+        sum: f64,
+        count: usize,
+        missing: bool,
+    },
+    Extreme {
+        row: Option<usize>,
+        missing: bool,
+    },
+    Distinct(HashSet<KeyValue>),
+}
+
+struct Group {
+    first_row: usize,
+    states: Vec<State>,
+}
+
+struct Aggregator<'a> {
+    aggregates: &'a [Aggregate],
+    inputs: Vec<Input>,
+    na_rm: bool,
+}
+
+impl<'a> Aggregator<'a> {
+    fn try_new(batch: &RecordBatch, aggregates: &'a [Aggregate], na_rm: bool) -> Result<Self> {
+        let schema = batch.schema();
+        let inputs = aggregates
+            .iter()
+            .map(|aggregate| {
+                let column = match &aggregate.column {
+                    Some(name) => batch.column(column_index(&schema, name)?).clone(),
+                    None => return Ok(Input::Rows),
+                };
+                let input = match aggregate.function {
+                    Function::Count => Ok(Input::Validity(column)),
+                    Function::Sum | Function::Mean => numbers(&column),
+                    Function::Min | Function::Max | Function::NDistinct => {
+                        Values::try_new(&column).map(|values| Input::Values(values, column))
+                    }
+                };
+                input.map_err(|e| e.for_field(aggregate.column.as_ref().unwrap().as_str()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Aggregator {
+            aggregates,
+            inputs,
+            na_rm,
+        })
+    }
+
+    fn initial_states(&self) -> Vec<State> {
+        self.aggregates
+            .iter()
+            .map(|aggregate| match aggregate.function {
+                Function::Count => State::Count(0),
+                Function::Sum | Function::Mean => State::Sum {
+                    sum: 0.0,
+                    count: 0,
+                    missing: false,
+                },
+                Function::Min | Function::Max => State::Extreme {
+                    row: None,
+                    missing: false,
+                },
+                Function::NDistinct => State::Distinct(HashSet::new()),
+            })
+            .collect()
+    }
+
+    fn aggregate_rows(&self, keys: &[Values], rows: Range<usize>) -> HashMap<Vec<KeyValue>, Group> {
+        let mut groups = HashMap::new();
+        for row in rows {
+            let key: Vec<KeyValue> = keys.iter().map(|values| values.value(row)).collect();
+            let group = groups.entry(key).or_insert_with(|| Group {
+                first_row: row,
+                states: self.initial_states(),
+            });
+            self.update(&mut group.states, row);
+        }
+        groups
+    }
+
+    fn update(&self, states: &mut [State], row: usize) {
+        for ((state, input), aggregate) in states.iter_mut().zip(&self.inputs).zip(self.aggregates)
+        {
+            match (state, input) {
+                (State::Count(n), Input::Rows) => *n += 1,
+                (State::Count(n), Input::Validity(array)) => {
+                    if array.is_valid(row) {
+                        *n += 1
+                    }
+                }
+                (
+                    State::Sum {
+                        sum,
+                        count,
+                        missing,
+                    },
+                    Input::Numbers(array),
+                ) => {
+                    if array.is_valid(row) {
+                        *sum += array.value(row);
+                        *count += 1;
+                    } else {
+                        *missing = true;
+                    }
+                }
+                (State::Extreme { row: best, missing }, Input::Values(values, _)) => {
+                    if !values.is_valid(row) {
+                        *missing = true;
+                    } else {
+                        *best = Some(match *best {
+                            Some(best) => pick(aggregate.function, values, best, row),
+                            None => row,
+                        });
+                    }
+                }
+                (State::Distinct(seen), Input::Values(values, _)) => {
+                    let value = values.value(row);
+                    if !(self.na_rm && value == KeyValue::Null) {
+                        seen.insert(value);
+                    }
+                }
+                _ => unreachable!("Aggregate state doesn't match its input"),
+            }
+        }
+    }
+
+    fn merge_groups(
+        &self,
+        groups: &mut HashMap<Vec<KeyValue>, Group>,
+        other: HashMap<Vec<KeyValue>, Group>,
+    ) {
+        for (key, group) in other {
+            match groups.entry(key) {
+                Entry::Occupied(mut entry) => {
+                    let existing = entry.get_mut();
+                    existing.first_row = existing.first_row.min(group.first_row);
+                    self.merge(&mut existing.states, group.states);
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(group);
+                }
+            }
+        }
+    }
+
+    fn merge(&self, states: &mut [State], other: Vec<State>) {
+        let inputs = self.inputs.iter().zip(self.aggregates);
+        for ((state, other), (input, aggregate)) in states.iter_mut().zip(other).zip(inputs) {
+            match (state, other, input) {
+                (State::Count(n), State::Count(m), _) => *n += m,
+                (
+                    State::Sum {
+                        sum,
+                        count,
+                        missing,
+                    },
+                    State::Sum {
+                        sum: other_sum,
+                        count: other_count,
+                        missing: other_missing,
+                    },
+                    _,
+                ) => {
+                    *sum += other_sum;
+                    *count += other_count;
+                    *missing |= other_missing;
+                }
+                (
+                    State::Extreme { row, missing },
+                    State::Extreme {
+                        row: other_row,
+                        missing: other_missing,
+                    },
+                    Input::Values(values, _),
+                ) => {
+                    *missing |= other_missing;
+                    *row = match (*row, other_row) {
+                        (Some(a), Some(b)) => {
+                            Some(pick(aggregate.function, values, a.min(b), a.max(b)))
+                        }
+                        (a, b) => a.or(b),
+                    };
+                }
+                (State::Distinct(seen), State::Distinct(other_seen), _) => seen.extend(other_seen),
+                _ => unreachable!("Aggregate states don't match"),
+            }
+        }
+    }
+
+    /// Builds the column of aggregate `j`, with one value per group
+    fn finish(&self, j: usize, groups: &[Group]) -> Result<ArrayRef> {
+        let na = |missing: bool| missing && !self.na_rm;
+        let column: ArrayRef = match (&self.aggregates[j].function, &self.inputs[j]) {
+            (Function::Count, _) | (Function::NDistinct, _) => Arc::new(Int32Array::from(
+                groups
+                    .iter()
+                    .map(|group| match &group.states[j] {
+                        State::Count(n) => *n as i32,
+                        State::Distinct(seen) => seen.len() as i32,
+                        _ => unreachable!(),
+                    })
+                    .collect::<Vec<_>>(),
+            )),
+            (function, Input::Numbers(_)) => Arc::new(Float64Array::from(
+                groups
+                    .iter()
+                    .map(|group| match group.states[j] {
+                        State::Sum { missing, .. } if na(missing) => None,
+                        State::Sum { sum, .. } if *function == Function::Sum => Some(sum),
+                        State::Sum { count: 0, .. } => None,
+                        State::Sum { sum, count, .. } => Some(sum / count as f64),
+                        _ => unreachable!(),
+                    })
+                    .collect::<Vec<_>>(),
+            )),
+            (_, Input::Values(_, array)) => {
+                let rows = UInt32Array::from(
+                    groups
+                        .iter()
+                        .map(|group| match group.states[j] {
+                            State::Extreme { missing, .. } if na(missing) => None,
+                            State::Extreme { row, .. } => row.map(|row| row as u32),
+                            _ => unreachable!(),
+                        })
+                        .collect::<Vec<_>>(),
+                );
+                take(array.as_ref(), &rows, None)
+                    .map_err(RecordBatchesError::arrow("Error taking aggregated values"))?
+            }
+            _ => unreachable!("Aggregate doesn't match its input"),
+        };
+        Ok(column)
+    }
+}
+
+/// Picks whichever of the rows `first` and `second` holds the minimum or
+/// maximum, preferring `first` on ties
+fn pick(function: Function, values: &Values, first: usize, second: usize) -> usize {
+    let wanted = if function == Function::Min {
+        Ordering::Less
+    } else {
+        Ordering::Greater
+    };
+    if values.compare(second, first) == wanted {
+        second
+    } else {
+        first
+    }
+}
+
+/// A numeric or logical column as doubles, for sums and means
+fn numbers(column: &ArrayRef) -> Result<Input> {
+    match column.data_type() {
+        DataType::Boolean
+        | DataType::Int8
+        | DataType::Int16
+        | DataType::Int32
+        | DataType::Int64
+        | DataType::UInt8
+        | DataType::UInt16
+        | DataType::UInt32
+        | DataType::UInt64
+        | DataType::Float16
+        | DataType::Float32
+        | DataType::Float64 => {
+            let numbers = cast(column, &DataType::Float64)
+                .map_err(RecordBatchesError::arrow("Error casting column"))?;
+            Ok(Input::Numbers(Float64Array::from(numbers.data())))
+        }
+        data_type => Err(RecordBatchesError::schema(
+            "Can only sum or average numbers",
+            Some("a numeric or logical column".to_string()),
+            Some(format!("{:?}", data_type)),
+        )),
+    }
+}
+
+#[allow(unused_imports)]
+mod test {
+    use super::*;
+
+    #[allow(dead_code)]
+    fn sample() -> RecordBatches {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("g", DataType::Utf8, true),
+            Field::new("x", DataType::Int32, true),
+        ]));
+        let batch = |g: Vec<Option<&str>>, x: Vec<Option<i32>>| {
+            RecordBatch::try_new(
+                schema.clone(),
+                vec![
+                    Arc::new(StringArray::from(g)),
+                    Arc::new(Int32Array::from(x)),
+                ],
+            )
+            .unwrap()
+        };
+        vec![
+            batch(
+                vec![Some("b"), Some("a"), None],
+                vec![Some(1), Some(2), Some(3)],
+            ),
+            batch(
+                vec![Some("a"), Some("b"), Some("b")],
+                vec![None, Some(5), Some(5)],
+            ),
+        ]
+        .into_iter()
+        .collect()
+    }
+
+    #[allow(dead_code)]
+    fn aggregates(specs: &[(&str, &str)]) -> Vec<Aggregate> {
+        specs
+            .iter()
+            .map(|(name, spec)| Aggregate::parse(name, spec).unwrap())
+            .collect()
+    }
+
+    #[allow(dead_code)]
+    fn floats(batch: &RecordBatch, i: usize) -> Vec<Option<f64>> {
+        let array = batch
+            .column(i)
+            .as_any()
+            .downcast_ref::<Float64Array>()
+            .unwrap();
+        (0..array.len())
+            .map(|j| Some(array.value(j)).filter(|_| array.is_valid(j)))
+            .collect()
+    }
+
+    #[allow(dead_code)]
+    fn ints(batch: &RecordBatch, i: usize) -> Vec<Option<i32>> {
+        let array = batch
+            .column(i)
+            .as_any()
+            .downcast_ref::<Int32Array>()
+            .unwrap();
+        (0..array.len())
+            .map(|j| Some(array.value(j)).filter(|_| array.is_valid(j)))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_aggregates() {
+        let aggregate = Aggregate::parse("m", " mean( `a b` ) ").unwrap();
+        assert_eq!(aggregate.function, Function::Mean);
+        assert_eq!(aggregate.column.as_deref(), Some("a b"));
+        assert_eq!(Aggregate::parse("n", "n()").unwrap().column, None);
+        assert!(Aggregate::parse("s", "sum()").is_err());
+        assert!(Aggregate::parse("s", "median(x)").is_err());
+        assert!(Aggregate::parse("s", "sum(x").is_err());
+    }
+
+    #[test]
+    fn test_group_aggregate_sorts_groups_with_na_last() {
+        let aggs = aggregates(&[
+            ("sum", "sum(x)"),
+            ("mean", "mean(x)"),
+            ("max", "max(x)"),
+            ("n", "n()"),
+            ("count", "count(x)"),
+            ("distinct", "n_distinct(x)"),
+        ]);
+        let result = group_aggregate_fn(sample(), &["g".to_string()], &aggs, false).unwrap();
+        let batch = result.into_iter().next().unwrap();
+        let keys = batch
+            .column(0)
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        assert_eq!(keys.value(0), "a");
+        assert_eq!(keys.value(1), "b");
+        assert!(keys.is_null(2));
+        assert_eq!(floats(&batch, 1), vec![None, Some(11.0), Some(3.0)]);
+        assert_eq!(floats(&batch, 2), vec![None, Some(11.0 / 3.0), Some(3.0)]);
+        assert_eq!(ints(&batch, 3), vec![None, Some(5), Some(3)]);
+        assert_eq!(ints(&batch, 4), vec![Some(2), Some(3), Some(1)]);
+        assert_eq!(ints(&batch, 5), vec![Some(1), Some(3), Some(1)]);
+        assert_eq!(ints(&batch, 6), vec![Some(2), Some(2), Some(1)]);
+
+        let result = group_aggregate_fn(sample(), &["g".to_string()], &aggs, true).unwrap();
+        let batch = result.into_iter().next().unwrap();
+        assert_eq!(floats(&batch, 1), vec![Some(2.0), Some(11.0), Some(3.0)]);
+        assert_eq!(ints(&batch, 3), vec![Some(2), Some(5), Some(3)]);
+        assert_eq!(ints(&batch, 6), vec![Some(1), Some(2), Some(1)]);
+    }
+
+    #[test]
+    fn test_group_aggregate_without_keys_across_chunks() {
+        let n = CHUNK_ROWS * 3 + 7;
+        let schema = Arc::new(Schema::new(vec![Field::new("x", DataType::Int32, false)]));
+        let values: Vec<i32> = (0..n as i32).map(|i| i % 10).collect();
+        let batch = RecordBatch::try_new(schema, vec![Arc::new(Int32Array::from(values))]).unwrap();
+        let aggs = aggregates(&[
+            ("n", "n()"),
+            ("distinct", "n_distinct(x)"),
+            ("min", "min(x)"),
+        ]);
+        let result =
+            group_aggregate_fn(vec![batch].into_iter().collect(), &[], &aggs, false).unwrap();
+        let batch = result.into_iter().next().unwrap();
+        assert_eq!(ints(&batch, 0), vec![Some(n as i32)]);
+        assert_eq!(ints(&batch, 1), vec![Some(10)]);
+        assert_eq!(ints(&batch, 2), vec![Some(0)]);
+    }
+
+    #[test]
+    fn test_group_aggregate_errors_name_the_column() {
+        let err = group_aggregate_fn(sample(), &["nope".to_string()], &[], false).unwrap_err();
+        assert_eq!(err.field(), Some("nope"));
+        let aggs = aggregates(&[("s", "sum(g)")]);
+        let err = group_aggregate_fn(sample(), &[], &aggs, false).unwrap_err();
+        assert_eq!(err.field(), Some("g"));
+    }
+}
@@ -1,11 +1,12 @@
-use extendr_api::prelude::{Real, RobjItertools};
+use crate::structs::{RSlice, RSliceMut};
 
-/// Implementation of a Bubble Sort algorithm
-pub(crate) fn bubble_sort_fn(input: Real) -> Real {
-    let mut nvec: Vec<_> = input.collect();
+/// Implementation of a Bubble Sort algorithm, sorting a copy of the input in
+/// place
+pub(crate) fn bubble_sort_fn(input: RSlice<f64>) -> RSliceMut<f64> {
+    let mut nvec = input.duplicate();
     let len = nvec.len();
 
-    for idx in 0..(len - 1) {
+    for idx in 0..len.saturating_sub(1) {
         let last_idx = len - idx - 1;
         for inner_idx in 0..last_idx {
             if nvec[inner_idx] > nvec[inner_idx + 1] {
@@ -14,5 +15,5 @@ pub(crate) fn bubble_sort_fn(input: Real) -> Real {
         }
     }
 
-    nvec.iter().collect_robj().as_real_iter().unwrap()
+    nvec
 }
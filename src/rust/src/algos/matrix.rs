@@ -0,0 +1,20 @@
+use crate::structs::{RLogical, RMatrix};
+use extendr_api::prelude::{RType, Robj};
+
+/// Transposes a double, integer, or logical matrix, keeping its type and
+/// swapping its row and column names
+pub(crate) fn matrix_transpose_fn(x: &Robj) -> Result<Robj, &'static str> {
+    match x.rtype() {
+        RType::Real => RMatrix::<f64>::try_from_robj(x)?.transpose().into_robj(),
+        RType::Integer => RMatrix::<i32>::try_from_robj(x)?.transpose().into_robj(),
+        RType::Logical => RMatrix::<RLogical>::try_from_robj(x)?
+            .transpose()
+            .into_robj(),
+        _ => Err("Input must be a double, integer, or logical matrix."),
+    }
+}
+
+/// Multiplies two double matrices, like `x %*% y`
+pub(crate) fn matrix_multiply_fn(x: RMatrix<f64>, y: RMatrix<f64>) -> Result<Robj, String> {
+    Ok(x.matmul(&y)?.into_robj()?)
+}
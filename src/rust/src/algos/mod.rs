@@ -1,5 +1,7 @@
 mod bubble_sort;
+mod matrix;
 mod naive_sqrt;
 
 pub(crate) use bubble_sort::bubble_sort_fn;
+pub(crate) use matrix::{matrix_multiply_fn, matrix_transpose_fn};
 pub(crate) use naive_sqrt::{multithreaded_naive_sqrt, rayon_naive_sqrt};
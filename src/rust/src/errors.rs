@@ -0,0 +1,249 @@
+//! This module is the boundary between Rust and R for errors and panics. Every
+//! exported function in `lib.rs` runs its body through [r_boundary], which
+//! expects an [RResult] and catches any panic before it can unwind into R
+//! (which would crash the R session). Errors and panics are both signalled to
+//! R as ordinary R errors, with classed conditions carrying the Rust message
+//! and, when one was captured, the Rust backtrace:
+//!
+//! - `rustbind_arrow_error` for a [RecordBatchesError], which also has the
+//!   fields `context`, `cause`, `message_index`, `field`, and `table`
+//! - `rustbind_panic` for a caught panic
+//! - `rustbind_error` for anything else, and as a parent class of both above
+//!
+//! ```r
+//! tryCatch(
+//!   pass_arrow_record_batch(as.raw(1:10)),
+//!   rustbind_error = function(e) cat(e$backtrace)
+//! )
+//! ```
+//!
+//! Backtraces of errors follow the usual `RUST_BACKTRACE` environment
+//! variable, backtraces of panics are always captured.
+
+use crate::structs::{RecordBatchesError, ARROW_ERROR_CLASS};
+use extendr_api::prelude::*;
+use std::backtrace::{Backtrace, BacktraceStatus};
+use std::cell::{Cell, RefCell};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Once;
+
+pub(crate) type RResult<T> = std::result::Result<T, RError>;
+
+/// Class vector of the R condition signalled for most errors
+pub const ERROR_CLASS: [&str; 3] = ["rustbind_error", "error", "condition"];
+
+/// Class vector of the R condition signalled for a caught panic
+pub const PANIC_CLASS: [&str; 4] = ["rustbind_panic", "rustbind_error", "error", "condition"];
+
+/// An error to be signalled to R by [r_boundary]
+#[derive(Debug)]
+pub(crate) enum RError {
+    /// An error converting or processing RecordBatches
+    Arrow {
+        source: RecordBatchesError,
+        backtrace: Option<String>,
+    },
+    /// Any other error, described by its message
+    Message {
+        message: String,
+        backtrace: Option<String>,
+    },
+    /// A panic caught at the boundary
+    Panic {
+        message: String,
+        backtrace: Option<String>,
+    },
+}
+
+impl RError {
+    /// Builds the R condition object (a classed list) describing this error
+    fn to_condition(&self) -> Robj {
+        let (condition, class): (Robj, &[&str]) = match self {
+            RError::Arrow { source, backtrace } => (
+                list!(
+                    message = source.to_string(),
+                    call = (),
+                    backtrace = backtrace.clone(),
+                    context = source.context(),
+                    cause = source.cause(),
+                    message_index = source.message_index().map(|i| i as i32 + 1),
+                    field = source.field().map(|f| f.to_string()),
+                    table = source.table().map(|t| t.to_string())
+                ),
+                &ARROW_ERROR_CLASS[..],
+            ),
+            RError::Message { message, backtrace } => (
+                list!(
+                    message = message.clone(),
+                    call = (),
+                    backtrace = backtrace.clone()
+                ),
+                &ERROR_CLASS[..],
+            ),
+            RError::Panic { message, backtrace } => (
+                list!(
+                    message = format!("Rust panic: {}", message),
+                    call = (),
+                    backtrace = backtrace.clone()
+                ),
+                &PANIC_CLASS[..],
+            ),
+        };
+        let class: Vec<String> = class.iter().map(|x| x.to_string()).collect();
+        condition
+            .set_attrib(class_symbol(), class)
+            .unwrap_or_else(|_| condition.clone())
+    }
+}
+
+impl From<RecordBatchesError> for RError {
+    fn from(source: RecordBatchesError) -> Self {
+        RError::Arrow {
+            source,
+            backtrace: captured(Backtrace::capture()),
+        }
+    }
+}
+
+impl From<&'static str> for RError {
+    fn from(message: &'static str) -> Self {
+        RError::Message {
+            message: message.to_string(),
+            backtrace: captured(Backtrace::capture()),
+        }
+    }
+}
+
+impl From<String> for RError {
+    fn from(message: String) -> Self {
+        RError::Message {
+            message,
+            backtrace: captured(Backtrace::capture()),
+        }
+    }
+}
+
+// -=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-
+// The Boundary ----------------------------------------------------------------
+// -=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-
+
+thread_local! {
+    // Whether this thread is currently inside `r_boundary`
+    static IN_BOUNDARY: Cell<bool> = const { Cell::new(false) };
+    // The message and backtrace of the last panic caught by the hook
+    static LAST_PANIC: RefCell<Option<(String, Option<String>)>> = const { RefCell::new(None) };
+}
+
+static INSTALL_HOOK: Once = Once::new();
+
+/// Runs the body of an exported function, converting its result to an `Robj`.
+/// If `f` returns an error or panics, the error is signalled to R (see the
+/// module documentation), and this never returns.
+pub(crate) fn r_boundary<T, F>(f: F) -> Robj
+where
+    T: Into<Robj>,
+    F: FnOnce() -> RResult<T>,
+{
+    install_panic_hook();
+    LAST_PANIC.with(|x| x.borrow_mut().take());
+    let was_inside = IN_BOUNDARY.with(|x| x.replace(true));
+    let result = panic::catch_unwind(AssertUnwindSafe(|| f().map(Into::into)));
+    IN_BOUNDARY.with(|x| x.set(was_inside));
+
+    let err = match result {
+        Ok(Ok(robj)) => return robj,
+        Ok(Err(err)) => err,
+        Err(payload) => {
+            let (message, backtrace) = LAST_PANIC
+                .with(|x| x.borrow_mut().take())
+                .unwrap_or_else(|| (panic_message(&*payload), None));
+            RError::Panic { message, backtrace }
+        }
+    };
+    let condition = err.to_condition();
+    drop(err);
+    throw_r_condition(condition)
+}
+
+/// Installs a panic hook that records the message and backtrace of panics
+/// inside [r_boundary] (instead of printing them), and defers to the previous
+/// hook for panics anywhere else
+fn install_panic_hook() {
+    INSTALL_HOOK.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if IN_BOUNDARY.with(|x| x.get()) {
+                let message = panic_message(info.payload());
+                let location = info
+                    .location()
+                    .map(|l| format!(" at {}:{}", l.file(), l.line()))
+                    .unwrap_or_default();
+                let backtrace = captured(Backtrace::force_capture());
+                LAST_PANIC.with(|x| {
+                    *x.borrow_mut() = Some((format!("{}{}", message, location), backtrace))
+                });
+            } else {
+                previous(info);
+            }
+        }));
+    });
+}
+
+/// Extracts the message from a panic payload, which is a `&str` or `String`
+/// for panics raised with `panic!`, `expect`, and friends
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+fn captured(backtrace: Backtrace) -> Option<String> {
+    match backtrace.status() {
+        BacktraceStatus::Captured => Some(backtrace.to_string()),
+        _ => None,
+    }
+}
+
+/// Signals an R condition object with `stop()`. Like
+/// [throw_r_error](extendr_api::throw_r_error), this never returns, so
+/// `condition` is dropped (but kept protected by the call) before R unwinds
+/// the stack.
+pub(crate) fn throw_r_condition(condition: Robj) -> ! {
+    unsafe {
+        let stop = libR_sys::Rf_install(b"stop\0".as_ptr() as *const std::os::raw::c_char);
+        let call = libR_sys::Rf_protect(libR_sys::Rf_lang2(stop, condition.get()));
+        drop(condition);
+        libR_sys::Rf_eval(call, libR_sys::R_BaseEnv);
+    }
+    unreachable!("stop() returned")
+}
+
+#[allow(unused_imports)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_panic_message() {
+        let payload: Box<dyn std::any::Any + Send> = Box::new("boom");
+        assert_eq!(panic_message(&*payload), "boom");
+        let payload: Box<dyn std::any::Any + Send> = Box::new(String::from("bang"));
+        assert_eq!(panic_message(&*payload), "bang");
+        let payload: Box<dyn std::any::Any + Send> = Box::new(42);
+        assert_eq!(panic_message(&*payload), "unknown panic");
+    }
+
+    #[test]
+    fn test_errors_convert_to_rerror() {
+        let err: RError = RecordBatchesError::invalid("Expected a list.").into();
+        match err {
+            RError::Arrow { source, .. } => assert_eq!(source.context(), "Expected a list."),
+            _ => panic!("Expected an Arrow error"),
+        }
+        let err: RError = "Unknown compression".into();
+        assert!(matches!(err, RError::Message { .. }));
+    }
+}
@@ -0,0 +1,486 @@
+//! This module joins two sets of RecordBatches on one or more key columns,
+//! like `merge()` or dplyr's `*_join()` functions.
+//!
+//! The right side is read into a hash table from key to rows, and each row on
+//! the left side then looks up its matches. Every [JoinType] gives rows in the
+//! order of the left side, with each left row followed by its matches in the
+//! order of the right side. Rows of the right side without a match (for
+//! `right` and `full` joins) come last.
+//!
+//! Keys are compared by value, so integer keys of different widths match, and
+//! so do factors with different levels, but an integer key never matches a
+//! double key. Following SQL, NA keys don't match anything, unless
+//! [NaMatches::Na] is chosen to match them to each other like `merge()` does.
+//!
+//! The result has the key columns and the other columns of the left side,
+//! followed by the other columns of the right side. When one of those right
+//! columns has the name of any left column, keys included, the two get the
+//! suffixes `.x` and `.y`, like `merge()`. Columns keep their nullability,
+//! unless the join can leave them without a value.
+
+use crate::aggregate::{KeyValue, Values};
+use crate::relational::{column_index, combine_arrays, combine_batches, without_row_names};
+use crate::structs::{RecordBatches, RecordBatchesError};
+use arrow::{
+    array::{make_array, ArrayRef, MutableArrayData, UInt32Array},
+    compute::{cast, take},
+    datatypes::{DataType, Field, Schema},
+    record_batch::RecordBatch,
+};
+use std::collections::HashMap;
+use std::mem::discriminant;
+use std::sync::Arc;
+
+type Result<T> = std::result::Result<T, RecordBatchesError>;
+
+/// Which rows a join keeps
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum JoinType {
+    /// Rows with matches on both sides
+    Inner,
+    /// Every row of the left side, with its matches if it has any
+    Left,
+    /// Every row of the right side, with its matches if it has any
+    Right,
+    /// Every row of both sides
+    Full,
+    /// Rows of the left side with a match, without the right side's columns
+    Semi,
+    /// Rows of the left side without a match, without the right side's columns
+    Anti,
+}
+
+impl JoinType {
+    pub fn parse(join_type: &str) -> Result<Self> {
+        match join_type.to_lowercase().as_str() {
+            "inner" => Ok(JoinType::Inner),
+            "left" => Ok(JoinType::Left),
+            "right" => Ok(JoinType::Right),
+            "full" => Ok(JoinType::Full),
+            "semi" => Ok(JoinType::Semi),
+            "anti" => Ok(JoinType::Anti),
+            _ => Err(RecordBatchesError::invalid(
+                "Unknown join type, expected one of 'inner', 'left', 'right', 'full', 'semi', or 'anti'",
+            )),
+        }
+    }
+
+    fn keeps_right_columns(self) -> bool {
+        !matches!(self, JoinType::Semi | JoinType::Anti)
+    }
+
+    fn keeps_unmatched_left(self) -> bool {
+        matches!(self, JoinType::Left | JoinType::Full)
+    }
+
+    fn keeps_unmatched_right(self) -> bool {
+        matches!(self, JoinType::Right | JoinType::Full)
+    }
+}
+
+/// Whether NA keys match each other
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum NaMatches {
+    /// NA keys match other NA keys, like `merge()` and dplyr
+    Na,
+    /// NA keys never match, like SQL
+    Never,
+}
+
+impl NaMatches {
+    pub fn parse(na_matches: &str) -> Result<Self> {
+        match na_matches.to_lowercase().as_str() {
+            "na" => Ok(NaMatches::Na),
+            "never" => Ok(NaMatches::Never),
+            _ => Err(RecordBatchesError::invalid(
+                "Unknown NA matching, expected one of 'na' or 'never'",
+            )),
+        }
+    }
+}
+
+/// Joins the rows of `left` and `right` whose key columns are equal. Each of
+/// `by` pairs a key column of the left side with one of the right side.
+pub(crate) fn join_record_batches_fn(
+    left: RecordBatches,
+    right: RecordBatches,
+    by: &[(String, String)],
+    join_type: JoinType,
+    na_matches: NaMatches,
+) -> Result<RecordBatches> {
+    if by.is_empty() {
+        return Err(RecordBatchesError::invalid(
+            "A join needs at least one key column",
+        ));
+    }
+    let empty = || RecordBatchesError::invalid("Cannot join an empty set of RecordBatches");
+    let left = combine_batches(left)?.ok_or_else(empty)?;
+    let right = combine_batches(right)?.ok_or_else(empty)?;
+    check_num_rows(&left)?;
+    check_num_rows(&right)?;
+    let left_schema = left.schema();
+    let right_schema = right.schema();
+
+    let mut left_keys = Vec::with_capacity(by.len());
+    let mut right_keys = Vec::with_capacity(by.len());
+    for (left_name, right_name) in by {
+        let left_index = column_index(&left_schema, left_name)?;
+        let right_index = column_index(&right_schema, right_name)?;
+        let left_values = key_values(left.column(left_index), left_name)?;
+        let right_values = key_values(right.column(right_index), right_name)?;
+        if discriminant(&left_values) != discriminant(&right_values) {
+            return Err(RecordBatchesError::schema(
+                "Join keys have different types",
+                Some(format!("{:?}", left_schema.field(left_index).data_type())),
+                Some(format!("{:?}", right_schema.field(right_index).data_type())),
+            )
+            .for_field(left_name.as_str()));
+        }
+        left_keys.push((left_index, left_values));
+        right_keys.push((right_index, right_values));
+    }
+
+    let key_of = |keys: &[(usize, Values)], row: usize| -> Option<Vec<KeyValue>> {
+        let key: Vec<KeyValue> = keys.iter().map(|(_, values)| values.value(row)).collect();
+        if na_matches == NaMatches::Never && key.contains(&KeyValue::Null) {
+            None
+        } else {
+            Some(key)
+        }
+    };
+    let mut table: HashMap<Vec<KeyValue>, Vec<usize>> = HashMap::new();
+    for row in 0..right.num_rows() {
+        if let Some(key) = key_of(&right_keys, row) {
+            table.entry(key).or_default().push(row);
+        }
+    }
+
+    let mut left_rows: Vec<Option<u32>> = Vec::new();
+    let mut right_rows: Vec<Option<u32>> = Vec::new();
+    let mut right_matched = vec![false; right.num_rows()];
+    for row in 0..left.num_rows() {
+        let matches = key_of(&left_keys, row).and_then(|key| table.get(&key));
+        match (matches, join_type) {
+            (Some(_), JoinType::Semi) | (None, JoinType::Anti) => left_rows.push(Some(row as u32)),
+            (Some(_), JoinType::Anti) | (None, JoinType::Semi) => {}
+            (Some(matches), _) => {
+                for &right_row in matches {
+                    left_rows.push(Some(row as u32));
+                    right_rows.push(Some(right_row as u32));
+                    right_matched[right_row] = true;
+                }
+            }
+            (None, join_type) if join_type.keeps_unmatched_left() => {
+                left_rows.push(Some(row as u32));
+                right_rows.push(None);
+            }
+            (None, _) => {}
+        }
+    }
+    // Rows of the right side without a match come last, so the keys of the
+    // left side can be completed with those of the right
+    let matched_rows = left_rows.len();
+    if join_type.keeps_unmatched_right() {
+        for (row, _) in right_matched.iter().enumerate().filter(|(_, &x)| !x) {
+            left_rows.push(None);
+            right_rows.push(Some(row as u32));
+        }
+    }
+
+    let matched_left = UInt32Array::from(left_rows[..matched_rows].to_vec());
+    let unmatched_right =
+        UInt32Array::from(right_rows[matched_rows.min(right_rows.len())..].to_vec());
+    let left_indices = UInt32Array::from(left_rows);
+    let right_indices = UInt32Array::from(right_rows);
+    let left_key_indices: Vec<usize> = left_keys.iter().map(|(i, _)| *i).collect();
+    let right_key_indices: Vec<usize> = right_keys.iter().map(|(i, _)| *i).collect();
+    let right_names: Vec<&str> = right_schema
+        .fields()
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| join_type.keeps_right_columns() && !right_key_indices.contains(i))
+        .map(|(_, field)| field.name().as_str())
+        .collect();
+    let left_names: Vec<&str> = left_schema
+        .fields()
+        .iter()
+        .map(|field| field.name().as_str())
+        .collect();
+
+    let mut fields = Vec::new();
+    let mut columns = Vec::new();
+    for (i, field) in left_schema.fields().iter().enumerate() {
+        let name = field.name().as_str();
+        let (column, nullable) = match left_key_indices.iter().position(|&x| x == i) {
+            Some(k) if join_type.keeps_unmatched_right() => {
+                let right_field = right_schema.field(right_key_indices[k]);
+                let unmatched =
+                    take_rows(right.column(right_key_indices[k]), &unmatched_right, name)?;
+                let unmatched = if unmatched.data_type() == field.data_type() {
+                    unmatched
+                } else {
+                    cast(&unmatched, field.data_type()).map_err(|e| {
+                        RecordBatchesError::arrow("Error casting join key")(e).for_field(name)
+                    })?
+                };
+                let matched = take_rows(left.column(i), &matched_left, name)?;
+                let column =
+                    combine_arrays(&[&matched, &unmatched]).map_err(|e| e.for_field(name))?;
+                (column, field.is_nullable() || right_field.is_nullable())
+            }
+            Some(_) => (
+                take_rows(left.column(i), &left_indices, name)?,
+                field.is_nullable(),
+            ),
+            None => (
+                take_rows(left.column(i), &left_indices, name)?,
+                field.is_nullable() || join_type.keeps_unmatched_right(),
+            ),
+        };
+        let name = if right_names.contains(&name) {
+            format!("{}.x", name)
+        } else {
+            name.to_string()
+        };
+        fields.push(renamed_field(field, &name, nullable, None));
+        columns.push(column);
+    }
+
+    let mut next_dict_id = left_schema
+        .fields()
+        .iter()
+        .filter_map(|field| field.dict_id())
+        .max()
+        .map_or(0, |id| id + 1);
+    for name in &right_names {
+        let i = column_index(&right_schema, name)?;
+        let field = right_schema.field(i);
+        let column = take_rows(right.column(i), &right_indices, name)?;
+        let nullable = field.is_nullable() || join_type.keeps_unmatched_left();
+        let dict_id = field.dict_id().map(|_| {
+            next_dict_id += 1;
+            next_dict_id - 1
+        });
+        let name = if left_names.contains(name) {
+            format!("{}.y", name)
+        } else {
+            name.to_string()
+        };
+        fields.push(renamed_field(field, &name, nullable, dict_id));
+        columns.push(column);
+    }
+
+    let metadata = without_row_names(&left_schema)?.metadata().clone();
+    let schema = Arc::new(Schema::new_with_metadata(fields, metadata));
+    let joined = RecordBatch::try_new(schema, columns).map_err(RecordBatchesError::arrow(
+        "Error creating joined RecordBatch",
+    ))?;
+    Ok(vec![joined].into_iter().collect())
+}
+
+/// Rows are picked with `UInt32Array` indices, like arrow's `take` expects,
+/// so each side can have at most `u32::MAX` rows
+fn check_num_rows(batch: &RecordBatch) -> Result<()> {
+    if batch.num_rows() > u32::MAX as usize {
+        return Err(RecordBatchesError::invalid(
+            "Cannot join more than 4294967295 rows on either side",
+        ));
+    }
+    Ok(())
+}
+
+/// The values of a key column, with dictionaries (like factors) decoded so
+/// keys with different dictionaries can be compared
+fn key_values(column: &ArrayRef, name: &str) -> Result<Values> {
+    let values = match column.data_type() {
+        DataType::Dictionary(_, value_type) => cast(column, value_type)
+            .map_err(|e| RecordBatchesError::arrow("Error decoding join key")(e).for_field(name))?,
+        _ => column.clone(),
+    };
+    Values::try_new(&values).map_err(|e| e.for_field(name))
+}
+
+/// Takes rows of a column, where a null index gives a null. Unlike `take`,
+/// this also works when the column is empty and every index is null.
+fn take_rows(column: &ArrayRef, indices: &UInt32Array, name: &str) -> Result<ArrayRef> {
+    if column.is_empty() {
+        let data = column.data();
+        let mut nulls = MutableArrayData::new(vec![&data], true, indices.len());
+        nulls.extend_nulls(indices.len());
+        return Ok(make_array(Arc::new(nulls.freeze())));
+    }
+    take(column.as_ref(), indices, None)
+        .map_err(|e| RecordBatchesError::arrow("Error taking joined rows")(e).for_field(name))
+}
+
+fn renamed_field(field: &Field, name: &str, nullable: bool, dict_id: Option<i64>) -> Field {
+    match (field.dict_id(), field.dict_is_ordered()) {
+        (Some(id), Some(ordered)) => Field::new_dict(
+            name,
+            field.data_type().clone(),
+            nullable,
+            dict_id.unwrap_or(id),
+            ordered,
+        ),
+        _ => Field::new(name, field.data_type().clone(), nullable),
+    }
+}
+
+#[allow(unused_imports)]
+mod test {
+    use super::*;
+    use arrow::array::{Array, Int32Array, Int64Array, StringArray};
+
+    #[allow(dead_code)]
+    fn side(name: &str, keys: ArrayRef, values: Vec<Option<&str>>) -> RecordBatches {
+        let schema = Schema::new(vec![
+            Field::new("id", keys.data_type().clone(), true),
+            Field::new(name, DataType::Utf8, true),
+        ]);
+        let values: ArrayRef = Arc::new(StringArray::from(values));
+        vec![RecordBatch::try_new(Arc::new(schema), vec![keys, values]).unwrap()]
+            .into_iter()
+            .collect()
+    }
+
+    #[allow(dead_code)]
+    fn left() -> RecordBatches {
+        let keys = Arc::new(Int32Array::from(vec![Some(1), Some(2), None, Some(3)]));
+        side("x", keys, vec![Some("a"), Some("b"), Some("c"), Some("d")])
+    }
+
+    #[allow(dead_code)]
+    fn right() -> RecordBatches {
+        let keys = Arc::new(Int64Array::from(vec![
+            Some(3),
+            None,
+            Some(1),
+            Some(1),
+            Some(4),
+        ]));
+        side(
+            "x",
+            keys,
+            vec![Some("p"), Some("q"), Some("r"), Some("s"), Some("t")],
+        )
+    }
+
+    #[allow(dead_code)]
+    fn join(join_type: JoinType, na_matches: NaMatches) -> RecordBatch {
+        let by = vec![("id".to_string(), "id".to_string())];
+        let joined = join_record_batches_fn(left(), right(), &by, join_type, na_matches).unwrap();
+        joined.into_iter().next().unwrap()
+    }
+
+    #[allow(dead_code)]
+    fn strings(batch: &RecordBatch, i: usize) -> Vec<Option<String>> {
+        let array = batch
+            .column(i)
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        (0..array.len())
+            .map(|j| Some(array.value(j).to_string()).filter(|_| array.is_valid(j)))
+            .collect()
+    }
+
+    #[allow(dead_code)]
+    fn some(values: &[&str]) -> Vec<Option<String>> {
+        values
+            .iter()
+            .map(|x| Some(x.to_string()).filter(|x| x != "NA"))
+            .collect()
+    }
+
+    #[test]
+    fn test_inner_and_left_joins() {
+        let joined = join(JoinType::Inner, NaMatches::Never);
+        assert_eq!(joined.num_columns(), 3);
+        assert_eq!(joined.schema().field(1).name(), "x.x");
+        assert_eq!(joined.schema().field(2).name(), "x.y");
+        assert_eq!(strings(&joined, 1), some(&["a", "a", "d"]));
+        assert_eq!(strings(&joined, 2), some(&["r", "s", "p"]));
+
+        let joined = join(JoinType::Left, NaMatches::Never);
+        assert_eq!(strings(&joined, 1), some(&["a", "a", "b", "c", "d"]));
+        assert_eq!(strings(&joined, 2), some(&["r", "s", "NA", "NA", "p"]));
+
+        let joined = join(JoinType::Left, NaMatches::Na);
+        assert_eq!(strings(&joined, 2), some(&["r", "s", "NA", "q", "p"]));
+    }
+
+    #[test]
+    fn test_right_and_full_joins_complete_the_keys() {
+        let joined = join(JoinType::Full, NaMatches::Never);
+        let keys = joined
+            .column(0)
+            .as_any()
+            .downcast_ref::<Int32Array>()
+            .unwrap();
+        let keys: Vec<Option<i32>> = (0..keys.len())
+            .map(|i| Some(keys.value(i)).filter(|_| keys.is_valid(i)))
+            .collect();
+        assert_eq!(
+            keys,
+            vec![Some(1), Some(1), Some(2), None, Some(3), None, Some(4)]
+        );
+        assert_eq!(
+            strings(&joined, 2),
+            some(&["r", "s", "NA", "NA", "p", "q", "t"])
+        );
+        assert!(joined.schema().field(1).is_nullable());
+
+        let joined = join(JoinType::Right, NaMatches::Na);
+        assert_eq!(strings(&joined, 1), some(&["a", "a", "c", "d", "NA"]));
+        assert_eq!(strings(&joined, 2), some(&["r", "s", "q", "p", "t"]));
+    }
+
+    #[test]
+    fn test_semi_and_anti_joins_keep_left_columns() {
+        let joined = join(JoinType::Semi, NaMatches::Never);
+        assert_eq!(joined.num_columns(), 2);
+        assert_eq!(joined.schema().field(1).name(), "x");
+        assert_eq!(strings(&joined, 1), some(&["a", "d"]));
+
+        let joined = join(JoinType::Anti, NaMatches::Never);
+        assert_eq!(strings(&joined, 1), some(&["b", "c"]));
+    }
+
+    #[test]
+    fn test_right_columns_named_like_left_keys_get_suffixes() {
+        let schema = Schema::new(vec![
+            Field::new("k", DataType::Int64, true),
+            Field::new("id", DataType::Utf8, true),
+        ]);
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(Int64Array::from(vec![1, 3])),
+            Arc::new(StringArray::from(vec!["p", "q"])),
+        ];
+        let right = vec![RecordBatch::try_new(Arc::new(schema), columns).unwrap()]
+            .into_iter()
+            .collect();
+        let by = vec![("id".to_string(), "k".to_string())];
+        let joined =
+            join_record_batches_fn(left(), right, &by, JoinType::Inner, NaMatches::Never).unwrap();
+        let joined = joined.into_iter().next().unwrap();
+        let schema = joined.schema();
+        let names: Vec<&str> = schema.fields().iter().map(|f| f.name().as_str()).collect();
+        assert_eq!(names, vec!["id.x", "x", "id.y"]);
+        assert_eq!(strings(&joined, 2), some(&["p", "q"]));
+    }
+
+    #[test]
+    fn test_join_key_types_must_be_comparable() {
+        let keys = Arc::new(StringArray::from(vec!["1"]));
+        let by = vec![("id".to_string(), "id".to_string())];
+        let err = join_record_batches_fn(
+            left(),
+            side("y", keys, vec![Some("z")]),
+            &by,
+            JoinType::Inner,
+            NaMatches::Never,
+        )
+        .unwrap_err();
+        assert_eq!(err.field(), Some("id"));
+    }
+}
@@ -1,8 +1,24 @@
-use crate::structs::{CharVec, RecordBatches};
+// The wrappers #[extendr] generates take raw SEXP's from R
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
+// RecordBatchesError keeps the context of the error, which makes it large
+#![allow(clippy::result_large_err)]
+
+use crate::errors::r_boundary;
+use crate::structs::{
+    CDataRecordBatches, CharVec, ComplexVec, IpcCompression, IpcFileRecordBatches, IpcFileSource,
+    NaVec, NamedRecordBatches, RMatrix, RSlice, RecordBatchFileSink, RecordBatchStream,
+    RecordBatchStreamSink, RecordBatches, RecordBatchesError,
+};
 use extendr_api::prelude::*;
 
+mod aggregate;
 mod algos;
+mod errors;
+mod join;
+mod parquet_io;
 mod passing_values;
+mod predicate;
+mod relational;
 mod structs;
 mod utils;
 
@@ -26,8 +42,8 @@ mod utils;
 ///
 /// @export
 #[extendr]
-fn pass_single_integer(n: Option<i32>) -> Option<i32> {
-    passing_values::pass_single_integer_fn(n)
+fn pass_single_integer(n: Option<i32>) -> Robj {
+    r_boundary(|| Ok(passing_values::pass_single_integer_fn(n)))
 }
 
 /// Multiplies an integer vector by 10 in Rust
@@ -37,22 +53,24 @@ fn pass_single_integer(n: Option<i32>) -> Option<i32> {
 ///
 /// The vector passed to this function must be an integer (from as.integer(n)) or
 /// an NA_integer_. The Rust function signature is
-/// `fn pass_multiple_integers(n: Int) -> Int`. In the current version of
-/// extendr-api (0.2.0), NA's are implicitly converted to 0's, so if you need
-/// NA handling you'll need to take care of that yourself for now.
+/// `fn pass_multiple_integers_fn(n: RSlice<i32>) -> RSliceMut<i32>`, where
+/// `RSlice` views the memory of the R vector without copying it. The result
+/// is a single copy of `n`, changed in place, with NA's skipped so they come
+/// back as NA's. Like in R, results too large for an integer are NA.
 ///
-/// @param n An integer vector to treat as an extendr_api::Int in Rust
+/// @param n An integer vector to treat as an RSlice<i32> in Rust
 /// @return n * 10
 ///
 /// @examples
 /// pass_multiple_integers(as.integer(c(5, 7, 9)))  # c(50, 70, 90)
 /// pass_multiple_integers(as.integer(30))          # 300
-/// pass_multiple_integers(NA_integer_)             # 0
+/// pass_multiple_integers(c(1L, NA_integer_))      # c(10, NA)
+/// pass_multiple_integers(c(1L, -.Machine$integer.max))  # c(10, NA)
 ///
 /// @export
 #[extendr]
-fn pass_multiple_integers(n: Int) -> Int {
-    passing_values::pass_multiple_integers_fn(n)
+fn pass_multiple_integers(n: RSlice<i32>) -> Robj {
+    r_boundary(|| Ok(passing_values::pass_multiple_integers_fn(n)))
 }
 
 /// Multiplies a double by 10 in Rust
@@ -76,8 +94,8 @@ fn pass_multiple_integers(n: Int) -> Int {
 ///
 /// @export
 #[extendr]
-fn pass_single_double(f: Option<f64>) -> Option<f64> {
-    passing_values::pass_single_double_fn(f)
+fn pass_single_double(f: Option<f64>) -> Robj {
+    r_boundary(|| Ok(passing_values::pass_single_double_fn(f)))
 }
 
 /// Multiplies a double vector by 10 in Rust
@@ -87,10 +105,12 @@ fn pass_single_double(f: Option<f64>) -> Option<f64> {
 ///
 /// The vector passed to this function must be a double vector (default numeric
 /// vector type in R) or an NA_real_. The Rust function signature is
-/// `fn pass_multiple_doubles_fn(f: Real) -> Real`. NA's, NaN's, Inf's, and
-/// -Inf's are handled implicitly by the f64 specification.
+/// `fn pass_multiple_doubles_fn(f: RSlice<f64>) -> RSliceMut<f64>`, so the
+/// vector is copied once and multiplied in place. NA's, NaN's, Inf's, and
+/// -Inf's are handled by the f64 specification, and an NA stays an NA while
+/// a NaN stays a NaN.
 ///
-/// @param f A double (float) vector to treat as an extendr_api::Real in Rust
+/// @param f A double (float) vector to treat as an RSlice<f64> in Rust
 /// @return f * 10
 ///
 /// @examples
@@ -98,8 +118,8 @@ fn pass_single_double(f: Option<f64>) -> Option<f64> {
 ///
 /// @export
 #[extendr]
-fn pass_multiple_doubles(f: Real) -> Real {
-    passing_values::pass_multiple_doubles_fn(f)
+fn pass_multiple_doubles(f: RSlice<f64>) -> Robj {
+    r_boundary(|| Ok(passing_values::pass_multiple_doubles_fn(f)))
 }
 
 /// Flips a logical value in Rust
@@ -121,8 +141,8 @@ fn pass_multiple_doubles(f: Real) -> Real {
 ///
 /// @export
 #[extendr]
-fn pass_single_logical(b: Option<bool>) -> Option<bool> {
-    passing_values::pass_single_logical_fn(b)
+fn pass_single_logical(b: Option<bool>) -> Robj {
+    r_boundary(|| Ok(passing_values::pass_single_logical_fn(b)))
 }
 
 /// Flip a logical vector in Rust
@@ -132,10 +152,10 @@ fn pass_single_logical(b: Option<bool>) -> Option<bool> {
 ///
 /// The vector passed to this function must be contain logical values, the
 /// boolean type in R, or NAs (plain NA is logical). The Rust function
-/// signature is `fn pass_multiple_logicals_fn(b: Logical) -> Logical`, with
-/// NA's being handled as `None` in Rust.
+/// signature is `fn pass_multiple_logicals_fn(b: NaVec<bool>) -> NaVec<bool>`,
+/// with NA's being handled as `None` in Rust.
 ///
-/// @param b A logical (boolean) vector to treat as an extender_api::Logical in Rust
+/// @param b A logical (boolean) vector to treat as a NaVec<bool> in Rust
 /// @return !b
 ///
 /// @examples
@@ -143,8 +163,8 @@ fn pass_single_logical(b: Option<bool>) -> Option<bool> {
 ///
 /// @export
 #[extendr]
-fn pass_multiple_logicals(b: Logical) -> Logical {
-    passing_values::pass_multiple_logicals_fn(b)
+fn pass_multiple_logicals(b: NaVec<bool>) -> Robj {
+    r_boundary(|| Ok(passing_values::pass_multiple_logicals_fn(b)))
 }
 
 /// Left shift a raw value in Rust
@@ -169,8 +189,8 @@ fn pass_multiple_logicals(b: Logical) -> Logical {
 ///
 /// @export
 #[extendr]
-fn pass_single_raw(r: u8) -> u8 {
-    passing_values::pass_single_raw_fn(r)
+fn pass_single_raw(r: u8) -> Robj {
+    r_boundary(|| Ok(passing_values::pass_single_raw_fn(r)))
 }
 
 /// Left shift a raw vector in Rust
@@ -191,8 +211,8 @@ fn pass_single_raw(r: u8) -> u8 {
 ///
 /// @export
 #[extendr]
-fn pass_multiple_raws(r: &[u8]) -> Vec<u8> {
-    passing_values::pass_multiple_raws_fn(r)
+fn pass_multiple_raws(r: &[u8]) -> Robj {
+    r_boundary(|| Ok(passing_values::pass_multiple_raws_fn(r)))
 }
 
 /// Uppercase a character value in Rust
@@ -214,8 +234,8 @@ fn pass_multiple_raws(r: &[u8]) -> Vec<u8> {
 ///
 /// @export
 #[extendr]
-fn pass_single_character(s: Option<String>) -> Option<String> {
-    passing_values::pass_single_character_fn(s)
+fn pass_single_character(s: Option<String>) -> Robj {
+    r_boundary(|| Ok(passing_values::pass_single_character_fn(s)))
 }
 
 /// Uppercase a character vector in Rust
@@ -237,13 +257,74 @@ fn pass_single_character(s: Option<String>) -> Option<String> {
 ///
 /// @export
 #[extendr]
-fn pass_multiple_characters(s: CharVec) -> CharVec {
-    passing_values::pass_multiple_characters_fn(s)
+fn pass_multiple_characters(s: CharVec) -> Robj {
+    r_boundary(|| Ok(passing_values::pass_multiple_characters_fn(s)))
+}
+
+/// Conjugate a complex value in Rust
+///
+/// Demonstrates passing a complex value back and forth between R and Rust,
+/// conjugating it in Rust to prove it works.
+///
+/// The value passed to this function must be a single complex value, or an
+/// NA_complex_. extendr_api (v0.2.0) doesn't convert complex values, so this
+/// takes a `ComplexVec` (see `pass_multiple_complexes()`) of length one and
+/// calls `fn pass_single_complex_fn(z: Option<Complex64>) -> Option<Complex64>`,
+/// with NA's being handled as `None` in Rust.
+///
+/// @param z A complex value to treat as a num_complex::Complex64 in Rust
+/// @return Conj(z)
+///
+/// @examples
+/// pass_single_complex(3+4i)         # 3-4i
+/// pass_single_complex(NA_complex_)  # NA_complex_
+///
+/// @export
+#[extendr]
+fn pass_single_complex(z: ComplexVec) -> Robj {
+    r_boundary(|| match z.len() {
+        1 => {
+            let z = z.into_iter().next().flatten();
+            Ok(ComplexVec::from(vec![
+                passing_values::pass_single_complex_fn(z),
+            ]))
+        }
+        _ => Err("Input must be a single complex value.".into()),
+    })
+}
+
+/// Conjugate a complex vector in Rust
+///
+/// Demonstrates passing a complex vector back and forth between R and Rust,
+/// conjugating the values in Rust to prove it works.
+///
+/// The vector passed to this function must be a complex vector. The Rust
+/// function signature is
+/// `fn pass_multiple_complexes_fn(z: ComplexVec) -> ComplexVec`, where
+/// `ComplexVec` is a `NaVec<Complex64>` wrapping a
+/// `Vec<Option<num_complex::Complex64>>`. A value with an NA in either part
+/// is handled as `None` in Rust and comes back as NA_complex_, while NaN's
+/// and Inf's are kept.
+///
+/// @param z A complex vector to treat as a ComplexVec in Rust
+/// @return Conj(z)
+///
+/// @examples
+/// pass_multiple_complexes(c(1+2i, NA, -3i))  # c(1-2i, NA, 0+3i)
+///
+/// @export
+#[extendr]
+fn pass_multiple_complexes(z: ComplexVec) -> Robj {
+    r_boundary(|| Ok(passing_values::pass_multiple_complexes_fn(z)))
 }
 
 /// Bubble Sort a vector of doubles
 ///
-/// Demonstrates using Rust to perform a Bubble Sort on a vector of doubles
+/// Demonstrates using Rust to perform a Bubble Sort on a vector of doubles.
+/// The Rust function signature is
+/// `fn bubble_sort_fn(input: RSlice<f64>) -> RSliceMut<f64>`, so the vector
+/// is copied once and sorted in place, rather than collected into a `Vec` and
+/// copied again into a new R vector.
 ///
 /// @param input A double vector to sort
 /// @return a sorted vector of doubles
@@ -252,8 +333,57 @@ fn pass_multiple_characters(s: CharVec) -> CharVec {
 ///
 /// @export
 #[extendr]
-fn bubble_sort(input: Real) -> Real {
-    algos::bubble_sort_fn(input)
+fn bubble_sort(input: RSlice<f64>) -> Robj {
+    r_boundary(|| Ok(algos::bubble_sort_fn(input)))
+}
+
+/// Transpose a matrix in Rust
+///
+/// Demonstrates passing a matrix back and forth between R and Rust, keeping
+/// its `dim` and `dimnames` attributes, by transposing it in Rust.
+///
+/// The matrix passed to this function must be a double, integer, or logical
+/// matrix. It's read as an `RMatrix<T>` in Rust, which holds the values in
+/// R's column-major order along with the dimensions and the row and column
+/// names, and is returned with its type kept and its names swapped, like
+/// `t()`.
+///
+/// @param x A double, integer, or logical matrix
+/// @return t(x)
+///
+/// @examples
+/// matrix_transpose(matrix(1:6, nrow = 2))  # matrix(c(1, 3, 5, 2, 4, 6), nrow = 3)
+///
+/// @export
+#[extendr]
+fn matrix_transpose(x: Robj) -> Robj {
+    r_boundary(|| Ok(algos::matrix_transpose_fn(&x)?))
+}
+
+/// Multiply two matrices in Rust
+///
+/// Demonstrates passing several matrices from R to Rust, and a new one back,
+/// by multiplying them in Rust.
+///
+/// The Rust function signature is
+/// `fn matrix_multiply_fn(x: RMatrix<f64>, y: RMatrix<f64>)`. Both matrices
+/// must be double matrices (use `storage.mode(x) <- "double"` for integer
+/// ones), and the number of columns of `x` must match the number of rows of
+/// `y`. Like `%*%`, the result has the row names of `x` and the column names
+/// of `y`, and NA's propagate.
+///
+/// @param x A double matrix
+/// @param y A double matrix with as many rows as `x` has columns
+/// @return x %*% y
+///
+/// @examples
+/// x <- matrix(c(1, 2, 3, 4, 5, 6), nrow = 2)
+/// matrix_multiply(x, t(x))  # x %*% t(x)
+///
+/// @export
+#[extendr]
+fn matrix_multiply(x: RMatrix<f64>, y: RMatrix<f64>) -> Robj {
+    r_boundary(|| Ok(algos::matrix_multiply_fn(x, y)?))
 }
 
 /// Pass an Arrow RecordBatch back and forth
@@ -266,7 +396,9 @@ fn bubble_sort(input: Real) -> Real {
 /// RecordBatches and back to a serialized RecordBatch using the IPC
 /// specification.
 ///
-/// @param srb a raw vector representing the contents of an IPC buffer
+/// @param srb a raw vector representing the contents of an IPC buffer, a data
+///   frame (see [data_frame_to_record_batch()]), or a list of data frames, one
+///   per RecordBatch
 /// @return RecordBatches to be serialized back to an IPC buffer
 ///
 /// @examples
@@ -277,8 +409,811 @@ fn bubble_sort(input: Real) -> Real {
 ///
 /// @export
 #[extendr]
-fn pass_arrow_record_batch(srb: RecordBatches) -> RecordBatches {
-    srb
+fn pass_arrow_record_batch(srb: Robj) -> Robj {
+    r_boundary(|| Ok(RecordBatches::try_from_robj(&srb)?.into_robj()?))
+}
+
+/// Convert a data frame to an Arrow RecordBatch
+///
+/// Demonstrates building an Arrow RecordBatch from a data frame in Rust,
+/// without the arrow package
+///
+/// Integer, double, logical, and character columns become `Int32`, `Float64`,
+/// `Boolean`, and `Utf8` arrays (or `LargeUtf8`, for more than 2 GB of
+/// strings), lists of raw vectors become `Binary` (or `LargeBinary`) arrays,
+/// factors become dictionary arrays, Dates become `Date32` arrays, and POSIXct
+/// columns become microsecond timestamps in the column's time zone. hms
+/// columns become microsecond `Time64` arrays, and other difftime columns
+/// become microsecond `Duration` arrays. Other list columns become `List`
+/// arrays (with `NULL` elements as nulls) and data frame columns become
+/// `Struct` arrays, nested to any depth. NA's become nulls. Row names and any
+/// other attributes are kept in the schema metadata under the `r` key, the
+/// same way the arrow package keeps them.
+///
+/// Factors can only be top-level columns. A factor inside a list or data
+/// frame column is an error, because the IPC writer (like Arrow's, as of
+/// v3.0.0) only writes the dictionaries of top-level columns. Convert such
+/// factors to character vectors first.
+///
+/// @param df a data frame
+/// @return a raw vector containing the RecordBatch as an IPC stream
+///
+/// @examples
+/// mtcars_out <- data_frame_to_record_batch(mtcars)
+/// mtcars_in <- record_batch_to_data_frame(mtcars_out)
+///
+/// @export
+#[extendr]
+fn data_frame_to_record_batch(df: Robj) -> Robj {
+    r_boundary(|| Ok(RecordBatches::from_data_frame(&df)?.into_robj()?))
+}
+
+/// Convert Arrow RecordBatches to a data frame
+///
+/// Demonstrates converting Arrow RecordBatches to a data frame in Rust,
+/// without the arrow package
+///
+/// This is the reverse of [data_frame_to_record_batch()]. The rows of every
+/// RecordBatch are combined into one data frame, and nulls become NA's.
+/// Dictionary arrays of strings become factors (ordered, if the dictionary
+/// is), and timestamps of any unit become POSIXct with the timestamp's time
+/// zone. `Date32` and `Date64` arrays become Dates, `Time32` and `Time64`
+/// arrays become hms, `Duration` and day-time `Interval` arrays become
+/// difftimes in seconds, year-month `Interval` arrays become integer numbers
+/// of months, and `Decimal` arrays become doubles. `LargeUtf8` arrays become
+/// character vectors, and `Binary`, `LargeBinary`, and `FixedSizeBinary`
+/// arrays become lists of raw vectors. `List`, `LargeList`,
+/// `FixedSizeList`, and `Map` arrays become list columns (a map is a list of
+/// key/value data frames), and `Struct` arrays become data frame columns,
+/// with NA's (or `NULL` elements, in list columns) in the rows where the
+/// struct is null. Row names and attributes
+/// kept in the schema metadata (by this package or the arrow package) are
+/// restored.
+///
+/// @param srb a raw vector or file path containing an IPC stream or file, or
+///   anything else [pass_arrow_record_batch()] accepts
+/// @return a data frame
+///
+/// @examples
+/// mtcars_out <- data_frame_to_record_batch(mtcars)
+/// mtcars_in <- record_batch_to_data_frame(mtcars_out)
+///
+/// @export
+#[extendr]
+fn record_batch_to_data_frame(srb: Robj) -> Robj {
+    r_boundary(|| Ok(RecordBatches::try_from_robj(&srb)?.into_data_frame()?))
+}
+
+/// Describe the schema of Arrow RecordBatches
+///
+/// Demonstrates inspecting the schema of an IPC stream or file in Rust
+///
+/// Only the schema message is read, so this also works for streams without
+/// any RecordBatches. Types are reported using the names of Rust's
+/// `arrow::datatypes::DataType`, e.g. `Float64` or `Dictionary(Int8, Utf8)`.
+///
+/// @param srb a raw vector or file path containing an IPC stream or file
+/// @return a data frame with one row per field and the columns `name`, `type`,
+///   `nullable`, and `metadata` (a list of named character vectors). The
+///   schema's own metadata is in the `metadata` attribute.
+///
+/// @examples
+/// mtcars_out <- arrow::write_to_raw(arrow::record_batch(mtcars), "stream")
+/// record_batch_schema(mtcars_out)
+///
+/// @export
+#[extendr]
+fn record_batch_schema(srb: Robj) -> Robj {
+    r_boundary(|| Ok(structs::schema_to_robj(&*structs::read_ipc_schema(&srb)?)?))
+}
+
+/// Check that all Arrow RecordBatches share one schema
+///
+/// Demonstrates validating RecordBatches in Rust before processing them
+///
+/// Signals a `rustbind_arrow_error` naming the first RecordBatch (and field)
+/// whose schema differs from the first RecordBatch.
+///
+/// @param srb a raw vector, file path, or list of `rustbind_arrow_c_data`
+///   lists containing RecordBatches
+/// @return TRUE, if the schemas match
+///
+/// @examples
+/// mtcars_out <- arrow::write_to_raw(arrow::record_batch(mtcars), "stream")
+/// validate_record_batches(mtcars_out)
+///
+/// @export
+#[extendr]
+fn validate_record_batches(srb: Robj) -> Robj {
+    r_boundary(|| {
+        RecordBatches::try_from_robj(&srb)?.validate_schema()?;
+        Ok(true)
+    })
+}
+
+/// Require Arrow RecordBatches to have an expected schema
+///
+/// Demonstrates a Rust function declaring the schema it expects
+///
+/// Every RecordBatch in `srb` must have the fields of `expected`, with the
+/// same names, types, and order. A non-nullable field is accepted where a
+/// nullable field is expected. Mismatched input signals a
+/// `rustbind_arrow_error` describing the first field that doesn't match,
+/// before any processing happens.
+///
+/// @param srb a raw vector or file path containing an IPC stream or file
+/// @param expected a raw vector or file path containing an IPC stream or file
+///   with the expected schema. It doesn't need any RecordBatches.
+/// @return a raw vector containing the RecordBatches as an IPC stream
+///
+/// @examples
+/// mtcars_out <- arrow::write_to_raw(arrow::record_batch(mtcars), "stream")
+/// expected <- arrow::write_to_raw(arrow::record_batch(mtcars[0, ]), "stream")
+/// mtcars_in <- expect_record_batch_schema(mtcars_out, expected)
+///
+/// @export
+#[extendr]
+fn expect_record_batch_schema(srb: Robj, expected: Robj) -> Robj {
+    r_boundary(|| {
+        let expected = structs::read_ipc_schema(&expected)?;
+        let batches = RecordBatches::try_from_robj(&srb)?.expect_schema(&expected)?;
+        Ok(batches.into_robj()?)
+    })
+}
+
+/// Pass an Arrow RecordBatch back and forth without copying
+///
+/// Demonstrates a strategy for passing an Arrow RecordBatch from/to R through
+/// the Arrow C Data Interface
+///
+/// Instead of serializing the RecordBatch to an IPC buffer, each column is
+/// exported by R's arrow package into C structs (`ArrowArray` and
+/// `ArrowSchema`), and only the addresses of those structs are passed to
+/// Rust. Rust takes ownership of the column buffers without copying them, and
+/// hands them back to R the same way. Use [record_batch_to_c_data()] and
+/// [record_batch_from_c_data()] to do the R side of the exchange.
+///
+/// @param cdata a `rustbind_arrow_c_data` list from [record_batch_to_c_data()]
+/// @return a list of `rustbind_arrow_c_data` lists, one per RecordBatch
+///
+/// @examples
+/// mtcars_rb <- arrow::record_batch(mtcars)
+/// mtcars_c <- record_batch_to_c_data(mtcars_rb)
+/// mtcars_in <- record_batch_from_c_data(pass_arrow_c_data(mtcars_c))
+/// delete_c_data(mtcars_c)
+///
+/// @export
+#[extendr]
+fn pass_arrow_c_data(cdata: Robj) -> Robj {
+    r_boundary(|| {
+        let batches = RecordBatches::try_from_robj(&cdata)?;
+        Ok(CDataRecordBatches(batches).into_robj()?)
+    })
+}
+
+/// Release Arrow C Data Interface structs allocated by Rust
+///
+/// Frees the `ArrowArray` and `ArrowSchema` structs that Rust allocated when
+/// exporting RecordBatches through the C Data Interface. Columns that were
+/// already imported by R only have their (empty) structs freed, any others
+/// have their buffers released as well. Called for you by
+/// [record_batch_from_c_data()].
+///
+/// The addresses in `cdata` are set to zero once the structs are freed, so
+/// releasing the same structs twice is an error rather than a double free.
+///
+/// @param cdata a list of `rustbind_arrow_c_data` lists returned from Rust
+/// @return NULL
+///
+/// @export
+#[extendr]
+fn release_arrow_c_data(cdata: Robj) -> Robj {
+    r_boundary(|| Ok(structs::release_c_data(&cdata)?))
+}
+
+/// Write Arrow RecordBatches to an IPC stream file
+///
+/// Demonstrates writing RecordBatches incrementally from Rust
+///
+/// Each RecordBatch is written to `path` as soon as it is received, using a
+/// [RecordBatchStreamSink](crate::structs::RecordBatchStreamSink). The sink
+/// can write to any `std::io::Write` implementor (files, pipes, in-memory
+/// buffers), so the same approach works for Rust functions that produce their
+/// output one batch at a time.
+///
+/// @param srb a raw vector representing the contents of an IPC buffer, or a
+///   data frame (see [data_frame_to_record_batch()])
+/// @param path the file to write the IPC stream to
+/// @param compression the codec used to compress each RecordBatch, one of
+///   "uncompressed", "lz4", or "zstd"
+/// @return NULL
+///
+/// @examples
+/// mtcars_out <- arrow::write_to_raw(arrow::record_batch(mtcars), "stream")
+/// path <- tempfile(fileext = ".arrows")
+/// write_record_batch_stream(mtcars_out, path, "uncompressed")
+/// mtcars_in <- arrow::read_ipc_stream(path)
+///
+/// @export
+#[extendr]
+fn write_record_batch_stream(srb: Robj, path: &str, compression: &str) -> Robj {
+    r_boundary(|| {
+        let srb = RecordBatches::try_from_robj(&srb)?;
+        let compression = IpcCompression::parse(compression)?;
+        let schema = srb.schema().ok_or_else(|| {
+            RecordBatchesError::invalid("Cannot write an empty set of RecordBatches")
+        })?;
+        let file =
+            std::fs::File::create(path).map_err(RecordBatchesError::io("Could not create file"))?;
+        let mut sink = RecordBatchStreamSink::try_new(file, schema)?.with_compression(compression);
+        for batch in srb {
+            sink.write(&batch)?;
+        }
+        sink.into_inner()?;
+        Ok(())
+    })
+}
+
+/// Compress Arrow RecordBatches in Rust
+///
+/// Demonstrates compressing the body of an Arrow IPC stream
+///
+/// Each buffer of each RecordBatch is compressed separately with the LZ4 frame
+/// format or ZSTD, as R's arrow package does for `arrow::write_feather()`.
+/// Compressed streams and files (from Rust or from R) are decompressed
+/// automatically when they are passed to Rust, so they can be moved between
+/// processes or written to disk without inflating them.
+///
+/// @param srb a raw vector or file path containing an IPC stream or file
+/// @param compression the codec used to compress each RecordBatch, one of
+///   "uncompressed", "lz4", or "zstd"
+/// @return a raw vector containing a (compressed) IPC stream
+///
+/// @examples
+/// mtcars_out <- arrow::write_to_raw(arrow::record_batch(mtcars), "stream")
+/// mtcars_lz4 <- compress_record_batches(mtcars_out, "lz4")
+/// mtcars_in <- arrow::read_ipc_stream(mtcars_lz4)
+///
+/// @export
+#[extendr]
+fn compress_record_batches(srb: Robj, compression: &str) -> Robj {
+    r_boundary(|| {
+        let batches = RecordBatches::try_from_robj(&srb)?;
+        let buffer = batches.into_ipc_stream(IpcCompression::parse(compression)?)?;
+        Ok(Robj::from(Raw(&buffer)))
+    })
+}
+
+/// Pass an Arrow IPC file (Feather v2) back and forth
+///
+/// Demonstrates reading and writing the Arrow IPC file format
+///
+/// The input may be a raw vector or a file path, in either the IPC stream or
+/// IPC file format (the format is detected from the leading 'magic' bytes).
+/// The RecordBatches are returned as a raw vector in the IPC file format, which
+/// includes a footer indexing the location of every RecordBatch.
+///
+/// @param srb a raw vector or file path containing an IPC stream or file
+/// @return a raw vector containing an IPC file
+///
+/// @examples
+/// path <- tempfile(fileext = ".feather")
+/// arrow::write_feather(mtcars, path)
+/// mtcars_in_buffer <- pass_arrow_ipc_file(path)
+/// mtcars_in <- arrow::read_feather(mtcars_in_buffer)
+///
+/// @export
+#[extendr]
+fn pass_arrow_ipc_file(srb: Robj) -> Robj {
+    r_boundary(|| {
+        let batches = RecordBatches::try_from_robj(&srb)?;
+        Ok(IpcFileRecordBatches(batches).into_robj()?)
+    })
+}
+
+/// Write Arrow RecordBatches to an IPC file (Feather v2)
+///
+/// Demonstrates writing the Arrow IPC file format from Rust
+///
+/// Each RecordBatch is written to `path` as soon as it is received using a
+/// [RecordBatchFileSink](crate::structs::RecordBatchFileSink), and the footer
+/// is written once all RecordBatches have been written.
+///
+/// @param srb a raw vector or file path containing an IPC stream or file
+/// @param path the file to write the IPC file to
+/// @param compression the codec used to compress each RecordBatch, one of
+///   "uncompressed", "lz4", or "zstd"
+/// @return NULL
+///
+/// @examples
+/// mtcars_out <- arrow::write_to_raw(arrow::record_batch(mtcars), "stream")
+/// path <- tempfile(fileext = ".feather")
+/// write_record_batch_file(mtcars_out, path, "lz4")
+/// mtcars_in <- arrow::read_feather(path)
+///
+/// @export
+#[extendr]
+fn write_record_batch_file(srb: Robj, path: &str, compression: &str) -> Robj {
+    r_boundary(|| {
+        let srb = RecordBatches::try_from_robj(&srb)?;
+        let compression = IpcCompression::parse(compression)?;
+        let schema = srb.schema().ok_or_else(|| {
+            RecordBatchesError::invalid("Cannot write an empty set of RecordBatches")
+        })?;
+        let file =
+            std::fs::File::create(path).map_err(RecordBatchesError::io("Could not create file"))?;
+        let mut sink = RecordBatchFileSink::try_new(file, schema)?.with_compression(compression);
+        for batch in srb {
+            sink.write(&batch)?;
+        }
+        sink.into_inner()?;
+        Ok(())
+    })
+}
+
+/// Count the RecordBatches in an Arrow IPC file
+///
+/// Reads the number of RecordBatches from the footer of an IPC file without
+/// reading any of the RecordBatches themselves.
+///
+/// @param source a raw vector or file path containing an IPC file
+/// @return the number of RecordBatches in the file
+///
+/// @examples
+/// path <- tempfile(fileext = ".feather")
+/// arrow::write_feather(mtcars, path)
+/// ipc_file_num_batches(path)  # 1
+///
+/// @export
+#[extendr]
+fn ipc_file_num_batches(source: IpcFileSource) -> Robj {
+    r_boundary(|| Ok(source.num_batches()? as i32))
+}
+
+/// Read a single RecordBatch from an Arrow IPC file
+///
+/// Demonstrates random access to the RecordBatches in an IPC file
+///
+/// Uses the block index in the footer of the IPC file to read only the
+/// RecordBatch at `index`, skipping over the rest of the file.
+///
+/// @param source a raw vector or file path containing an IPC file
+/// @param index the (one-based) index of the RecordBatch to read
+/// @return a raw vector containing an IPC stream with the selected RecordBatch
+///
+/// @examples
+/// path <- tempfile(fileext = ".feather")
+/// arrow::write_feather(mtcars, path, chunk_size = 8)
+/// second <- arrow::read_ipc_stream(as.raw(read_ipc_file_batch(path, 2L)))
+///
+/// @export
+#[extendr]
+fn read_ipc_file_batch(source: IpcFileSource, index: i32) -> Robj {
+    r_boundary(|| {
+        if index < 1 {
+            return Err("`index` must be a positive integer".into());
+        }
+        let batch = source.read_batch(index as usize - 1)?;
+        let batches: RecordBatches = vec![batch].into_iter().collect();
+        Ok(batches.into_robj()?)
+    })
+}
+
+/// Read a Parquet file into Arrow RecordBatches in Rust
+///
+/// Demonstrates reading a Parquet file from Rust, without going through R's
+/// arrow package
+///
+/// Only the requested columns and row groups are read from the file. The
+/// RecordBatches are returned to R serialized as an IPC stream.
+///
+/// @param path the Parquet file to read
+/// @param columns a character vector of the columns to read, or NULL for all
+/// @param row_groups a vector of the (one-based) row groups to read, or NULL for all
+/// @return a raw vector containing an IPC stream
+///
+/// @examples
+/// path <- tempfile(fileext = ".parquet")
+/// arrow::write_parquet(mtcars, path, chunk_size = 8)
+/// batches <- read_parquet_batches(path, c("mpg", "cyl"), c(1, 2))
+/// mtcars_in <- arrow::read_ipc_stream(as.raw(batches))
+///
+/// @export
+#[extendr]
+fn read_parquet_batches(path: &str, columns: Robj, row_groups: Robj) -> Robj {
+    r_boundary(|| {
+        let columns = utils::optional_strings(&columns)?;
+        let row_groups = utils::optional_indices(&row_groups)?;
+        let batches = parquet_io::read_parquet_batches_fn(path, columns, row_groups)?;
+        Ok(batches.into_robj()?)
+    })
+}
+
+/// Write Arrow RecordBatches to a Parquet file in Rust
+///
+/// Demonstrates writing a Parquet file from Rust, without going through R's
+/// arrow package
+///
+/// @param batches a raw vector containing an IPC stream or file
+/// @param path the Parquet file to write
+/// @param compression the compression codec to use, one of "uncompressed",
+///   "snappy", "gzip", "lzo", "brotli", "lz4", or "zstd"
+/// @return NULL
+///
+/// @examples
+/// mtcars_out <- arrow::write_to_raw(arrow::record_batch(mtcars), "stream")
+/// path <- tempfile(fileext = ".parquet")
+/// write_parquet_batches(mtcars_out, path, "snappy")
+/// mtcars_in <- arrow::read_parquet(path)
+///
+/// @export
+#[extendr]
+fn write_parquet_batches(batches: Robj, path: &str, compression: &str) -> Robj {
+    r_boundary(|| {
+        let batches = RecordBatches::try_from_robj(&batches)?;
+        Ok(parquet_io::write_parquet_batches_fn(
+            batches,
+            path,
+            compression,
+        )?)
+    })
+}
+
+/// Select columns of Arrow RecordBatches in Rust
+///
+/// Demonstrates projecting RecordBatches by column name in Rust
+///
+/// The columns are returned in the order given, and each RecordBatch is kept
+/// as it is. An unknown column signals a `rustbind_arrow_error` naming it.
+///
+/// @param srb a raw vector or file path containing an IPC stream or file, or
+///   anything else [pass_arrow_record_batch()] accepts
+/// @param columns a character vector of the columns to keep
+/// @return a raw vector containing the RecordBatches as an IPC stream
+///
+/// @examples
+/// mtcars_out <- data_frame_to_record_batch(mtcars)
+/// selected <- select_columns(mtcars_out, c("mpg", "cyl"))
+/// record_batch_to_data_frame(selected)
+///
+/// @export
+#[extendr]
+fn select_columns(srb: Robj, columns: Robj) -> Robj {
+    r_boundary(|| {
+        let columns = columns
+            .as_string_vector()
+            .ok_or("`columns` must be a character vector")?;
+        let batches = RecordBatches::try_from_robj(&srb)?;
+        Ok(relational::select_columns_fn(batches, &columns)?.into_robj()?)
+    })
+}
+
+/// Filter the rows of Arrow RecordBatches in Rust
+///
+/// Demonstrates filtering RecordBatches with arrow's compute kernels
+///
+/// `predicate` is a small R-like expression, such as
+/// `"cyl == 4 & (mpg > 30 | is.na(hp))"`. It may compare columns with
+/// numbers, strings (in single or double quotes), `TRUE`/`FALSE`, or other
+/// columns using `==`, `!=`, `<`, `<=`, `>`, and `>=`, and combine those with
+/// `&`, `|`, `!`, and parentheses. Logical columns can be used on their own,
+/// and names that aren't syntactic can be quoted with backticks. Like
+/// `dplyr::filter()`, rows where the predicate is NA are dropped, and row
+/// names are dropped too.
+///
+/// @param srb a raw vector or file path containing an IPC stream or file, or
+///   anything else [pass_arrow_record_batch()] accepts
+/// @param predicate the expression rows must match, as a string
+/// @return a raw vector containing the RecordBatches as an IPC stream
+///
+/// @examples
+/// mtcars_out <- data_frame_to_record_batch(mtcars)
+/// filtered <- filter_record_batches(mtcars_out, "cyl == 4 & mpg > 30")
+/// record_batch_to_data_frame(filtered)
+///
+/// @export
+#[extendr]
+fn filter_record_batches(srb: Robj, predicate: &str) -> Robj {
+    r_boundary(|| {
+        let batches = RecordBatches::try_from_robj(&srb)?;
+        Ok(relational::filter_record_batches_fn(batches, predicate)?.into_robj()?)
+    })
+}
+
+/// Sort the rows of Arrow RecordBatches in Rust
+///
+/// Demonstrates sorting RecordBatches with arrow's compute kernels
+///
+/// Rows are sorted by each column in `by` in turn, and ties keep their
+/// original order. Factors (dictionary arrays) are sorted by the order of
+/// their levels. All RecordBatches are combined and returned as one, and row
+/// names are dropped.
+///
+/// @param srb a raw vector or file path containing an IPC stream or file, or
+///   anything else [pass_arrow_record_batch()] accepts
+/// @param by a character vector of the columns to sort by
+/// @param descending a logical vector saying which columns of `by` to sort in
+///   descending order, recycled to the length of `by`
+/// @param na_last TRUE to put NA's last, FALSE to put them first
+/// @return a raw vector containing the sorted RecordBatch as an IPC stream
+///
+/// @examples
+/// mtcars_out <- data_frame_to_record_batch(mtcars)
+/// sorted <- sort_record_batches(mtcars_out, c("cyl", "mpg"), c(FALSE, TRUE), TRUE)
+/// record_batch_to_data_frame(sorted)
+///
+/// @export
+#[extendr]
+fn sort_record_batches(srb: Robj, by: Robj, descending: Robj, na_last: bool) -> Robj {
+    r_boundary(|| {
+        let by = by
+            .as_string_vector()
+            .ok_or("`by` must be a character vector")?;
+        let descending = utils::recycled_logicals(&descending, by.len())?;
+        let keys: Vec<relational::SortKey> = by
+            .into_iter()
+            .zip(descending)
+            .map(|(column, descending)| relational::SortKey { column, descending })
+            .collect();
+        let batches = RecordBatches::try_from_robj(&srb)?;
+        Ok(relational::sort_record_batches_fn(batches, &keys, na_last)?.into_robj()?)
+    })
+}
+
+/// Take rows of Arrow RecordBatches by index in Rust
+///
+/// Demonstrates picking rows of RecordBatches with arrow's `take` kernel
+///
+/// Rows are counted across all RecordBatches, which are combined and returned
+/// as one. Like indexing a data frame, rows can be repeated, and an NA index
+/// gives a row of NA's. Row names are dropped.
+///
+/// @param srb a raw vector or file path containing an IPC stream or file, or
+///   anything else [pass_arrow_record_batch()] accepts
+/// @param rows a vector of (one-based) row indices, which may contain NA's
+/// @return a raw vector containing the RecordBatch as an IPC stream
+///
+/// @examples
+/// mtcars_out <- data_frame_to_record_batch(mtcars)
+/// taken <- take_rows(mtcars_out, c(3, 1, NA, 1))
+/// record_batch_to_data_frame(taken)
+///
+/// @export
+#[extendr]
+fn take_rows(srb: Robj, rows: Robj) -> Robj {
+    r_boundary(|| {
+        let rows = utils::indices_with_na(&rows)?;
+        let batches = RecordBatches::try_from_robj(&srb)?;
+        Ok(relational::take_rows_fn(batches, &rows)?.into_robj()?)
+    })
+}
+
+/// Summarise groups of rows of Arrow RecordBatches in Rust
+///
+/// Demonstrates a parallel hash aggregation over RecordBatches, like
+/// `dplyr::group_by()` followed by `dplyr::summarise()`
+///
+/// Rows with the same values in the `keys` columns form a group, and NA keys
+/// form a group of their own. Each aggregate is written like an R function
+/// call, e.g. `"mean(mpg)"`: `sum()` and `mean()` of numeric or logical
+/// columns, `min()` and `max()` (keeping the column's type), `n()` or
+/// `count()` of the rows, `count(x)` of the non-NA values of `x`, and
+/// `n_distinct(x)`. Rows are aggregated in chunks in parallel with rayon, so
+/// tens of millions of rows can be summarised quickly. All RecordBatches are
+/// combined, and the result has one row per group, sorted by the keys with
+/// NA's last.
+///
+/// @param srb a raw vector or file path containing an IPC stream or file, or
+///   anything else [pass_arrow_record_batch()] accepts
+/// @param keys a character vector of the columns to group by, which may be
+///   empty to summarise all rows
+/// @param aggs a character vector of aggregates. Its names are the names of
+///   the result columns; unnamed aggregates are named after themselves.
+/// @param na_rm TRUE to skip NA's, FALSE to give NA for any group with NA's
+/// @return a raw vector containing the summary RecordBatch as an IPC stream
+///
+/// @examples
+/// mtcars_out <- data_frame_to_record_batch(mtcars)
+/// summary <- group_aggregate(
+///   mtcars_out,
+///   c("cyl", "gear"),
+///   c(mpg = "mean(mpg)", hp = "max(hp)", n = "n()"),
+///   FALSE
+/// )
+/// record_batch_to_data_frame(summary)
+///
+/// @export
+#[extendr]
+fn group_aggregate(srb: Robj, keys: Robj, aggs: Robj, na_rm: bool) -> Robj {
+    r_boundary(|| {
+        let keys = utils::optional_strings(&keys)?.unwrap_or_default();
+        let specs = aggs
+            .as_string_vector()
+            .ok_or("`aggs` must be a character vector")?;
+        let names: Vec<String> = match aggs.names() {
+            Some(names) => names.map(String::from).collect(),
+            None => vec![String::new(); specs.len()],
+        };
+        let aggregates = specs
+            .iter()
+            .zip(names)
+            .map(|(spec, name)| {
+                let name = if name.is_empty() { spec.trim() } else { &name };
+                aggregate::Aggregate::parse(name, spec)
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let batches = RecordBatches::try_from_robj(&srb)?;
+        Ok(aggregate::group_aggregate_fn(batches, &keys, &aggregates, na_rm)?.into_robj()?)
+    })
+}
+
+/// Join two sets of Arrow RecordBatches in Rust
+///
+/// Demonstrates a hash join between two inputs passed to Rust
+///
+/// Rows of `left` and `right` are matched on the key columns in `by`, which
+/// can be integers, doubles, strings, logicals, factors, dates, or times. A
+/// character vector `by` names columns found on both sides; a named one, like
+/// `c(id = "key")`, matches `left$id` with `right$key`. The result has the
+/// key columns and the other columns of `left`, then the other columns of
+/// `right`, with `.x` and `.y` added when one of those has the name of a
+/// column of `left` (including a key).
+/// Rows are in the order of `left`, and rows of `right` without a match (for
+/// `"right"` and `"full"` joins) come last.
+///
+/// Like SQL, NA keys don't match anything with `na_matches = "never"`. Use
+/// `na_matches = "na"` to match NA keys with each other, as `merge()` does.
+///
+/// @param left,right raw vectors or file paths containing IPC streams or files,
+///   or anything else [pass_arrow_record_batch()] accepts
+/// @param by a (named) character vector of key columns
+/// @param join_type one of "inner", "left", "right", "full", "semi", or "anti"
+/// @param na_matches "never" to never match NA keys, or "na" to match them
+/// @return a raw vector containing the joined RecordBatch as an IPC stream
+///
+/// @examples
+/// cars <- data.frame(model = rownames(mtcars), cyl = mtcars$cyl)
+/// engines <- data.frame(cyl = c(4, 6), engine = c("inline", "v6"))
+/// joined <- join_record_batches(
+///   data_frame_to_record_batch(cars),
+///   data_frame_to_record_batch(engines),
+///   "cyl",
+///   "left",
+///   "never"
+/// )
+/// record_batch_to_data_frame(joined)
+///
+/// @export
+#[extendr]
+fn join_record_batches(
+    left: Robj,
+    right: Robj,
+    by: Robj,
+    join_type: &str,
+    na_matches: &str,
+) -> Robj {
+    r_boundary(|| {
+        let right_keys = by
+            .as_string_vector()
+            .ok_or("`by` must be a character vector")?;
+        let left_keys: Vec<String> = match by.names() {
+            Some(names) => names
+                .zip(&right_keys)
+                .map(|(name, key)| {
+                    if name.is_empty() {
+                        key.clone()
+                    } else {
+                        name.to_string()
+                    }
+                })
+                .collect(),
+            None => right_keys.clone(),
+        };
+        let by: Vec<(String, String)> = left_keys.into_iter().zip(right_keys).collect();
+        let join_type = join::JoinType::parse(join_type)?;
+        let na_matches = join::NaMatches::parse(na_matches)?;
+        let left = RecordBatches::try_from_robj(&left)?;
+        let right = RecordBatches::try_from_robj(&right)?;
+        Ok(join::join_record_batches_fn(left, right, &by, join_type, na_matches)?.into_robj()?)
+    })
+}
+
+/// Filter and select Arrow RecordBatches one at a time in Rust
+///
+/// Demonstrates processing RecordBatches lazily, as a stream
+///
+/// Unlike the other functions, which read every RecordBatch into memory
+/// first, this reads one RecordBatch at a time from `input`, filters it (see
+/// [filter_record_batches()]) and selects its columns (see
+/// [select_columns()]), and writes the result to `output` before reading the
+/// next one. Only one RecordBatch is held in memory at a time, so inputs
+/// larger than memory can be processed from file to file.
+///
+/// @param input a raw vector or file path containing an IPC stream or file
+/// @param output a file path to write the IPC stream to, or NULL to return it
+///   as a raw vector
+/// @param columns a character vector of the columns to keep, or NULL for all
+/// @param predicate the expression rows must match, or NULL for all rows
+/// @param compression the codec used to compress each RecordBatch, one of
+///   "uncompressed", "lz4", or "zstd"
+/// @return NULL, or a raw vector containing an IPC stream if `output` is NULL
+///
+/// @examples
+/// input <- tempfile(fileext = ".feather")
+/// output <- tempfile(fileext = ".arrows")
+/// arrow::write_feather(mtcars, input, chunk_size = 8)
+/// stream_record_batches(input, output, c("mpg", "cyl"), "cyl == 4", "uncompressed")
+/// arrow::read_ipc_stream(output)
+///
+/// @export
+#[extendr]
+fn stream_record_batches(
+    input: Robj,
+    output: Robj,
+    columns: Robj,
+    predicate: Robj,
+    compression: &str,
+) -> Robj {
+    r_boundary(|| {
+        let columns = utils::optional_strings(&columns)?;
+        let predicate = utils::optional_strings(&predicate)?.and_then(|x| x.into_iter().next());
+        let compression = IpcCompression::parse(compression)?;
+        let stream = RecordBatchStream::try_from_robj(&input)?;
+        let columns = columns.as_deref();
+        let predicate = predicate.as_deref();
+        if output.is_null() {
+            let buffer = relational::stream_record_batches_fn(
+                stream,
+                Vec::new(),
+                columns,
+                predicate,
+                compression,
+            )?;
+            return Ok(Robj::from(Raw(&buffer)));
+        }
+        let path = output
+            .as_str()
+            .ok_or("`output` must be a file path or NULL")?;
+        let file =
+            std::fs::File::create(path).map_err(RecordBatchesError::io("Could not create file"))?;
+        let writer = std::io::BufWriter::new(file);
+        relational::stream_record_batches_fn(stream, writer, columns, predicate, compression)?;
+        Ok(Robj::from(()))
+    })
+}
+
+/// Split Arrow RecordBatches in two in Rust
+///
+/// Demonstrates passing several tables, each with its own schema, to and from
+/// Rust in one call
+///
+/// `tables` is a named list of tables, each of which can be anything
+/// [pass_arrow_record_batch()] accepts. The `data` table is split by
+/// `predicate` (see [filter_record_batches()]) into the rows where it is TRUE
+/// and the rows where it is FALSE or NA. Row names are dropped.
+///
+/// @param tables a named list of tables, with a `data` table to split
+/// @param predicate the expression the `matched` rows match, as a string
+/// @param as_data_frames TRUE to return data frames, FALSE for raw vectors
+/// @return a named list with the `matched` and `unmatched` tables, as raw
+///   vectors containing IPC streams or as data frames
+///
+/// @examples
+/// split <- split_record_batches(list(data = mtcars), "cyl == 4", TRUE)
+/// split$matched
+/// split$unmatched
+///
+/// @export
+#[extendr]
+fn split_record_batches(tables: Robj, predicate: &str, as_data_frames: bool) -> Robj {
+    r_boundary(|| {
+        let tables = NamedRecordBatches::try_from_robj(&tables)?;
+        let out = relational::split_record_batches_fn(tables, predicate)?;
+        match as_data_frames {
+            true => Ok(out.into_data_frames()?),
+            false => Ok(out.into_robj()?),
+        }
+    })
 }
 
 /// Perform a sample multithreaded operation
@@ -294,8 +1229,8 @@ fn pass_arrow_record_batch(srb: RecordBatches) -> RecordBatches {
 ///
 /// @export
 #[extendr]
-fn multithreaded_naive_sqrt(f: &[f64]) -> Vec<f64> {
-    algos::multithreaded_naive_sqrt(&f)
+fn multithreaded_naive_sqrt(f: &[f64]) -> Robj {
+    r_boundary(|| Ok(algos::multithreaded_naive_sqrt(f)))
 }
 
 /// Perform a sample multithreaded operation (rayon)
@@ -311,8 +1246,8 @@ fn multithreaded_naive_sqrt(f: &[f64]) -> Vec<f64> {
 ///
 /// @export
 #[extendr]
-fn rayon_naive_sqrt(f: &[f64]) -> Vec<f64> {
-    algos::rayon_naive_sqrt(&f)
+fn rayon_naive_sqrt(f: &[f64]) -> Robj {
+    r_boundary(|| Ok(algos::rayon_naive_sqrt(f)))
 }
 
 mod export {
@@ -333,8 +1268,35 @@ mod export {
         fn pass_multiple_raws;
         fn pass_single_character;
         fn pass_multiple_characters;
+        fn pass_single_complex;
+        fn pass_multiple_complexes;
         fn bubble_sort;
+        fn matrix_transpose;
+        fn matrix_multiply;
         fn pass_arrow_record_batch;
+        fn data_frame_to_record_batch;
+        fn record_batch_to_data_frame;
+        fn record_batch_schema;
+        fn validate_record_batches;
+        fn expect_record_batch_schema;
+        fn pass_arrow_c_data;
+        fn release_arrow_c_data;
+        fn write_record_batch_stream;
+        fn compress_record_batches;
+        fn pass_arrow_ipc_file;
+        fn write_record_batch_file;
+        fn ipc_file_num_batches;
+        fn read_ipc_file_batch;
+        fn read_parquet_batches;
+        fn write_parquet_batches;
+        fn select_columns;
+        fn filter_record_batches;
+        fn sort_record_batches;
+        fn take_rows;
+        fn group_aggregate;
+        fn join_record_batches;
+        fn stream_record_batches;
+        fn split_record_batches;
         fn multithreaded_naive_sqrt;
         fn rayon_naive_sqrt;
     }
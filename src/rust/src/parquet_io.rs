@@ -0,0 +1,177 @@
+//! This module demonstrates reading and writing Parquet files from Rust using
+//! the [parquet](https://docs.rs/parquet) crate's Arrow integration, producing
+//! and consuming [RecordBatches](crate::structs::RecordBatches) so that data
+//! can be persisted and reloaded without going through R's arrow package.
+
+use crate::structs::{RecordBatches, RecordBatchesError};
+use parquet::{
+    arrow::{ArrowReader, ArrowWriter, ParquetFileArrowReader},
+    basic::Compression,
+    file::{
+        metadata::ParquetMetaData,
+        properties::WriterProperties,
+        reader::{FileReader, RowGroupReader, SerializedFileReader},
+    },
+    record::reader::RowIter,
+    schema::types::Type as SchemaType,
+};
+use std::fs::File;
+use std::sync::Arc;
+
+/// Number of rows in each RecordBatch read from a Parquet file
+pub const PARQUET_BATCH_SIZE: usize = 65_536;
+
+type Result<T> = std::result::Result<T, RecordBatchesError>;
+
+/// Reads a Parquet file into RecordBatches. If `columns` is given, only those
+/// (top-level) columns are read. If `row_groups` is given, only those
+/// (zero-based) row groups are read, in the order given.
+pub(crate) fn read_parquet_batches_fn(
+    path: &str,
+    columns: Option<Vec<String>>,
+    row_groups: Option<Vec<usize>>,
+) -> Result<RecordBatches> {
+    let file = File::open(path).map_err(RecordBatchesError::io("Could not open file"))?;
+    let file_reader = SerializedFileReader::new(file)
+        .map_err(RecordBatchesError::parquet("Error reading Parquet file"))?;
+    let file_reader: Arc<dyn FileReader> = match row_groups {
+        Some(row_groups) => Arc::new(RowGroupSubset::try_new(file_reader, row_groups)?),
+        None => Arc::new(file_reader),
+    };
+
+    let leaf_indices = match columns {
+        Some(columns) => Some(leaf_column_indices(file_reader.metadata(), &columns)?),
+        None => None,
+    };
+
+    let mut arrow_reader = ParquetFileArrowReader::new(file_reader);
+    let record_reader = match leaf_indices {
+        Some(indices) => arrow_reader.get_record_reader_by_columns(indices, PARQUET_BATCH_SIZE),
+        None => arrow_reader.get_record_reader(PARQUET_BATCH_SIZE),
+    }
+    .map_err(RecordBatchesError::parquet(
+        "Error creating Parquet RecordBatch reader",
+    ))?;
+
+    record_reader
+        .enumerate()
+        .map(|(i, rb)| {
+            rb.map_err(|e| {
+                RecordBatchesError::arrow("Error reading RecordBatch from Parquet file")(e)
+                    .at_message(i)
+            })
+        })
+        .collect()
+}
+
+/// Writes RecordBatches to a Parquet file, compressing each column chunk with
+/// the named `compression` codec
+pub(crate) fn write_parquet_batches_fn(
+    batches: RecordBatches,
+    path: &str,
+    compression: &str,
+) -> Result<()> {
+    let schema = batches
+        .schema()
+        .ok_or_else(|| RecordBatchesError::invalid("Cannot write an empty set of RecordBatches"))?;
+    let props = WriterProperties::builder()
+        .set_compression(parse_compression(compression)?)
+        .build();
+    let file = File::create(path).map_err(RecordBatchesError::io("Could not create file"))?;
+    let mut writer = ArrowWriter::try_new(file, schema, Some(props))
+        .map_err(RecordBatchesError::parquet("Error creating ArrowWriter"))?;
+    for batch in batches {
+        writer.write(&batch).map_err(RecordBatchesError::parquet(
+            "Error writing RecordBatch to Parquet file",
+        ))?;
+    }
+    writer
+        .close()
+        .map_err(RecordBatchesError::parquet("Error closing Parquet file"))?;
+    Ok(())
+}
+
+/// Parses the name of a Parquet compression codec, using the same names as
+/// R's `arrow::write_parquet()`
+fn parse_compression(compression: &str) -> Result<Compression> {
+    match compression.to_lowercase().as_str() {
+        "uncompressed" => Ok(Compression::UNCOMPRESSED),
+        "snappy" => Ok(Compression::SNAPPY),
+        "gzip" => Ok(Compression::GZIP),
+        "lzo" => Ok(Compression::LZO),
+        "brotli" => Ok(Compression::BROTLI),
+        "lz4" => Ok(Compression::LZ4),
+        "zstd" => Ok(Compression::ZSTD),
+        _ => Err(RecordBatchesError::invalid("Unknown compression, expected one of 'uncompressed', 'snappy', 'gzip', 'lzo', 'brotli', 'lz4', or 'zstd'")),
+    }
+}
+
+/// Maps top-level column names to the indices of the leaf columns in the
+/// Parquet schema. Nested columns have more than one leaf, all of which are
+/// selected.
+fn leaf_column_indices(metadata: &ParquetMetaData, columns: &[String]) -> Result<Vec<usize>> {
+    let schema_descr = metadata.file_metadata().schema_descr();
+    let mut indices = Vec::new();
+    for name in columns {
+        let before = indices.len();
+        for i in 0..schema_descr.num_columns() {
+            if schema_descr.get_column_root(i).name() == name {
+                indices.push(i);
+            }
+        }
+        if indices.len() == before {
+            return Err(
+                RecordBatchesError::invalid("Column not found in Parquet file")
+                    .for_field(name.as_str()),
+            );
+        }
+    }
+    Ok(indices)
+}
+
+/// Wraps a FileReader to expose only a subset of its row groups. The Arrow
+/// reader only reaches row groups through `num_row_groups()` and
+/// `get_row_group()`, so the file metadata is passed through unchanged.
+struct RowGroupSubset<R: FileReader> {
+    inner: R,
+    row_groups: Vec<usize>,
+}
+
+impl<R: FileReader> RowGroupSubset<R> {
+    fn try_new(inner: R, row_groups: Vec<usize>) -> Result<Self> {
+        if row_groups.iter().any(|i| *i >= inner.num_row_groups()) {
+            return Err(RecordBatchesError::invalid("Row group index out of bounds"));
+        }
+        Ok(RowGroupSubset { inner, row_groups })
+    }
+}
+
+impl<R: FileReader> FileReader for RowGroupSubset<R> {
+    fn metadata(&self) -> &ParquetMetaData {
+        self.inner.metadata()
+    }
+
+    fn num_row_groups(&self) -> usize {
+        self.row_groups.len()
+    }
+
+    fn get_row_group(&self, i: usize) -> parquet::errors::Result<Box<dyn RowGroupReader + '_>> {
+        self.inner.get_row_group(self.row_groups[i])
+    }
+
+    fn get_row_iter(&self, projection: Option<SchemaType>) -> parquet::errors::Result<RowIter<'_>> {
+        RowIter::from_file(projection, self)
+    }
+}
+
+#[allow(unused_imports)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_compression() {
+        assert_eq!(parse_compression("snappy").unwrap(), Compression::SNAPPY);
+        assert_eq!(parse_compression("ZSTD").unwrap(), Compression::ZSTD);
+        assert!(parse_compression("lzma").is_err());
+    }
+}
@@ -1,6 +1,5 @@
-use crate::structs::CharVec;
-use crate::utils::flip;
-use extendr_api::prelude::*;
+use crate::structs::{parse_rint, CharVec, ComplexVec, NaVec, RSlice, RSliceMut};
+use num_complex::Complex64;
 
 /// Receives a single integer from R, multiplies it by 10, then returns it.
 /// Demonstrates passing a single integer from/to R.
@@ -9,13 +8,17 @@ pub(crate) fn pass_single_integer_fn(n: Option<i32>) -> Option<i32> {
 }
 
 /// Receives an integer vector from R, multiplies it by 10, then returns it.
-/// Demonstrates passing an integer vector from/to R.
-pub(crate) fn pass_multiple_integers_fn(n: Int) -> Int {
-    n.into_iter()
-        .map(|x| x * 10)
-        .collect_robj()
-        .as_integer_iter()
-        .unwrap()
+/// Demonstrates passing an integer vector from/to R, changing a copy of it in
+/// place. A result too large for an integer is NA, as in R.
+pub(crate) fn pass_multiple_integers_fn(n: RSlice<i32>) -> RSliceMut<i32> {
+    let mut out = n.duplicate();
+    for x in out.iter_mut() {
+        if let Some(value) = parse_rint(*x) {
+            // i32::MIN is NA_integer_
+            *x = value.checked_mul(10).unwrap_or(i32::MIN);
+        }
+    }
+    out
 }
 
 /// Receives a double (float) value from R, multiplies it by 10, then returns it.
@@ -25,13 +28,15 @@ pub(crate) fn pass_single_double_fn(f: Option<f64>) -> Option<f64> {
 }
 
 /// Receives a double (float) vector from R, multiplies it by 10, then returns it.
-/// Demonstrates passing a double vector from/to R.
-pub(crate) fn pass_multiple_doubles_fn(f: Real) -> Real {
-    f.into_iter()
-        .map(|x| x * 10.0)
-        .collect_robj()
-        .as_real_iter()
-        .unwrap()
+/// Demonstrates passing a double vector from/to R, changing a copy of it in
+/// place. NA_real_ and NaN both stay as they are, since arithmetic on a NaN
+/// keeps its payload.
+pub(crate) fn pass_multiple_doubles_fn(f: RSlice<f64>) -> RSliceMut<f64> {
+    let mut out = f.duplicate();
+    for x in out.iter_mut() {
+        *x *= 10.0;
+    }
+    out
 }
 
 /// Receives a logical (bool) value from R, flips it, then returns it.
@@ -42,12 +47,8 @@ pub(crate) fn pass_single_logical_fn(b: Option<bool>) -> Option<bool> {
 
 /// Receives a logical (bool) vector from R, flips it, then returns it.
 /// Demonstrates passing a logical vector from/to R.
-pub(crate) fn pass_multiple_logicals_fn(b: Logical) -> Logical {
-    b.into_iter()
-        .map(flip)
-        .collect_robj()
-        .as_logical_iter()
-        .unwrap()
+pub(crate) fn pass_multiple_logicals_fn(b: NaVec<bool>) -> NaVec<bool> {
+    b.into_iter().map(|x| x.map(|x| !x)).collect()
 }
 
 /// Receives a raw (byte) value from R, shifts it left by 1 (wrapping), then returns it.
@@ -74,6 +75,18 @@ pub(crate) fn pass_multiple_characters_fn(s: CharVec) -> CharVec {
     s.into_iter().map(|x| x.map(to_uppercase)).collect()
 }
 
+/// Receives a complex number from R, conjugates it, then returns it
+/// Demonstrates passing a single complex value from/to R
+pub(crate) fn pass_single_complex_fn(z: Option<Complex64>) -> Option<Complex64> {
+    z.map(|x| x.conj())
+}
+
+/// Receives a complex vector from R, conjugates it, then returns it
+/// Demonstrates passing a complex vector from/to R
+pub(crate) fn pass_multiple_complexes_fn(z: ComplexVec) -> ComplexVec {
+    z.into_iter().map(|x| x.map(|x| x.conj())).collect()
+}
+
 // Just a helper function
 fn to_uppercase(s: String) -> String {
     s.to_uppercase()
@@ -0,0 +1,769 @@
+//! This module parses and evaluates the row predicates used to filter
+//! RecordBatches (see [filter_record_batches_fn](crate::relational::filter_record_batches_fn)).
+//! Predicates use a small subset of R's syntax:
+//!
+//! ```text
+//! cyl == 4 & (mpg > 25 | is.na(hp))
+//! name != "Valiant" & !vs
+//! `Sepal.Length` >= 5.5
+//! ```
+//!
+//! - comparisons (`==`, `!=`, `<`, `<=`, `>`, `>=`) between a column and a
+//!   number, a string, `TRUE` or `FALSE`, or between two columns of the same
+//!   kind
+//! - `is.na(column)`, which is true for nulls
+//! - a logical column on its own
+//! - `&`, `|`, and `!` (or `&&` and `||`), and parentheses
+//!
+//! Numeric columns of any type are compared as doubles, and factors
+//! (dictionaries of strings) by their labels. As in R, NA's propagate through
+//! comparisons and `!`, while `NA & FALSE` is `FALSE` and `NA | TRUE` is
+//! `TRUE`. Rows where the predicate is NA are filtered out, like
+//! `dplyr::filter()` does.
+
+use crate::structs::RecordBatchesError;
+use arrow::{
+    array::{Array, ArrayRef, BooleanArray, Float64Array, StringArray},
+    compute::{
+        cast, is_null,
+        kernels::comparison::{
+            eq, eq_scalar, eq_utf8, eq_utf8_scalar, gt, gt_eq, gt_eq_scalar, gt_eq_utf8,
+            gt_eq_utf8_scalar, gt_scalar, gt_utf8, gt_utf8_scalar, lt, lt_eq, lt_eq_scalar,
+            lt_eq_utf8, lt_eq_utf8_scalar, lt_scalar, lt_utf8, lt_utf8_scalar, neq, neq_scalar,
+            neq_utf8, neq_utf8_scalar,
+        },
+        not,
+    },
+    datatypes::DataType,
+    error::Result as ArrowResult,
+    record_batch::RecordBatch,
+};
+
+type Result<T> = std::result::Result<T, RecordBatchesError>;
+
+/// A parsed predicate, which can be evaluated against any RecordBatch that
+/// has the columns it refers to
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Predicate {
+    Compare(Comparison, Operand, Operand),
+    IsNa(String),
+    Column(String),
+    Literal(bool),
+    Not(Box<Predicate>),
+    And(Box<Predicate>, Box<Predicate>),
+    Or(Box<Predicate>, Box<Predicate>),
+}
+
+/// One side of a comparison
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Operand {
+    Column(String),
+    Number(f64),
+    Text(String),
+    Logical(bool),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Comparison {
+    Eq,
+    NotEq,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+}
+
+impl Comparison {
+    /// The comparison with its operands swapped, so `1 < x` becomes `x > 1`
+    fn flip(self) -> Self {
+        match self {
+            Comparison::Lt => Comparison::Gt,
+            Comparison::LtEq => Comparison::GtEq,
+            Comparison::Gt => Comparison::Lt,
+            Comparison::GtEq => Comparison::LtEq,
+            other => other,
+        }
+    }
+}
+
+impl Predicate {
+    /// Parses a predicate, reporting the first token that doesn't fit
+    pub(crate) fn parse(input: &str) -> Result<Self> {
+        let mut parser = Parser {
+            tokens: tokenize(input)?,
+            position: 0,
+            depth: 0,
+        };
+        let predicate = parser.or()?;
+        match parser.peek() {
+            None => Ok(predicate),
+            Some(_) => Err(parser.unexpected("an operator or the end of the predicate")),
+        }
+    }
+
+    /// Evaluates the predicate for each row of a RecordBatch, naming the
+    /// column involved in any error
+    pub(crate) fn evaluate(&self, batch: &RecordBatch) -> Result<BooleanArray> {
+        match self {
+            Predicate::Compare(op, left, right) => compare(*op, left, right, batch),
+            Predicate::IsNa(name) => is_null(column(batch, name)?.as_ref()).map_err(|e| {
+                RecordBatchesError::arrow("Error evaluating is.na()")(e).for_field(name.as_str())
+            }),
+            Predicate::Column(name) => match values(batch, name)? {
+                Values::Logicals(x) => Ok(x),
+                _ => Err(RecordBatchesError::invalid(
+                    "Only logical columns can be used as a predicate on their own",
+                )
+                .for_field(name.as_str())),
+            },
+            Predicate::Literal(value) => Ok(vec![Some(*value); batch.num_rows()].into()),
+            Predicate::Not(x) => not(&x.evaluate(batch)?)
+                .map_err(RecordBatchesError::arrow("Error evaluating predicate")),
+            Predicate::And(a, b) => Ok(kleene(&a.evaluate(batch)?, &b.evaluate(batch)?, false)),
+            Predicate::Or(a, b) => Ok(kleene(&a.evaluate(batch)?, &b.evaluate(batch)?, true)),
+        }
+    }
+}
+
+/// Combines two logical arrays with R's three-valued logic: `dominant` (`TRUE`
+/// for `|`, `FALSE` for `&`) on either side decides the result even if the
+/// other side is NA
+fn kleene(a: &BooleanArray, b: &BooleanArray, dominant: bool) -> BooleanArray {
+    let get = |x: &BooleanArray, i: usize| Some(x.value(i)).filter(|_| x.is_valid(i));
+    (0..a.len())
+        .map(|i| {
+            let (x, y) = (get(a, i), get(b, i));
+            if x == Some(dominant) || y == Some(dominant) {
+                Some(dominant)
+            } else if x.is_some() && y.is_some() {
+                Some(!dominant)
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<_>>()
+        .into()
+}
+
+fn column(batch: &RecordBatch, name: &str) -> Result<ArrayRef> {
+    match batch.schema().index_of(name) {
+        Ok(i) => Ok(batch.column(i).clone()),
+        Err(_) => Err(RecordBatchesError::invalid("Unknown column in predicate").for_field(name)),
+    }
+}
+
+/// The values of a column, as one of the kinds predicates can compare
+enum Values {
+    Numbers(Float64Array),
+    Strings(StringArray),
+    Logicals(BooleanArray),
+}
+
+impl Values {
+    fn kind(&self) -> &'static str {
+        match self {
+            Values::Numbers(_) => "a number",
+            Values::Strings(_) => "a string",
+            Values::Logicals(_) => "a logical",
+        }
+    }
+}
+
+/// Reads a column as numbers, strings, or logicals, casting numeric columns to
+/// `Float64`, and `LargeUtf8` and dictionaries of strings to `Utf8`
+fn values(batch: &RecordBatch, name: &str) -> Result<Values> {
+    let array = column(batch, name)?;
+    let cast_to = |data_type: &DataType| {
+        cast(&array, data_type).map_err(|e| {
+            RecordBatchesError::arrow("Error casting column in predicate")(e).for_field(name)
+        })
+    };
+    match array.data_type() {
+        DataType::Boolean => Ok(Values::Logicals(BooleanArray::from(array.data()))),
+        DataType::Utf8 => Ok(Values::Strings(StringArray::from(array.data()))),
+        DataType::LargeUtf8 => Ok(Values::Strings(StringArray::from(
+            cast_to(&DataType::Utf8)?.data(),
+        ))),
+        DataType::Dictionary(_, value_type) if **value_type == DataType::Utf8 => Ok(
+            Values::Strings(StringArray::from(cast_to(&DataType::Utf8)?.data())),
+        ),
+        DataType::Int8
+        | DataType::Int16
+        | DataType::Int32
+        | DataType::Int64
+        | DataType::UInt8
+        | DataType::UInt16
+        | DataType::UInt32
+        | DataType::UInt64
+        | DataType::Float32
+        | DataType::Float64 => Ok(Values::Numbers(Float64Array::from(
+            cast_to(&DataType::Float64)?.data(),
+        ))),
+        data_type => Err(RecordBatchesError::schema(
+            "Unsupported column type in predicate",
+            Some("a numeric, string, or logical column".to_string()),
+            Some(format!("{:?}", data_type)),
+        )
+        .for_field(name)),
+    }
+}
+
+fn compare(
+    op: Comparison,
+    left: &Operand,
+    right: &Operand,
+    batch: &RecordBatch,
+) -> Result<BooleanArray> {
+    let (name, column_values, other, op) = match (left, right) {
+        (Operand::Column(name), other) => (name, values(batch, name)?, other, op),
+        (other, Operand::Column(name)) => (name, values(batch, name)?, other, op.flip()),
+        _ => {
+            return Err(RecordBatchesError::invalid(
+                "Each comparison in a predicate needs at least one column",
+            ))
+        }
+    };
+    let mismatch = |expected: &Values, found: &str| {
+        RecordBatchesError::schema(
+            "Column compared with a value of a different kind",
+            Some(expected.kind().to_string()),
+            Some(found.to_string()),
+        )
+        .for_field(name.as_str())
+    };
+    let result = match (&column_values, other) {
+        (Values::Numbers(a), Operand::Number(b)) => compare_numbers(op, a, *b),
+        (Values::Strings(a), Operand::Text(b)) => compare_strings(op, a, b),
+        (Values::Logicals(a), Operand::Logical(b)) => {
+            return compare_logicals(op, a, *b).map_err(|e| e.for_field(name.as_str()))
+        }
+        (_, Operand::Column(other)) => match (&column_values, values(batch, other)?) {
+            (Values::Numbers(a), Values::Numbers(b)) => compare_number_arrays(op, a, &b),
+            (Values::Strings(a), Values::Strings(b)) => compare_string_arrays(op, a, &b),
+            (_, b) => return Err(mismatch(&column_values, b.kind()).for_field(other.as_str())),
+        },
+        (_, Operand::Number(_)) => return Err(mismatch(&column_values, "a number")),
+        (_, Operand::Text(_)) => return Err(mismatch(&column_values, "a string")),
+        (_, Operand::Logical(_)) => return Err(mismatch(&column_values, "a logical")),
+    };
+    result.map_err(|e| {
+        RecordBatchesError::arrow("Error evaluating comparison")(e).for_field(name.as_str())
+    })
+}
+
+fn compare_numbers(op: Comparison, a: &Float64Array, b: f64) -> ArrowResult<BooleanArray> {
+    match op {
+        Comparison::Eq => eq_scalar(a, b),
+        Comparison::NotEq => neq_scalar(a, b),
+        Comparison::Lt => lt_scalar(a, b),
+        Comparison::LtEq => lt_eq_scalar(a, b),
+        Comparison::Gt => gt_scalar(a, b),
+        Comparison::GtEq => gt_eq_scalar(a, b),
+    }
+}
+
+fn compare_number_arrays(
+    op: Comparison,
+    a: &Float64Array,
+    b: &Float64Array,
+) -> ArrowResult<BooleanArray> {
+    match op {
+        Comparison::Eq => eq(a, b),
+        Comparison::NotEq => neq(a, b),
+        Comparison::Lt => lt(a, b),
+        Comparison::LtEq => lt_eq(a, b),
+        Comparison::Gt => gt(a, b),
+        Comparison::GtEq => gt_eq(a, b),
+    }
+}
+
+fn compare_strings(op: Comparison, a: &StringArray, b: &str) -> ArrowResult<BooleanArray> {
+    match op {
+        Comparison::Eq => eq_utf8_scalar(a, b),
+        Comparison::NotEq => neq_utf8_scalar(a, b),
+        Comparison::Lt => lt_utf8_scalar(a, b),
+        Comparison::LtEq => lt_eq_utf8_scalar(a, b),
+        Comparison::Gt => gt_utf8_scalar(a, b),
+        Comparison::GtEq => gt_eq_utf8_scalar(a, b),
+    }
+}
+
+fn compare_string_arrays(
+    op: Comparison,
+    a: &StringArray,
+    b: &StringArray,
+) -> ArrowResult<BooleanArray> {
+    match op {
+        Comparison::Eq => eq_utf8(a, b),
+        Comparison::NotEq => neq_utf8(a, b),
+        Comparison::Lt => lt_utf8(a, b),
+        Comparison::LtEq => lt_eq_utf8(a, b),
+        Comparison::Gt => gt_utf8(a, b),
+        Comparison::GtEq => gt_eq_utf8(a, b),
+    }
+}
+
+/// Logical columns can only be tested for equality with `TRUE` or `FALSE`
+fn compare_logicals(op: Comparison, a: &BooleanArray, b: bool) -> Result<BooleanArray> {
+    let same = match op {
+        Comparison::Eq => b,
+        Comparison::NotEq => !b,
+        _ => {
+            return Err(RecordBatchesError::invalid(
+                "Logical columns can only be compared with == or !=",
+            ))
+        }
+    };
+    if same {
+        Ok(BooleanArray::from(a.data()))
+    } else {
+        not(a).map_err(RecordBatchesError::arrow("Error evaluating comparison"))
+    }
+}
+
+// -=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-
+// Parsing ---------------------------------------------------------------------
+// -=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Name(String),
+    Number(f64),
+    Text(String),
+    Symbol(&'static str),
+}
+
+/// Symbols, longest first so `<=` isn't read as `<` followed by `=`
+const SYMBOLS: [&str; 14] = [
+    "==", "!=", "<=", ">=", "&&", "||", "<", ">", "&", "|", "!", "(", ")", "-",
+];
+
+/// Splits a predicate into tokens, each with its (zero-based) position
+fn tokenize(input: &str) -> Result<Vec<(Token, usize)>> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        let rest: String = chars[i..].iter().take(2).collect();
+        if let Some(symbol) = SYMBOLS.iter().find(|s| rest.starts_with(*s)) {
+            tokens.push((Token::Symbol(symbol), start));
+            i += symbol.len();
+        } else if c == '"' || c == '\'' || c == '`' {
+            let end = (i + 1..chars.len())
+                .find(|&j| chars[j] == c && chars[j - 1] != '\\')
+                .ok_or_else(|| parse_error("a closing quote", "the end of the predicate"))?;
+            let text: String = chars[i + 1..end].iter().collect();
+            let text = text.replace(&format!("\\{}", c), &c.to_string());
+            tokens.push((
+                if c == '`' {
+                    Token::Name(text)
+                } else {
+                    Token::Text(text)
+                },
+                start,
+            ));
+            i = end + 1;
+        } else if c.is_ascii_digit()
+            || (c == '.' && chars.get(i + 1).map_or(false, |x| x.is_ascii_digit()))
+        {
+            while i < chars.len()
+                && (chars[i].is_ascii_alphanumeric()
+                    || chars[i] == '.'
+                    || ((chars[i] == '+' || chars[i] == '-') && matches!(chars[i - 1], 'e' | 'E')))
+            {
+                i += 1;
+            }
+            let text: String = chars[start..i].iter().collect();
+            let number = text
+                .strip_suffix('L')
+                .unwrap_or(&text)
+                .parse::<f64>()
+                .map_err(|_| {
+                    parse_error("a number", &format!("'{}' at position {}", text, start + 1))
+                })?;
+            tokens.push((Token::Number(number), start));
+        } else if c.is_alphabetic() || c == '.' || c == '_' {
+            while i < chars.len()
+                && (chars[i].is_alphanumeric() || chars[i] == '.' || chars[i] == '_')
+            {
+                i += 1;
+            }
+            tokens.push((Token::Name(chars[start..i].iter().collect()), start));
+        } else {
+            return Err(parse_error(
+                "a column, value, or operator",
+                &format!("'{}' at position {}", c, start + 1),
+            ));
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_error(expected: &str, found: &str) -> RecordBatchesError {
+    RecordBatchesError::schema(
+        "Could not parse predicate",
+        Some(expected.to_string()),
+        Some(found.to_string()),
+    )
+}
+
+/// How deeply operators and parentheses may nest. Parsing, evaluating, and
+/// dropping a predicate all recurse once per level, so this keeps a long
+/// predicate from overflowing the stack, which would crash R. Each level of
+/// parentheses takes a few parser frames, so unoptimized builds need most of
+/// a 2 MB thread stack for 256 levels; 128 leaves room to spare.
+const MAX_DEPTH: usize = 128;
+
+/// A recursive descent parser, from the loosest binding operator (`|`) to the
+/// tightest (`!`)
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    position: usize,
+    depth: usize,
+}
+
+impl Parser {
+    /// Goes one level deeper into the predicate, before consuming the
+    /// operator or parenthesis that opens the level
+    fn descend(&mut self) -> Result<()> {
+        if self.depth == MAX_DEPTH {
+            return Err(self.unexpected("at most 128 nested operators or parentheses"));
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(token, _)| token)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.peek().cloned();
+        self.position += 1;
+        token
+    }
+
+    /// Whether the next token is one of `symbols`
+    fn at(&self, symbols: &[&str]) -> bool {
+        matches!(self.peek(), Some(Token::Symbol(s)) if symbols.contains(s))
+    }
+
+    /// Consumes the next token if it is one of `symbols`
+    fn eat(&mut self, symbols: &[&str]) -> Option<&'static str> {
+        match self.peek() {
+            Some(Token::Symbol(s)) if symbols.contains(s) => {
+                let s = *s;
+                self.position += 1;
+                Some(s)
+            }
+            _ => None,
+        }
+    }
+
+    /// An error for the current token, which isn't what was `expected`
+    fn unexpected(&self, expected: &str) -> RecordBatchesError {
+        let found = match self.tokens.get(self.position) {
+            Some((token, position)) => {
+                let text = match token {
+                    Token::Name(x) | Token::Text(x) => x.clone(),
+                    Token::Number(x) => x.to_string(),
+                    Token::Symbol(x) => x.to_string(),
+                };
+                format!("'{}' at position {}", text, position + 1)
+            }
+            None => "the end of the predicate".to_string(),
+        };
+        parse_error(expected, &found)
+    }
+
+    // Each `|` or `&` in a chain nests the predicate one level deeper
+    fn or(&mut self) -> Result<Predicate> {
+        let depth = self.depth;
+        let mut left = self.and()?;
+        while self.at(&["|", "||"]) {
+            self.descend()?;
+            self.position += 1;
+            left = Predicate::Or(Box::new(left), Box::new(self.and()?));
+        }
+        self.depth = depth;
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Predicate> {
+        let depth = self.depth;
+        let mut left = self.unary()?;
+        while self.at(&["&", "&&"]) {
+            self.descend()?;
+            self.position += 1;
+            left = Predicate::And(Box::new(left), Box::new(self.unary()?));
+        }
+        self.depth = depth;
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Predicate> {
+        if self.at(&["!"]) {
+            self.descend()?;
+            self.position += 1;
+            let predicate = Predicate::Not(Box::new(self.unary()?));
+            self.depth -= 1;
+            return Ok(predicate);
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Predicate> {
+        if self.at(&["("]) {
+            self.descend()?;
+            self.position += 1;
+            let predicate = self.or()?;
+            self.depth -= 1;
+            return match self.eat(&[")"]) {
+                Some(_) => Ok(predicate),
+                None => Err(self.unexpected("')'")),
+            };
+        }
+        if self.peek() == Some(&Token::Name("is.na".to_string()))
+            && self.tokens.get(self.position + 1).map(|(t, _)| t) == Some(&Token::Symbol("("))
+        {
+            self.position += 2;
+            let name = match self.next() {
+                Some(Token::Name(name)) => name,
+                _ => {
+                    self.position -= 1;
+                    return Err(self.unexpected("a column"));
+                }
+            };
+            return match self.eat(&[")"]) {
+                Some(_) => Ok(Predicate::IsNa(name)),
+                None => Err(self.unexpected("')'")),
+            };
+        }
+
+        let left = self.operand()?;
+        let op = match self.eat(&["==", "!=", "<", "<=", ">", ">="]) {
+            Some("==") => Comparison::Eq,
+            Some("!=") => Comparison::NotEq,
+            Some("<") => Comparison::Lt,
+            Some("<=") => Comparison::LtEq,
+            Some(">") => Comparison::Gt,
+            Some(">=") => Comparison::GtEq,
+            _ => {
+                return match left {
+                    Operand::Column(name) => Ok(Predicate::Column(name)),
+                    Operand::Logical(value) => Ok(Predicate::Literal(value)),
+                    _ => Err(self.unexpected("a comparison")),
+                }
+            }
+        };
+        let right = self.operand()?;
+        Ok(Predicate::Compare(op, left, right))
+    }
+
+    fn operand(&mut self) -> Result<Operand> {
+        let negative = self.eat(&["-"]).is_some();
+        let operand = match self.peek().cloned() {
+            Some(Token::Number(x)) => Operand::Number(if negative { -x } else { x }),
+            _ if negative => return Err(self.unexpected("a number")),
+            Some(Token::Text(x)) => Operand::Text(x),
+            Some(Token::Name(x)) if x == "TRUE" => Operand::Logical(true),
+            Some(Token::Name(x)) if x == "FALSE" => Operand::Logical(false),
+            Some(Token::Name(x)) => Operand::Column(x),
+            _ => return Err(self.unexpected("a column or value")),
+        };
+        self.position += 1;
+        Ok(operand)
+    }
+}
+
+#[allow(unused_imports)]
+mod test {
+    use super::*;
+    use arrow::array::{DictionaryArray, Int32Array};
+    use arrow::datatypes::{Field, Int32Type, Schema};
+    use std::sync::Arc;
+
+    #[allow(dead_code)]
+    fn batch() -> RecordBatch {
+        let cyl: DictionaryArray<Int32Type> =
+            vec!["four", "six", "four", "eight"].into_iter().collect();
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(Int32Array::from(vec![Some(4), Some(6), None, Some(8)])),
+            Arc::new(Float64Array::from(vec![
+                Some(30.5),
+                Some(21.0),
+                Some(25.0),
+                None,
+            ])),
+            Arc::new(BooleanArray::from(vec![
+                Some(true),
+                None,
+                Some(false),
+                Some(true),
+            ])),
+            Arc::new(StringArray::from(vec![
+                Some("a"),
+                Some("b"),
+                None,
+                Some("d"),
+            ])),
+            Arc::new(cyl),
+        ];
+        let fields = ["n", "mpg", "am", "name", "cyl"]
+            .iter()
+            .zip(columns.iter())
+            .map(|(name, column)| Field::new(name, column.data_type().clone(), true))
+            .collect();
+        RecordBatch::try_new(Arc::new(Schema::new(fields)), columns).unwrap()
+    }
+
+    #[allow(dead_code)]
+    fn evaluate(predicate: &str) -> Vec<Option<bool>> {
+        let result = Predicate::parse(predicate)
+            .unwrap()
+            .evaluate(&batch())
+            .unwrap();
+        (0..result.len())
+            .map(|i| Some(result.value(i)).filter(|_| result.is_valid(i)))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_precedence() {
+        let parsed = Predicate::parse("!am | n >= 6 & `mpg` < -1.5e1").unwrap();
+        let expected = Predicate::Or(
+            Box::new(Predicate::Not(Box::new(Predicate::Column("am".into())))),
+            Box::new(Predicate::And(
+                Box::new(Predicate::Compare(
+                    Comparison::GtEq,
+                    Operand::Column("n".into()),
+                    Operand::Number(6.0),
+                )),
+                Box::new(Predicate::Compare(
+                    Comparison::Lt,
+                    Operand::Column("mpg".into()),
+                    Operand::Number(-15.0),
+                )),
+            )),
+        );
+        assert_eq!(parsed, expected);
+    }
+
+    #[test]
+    fn test_parse_limits_nesting() {
+        let nested =
+            |open: &str, close: &str, n: usize| format!("{}am{}", open.repeat(n), close.repeat(n));
+        assert!(Predicate::parse(&nested("!", "", MAX_DEPTH)).is_ok());
+        assert!(Predicate::parse(&nested("(", ")", MAX_DEPTH)).is_ok());
+        assert!(Predicate::parse(&nested("!(", ")", MAX_DEPTH / 2)).is_ok());
+        assert!(Predicate::parse(&vec!["am"; MAX_DEPTH + 1].join(" | ")).is_ok());
+        assert_eq!(evaluate(&nested("(!", ")", 50))[0], Some(true));
+
+        for input in [
+            nested("!", "", MAX_DEPTH + 1),
+            nested("(", ")", 100_000),
+            nested("!", "", 100_000),
+            vec!["am"; 100_000].join(" & "),
+        ]
+        .iter()
+        {
+            let err = Predicate::parse(input).unwrap_err();
+            assert_eq!(err.context(), "Could not parse predicate");
+        }
+    }
+
+    #[test]
+    fn test_parse_errors_report_position() {
+        let err = Predicate::parse("n > 4 &").unwrap_err();
+        assert_eq!(err.context(), "Could not parse predicate");
+        assert_eq!(
+            err.cause().unwrap(),
+            "expected a column or value, found the end of the predicate"
+        );
+        let err = Predicate::parse("(n > 4").unwrap_err();
+        assert_eq!(
+            err.cause().unwrap(),
+            "expected ')', found the end of the predicate"
+        );
+        let err = Predicate::parse("n > 4 mpg").unwrap_err();
+        assert!(err.cause().unwrap().ends_with("found 'mpg' at position 7"));
+        assert!(Predicate::parse("4 == 'x").is_err());
+    }
+
+    #[test]
+    fn test_comparisons() {
+        assert_eq!(
+            evaluate("n > 4"),
+            vec![Some(false), Some(true), None, Some(true)]
+        );
+        assert_eq!(
+            evaluate("5 <= n"),
+            vec![Some(false), Some(true), None, Some(true)]
+        );
+        assert_eq!(
+            evaluate("name == 'b'"),
+            vec![Some(false), Some(true), None, Some(false)]
+        );
+        assert_eq!(
+            evaluate("cyl != \"four\""),
+            vec![Some(false), Some(true), Some(false), Some(true)]
+        );
+        assert_eq!(
+            evaluate("mpg > n"),
+            vec![Some(true), Some(true), None, None]
+        );
+        assert_eq!(
+            evaluate("am == FALSE"),
+            vec![Some(false), None, Some(true), Some(false)]
+        );
+        assert_eq!(
+            evaluate("is.na(name)"),
+            vec![Some(false), Some(false), Some(true), Some(false)]
+        );
+    }
+
+    #[test]
+    fn test_logical_operators_follow_r() {
+        assert_eq!(
+            evaluate("am & n > 4"),
+            vec![Some(false), None, Some(false), Some(true)]
+        );
+        assert_eq!(
+            evaluate("am | n > 4"),
+            vec![Some(true), Some(true), None, Some(true)]
+        );
+        assert_eq!(
+            evaluate("!(am | n > 4)"),
+            vec![Some(false), Some(false), None, Some(false)]
+        );
+        assert_eq!(evaluate("TRUE"), vec![Some(true); 4]);
+    }
+
+    #[test]
+    fn test_evaluation_errors_name_the_column() {
+        let batch = batch();
+        let err = Predicate::parse("nope > 1")
+            .unwrap()
+            .evaluate(&batch)
+            .unwrap_err();
+        assert_eq!(err.field(), Some("nope"));
+        let err = Predicate::parse("name > 1")
+            .unwrap()
+            .evaluate(&batch)
+            .unwrap_err();
+        assert_eq!(err.field(), Some("name"));
+        assert_eq!(err.cause().unwrap(), "expected a string, found a number");
+        let err = Predicate::parse("n").unwrap().evaluate(&batch).unwrap_err();
+        assert_eq!(err.field(), Some("n"));
+        assert!(Predicate::parse("1 == 1")
+            .unwrap()
+            .evaluate(&batch)
+            .is_err());
+    }
+}
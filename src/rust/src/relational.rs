@@ -0,0 +1,532 @@
+//! This module runs simple relational operations on RecordBatches in Rust,
+//! using arrow's compute kernels, so R can hand over a whole stream of
+//! RecordBatches and get the result back in one call:
+//!
+//! - [select_columns_fn] keeps some columns, by name and in the order given
+//! - [filter_record_batches_fn] keeps the rows matching a
+//!   [Predicate](crate::predicate::Predicate)
+//! - [sort_record_batches_fn] sorts by one or more columns
+//! - [take_rows_fn] picks rows by index
+//! - [split_record_batches_fn] splits a table in two by a
+//!   [Predicate](crate::predicate::Predicate), as an example of returning
+//!   several tables at once (see [NamedRecordBatches])
+//!
+//! Selecting and filtering work on each RecordBatch in turn, so the batches
+//! stay as they are, and they can also be applied to a [RecordBatchStream] as
+//! it is read (see [stream_record_batches_fn]). Sorting and taking rows work on all rows at once, so the
+//! RecordBatches are first combined into one (see [combine_batches]).
+//!
+//! Row names kept in the schema metadata (see
+//! [RMetadata](crate::structs::RMetadata)) no longer match once rows are
+//! filtered or reordered, so they are dropped, like `dplyr` does.
+
+use crate::predicate::Predicate;
+use crate::structs::{
+    IpcCompression, NamedRecordBatches, RMetadata, RecordBatchStream, RecordBatches,
+    RecordBatchesError, R_METADATA_KEY,
+};
+use arrow::{
+    array::{make_array, Array, ArrayData, ArrayRef, BooleanArray, UInt32Array},
+    compute::{
+        cast, concat, filter_record_batch,
+        kernels::sort::{lexsort_to_indices, SortColumn, SortOptions},
+        take,
+    },
+    datatypes::{DataType, Schema, SchemaRef},
+    record_batch::RecordBatch,
+};
+use std::io::Write;
+use std::sync::Arc;
+
+type Result<T> = std::result::Result<T, RecordBatchesError>;
+
+/// One column to sort by
+#[derive(Debug, Clone)]
+pub(crate) struct SortKey {
+    pub column: String,
+    pub descending: bool,
+}
+
+/// Keeps only the named columns, in the order given. The schema metadata is
+/// kept as it is.
+pub(crate) fn select_columns_fn(
+    batches: RecordBatches,
+    columns: &[String],
+) -> Result<RecordBatches> {
+    let schema = match batches.validate_schema()? {
+        Some(schema) => schema,
+        None => return Ok(batches),
+    };
+    let (indices, selected) = projection(&schema, columns)?;
+    batches
+        .into_iter()
+        .enumerate()
+        .map(|(i, batch)| select_batch(&batch, &indices, &selected).map_err(|e| e.at_message(i)))
+        .collect()
+}
+
+/// Keeps the rows of each RecordBatch for which `predicate` is true (not
+/// false or NA)
+pub(crate) fn filter_record_batches_fn(
+    batches: RecordBatches,
+    predicate: &str,
+) -> Result<RecordBatches> {
+    let predicate = Predicate::parse(predicate)?;
+    let schema = match batches.validate_schema()? {
+        Some(schema) => without_row_names(&schema)?,
+        None => return Ok(batches),
+    };
+    batches
+        .into_iter()
+        .enumerate()
+        .map(|(i, batch)| filter_batch(&predicate, &batch, &schema).map_err(|e| e.at_message(i)))
+        .collect()
+}
+
+/// Filters (if there is a `predicate`) and then selects the `columns` (if
+/// given) of each RecordBatch of `stream` as it is read, writing each result
+/// to `writer` before reading the next RecordBatch
+pub(crate) fn stream_record_batches_fn<W: Write>(
+    stream: RecordBatchStream,
+    writer: W,
+    columns: Option<&[String]>,
+    predicate: Option<&str>,
+    compression: Option<IpcCompression>,
+) -> Result<W> {
+    let predicate = predicate.map(Predicate::parse).transpose()?;
+    let filtered = match predicate {
+        Some(_) => without_row_names(&stream.schema())?,
+        None => stream.schema(),
+    };
+    let projection = columns
+        .map(|columns| projection(&filtered, columns))
+        .transpose()?;
+    let schema = match &projection {
+        Some((_, selected)) => selected.clone(),
+        None => filtered.clone(),
+    };
+    stream.map_into(writer, schema, compression, |batch| {
+        let batch = match &predicate {
+            Some(predicate) => filter_batch(predicate, &batch, &filtered)?,
+            None => batch,
+        };
+        match &projection {
+            Some((indices, selected)) => select_batch(&batch, indices, selected),
+            None => Ok(batch),
+        }
+    })
+}
+
+/// The indices of the named columns, and the schema holding just them
+fn projection(schema: &SchemaRef, columns: &[String]) -> Result<(Vec<usize>, SchemaRef)> {
+    let indices = columns
+        .iter()
+        .map(|name| column_index(schema, name))
+        .collect::<Result<Vec<_>>>()?;
+    let fields = indices.iter().map(|&i| schema.field(i).clone()).collect();
+    let selected = Schema::new_with_metadata(fields, schema.metadata().clone());
+    Ok((indices, Arc::new(selected)))
+}
+
+fn select_batch(
+    batch: &RecordBatch,
+    indices: &[usize],
+    selected: &SchemaRef,
+) -> Result<RecordBatch> {
+    let columns = indices.iter().map(|&j| batch.column(j).clone()).collect();
+    RecordBatch::try_new(selected.clone(), columns)
+        .map_err(RecordBatchesError::arrow("Error selecting columns"))
+}
+
+/// Keeps the rows of `batch` matching `predicate`, giving the result
+/// `schema` (the schema of `batch` without row names)
+fn filter_batch(
+    predicate: &Predicate,
+    batch: &RecordBatch,
+    schema: &SchemaRef,
+) -> Result<RecordBatch> {
+    let matches = matching_rows(predicate, batch)?;
+    take_matching(batch, &matches, true, schema)
+}
+
+/// Whether each row of `batch` matches `predicate`. NA's are dropped when
+/// filtering, so they count as false.
+fn matching_rows(predicate: &Predicate, batch: &RecordBatch) -> Result<Vec<bool>> {
+    let matches = predicate.evaluate(batch)?;
+    Ok((0..matches.len())
+        .map(|j| matches.is_valid(j) && matches.value(j))
+        .collect())
+}
+
+/// Keeps the rows of `batch` where `matches` equals `keep`, giving the result
+/// `schema`
+fn take_matching(
+    batch: &RecordBatch,
+    matches: &[bool],
+    keep: bool,
+    schema: &SchemaRef,
+) -> Result<RecordBatch> {
+    let mask: BooleanArray = matches
+        .iter()
+        .map(|&matched| Some(matched == keep))
+        .collect::<Vec<_>>()
+        .into();
+    filter_record_batch(batch, &mask)
+        .and_then(|filtered| RecordBatch::try_new(schema.clone(), filtered.columns().to_vec()))
+        .map_err(RecordBatchesError::arrow("Error filtering RecordBatch"))
+}
+
+/// Splits the required `data` table in two: the `matched` rows, for which
+/// `predicate` is true, and the `unmatched` rows, for which it is false or
+/// NA. Each RecordBatch of `data` is split in turn, so both tables have as
+/// many RecordBatches as `data`.
+pub(crate) fn split_record_batches_fn(
+    mut tables: NamedRecordBatches,
+    predicate: &str,
+) -> Result<NamedRecordBatches> {
+    let predicate = Predicate::parse(predicate)?;
+    let data = tables.take_required("data")?;
+    let schema = match data.validate_schema().map_err(|e| e.for_table("data"))? {
+        Some(schema) => without_row_names(&schema)?,
+        None => Arc::new(Schema::empty()),
+    };
+    let mut matched = Vec::new();
+    let mut unmatched = Vec::new();
+    for (i, batch) in data.into_iter().enumerate() {
+        let split = matching_rows(&predicate, &batch).and_then(|matches| {
+            matched.push(take_matching(&batch, &matches, true, &schema)?);
+            unmatched.push(take_matching(&batch, &matches, false, &schema)?);
+            Ok(())
+        });
+        split.map_err(|e| e.at_message(i).for_table("data"))?;
+    }
+    let mut out = NamedRecordBatches::new();
+    out.insert("matched", matched.into_iter().collect());
+    out.insert("unmatched", unmatched.into_iter().collect());
+    Ok(out)
+}
+
+/// Sorts all rows by each key in turn. Ties keep their original order, and
+/// NA's go last or first depending on `na_last`, whichever way each key is
+/// sorted. Dictionary columns (like factors) are sorted by their keys, which
+/// is the order of the levels.
+pub(crate) fn sort_record_batches_fn(
+    batches: RecordBatches,
+    keys: &[SortKey],
+    na_last: bool,
+) -> Result<RecordBatches> {
+    let batch = match combine_batches(batches)? {
+        Some(batch) => batch,
+        None => return Ok(Vec::<RecordBatch>::new().into_iter().collect()),
+    };
+    let schema = batch.schema();
+    let mut columns = keys
+        .iter()
+        .map(|key| {
+            let column = batch.column(column_index(&schema, &key.column)?);
+            let values = match column.data_type() {
+                DataType::Dictionary(_, _) => dictionary_keys(column),
+                _ => column.clone(),
+            };
+            Ok(SortColumn {
+                values,
+                options: Some(SortOptions {
+                    descending: key.descending,
+                    nulls_first: !na_last,
+                }),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    // Sorting by row number last makes the sort stable
+    columns.push(SortColumn {
+        values: Arc::new(UInt32Array::from(
+            (0..batch.num_rows() as u32).collect::<Vec<_>>(),
+        )),
+        options: None,
+    });
+    let indices = lexsort_to_indices(&columns)
+        .map_err(RecordBatchesError::arrow("Error sorting RecordBatches"))?;
+    Ok(vec![take_batch(&batch, &indices)?].into_iter().collect())
+}
+
+/// Picks rows by (zero-based) index across all RecordBatches, in the order
+/// given. Rows can be repeated, and a `None` index gives a row of nulls.
+pub(crate) fn take_rows_fn(
+    batches: RecordBatches,
+    rows: &[Option<usize>],
+) -> Result<RecordBatches> {
+    let batch = match combine_batches(batches)? {
+        Some(batch) => batch,
+        None if rows.is_empty() => return Ok(Vec::<RecordBatch>::new().into_iter().collect()),
+        None => return Err(RecordBatchesError::invalid("Row index out of bounds")),
+    };
+    if rows.iter().flatten().any(|&i| i >= batch.num_rows()) {
+        return Err(RecordBatchesError::invalid("Row index out of bounds"));
+    }
+    let indices = UInt32Array::from(rows.iter().map(|x| x.map(|i| i as u32)).collect::<Vec<_>>());
+    Ok(vec![take_batch(&batch, &indices)?].into_iter().collect())
+}
+
+/// Combines RecordBatches that share a schema into a single RecordBatch, or
+/// returns `None` if there are none. Dictionary columns keep their dictionary
+/// if every RecordBatch has the same one; otherwise the values are combined
+/// into a new dictionary, in the order they first appear.
+pub(crate) fn combine_batches(batches: RecordBatches) -> Result<Option<RecordBatch>> {
+    let schema = match batches.validate_schema()? {
+        Some(schema) => schema,
+        None => return Ok(None),
+    };
+    let batches: Vec<RecordBatch> = batches.into_iter().collect();
+    if batches.len() == 1 {
+        return Ok(batches.into_iter().next());
+    }
+    let columns = schema
+        .fields()
+        .iter()
+        .enumerate()
+        .map(|(i, field)| {
+            let arrays: Vec<&ArrayRef> = batches.iter().map(|batch| batch.column(i)).collect();
+            combine_arrays(&arrays).map_err(|e| e.for_field(field.name().as_str()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    RecordBatch::try_new(schema, columns)
+        .map(Some)
+        .map_err(RecordBatchesError::arrow("Error combining RecordBatches"))
+}
+
+/// Concatenates arrays of one type, combining their dictionaries if they differ
+pub(crate) fn combine_arrays(arrays: &[&ArrayRef]) -> Result<ArrayRef> {
+    let concat_arrays = |arrays: &[ArrayRef]| {
+        let arrays: Vec<&dyn Array> = arrays.iter().map(|a| a.as_ref()).collect();
+        concat(&arrays).map_err(RecordBatchesError::arrow("Error combining RecordBatches"))
+    };
+    let data_type = arrays[0].data_type();
+    let value_type = match data_type {
+        DataType::Dictionary(_, value_type) => value_type,
+        _ => return concat_arrays(&arrays.iter().map(|&a| a.clone()).collect::<Vec<_>>()),
+    };
+
+    let values = arrays[0].data_ref().child_data()[0].clone();
+    let same_dictionary = arrays.iter().all(|a| {
+        make_array(a.data_ref().child_data()[0].clone()).as_ref()
+            == make_array(values.clone()).as_ref()
+    });
+    if same_dictionary {
+        let keys: Vec<ArrayRef> = arrays.iter().map(|a| dictionary_keys(a)).collect();
+        let keys = concat_arrays(&keys)?.data();
+        let data = ArrayData::new(
+            data_type.clone(),
+            keys.len(),
+            Some(keys.null_count()),
+            keys.null_buffer().cloned(),
+            keys.offset(),
+            keys.buffers().to_vec(),
+            vec![values],
+        );
+        return Ok(make_array(Arc::new(data)));
+    }
+
+    let decoded = arrays
+        .iter()
+        .map(|a| cast(a, value_type))
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(RecordBatchesError::arrow("Error combining dictionaries"))?;
+    let combined = concat_arrays(&decoded)?;
+    cast(&combined, data_type).map_err(RecordBatchesError::arrow("Error combining dictionaries"))
+}
+
+/// The keys of a dictionary array, as an array of its key type
+pub(crate) fn dictionary_keys(array: &ArrayRef) -> ArrayRef {
+    let data = array.data_ref();
+    let key_type = match data.data_type() {
+        DataType::Dictionary(key_type, _) => (**key_type).clone(),
+        data_type => data_type.clone(),
+    };
+    let keys = ArrayData::new(
+        key_type,
+        data.len(),
+        Some(data.null_count()),
+        data.null_buffer().cloned(),
+        data.offset(),
+        data.buffers().to_vec(),
+        vec![],
+    );
+    make_array(Arc::new(keys))
+}
+
+/// Takes rows from every column of a RecordBatch, dropping any row names
+fn take_batch(batch: &RecordBatch, indices: &UInt32Array) -> Result<RecordBatch> {
+    let schema = without_row_names(&batch.schema())?;
+    let columns = batch
+        .columns()
+        .iter()
+        .zip(schema.fields())
+        .map(|(column, field)| {
+            take(column.as_ref(), indices, None).map_err(|e| {
+                RecordBatchesError::arrow("Error taking rows")(e).for_field(field.name().as_str())
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    RecordBatch::try_new(schema, columns).map_err(RecordBatchesError::arrow("Error taking rows"))
+}
+
+/// The schema with any row names removed from its R metadata
+pub(crate) fn without_row_names(schema: &SchemaRef) -> Result<SchemaRef> {
+    let r_metadata = match schema.metadata().get(R_METADATA_KEY) {
+        Some(r_metadata) => r_metadata,
+        None => return Ok(schema.clone()),
+    };
+    let mut metadata = schema.metadata().clone();
+    match RMetadata::unserialize(r_metadata).and_then(|x| x.without_row_names()) {
+        Some(r_metadata) => metadata.insert(R_METADATA_KEY.to_string(), r_metadata.serialize()?),
+        None => metadata.remove(R_METADATA_KEY),
+    };
+    Ok(Arc::new(Schema::new_with_metadata(
+        schema.fields().clone(),
+        metadata,
+    )))
+}
+
+pub(crate) fn column_index(schema: &Schema, name: &str) -> Result<usize> {
+    schema
+        .index_of(name)
+        .map_err(|_| RecordBatchesError::invalid("Unknown column").for_field(name))
+}
+
+#[allow(unused_imports)]
+mod test {
+    use super::*;
+    use arrow::array::{DictionaryArray, Float64Array, Int32Array, StringArray};
+    use arrow::datatypes::{Field, Int32Type};
+
+    #[allow(dead_code)]
+    fn batch(ids: Vec<Option<i32>>, groups: Vec<&str>) -> RecordBatch {
+        let schema = Schema::new(vec![
+            Field::new("id", DataType::Int32, true),
+            Field::new("group", DataType::Utf8, true),
+        ]);
+        RecordBatch::try_new(
+            Arc::new(schema),
+            vec![
+                Arc::new(Int32Array::from(ids)),
+                Arc::new(StringArray::from(groups)),
+            ],
+        )
+        .unwrap()
+    }
+
+    #[allow(dead_code)]
+    fn sample() -> RecordBatches {
+        vec![
+            batch(vec![Some(3), None, Some(1)], vec!["b", "a", "b"]),
+            batch(vec![Some(2), Some(1)], vec!["a", "a"]),
+        ]
+        .into_iter()
+        .collect()
+    }
+
+    #[allow(dead_code)]
+    fn ids(batches: RecordBatches) -> Vec<Option<i32>> {
+        let mut out = Vec::new();
+        for batch in batches {
+            let ids = batch
+                .column(0)
+                .as_any()
+                .downcast_ref::<Int32Array>()
+                .unwrap();
+            out.extend((0..ids.len()).map(|i| Some(ids.value(i)).filter(|_| ids.is_valid(i))));
+        }
+        out
+    }
+
+    #[test]
+    fn test_select_columns() {
+        let selected = select_columns_fn(sample(), &["group".to_string()]).unwrap();
+        let schema = selected.schema().unwrap();
+        assert_eq!(schema.fields().len(), 1);
+        assert_eq!(schema.field(0).name(), "group");
+        assert_eq!(selected.into_iter().count(), 2);
+
+        let err = select_columns_fn(sample(), &["nope".to_string()]).unwrap_err();
+        assert_eq!(err.field(), Some("nope"));
+    }
+
+    #[test]
+    fn test_filter_keeps_batches_and_drops_na() {
+        let filtered = filter_record_batches_fn(sample(), "id >= 2 | group == 'a'").unwrap();
+        let counts: Vec<usize> = filtered.into_iter().map(|b| b.num_rows()).collect();
+        assert_eq!(counts, vec![2, 2]);
+
+        let filtered = filter_record_batches_fn(sample(), "id > 1").unwrap();
+        assert_eq!(ids(filtered), vec![Some(3), Some(2)]);
+    }
+
+    #[test]
+    fn test_split_keeps_na_with_unmatched_rows() {
+        let mut tables = NamedRecordBatches::new();
+        tables.insert("data", sample());
+        let mut split = split_record_batches_fn(tables, "id > 1").unwrap();
+        let matched = split.take_required("matched").unwrap();
+        let unmatched = split.take_required("unmatched").unwrap();
+        assert_eq!(ids(matched), vec![Some(3), Some(2)]);
+        assert_eq!(ids(unmatched), vec![None, Some(1), Some(1)]);
+
+        let err = split_record_batches_fn(NamedRecordBatches::new(), "id > 1").unwrap_err();
+        assert_eq!(err.table(), Some("data"));
+    }
+
+    #[test]
+    fn test_sort_is_stable_with_na_placement() {
+        let keys = vec![
+            SortKey {
+                column: "group".to_string(),
+                descending: false,
+            },
+            SortKey {
+                column: "id".to_string(),
+                descending: true,
+            },
+        ];
+        let sorted = sort_record_batches_fn(sample(), &keys, true).unwrap();
+        assert_eq!(ids(sorted), vec![Some(2), Some(1), None, Some(3), Some(1)]);
+
+        let sorted = sort_record_batches_fn(sample(), &keys[1..], false).unwrap();
+        assert_eq!(ids(sorted), vec![None, Some(3), Some(2), Some(1), Some(1)]);
+    }
+
+    #[test]
+    fn test_take_rows() {
+        let taken = take_rows_fn(sample(), &[Some(4), None, Some(0), Some(0)]).unwrap();
+        assert_eq!(ids(taken), vec![Some(1), None, Some(3), Some(3)]);
+        assert!(take_rows_fn(sample(), &[Some(5)]).is_err());
+    }
+
+    #[test]
+    fn test_combine_batches_with_dictionaries() {
+        let dictionary_batch = |values: Vec<&str>| {
+            let array: DictionaryArray<Int32Type> = values.into_iter().collect();
+            let field = Field::new("f", array.data_type().clone(), true);
+            RecordBatch::try_new(Arc::new(Schema::new(vec![field])), vec![Arc::new(array)]).unwrap()
+        };
+        let same: RecordBatches = vec![
+            dictionary_batch(vec!["a", "b"]),
+            dictionary_batch(vec!["a", "b"]),
+        ]
+        .into_iter()
+        .collect();
+        let combined = combine_batches(same).unwrap().unwrap();
+        assert_eq!(combined.num_rows(), 4);
+
+        let different: RecordBatches = vec![
+            dictionary_batch(vec!["a", "b"]),
+            dictionary_batch(vec!["c", "a"]),
+        ]
+        .into_iter()
+        .collect();
+        let combined = combine_batches(different).unwrap().unwrap();
+        let decoded = cast(combined.column(0), &DataType::Utf8).unwrap();
+        let decoded = decoded.as_any().downcast_ref::<StringArray>().unwrap();
+        let values: Vec<&str> = (0..decoded.len()).map(|i| decoded.value(i)).collect();
+        assert_eq!(values, vec!["a", "b", "c", "a"]);
+    }
+}
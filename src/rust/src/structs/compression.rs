@@ -0,0 +1,655 @@
+//! This module adds IPC body compression, which arrow (v3.0.0) doesn't support
+//! yet. Arrow compresses each buffer of a RecordBatch (or DictionaryBatch)
+//! separately, with either the LZ4 frame format or ZSTD, and records the codec
+//! in the message's `BodyCompression` table. Each compressed buffer starts
+//! with its uncompressed length as a little-endian i64, or -1 if the writer
+//! decided the buffer wasn't worth compressing.
+//!
+//! On the write side, [RecordBatchStreamSink](crate::structs::RecordBatchStreamSink)
+//! and [RecordBatchFileSink](crate::structs::RecordBatchFileSink) take an
+//! optional [IpcCompression]. On the read side, [rewrite_ipc_messages]
+//! rewrites any compressed messages as uncompressed ones, so the result can be
+//! handed to arrow's `StreamReader`. Dictionary deltas, which arrow can't read
+//! either, are rewritten on the way (see
+//! [DictionaryDeltas](crate::structs::DictionaryDeltas)), and so are schemas
+//! with `Map` fields, which are read as lists of key/value structs.
+//! [RewritingReader] does the same one message at a time, for streams that
+//! are read lazily.
+
+use crate::structs::{
+    schema_from_message, schema_to_bytes, write_encoded_data_to_stream, DictionaryDeltas,
+    RecordBatchesError, ARROW_MAGIC,
+};
+use arrow::ipc::{
+    writer::EncodedData, BodyCompressionMethod, CompressionType, MessageHeader, MetadataVersion,
+};
+use flatbuffers::FlatBufferBuilder;
+use std::convert::{TryFrom, TryInto};
+use std::io::{ErrorKind, Read, Write};
+
+type Result<T> = std::result::Result<T, RecordBatchesError>;
+
+/// The end-of-stream marker: a continuation marker and a zero length
+const END_OF_STREAM: [u8; 8] = [0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0];
+
+/// The codecs that can be used to compress the body of an IPC message
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IpcCompression {
+    Lz4Frame,
+    Zstd,
+}
+
+impl IpcCompression {
+    /// Parses the name of an IPC compression codec, using the same names as
+    /// R's `arrow::write_feather()`. "uncompressed" is `None`.
+    pub fn parse(compression: &str) -> Result<Option<Self>> {
+        match compression.to_lowercase().as_str() {
+            "uncompressed" => Ok(None),
+            "lz4" | "lz4_frame" => Ok(Some(IpcCompression::Lz4Frame)),
+            "zstd" => Ok(Some(IpcCompression::Zstd)),
+            _ => Err(RecordBatchesError::invalid(
+                "Unknown compression, expected one of 'uncompressed', 'lz4', or 'zstd'",
+            )),
+        }
+    }
+
+    fn from_fb(codec: CompressionType) -> Result<Self> {
+        match codec {
+            CompressionType::LZ4_FRAME => Ok(IpcCompression::Lz4Frame),
+            CompressionType::ZSTD => Ok(IpcCompression::Zstd),
+            _ => Err(RecordBatchesError::invalid(
+                "Unsupported IPC body compression codec",
+            )),
+        }
+    }
+
+    /// The flatbuffer enum value recorded in `BodyCompression`
+    pub fn to_fb(self) -> CompressionType {
+        match self {
+            IpcCompression::Lz4Frame => CompressionType::LZ4_FRAME,
+            IpcCompression::Zstd => CompressionType::ZSTD,
+        }
+    }
+
+    /// Compresses one buffer, including the leading uncompressed length
+    pub fn compress(self, input: &[u8]) -> Result<Vec<u8>> {
+        let mut output = (input.len() as i64).to_le_bytes().to_vec();
+        match self {
+            IpcCompression::Lz4Frame => {
+                let mut encoder = lz4::EncoderBuilder::new()
+                    .build(output)
+                    .map_err(RecordBatchesError::io("Could not create LZ4 encoder"))?;
+                encoder
+                    .write_all(input)
+                    .map_err(RecordBatchesError::io("Could not compress buffer with LZ4"))?;
+                let (buffer, result) = encoder.finish();
+                result.map_err(RecordBatchesError::io("Could not compress buffer with LZ4"))?;
+                output = buffer;
+            }
+            IpcCompression::Zstd => {
+                let compressed = zstd::stream::encode_all(input, 0).map_err(
+                    RecordBatchesError::io("Could not compress buffer with ZSTD"),
+                )?;
+                output.extend_from_slice(&compressed);
+            }
+        }
+        Ok(output)
+    }
+
+    /// The largest ratio of uncompressed to compressed size the codec can
+    /// produce: LZ4 emits at least one byte per 255 repeated bytes, and a
+    /// ZSTD block of up to 128 KiB can be a single repeated byte.
+    fn max_ratio(self) -> usize {
+        match self {
+            IpcCompression::Lz4Frame => 256,
+            IpcCompression::Zstd => 32 * 1024,
+        }
+    }
+
+    /// Decompresses one buffer, which starts with its uncompressed length.
+    /// The length comes from the input, so it is checked against the size of
+    /// the compressed data before any memory is allocated for it.
+    pub fn decompress(self, input: &[u8]) -> Result<Vec<u8>> {
+        if input.is_empty() {
+            return Ok(Vec::new());
+        }
+        if input.len() < 8 {
+            return Err(RecordBatchesError::invalid(
+                "Compressed buffer is missing its uncompressed length",
+            ));
+        }
+        let (len, data) = input.split_at(8);
+        let len = i64::from_le_bytes(len.try_into().unwrap());
+        if len == -1 {
+            return Ok(data.to_vec());
+        }
+        if len < 0 {
+            return Err(RecordBatchesError::invalid(
+                "Compressed buffer has a negative uncompressed length",
+            ));
+        }
+        let len = len as u64;
+        if len > (data.len() as u64 + 64) * self.max_ratio() as u64 {
+            return Err(RecordBatchesError::invalid(
+                "Compressed buffer is too short for its uncompressed length",
+            ));
+        }
+        // Reading one byte past `len` is enough to tell the length was wrong
+        let mut output = Vec::with_capacity(len as usize);
+        match self {
+            IpcCompression::Lz4Frame => {
+                lz4::Decoder::new(data)
+                    .and_then(|decoder| decoder.take(len + 1).read_to_end(&mut output))
+                    .map_err(RecordBatchesError::io("Could not decompress LZ4 buffer"))?;
+            }
+            IpcCompression::Zstd => {
+                zstd::stream::Decoder::new(data)
+                    .and_then(|decoder| decoder.take(len + 1).read_to_end(&mut output))
+                    .map_err(RecordBatchesError::io("Could not decompress ZSTD buffer"))?;
+            }
+        }
+        if output.len() as u64 != len {
+            return Err(RecordBatchesError::invalid(
+                "Decompressed buffer has the wrong length",
+            ));
+        }
+        Ok(output)
+    }
+}
+
+/// Rewrites the IPC stream (or the messages of an IPC file) in `input` so
+/// arrow's `StreamReader` can read it: every compressed RecordBatch and
+/// DictionaryBatch body is decompressed, every delta DictionaryBatch is
+/// replaced by the whole dictionary, and `Map` fields in the schema become
+/// `List` fields. Returns `None`, without copying anything,
+/// if no message needed rewriting. The output is always in the stream format,
+/// ending with an end-of-stream marker.
+pub fn rewrite_ipc_messages(input: &[u8]) -> Result<Option<Vec<u8>>> {
+    let input = ipc_file_messages(input)?.unwrap_or(input);
+    let mut output: Option<Vec<u8>> = None;
+    let mut rewriter = MessageRewriter::new();
+    let mut position = 0;
+
+    while position + 4 <= input.len() {
+        let start = position;
+        let mut meta_len = read_i32(input, position);
+        position += 4;
+        if meta_len == -1 {
+            if position + 4 > input.len() {
+                break;
+            }
+            meta_len = read_i32(input, position);
+            position += 4;
+        }
+        if meta_len <= 0 {
+            break; // end-of-stream marker
+        }
+        let message_index = rewriter.message_index;
+        let meta_end = position
+            .checked_add(meta_len as usize)
+            .ok_or_else(|| truncated(message_index))?;
+        let meta = input
+            .get(position..meta_end)
+            .ok_or_else(|| truncated(message_index))?;
+        let message = read_message(meta, message_index)?;
+        let body_end = meta_end
+            .checked_add(body_length(&message, message_index)?)
+            .ok_or_else(|| truncated(message_index))?;
+        let body = input
+            .get(meta_end..body_end)
+            .ok_or_else(|| truncated(message_index))?;
+        position = body_end;
+
+        if let Some(encoded) = rewriter.rewrite(meta, body)? {
+            // Copy everything before the first rewritten message as-is
+            let out = output.get_or_insert_with(|| input[..start].to_vec());
+            write_encoded_data_to_stream(out, encoded)?;
+        } else if let Some(out) = output.as_mut() {
+            out.extend_from_slice(&input[start..position]);
+        }
+    }
+
+    Ok(output.map(|mut out| {
+        out.extend_from_slice(&END_OF_STREAM);
+        out
+    }))
+}
+
+/// Rewrites IPC messages one at a time for [rewrite_ipc_messages] and
+/// [RewritingReader], keeping track of the dictionaries read so far
+pub struct MessageRewriter {
+    deltas: DictionaryDeltas,
+    message_index: usize,
+}
+
+impl MessageRewriter {
+    pub fn new() -> Self {
+        MessageRewriter {
+            deltas: DictionaryDeltas::new(),
+            message_index: 0,
+        }
+    }
+
+    /// Rewrites the message with the flatbuffer `meta` and `body`, or returns
+    /// `None` if it can be read as it is
+    pub fn rewrite(&mut self, meta: &[u8], body: &[u8]) -> Result<Option<EncodedData>> {
+        let message_index = self.message_index;
+        let message = read_message(meta, message_index)?;
+        let decompressed =
+            decompress_message(&message, body).map_err(|e| e.at_message(message_index))?;
+        let rewritten = match message.header_type() {
+            MessageHeader::Schema => {
+                let (schema, has_maps) = schema_from_message(&message)
+                    .ok_or_else(|| RecordBatchesError::invalid("Schema message has no schema"))?;
+                self.deltas.read_schema(&schema);
+                if has_maps {
+                    Some(schema_to_bytes(&schema))
+                } else {
+                    decompressed
+                }
+            }
+            MessageHeader::DictionaryBatch => {
+                let resolved = match &decompressed {
+                    Some(encoded) => self.deltas.resolve(
+                        &read_message(&encoded.ipc_message, message_index)?,
+                        &encoded.arrow_data,
+                    ),
+                    None => self.deltas.resolve(&message, body),
+                };
+                resolved
+                    .map_err(|e| e.at_message(message_index))?
+                    .or(decompressed)
+            }
+            MessageHeader::RecordBatch => {
+                self.message_index += 1;
+                decompressed
+            }
+            _ => decompressed,
+        };
+        Ok(rewritten)
+    }
+}
+
+/// Reads an IPC stream from `R`, rewriting each message as it is read the way
+/// [rewrite_ipc_messages] does. Arrow's `StreamReader` can then read
+/// compressed streams and dictionary deltas lazily, holding one message in
+/// memory at a time instead of the whole stream.
+pub struct RewritingReader<R: Read> {
+    inner: R,
+    rewriter: MessageRewriter,
+    buffer: Vec<u8>,
+    position: usize,
+    finished: bool,
+}
+
+impl<R: Read> RewritingReader<R> {
+    pub fn new(inner: R) -> Self {
+        RewritingReader {
+            inner,
+            rewriter: MessageRewriter::new(),
+            buffer: Vec::new(),
+            position: 0,
+            finished: false,
+        }
+    }
+
+    /// Reads the next message into the buffer, rewritten if necessary, or
+    /// the end-of-stream marker once there are no more messages
+    fn read_message(&mut self) -> Result<()> {
+        self.buffer.clear();
+        self.position = 0;
+        let mut meta_len = self.read_prefix()?;
+        if meta_len == Some(-1) {
+            meta_len = self.read_prefix()?;
+        }
+        let meta_len = match meta_len {
+            Some(len) if len > 0 => len as usize,
+            _ => {
+                self.buffer.extend_from_slice(&END_OF_STREAM);
+                self.finished = true;
+                return Ok(());
+            }
+        };
+        let message_index = self.rewriter.message_index;
+        let meta = self.read_bytes(meta_len, message_index)?;
+        let body_len = body_length(&read_message(&meta, message_index)?, message_index)?;
+        let body = self.read_bytes(body_len, message_index)?;
+
+        match self.rewriter.rewrite(&meta, &body)? {
+            Some(encoded) => {
+                write_encoded_data_to_stream(&mut self.buffer, encoded)?;
+            }
+            None => {
+                self.buffer.extend_from_slice(&[0xff, 0xff, 0xff, 0xff]);
+                self.buffer
+                    .extend_from_slice(&(meta_len as i32).to_le_bytes());
+                self.buffer.extend_from_slice(&meta);
+                self.buffer.extend_from_slice(&body);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads exactly `len` bytes. The length comes from the input, so the
+    /// buffer only grows as the bytes actually arrive, rather than being
+    /// allocated up front.
+    fn read_bytes(&mut self, len: usize, message_index: usize) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        (&mut self.inner)
+            .take(len as u64)
+            .read_to_end(&mut bytes)
+            .map_err(RecordBatchesError::io("Could not read IPC message"))?;
+        if bytes.len() != len {
+            return Err(truncated(message_index));
+        }
+        Ok(bytes)
+    }
+
+    /// Reads a length prefix (or continuation marker), or `None` at the end
+    /// of the input
+    fn read_prefix(&mut self) -> Result<Option<i32>> {
+        let mut prefix = [0u8; 4];
+        match self.inner.read_exact(&mut prefix) {
+            Ok(()) => Ok(Some(i32::from_le_bytes(prefix))),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(RecordBatchesError::io("Could not read IPC message")(e)),
+        }
+    }
+}
+
+impl<R: Read> Read for RewritingReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        if self.position == self.buffer.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            self.read_message()
+                .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e.to_string()))?;
+        }
+        let len = out.len().min(self.buffer.len() - self.position);
+        out[..len].copy_from_slice(&self.buffer[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+/// The bytes between the leading magic bytes and the footer of an IPC file,
+/// which are the messages of the file in the stream format, or `None` if
+/// `input` isn't an IPC file
+fn ipc_file_messages(input: &[u8]) -> Result<Option<&[u8]>> {
+    let magic = ARROW_MAGIC.len();
+    if input.len() < 2 * magic + 6 || input[..magic] != ARROW_MAGIC {
+        return Ok(None);
+    }
+    let footer_len_start = input.len() - magic - 4;
+    let footer_len = read_i32(input, footer_len_start);
+    usize::try_from(footer_len)
+        .ok()
+        .and_then(|footer_len| footer_len_start.checked_sub(footer_len))
+        .and_then(|footer_start| input.get(magic + 2..footer_start))
+        .map(Some)
+        .ok_or_else(|| RecordBatchesError::invalid("IPC file footer is out of bounds"))
+}
+
+/// Decompresses the body of a RecordBatch or DictionaryBatch message, or
+/// returns `None` if the message isn't compressed
+fn decompress_message(message: &arrow::ipc::Message, body: &[u8]) -> Result<Option<EncodedData>> {
+    let (batch, dictionary) = match message.header_type() {
+        MessageHeader::RecordBatch => (message.header_as_record_batch(), None),
+        MessageHeader::DictionaryBatch => {
+            let dictionary = message.header_as_dictionary_batch();
+            (dictionary.and_then(|d| d.data()), dictionary)
+        }
+        _ => return Ok(None),
+    };
+    let batch = match batch {
+        Some(batch) => batch,
+        None => return Ok(None),
+    };
+    let compression = match batch.compression() {
+        Some(compression) => compression,
+        None => return Ok(None),
+    };
+    if compression.method() != BodyCompressionMethod::BUFFER {
+        return Err(RecordBatchesError::invalid(
+            "Unsupported IPC body compression method",
+        ));
+    }
+    let codec = IpcCompression::from_fb(compression.codec())?;
+
+    let mut buffers = Vec::new();
+    let mut arrow_data = Vec::new();
+    for buffer in batch.buffers().unwrap_or(&[]) {
+        let compressed = buffer_range(buffer)
+            .and_then(|range| body.get(range))
+            .ok_or_else(|| RecordBatchesError::invalid("IPC buffer out of bounds"))?;
+        let decompressed = codec.decompress(compressed)?;
+        let offset = arrow_data.len();
+        arrow_data.extend_from_slice(&decompressed);
+        arrow_data.resize(offset + ((decompressed.len() + 7) & !7), 0);
+        buffers.push(arrow::ipc::Buffer::new(
+            offset as i64,
+            decompressed.len() as i64,
+        ));
+    }
+    let nodes: Vec<_> = batch
+        .nodes()
+        .unwrap_or(&[])
+        .iter()
+        .map(|n| arrow::ipc::FieldNode::new(n.length(), n.null_count()))
+        .collect();
+
+    let mut fbb = FlatBufferBuilder::new();
+    let buffers = fbb.create_vector(&buffers);
+    let nodes = fbb.create_vector(&nodes);
+    let root = {
+        let mut batch_builder = arrow::ipc::RecordBatchBuilder::new(&mut fbb);
+        batch_builder.add_length(batch.length());
+        batch_builder.add_nodes(nodes);
+        batch_builder.add_buffers(buffers);
+        batch_builder.finish()
+    };
+    let (header_type, root) = match dictionary {
+        Some(dictionary) => {
+            let mut dictionary_builder = arrow::ipc::DictionaryBatchBuilder::new(&mut fbb);
+            dictionary_builder.add_id(dictionary.id());
+            dictionary_builder.add_data(root);
+            dictionary_builder.add_isDelta(dictionary.isDelta());
+            (
+                MessageHeader::DictionaryBatch,
+                dictionary_builder.finish().as_union_value(),
+            )
+        }
+        None => (MessageHeader::RecordBatch, root.as_union_value()),
+    };
+    let root = {
+        let mut message_builder = arrow::ipc::MessageBuilder::new(&mut fbb);
+        message_builder.add_version(MetadataVersion::V5);
+        message_builder.add_header_type(header_type);
+        message_builder.add_bodyLength(arrow_data.len() as i64);
+        message_builder.add_header(root);
+        message_builder.finish()
+    };
+    fbb.finish(root, None);
+
+    Ok(Some(EncodedData {
+        ipc_message: fbb.finished_data().to_vec(),
+        arrow_data,
+    }))
+}
+
+fn read_i32(input: &[u8], position: usize) -> i32 {
+    i32::from_le_bytes(input[position..position + 4].try_into().unwrap())
+}
+
+fn truncated(message_index: usize) -> RecordBatchesError {
+    RecordBatchesError::invalid("IPC message is truncated").at_message(message_index)
+}
+
+/// The byte range of a buffer within its message body, or `None` if its
+/// offset or length is negative or its end overflows
+fn buffer_range(buffer: &arrow::ipc::Buffer) -> Option<std::ops::Range<usize>> {
+    let start = usize::try_from(buffer.offset()).ok()?;
+    let len = usize::try_from(buffer.length()).ok()?;
+    Some(start..start.checked_add(len)?)
+}
+
+/// The body length of an IPC message, which must not be negative
+fn body_length(message: &arrow::ipc::Message, message_index: usize) -> Result<usize> {
+    usize::try_from(message.bodyLength()).map_err(|_| {
+        RecordBatchesError::invalid("IPC message has a negative body length")
+            .at_message(message_index)
+    })
+}
+
+/// Reads the flatbuffer of an IPC message, checking that it's valid
+fn read_message(meta: &[u8], message_index: usize) -> Result<arrow::ipc::Message<'_>> {
+    arrow::ipc::root_as_message(meta).map_err(|_| {
+        RecordBatchesError::invalid("IPC message is not a valid flatbuffer")
+            .at_message(message_index)
+    })
+}
+
+#[allow(unused_imports)]
+mod test {
+    use super::*;
+    use crate::structs::{RecordBatchStreamSink, RecordBatches};
+    use arrow::array::{Float64Array, StringArray};
+    use arrow::datatypes::{DataType, Field, Schema};
+    use arrow::record_batch::RecordBatch;
+    use std::io::Cursor;
+    use std::sync::Arc;
+
+    #[allow(dead_code)]
+    fn sample_batch() -> RecordBatch {
+        let schema = Schema::new(vec![
+            Field::new("x", DataType::Float64, true),
+            Field::new("label", DataType::Utf8, true),
+        ]);
+        RecordBatch::try_new(
+            Arc::new(schema),
+            vec![
+                Arc::new(Float64Array::from(vec![Some(1.5); 1000])),
+                Arc::new(StringArray::from(vec![Some("repeated"); 1000])),
+            ],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_compress_round_trip() {
+        let input: Vec<u8> = (0..4096).map(|i| (i % 7) as u8).collect();
+        for codec in [IpcCompression::Lz4Frame, IpcCompression::Zstd].iter() {
+            let compressed = codec.compress(&input).unwrap();
+            assert!(compressed.len() < input.len());
+            assert_eq!(codec.decompress(&compressed).unwrap(), input);
+        }
+    }
+
+    #[test]
+    fn test_decompress_checks_uncompressed_length() {
+        let input = vec![0u8; 100_000];
+        for codec in [IpcCompression::Lz4Frame, IpcCompression::Zstd].iter() {
+            let compressed = codec.compress(&input).unwrap();
+            assert_eq!(codec.decompress(&compressed).unwrap(), input);
+
+            let with_len = |len: i64| {
+                let mut buffer = len.to_le_bytes().to_vec();
+                buffer.extend_from_slice(&compressed[8..]);
+                buffer
+            };
+            assert_eq!(codec.decompress(&with_len(-1)).unwrap(), &compressed[8..]);
+            assert!(codec.decompress(&with_len(-2)).is_err());
+            assert!(codec.decompress(&with_len(i64::MAX)).is_err());
+            assert!(codec.decompress(&with_len(99_999)).is_err());
+            assert!(codec.decompress(&with_len(100_001)).is_err());
+        }
+    }
+
+    /// An IPC stream holding a single RecordBatch message that claims a body
+    /// of `body_len` bytes, followed by only a few bytes of body
+    #[allow(dead_code)]
+    fn stream_with_body_length(body_len: i64) -> Vec<u8> {
+        let mut fbb = FlatBufferBuilder::new();
+        let batch = arrow::ipc::RecordBatchBuilder::new(&mut fbb).finish();
+        let mut message = arrow::ipc::MessageBuilder::new(&mut fbb);
+        message.add_version(MetadataVersion::V5);
+        message.add_header_type(MessageHeader::RecordBatch);
+        message.add_header(batch.as_union_value());
+        message.add_bodyLength(body_len);
+        let message = message.finish();
+        fbb.finish(message, None);
+        let meta = fbb.finished_data();
+
+        let mut stream = vec![0xff, 0xff, 0xff, 0xff];
+        stream.extend_from_slice(&(meta.len() as i32).to_le_bytes());
+        stream.extend_from_slice(meta);
+        stream.extend_from_slice(&[0; 16]);
+        stream
+    }
+
+    #[test]
+    fn test_untrusted_body_lengths_are_errors() {
+        for body_len in [-1, -(1 << 40), i64::MAX, 1 << 40].iter() {
+            let stream = stream_with_body_length(*body_len);
+            let mut out = Vec::new();
+            let read = RewritingReader::new(Cursor::new(stream.clone())).read_to_end(&mut out);
+            assert!(read.is_err(), "body length {}", body_len);
+            assert!(rewrite_ipc_messages(&stream).is_err());
+        }
+    }
+
+    #[test]
+    fn test_buffer_range_rejects_negative_and_overflowing_buffers() {
+        let range = |offset, length| buffer_range(&arrow::ipc::Buffer::new(offset, length));
+        assert_eq!(range(8, 16), Some(8..24));
+        assert_eq!(range(-8, 16), None);
+        assert_eq!(range(8, -16), None);
+    }
+
+    #[test]
+    fn test_negative_footer_length_is_an_error() {
+        let mut input = ARROW_MAGIC.to_vec();
+        input.extend_from_slice(&[0; 10]);
+        input.extend_from_slice(&(-20i32).to_le_bytes());
+        input.extend_from_slice(&ARROW_MAGIC);
+        assert!(rewrite_ipc_messages(&input).is_err());
+    }
+
+    #[test]
+    fn test_compressed_stream_round_trip() {
+        let batch = sample_batch();
+        for codec in [IpcCompression::Lz4Frame, IpcCompression::Zstd].iter() {
+            let mut sink = RecordBatchStreamSink::try_new(Vec::new(), batch.schema())
+                .unwrap()
+                .with_compression(Some(*codec));
+            sink.write(&batch).unwrap();
+            let buffer = sink.into_inner().unwrap();
+
+            let decompressed = rewrite_ipc_messages(&buffer).unwrap().unwrap();
+            assert!(decompressed.len() > buffer.len());
+            let read = RecordBatches::from_bytes(&buffer).unwrap();
+            let read: Vec<_> = read.into_iter().collect();
+            assert_eq!(read[0].column(1).data(), batch.column(1).data());
+        }
+    }
+
+    #[test]
+    fn test_uncompressed_stream_is_not_copied() {
+        let batch = sample_batch();
+        let mut sink = RecordBatchStreamSink::try_new(Vec::new(), batch.schema()).unwrap();
+        sink.write(&batch).unwrap();
+        let buffer = sink.into_inner().unwrap();
+        assert!(rewrite_ipc_messages(&buffer).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_parse_compression() {
+        assert_eq!(IpcCompression::parse("uncompressed").unwrap(), None);
+        assert_eq!(
+            IpcCompression::parse("LZ4").unwrap(),
+            Some(IpcCompression::Lz4Frame)
+        );
+        assert!(IpcCompression::parse("snappy").is_err());
+    }
+}
@@ -0,0 +1,1009 @@
+//! This module converts an R data frame directly into an Arrow RecordBatch, and
+//! RecordBatches back into a data frame, so RecordBatches can be built from
+//! plain R objects without the R arrow package. Columns are converted as
+//! follows:
+//!
+//! | R column         | Arrow data type                          |
+//! |------------------|------------------------------------------|
+//! | integer          | `Int32`                                  |
+//! | double           | `Float64`                                |
+//! | logical          | `Boolean`                                |
+//! | character        | `Utf8`, or `LargeUtf8` over 2 GB         |
+//! | factor, ordered  | `Dictionary(Int32, Utf8)`                |
+//! | Date             | `Date32`                                 |
+//! | POSIXct          | `Timestamp(Microsecond, tzone)`          |
+//! | hms              | `Time64(Microsecond)`                    |
+//! | difftime         | `Duration(Microsecond)`                  |
+//! | list of raws     | `Binary`, or `LargeBinary` over 2 GB     |
+//! | list             | `List`, of the type of its elements      |
+//! | data frame       | `Struct`, with a child per column        |
+//!
+//! Factors are only supported as top-level columns, not inside list or data
+//! frame columns: [encode_batch](crate::structs::encode_batch) only writes the
+//! dictionaries of top-level fields, as does arrow (v3.0.0), whose reader
+//! can't find nested dictionaries either.
+//!
+//! NA's become nulls, and nulls become NA's, the same way
+//! [NaVec](crate::structs::NaVec) maps NA's to `None`. For
+//! doubles, only `NA_real_` becomes a null, `NaN` stays a (non-null) NaN.
+//!
+//! On the way back, the levels of a factor are the values of the dictionaries
+//! of every RecordBatch, in the order they first appear, and dictionaries with
+//! any integer key type are accepted. Other Arrow types without a direct
+//! counterpart in R are converted as follows:
+//!
+//! | Arrow data type                 | R column                             |
+//! |---------------------------------|--------------------------------------|
+//! | `Date64`                        | Date                                 |
+//! | `Timestamp`, any unit           | POSIXct, with the time zone as tzone |
+//! | `Time32`, `Time64`              | hms (seconds since midnight)         |
+//! | `Duration`, `Interval(DayTime)` | difftime, in seconds                 |
+//! | `Interval(YearMonth)`           | integer, the number of months        |
+//! | `Decimal`                       | double                               |
+//!
+//! Decimals with more than 15 significant digits lose precision as doubles.
+//! `LargeUtf8` arrays become character vectors, and `Binary`, `LargeBinary`,
+//! and `FixedSizeBinary` arrays become lists of raw vectors, with `NULL` for
+//! nulls.
+//!
+//! `Utf8` and `Binary` arrays have 32-bit offsets, so they can hold at most
+//! 2 GB of values. Character columns (and lists of raw vectors) with more
+//! bytes than that become `LargeUtf8` (and `LargeBinary`) arrays, with 64-bit
+//! offsets, instead.
+//! `LargeList` and `FixedSizeList` arrays become list columns too.
+//!
+//! The elements of a list column are converted like columns, and must all
+//! have the same type. `NULL` elements are null lists, and null lists are
+//! `NULL`. Since R can't represent a missing row of a data frame, a null
+//! struct becomes NA in each of its columns. Factors can only be top-level
+//! columns, not inside a list or a data frame column.
+//!
+//! Any other attributes, like row names or the units of a column, are kept in
+//! the schema metadata and restored on the way back, see
+//! [RMetadata](crate::structs::RMetadata).
+
+use crate::structs::{
+    parse_rint, parse_rreal, CharVec, IntVec, RMetadata, RecordBatchesError, R_METADATA_KEY,
+};
+use arrow::{
+    array::{
+        make_array, Array, ArrayData, ArrayRef, BinaryArray, BooleanArray, BooleanBufferBuilder,
+        Date32Array, Date64Array, DecimalArray, DictionaryArray, DurationMicrosecondArray,
+        DurationMillisecondArray, DurationNanosecondArray, DurationSecondArray,
+        FixedSizeBinaryArray, FixedSizeListArray, Float64Array, Int32Array, IntervalDayTimeArray,
+        IntervalYearMonthArray, LargeBinaryArray, LargeListArray, LargeStringArray, ListArray,
+        NullArray, StringArray, StructArray, Time32MillisecondArray, Time32SecondArray,
+        Time64MicrosecondArray, Time64NanosecondArray, TimestampMicrosecondArray,
+        TimestampMillisecondArray, TimestampNanosecondArray, TimestampSecondArray,
+    },
+    buffer::Buffer,
+    compute::concat,
+    datatypes::{
+        ArrowDictionaryKeyType, ArrowNativeType, DataType, Field, Int16Type, Int32Type, Int64Type,
+        Int8Type, IntervalUnit, Schema, TimeUnit, ToByteSlice,
+    },
+    record_batch::RecordBatch,
+};
+use extendr_api::prelude::*;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+type Result<T> = std::result::Result<T, RecordBatchesError>;
+
+const SECONDS_PER_DAY: f64 = 86400.0;
+
+/// The most bytes of values a `Utf8` or `Binary` array can hold, since its
+/// offsets are 32-bit
+const MAX_SMALL_OFFSET: usize = i32::MAX as usize;
+
+// -=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-
+// Data Frame to RecordBatch ---------------------------------------------------
+// -=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-
+
+/// Converts an R data frame into a single RecordBatch, naming the column
+/// involved in any error
+pub fn record_batch_from_data_frame(df: &Robj) -> Result<RecordBatch> {
+    if !df.inherits("data.frame") {
+        return Err(RecordBatchesError::invalid("Expected a data frame."));
+    }
+    let (fields, arrays) = data_frame_columns(df)?;
+    let mut metadata = HashMap::new();
+    if let Some(r_metadata) = RMetadata::from_data_frame(df)? {
+        metadata.insert(R_METADATA_KEY.to_string(), r_metadata.serialize()?);
+    }
+    let schema = Schema::new_with_metadata(fields, metadata);
+    RecordBatch::try_new(Arc::new(schema), arrays).map_err(RecordBatchesError::arrow(
+        "Error creating RecordBatch from data frame",
+    ))
+}
+
+/// Converts the columns of a data frame, naming the column involved in any
+/// error
+fn data_frame_columns(df: &Robj) -> Result<(Vec<Field>, Vec<ArrayRef>)> {
+    let names: Vec<String> = df
+        .names()
+        .map(|names| names.map(String::from).collect())
+        .unwrap_or_default();
+    let columns = df
+        .as_list_iter()
+        .ok_or_else(|| RecordBatchesError::invalid("Expected a data frame."))?;
+
+    let mut fields = Vec::with_capacity(names.len());
+    let mut arrays = Vec::with_capacity(names.len());
+    for (i, (name, column)) in names.iter().zip(columns).enumerate() {
+        let (field, array) =
+            column_to_array(name, &column, i as i64).map_err(|e| e.for_field(name.as_str()))?;
+        fields.push(field);
+        arrays.push(array);
+    }
+    Ok((fields, arrays))
+}
+
+/// Converts one column of a data frame. Every field is nullable, and the
+/// position of the column is used as the id of a factor's dictionary.
+fn column_to_array(name: &str, column: &Robj, dict_id: i64) -> Result<(Field, ArrayRef)> {
+    if column.inherits("factor") {
+        let array = factor_to_array(column)?;
+        let ordered = column.inherits("ordered");
+        let field = Field::new_dict(name, array.data_type().clone(), true, dict_id, ordered);
+        return Ok((field, array));
+    }
+
+    let array: ArrayRef = if column.inherits("data.frame") {
+        data_frame_to_struct(column)?
+    } else if is_raw_list(column) {
+        raw_list_to_array(column)?
+    } else if column.is_list() && !column.inherits("POSIXlt") {
+        list_to_array(column)?
+    } else if column.inherits("Date") {
+        let days = r_numbers(column)?
+            .into_iter()
+            .map(|x| x.map(|days| days.floor() as i32))
+            .collect::<Vec<_>>();
+        Arc::new(Date32Array::from(days))
+    } else if column.inherits("POSIXct") {
+        let microseconds = r_numbers(column)?
+            .into_iter()
+            .map(|x| x.map(|seconds| (seconds * 1e6).round() as i64))
+            .collect();
+        let tzone = attribute(column, "tzone")
+            .and_then(|x| x.as_str().map(String::from))
+            .filter(|x| !x.is_empty());
+        Arc::new(TimestampMicrosecondArray::from_opt_vec(microseconds, tzone))
+    } else if column.inherits("hms") {
+        let microseconds = difftime_microseconds(column)?;
+        let day = (SECONDS_PER_DAY * 1e6) as i64;
+        if microseconds.iter().flatten().any(|&x| x < 0 || x >= day) {
+            return Err(RecordBatchesError::invalid(
+                "hms values must be times of day, from 00:00:00 to 23:59:59",
+            ));
+        }
+        Arc::new(Time64MicrosecondArray::from(microseconds))
+    } else if column.inherits("difftime") {
+        Arc::new(DurationMicrosecondArray::from(difftime_microseconds(
+            column,
+        )?))
+    } else if attribute(column, "class").is_some() {
+        return Err(RecordBatchesError::invalid(
+            "Unsupported column class, expected a factor, Date, POSIXct, hms, difftime, list, or data frame",
+        ));
+    } else if let Some(v) = column.as_logical_iter() {
+        let values = v
+            .map(|x| {
+                if x.is_true() {
+                    Some(true)
+                } else if x.is_false() {
+                    Some(false)
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        Arc::new(BooleanArray::from(values))
+    } else if let Ok(v) = IntVec::from_robj(column) {
+        Arc::new(Int32Array::from(v.into_iter().collect::<Vec<_>>()))
+    } else if let Some(v) = column.as_real_slice() {
+        Arc::new(Float64Array::from(
+            v.iter().map(|&x| parse_rreal(x)).collect::<Vec<_>>(),
+        ))
+    } else {
+        let strings: Vec<Option<String>> = CharVec::from_robj(column)
+            .map_err(|_| {
+                RecordBatchesError::invalid(
+                    "Unsupported column type, expected an integer, double, logical, or character vector",
+                )
+            })?
+            .into_iter()
+            .collect();
+        string_array(
+            strings.iter().map(|x| x.as_deref()).collect(),
+            MAX_SMALL_OFFSET,
+        )
+    };
+    Ok((Field::new(name, array.data_type().clone(), true), array))
+}
+
+/// Converts a data frame column (a data frame nested in a data frame) into a
+/// `Struct` array with one child per column
+fn data_frame_to_struct(df: &Robj) -> Result<ArrayRef> {
+    let (fields, arrays) = data_frame_columns(df)?;
+    if fields.is_empty() {
+        return Err(RecordBatchesError::invalid(
+            "Data frame columns must have at least one column",
+        ));
+    }
+    if fields.iter().any(|f| has_dictionary(f.data_type())) {
+        return Err(RecordBatchesError::invalid(
+            "Factors in data frame columns are not supported",
+        ));
+    }
+    Ok(Arc::new(StructArray::from(
+        fields.into_iter().zip(arrays).collect::<Vec<_>>(),
+    )))
+}
+
+/// Builds a `Utf8` array, or a `LargeUtf8` array if the strings have more
+/// than `max_small_offset` bytes in total
+fn string_array(strings: Vec<Option<&str>>, max_small_offset: usize) -> ArrayRef {
+    let total: usize = strings.iter().flatten().map(|x| x.len()).sum();
+    if total > max_small_offset {
+        Arc::new(LargeStringArray::from(strings))
+    } else {
+        Arc::new(StringArray::from(strings))
+    }
+}
+
+/// Builds a `Binary` array, or a `LargeBinary` array if the values have more
+/// than `max_small_offset` bytes in total
+fn binary_array(values: Vec<Option<&[u8]>>, max_small_offset: usize) -> ArrayRef {
+    let total: usize = values.iter().flatten().map(|x| x.len()).sum();
+    if total > max_small_offset {
+        Arc::new(LargeBinaryArray::from_opt_vec(values))
+    } else {
+        Arc::new(BinaryArray::from_opt_vec(values))
+    }
+}
+
+/// Whether a list column holds only raw vectors (and `NULL`s), with at least
+/// one raw vector
+fn is_raw_list(column: &Robj) -> bool {
+    if !column.is_list() || attribute(column, "class").is_some() {
+        return false;
+    }
+    let mut raws = 0;
+    for element in column.as_list_iter().into_iter().flatten() {
+        if element.as_raw_slice().is_some() {
+            raws += 1;
+        } else if !element.is_null() {
+            return false;
+        }
+    }
+    raws > 0
+}
+
+/// Converts a list of raw vectors into a `Binary` (or `LargeBinary`) array,
+/// with `NULL` elements as nulls
+fn raw_list_to_array(column: &Robj) -> Result<ArrayRef> {
+    let elements: Vec<Robj> = column
+        .as_list_iter()
+        .ok_or_else(|| RecordBatchesError::invalid("Expected a list column."))?
+        .collect();
+    let values = elements.iter().map(|x| x.as_raw_slice()).collect();
+    Ok(binary_array(values, MAX_SMALL_OFFSET))
+}
+
+/// Converts a list column into a `List` array. Every element that isn't
+/// `NULL` must convert to the same Arrow type, `NULL` elements are nulls. A
+/// list with only `NULL` elements is a `List(Null)`.
+fn list_to_array(column: &Robj) -> Result<ArrayRef> {
+    let elements = column
+        .as_list_iter()
+        .ok_or_else(|| RecordBatchesError::invalid("Expected a list column."))?;
+
+    let mut offsets = vec![0i32];
+    let mut validity = Vec::new();
+    let mut item: Option<Field> = None;
+    let mut children: Vec<ArrayRef> = Vec::new();
+    for element in elements {
+        let end = *offsets.last().unwrap_or(&0);
+        if element.is_null() {
+            offsets.push(end);
+            validity.push(false);
+            continue;
+        }
+        let (field, array) = column_to_array("item", &element, 0)?;
+        if has_dictionary(field.data_type()) {
+            return Err(RecordBatchesError::invalid(
+                "Factors in list columns are not supported",
+            ));
+        }
+        match &item {
+            Some(expected) if expected.data_type() != field.data_type() => {
+                return Err(RecordBatchesError::schema(
+                    "Elements of a list column must all have the same type",
+                    Some(format!("{:?}", expected.data_type())),
+                    Some(format!("{:?}", field.data_type())),
+                ))
+            }
+            Some(_) => {}
+            None => item = Some(field),
+        }
+        let end = i32::try_from(end as usize + array.len()).map_err(|_| {
+            RecordBatchesError::invalid("List column has too many values for a List array")
+        })?;
+        offsets.push(end);
+        validity.push(true);
+        children.push(array);
+    }
+
+    let item = item.unwrap_or_else(|| Field::new("item", DataType::Null, true));
+    let values: ArrayRef = if children.is_empty() {
+        Arc::new(NullArray::new(0))
+    } else {
+        let children: Vec<&dyn Array> = children.iter().map(|a| a.as_ref()).collect();
+        concat(&children).map_err(RecordBatchesError::arrow("Error combining list elements"))?
+    };
+    let null_count = validity.iter().filter(|&&x| !x).count();
+    let null_buffer = if null_count > 0 {
+        Some(BooleanArray::from(validity.clone()).data_ref().buffers()[0].clone())
+    } else {
+        None
+    };
+    let data = ArrayData::new(
+        DataType::List(Box::new(item)),
+        validity.len(),
+        Some(null_count),
+        null_buffer,
+        0,
+        vec![Buffer::from(offsets.to_byte_slice())],
+        vec![values.data()],
+    );
+    Ok(make_array(Arc::new(data)))
+}
+
+/// Whether a data type is, or contains, a dictionary
+pub(crate) fn has_dictionary(data_type: &DataType) -> bool {
+    match data_type {
+        DataType::Dictionary(_, _) => true,
+        DataType::List(item) | DataType::LargeList(item) | DataType::FixedSizeList(item, _) => {
+            has_dictionary(item.data_type())
+        }
+        DataType::Struct(fields) => fields.iter().any(|f| has_dictionary(f.data_type())),
+        _ => false,
+    }
+}
+
+/// Converts a factor into a dictionary array, keeping every level (used or
+/// not) in order as the dictionary
+fn factor_to_array(column: &Robj) -> Result<ArrayRef> {
+    let codes = column
+        .as_integer_slice()
+        .ok_or_else(|| RecordBatchesError::invalid("Expected a factor with integer codes."))?;
+    let levels = attribute(column, "levels")
+        .and_then(|x| x.as_string_vector())
+        .unwrap_or_default();
+    if codes
+        .iter()
+        .any(|&x| parse_rint(x).map_or(false, |x| x < 1 || x as usize > levels.len()))
+    {
+        return Err(RecordBatchesError::invalid(
+            "Factor has codes outside of its levels",
+        ));
+    }
+    let keys = Int32Array::from(
+        codes
+            .iter()
+            .map(|&x| parse_rint(x).map(|code| code - 1))
+            .collect::<Vec<_>>(),
+    );
+    let values = StringArray::from(levels.iter().map(|x| x.as_str()).collect::<Vec<_>>());
+    Ok(dictionary_array(&keys, &values))
+}
+
+/// Builds a `Dictionary(Int32, Utf8)` array from its keys and values
+fn dictionary_array(keys: &Int32Array, values: &StringArray) -> ArrayRef {
+    let keys = keys.data();
+    let data_type = DataType::Dictionary(Box::new(DataType::Int32), Box::new(DataType::Utf8));
+    let data = ArrayData::new(
+        data_type,
+        keys.len(),
+        Some(keys.null_count()),
+        keys.null_buffer().cloned(),
+        keys.offset(),
+        keys.buffers().to_vec(),
+        vec![values.data()],
+    );
+    make_array(Arc::new(data))
+}
+
+/// An attribute of an Robj, or `None` if it isn't set
+pub(crate) fn attribute(robj: &Robj, name: &str) -> Option<Robj> {
+    robj.get_attrib(name).filter(|x| !x.is_null())
+}
+
+/// The values of a difftime (or hms) in microseconds, converted from its units
+fn difftime_microseconds(column: &Robj) -> Result<Vec<Option<i64>>> {
+    let units = attribute(column, "units")
+        .and_then(|x| x.as_str().map(String::from))
+        .unwrap_or_else(|| "secs".to_string());
+    let seconds = match units.as_str() {
+        "secs" => 1.0,
+        "mins" => 60.0,
+        "hours" => 3600.0,
+        "days" => SECONDS_PER_DAY,
+        "weeks" => 7.0 * SECONDS_PER_DAY,
+        _ => {
+            return Err(RecordBatchesError::schema(
+                "Unsupported difftime units",
+                Some("secs, mins, hours, days, or weeks".to_string()),
+                Some(units),
+            ))
+        }
+    };
+    Ok(r_numbers(column)?
+        .into_iter()
+        .map(|x| x.map(|x| (x * seconds * 1e6).round() as i64))
+        .collect())
+}
+
+/// The values of an integer or double vector as doubles, where NA's (and any
+/// other non-finite values) are `None`
+fn r_numbers(column: &Robj) -> Result<Vec<Option<f64>>> {
+    if let Some(v) = column.as_integer_slice() {
+        Ok(v.iter().map(|&x| parse_rint(x).map(f64::from)).collect())
+    } else if let Some(v) = column.as_real_slice() {
+        Ok(v.iter()
+            .map(|&x| Some(x).filter(|x| x.is_finite()))
+            .collect())
+    } else {
+        Err(RecordBatchesError::invalid(
+            "Expected a Date, POSIXct, or difftime stored as a numeric vector.",
+        ))
+    }
+}
+
+// -=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-
+// RecordBatches to Data Frame -------------------------------------------------
+// -=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-
+
+/// Converts RecordBatches that share a schema into a single R data frame,
+/// with the rows of each RecordBatch in order, restoring any R attributes
+/// found in the schema metadata. With no RecordBatches, the
+/// data frame has no columns.
+pub fn data_frame_from_record_batches(batches: &[RecordBatch]) -> Result<Robj> {
+    let schema = match batches.first() {
+        Some(batch) => batch.schema(),
+        None => return new_data_frame(Vec::new(), Vec::new(), 0),
+    };
+    let mut names = Vec::with_capacity(schema.fields().len());
+    let mut columns = Vec::with_capacity(schema.fields().len());
+    for (i, field) in schema.fields().iter().enumerate() {
+        let arrays: Vec<&ArrayRef> = batches.iter().map(|batch| batch.column(i)).collect();
+        let column =
+            arrays_to_robj(field, &arrays).map_err(|e| e.for_field(field.name().as_str()))?;
+        names.push(field.name().clone());
+        columns.push(column);
+    }
+    let num_rows = batches.iter().map(|batch| batch.num_rows()).sum();
+    match schema
+        .metadata()
+        .get(R_METADATA_KEY)
+        .and_then(|x| RMetadata::unserialize(x))
+    {
+        Some(r_metadata) => {
+            let columns = r_metadata.restore_columns(&names, columns)?;
+            r_metadata.restore_data_frame(new_data_frame(names, columns, num_rows)?, num_rows)
+        }
+        None => new_data_frame(names, columns, num_rows),
+    }
+}
+
+/// Converts the arrays of one field, one per RecordBatch, into an R vector
+fn arrays_to_robj(field: &Field, arrays: &[&ArrayRef]) -> Result<Robj> {
+    match field.data_type() {
+        DataType::Int32 => Ok(Robj::from(values::<Int32Array, _>(arrays, |a, i| {
+            a.value(i)
+        })?)),
+        DataType::Float64 => Ok(Robj::from(values::<Float64Array, _>(arrays, |a, i| {
+            a.value(i)
+        })?)),
+        DataType::Boolean => Ok(Robj::from(values::<BooleanArray, _>(arrays, |a, i| {
+            a.value(i)
+        })?)),
+        DataType::Utf8 => {
+            let strings: CharVec = values::<StringArray, _>(arrays, |a, i| a.value(i).to_string())?
+                .into_iter()
+                .collect();
+            Ok(Robj::from(strings))
+        }
+        DataType::LargeUtf8 => {
+            let strings: CharVec =
+                values::<LargeStringArray, _>(arrays, |a, i| a.value(i).to_string())?
+                    .into_iter()
+                    .collect();
+            Ok(Robj::from(strings))
+        }
+        DataType::Binary => raws_to_robj(values::<BinaryArray, _>(arrays, |a, i| {
+            Robj::from(Raw(a.value(i)))
+        })?),
+        DataType::LargeBinary => raws_to_robj(values::<LargeBinaryArray, _>(arrays, |a, i| {
+            Robj::from(Raw(a.value(i)))
+        })?),
+        DataType::FixedSizeBinary(_) => {
+            raws_to_robj(values::<FixedSizeBinaryArray, _>(arrays, |a, i| {
+                Robj::from(Raw(a.value(i)))
+            })?)
+        }
+        DataType::Date32(_) => {
+            let days = values::<Date32Array, _>(arrays, |a, i| a.value(i) as f64)?;
+            set_attributes(Robj::from(days), &[("class", Robj::from("Date"))])
+        }
+        DataType::Date64(_) => {
+            let days = values::<Date64Array, _>(arrays, |a, i| {
+                (a.value(i) as f64 / (SECONDS_PER_DAY * 1e3)).floor()
+            })?;
+            set_attributes(Robj::from(days), &[("class", Robj::from("Date"))])
+        }
+        DataType::Timestamp(_, tz) => {
+            let tzone = tz.as_ref().map(|x| x.to_string()).unwrap_or_default();
+            set_attributes(
+                Robj::from(seconds(field, arrays)?),
+                &[
+                    ("class", Robj::from(vec!["POSIXct", "POSIXt"])),
+                    ("tzone", Robj::from(tzone)),
+                ],
+            )
+        }
+        DataType::Time32(_) | DataType::Time64(_) => set_attributes(
+            Robj::from(seconds(field, arrays)?),
+            &[
+                ("class", Robj::from(vec!["hms", "difftime"])),
+                ("units", Robj::from("secs")),
+            ],
+        ),
+        DataType::Duration(_) | DataType::Interval(IntervalUnit::DayTime) => set_attributes(
+            Robj::from(seconds(field, arrays)?),
+            &[
+                ("class", Robj::from("difftime")),
+                ("units", Robj::from("secs")),
+            ],
+        ),
+        DataType::Interval(IntervalUnit::YearMonth) => {
+            Ok(Robj::from(values::<IntervalYearMonthArray, _>(
+                arrays,
+                |a, i| a.value(i),
+            )?))
+        }
+        DataType::Decimal(_, scale) => {
+            let divisor = 10f64.powi(*scale as i32);
+            Ok(Robj::from(values::<DecimalArray, _>(arrays, |a, i| {
+                a.value(i) as f64 / divisor
+            })?))
+        }
+        DataType::Dictionary(key_type, value_type) if **value_type == DataType::Utf8 => {
+            let (levels, codes) = match **key_type {
+                DataType::Int8 => factor_codes::<Int8Type>(arrays)?,
+                DataType::Int16 => factor_codes::<Int16Type>(arrays)?,
+                DataType::Int32 => factor_codes::<Int32Type>(arrays)?,
+                DataType::Int64 => factor_codes::<Int64Type>(arrays)?,
+                _ => return Err(unsupported_type(field)),
+            };
+            let class = if field.dict_is_ordered() == Some(true) {
+                Robj::from(vec!["ordered", "factor"])
+            } else {
+                Robj::from("factor")
+            };
+            set_attributes(
+                Robj::from(codes),
+                &[("levels", Robj::from(levels)), ("class", class)],
+            )
+        }
+        DataType::List(item) => list_to_robj::<ListArray>(item, arrays, |a, i| a.value(i)),
+        DataType::LargeList(item) => {
+            list_to_robj::<LargeListArray>(item, arrays, |a, i| a.value(i))
+        }
+        DataType::FixedSizeList(item, _) => {
+            list_to_robj::<FixedSizeListArray>(item, arrays, |a, i| a.value(i))
+        }
+        DataType::Struct(fields) => struct_to_robj(fields, arrays),
+        _ => Err(unsupported_type(field)),
+    }
+}
+
+/// A list of raw vectors, with `NULL` for nulls
+fn raws_to_robj(raws: Vec<Option<Robj>>) -> Result<Robj> {
+    let elements: Vec<Robj> = raws
+        .into_iter()
+        .map(|x| x.unwrap_or_else(|| Robj::from(())))
+        .collect();
+    Ok(Robj::from(List(elements)))
+}
+
+/// Converts list arrays of any kind into an R list, with one R vector per
+/// list (converted like a column of type `item`) and `NULL` for nulls
+fn list_to_robj<A>(
+    item: &Field,
+    arrays: &[&ArrayRef],
+    value: impl Fn(&A, usize) -> ArrayRef,
+) -> Result<Robj>
+where
+    A: Array + 'static,
+{
+    let elements = values::<A, _>(arrays, value)?
+        .into_iter()
+        .map(|element| match element {
+            Some(array) => arrays_to_robj(item, &[&array]),
+            None => Ok(Robj::from(())),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(Robj::from(List(elements)))
+}
+
+/// Converts struct arrays into a data frame with one column per child. R
+/// can't mark a whole row of a data frame as missing, so the values of every
+/// child are NA in the rows where the struct is null.
+fn struct_to_robj(fields: &[Field], arrays: &[&ArrayRef]) -> Result<Robj> {
+    let structs = arrays
+        .iter()
+        .map(|array| {
+            array
+                .as_any()
+                .downcast_ref::<StructArray>()
+                .ok_or_else(|| RecordBatchesError::invalid("Array does not match its field type"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let num_rows = structs.iter().map(|array| array.len()).sum();
+    let mut names = Vec::with_capacity(fields.len());
+    let mut columns = Vec::with_capacity(fields.len());
+    for (j, field) in fields.iter().enumerate() {
+        // The children of a sliced StructArray aren't sliced themselves
+        let children: Vec<ArrayRef> = structs
+            .iter()
+            .map(|array| {
+                let child = make_array(array.data_ref().child_data()[j].clone())
+                    .slice(array.offset(), array.len());
+                with_struct_nulls(child, array)
+            })
+            .collect();
+        let children: Vec<&ArrayRef> = children.iter().collect();
+        let column =
+            arrays_to_robj(field, &children).map_err(|e| e.for_field(field.name().as_str()))?;
+        names.push(field.name().clone());
+        columns.push(column);
+    }
+    new_data_frame(names, columns, num_rows)
+}
+
+/// Marks the rows of `child` where its parent struct is null as null too, so
+/// they become NA's (or `NULL` elements, in a list column)
+fn with_struct_nulls(child: ArrayRef, parent: &StructArray) -> ArrayRef {
+    if parent.null_count() == 0 || child.data_type() == &DataType::Null {
+        return child;
+    }
+    let data = child.data_ref();
+    let mut validity = BooleanBufferBuilder::new(data.offset() + child.len());
+    validity.append_n(data.offset(), false);
+    for i in 0..child.len() {
+        validity.append(child.is_valid(i) && parent.is_valid(i));
+    }
+    make_array(Arc::new(ArrayData::new(
+        data.data_type().clone(),
+        child.len(),
+        None,
+        Some(validity.finish()),
+        data.offset(),
+        data.buffers().to_vec(),
+        data.child_data().to_vec(),
+    )))
+}
+
+/// The values of timestamp, time, duration, or day-time interval arrays in
+/// seconds (since the epoch, since midnight, or in total)
+fn seconds(field: &Field, arrays: &[&ArrayRef]) -> Result<Vec<Option<f64>>> {
+    match field.data_type() {
+        DataType::Timestamp(TimeUnit::Second, _) => {
+            values::<TimestampSecondArray, _>(arrays, |a, i| a.value(i) as f64)
+        }
+        DataType::Timestamp(TimeUnit::Millisecond, _) => {
+            values::<TimestampMillisecondArray, _>(arrays, |a, i| a.value(i) as f64 / 1e3)
+        }
+        DataType::Timestamp(TimeUnit::Microsecond, _) => {
+            values::<TimestampMicrosecondArray, _>(arrays, |a, i| a.value(i) as f64 / 1e6)
+        }
+        DataType::Timestamp(TimeUnit::Nanosecond, _) => {
+            values::<TimestampNanosecondArray, _>(arrays, |a, i| a.value(i) as f64 / 1e9)
+        }
+        DataType::Time32(TimeUnit::Second) => {
+            values::<Time32SecondArray, _>(arrays, |a, i| a.value(i) as f64)
+        }
+        DataType::Time32(TimeUnit::Millisecond) => {
+            values::<Time32MillisecondArray, _>(arrays, |a, i| a.value(i) as f64 / 1e3)
+        }
+        DataType::Time64(TimeUnit::Microsecond) => {
+            values::<Time64MicrosecondArray, _>(arrays, |a, i| a.value(i) as f64 / 1e6)
+        }
+        DataType::Time64(TimeUnit::Nanosecond) => {
+            values::<Time64NanosecondArray, _>(arrays, |a, i| a.value(i) as f64 / 1e9)
+        }
+        DataType::Duration(TimeUnit::Second) => {
+            values::<DurationSecondArray, _>(arrays, |a, i| a.value(i) as f64)
+        }
+        DataType::Duration(TimeUnit::Millisecond) => {
+            values::<DurationMillisecondArray, _>(arrays, |a, i| a.value(i) as f64 / 1e3)
+        }
+        DataType::Duration(TimeUnit::Microsecond) => {
+            values::<DurationMicrosecondArray, _>(arrays, |a, i| a.value(i) as f64 / 1e6)
+        }
+        DataType::Duration(TimeUnit::Nanosecond) => {
+            values::<DurationNanosecondArray, _>(arrays, |a, i| a.value(i) as f64 / 1e9)
+        }
+        DataType::Interval(IntervalUnit::DayTime) => {
+            values::<IntervalDayTimeArray, _>(arrays, |a, i| day_time_seconds(a.value(i)))
+        }
+        _ => Err(unsupported_type(field)),
+    }
+}
+
+/// The length in seconds of a day-time interval, which packs the number of
+/// days into its low 32 bits and the milliseconds into its high 32 bits
+fn day_time_seconds(value: i64) -> f64 {
+    let days = value as i32;
+    let milliseconds = (value >> 32) as i32;
+    days as f64 * SECONDS_PER_DAY + milliseconds as f64 / 1e3
+}
+
+fn unsupported_type(field: &Field) -> RecordBatchesError {
+    RecordBatchesError::schema(
+        "Unsupported data type for a data frame column",
+        None,
+        Some(format!("{:?}", field.data_type())),
+    )
+}
+
+/// The values of the arrays (which must all be an `A`) in order, with nulls
+/// as `None`
+fn values<A, T>(arrays: &[&ArrayRef], value: impl Fn(&A, usize) -> T) -> Result<Vec<Option<T>>>
+where
+    A: Array + 'static,
+{
+    let mut out = Vec::with_capacity(arrays.iter().map(|a| a.len()).sum());
+    for array in arrays {
+        let array = array
+            .as_any()
+            .downcast_ref::<A>()
+            .ok_or_else(|| RecordBatchesError::invalid("Array does not match its field type"))?;
+        out.extend((0..array.len()).map(|i| {
+            if array.is_null(i) {
+                None
+            } else {
+                Some(value(array, i))
+            }
+        }));
+    }
+    Ok(out)
+}
+
+/// The levels and (one-based) codes of a factor built from dictionary arrays.
+/// Each array's dictionary is mapped onto the combined levels, which are kept
+/// in the order they first appear. Null keys, and keys of null values, are
+/// NA.
+fn factor_codes<K: ArrowDictionaryKeyType>(
+    arrays: &[&ArrayRef],
+) -> Result<(Vec<String>, Vec<Option<i32>>)> {
+    let mut levels: Vec<String> = Vec::new();
+    let mut positions: HashMap<String, i32> = HashMap::new();
+    let mut codes = Vec::with_capacity(arrays.iter().map(|a| a.len()).sum());
+    for array in arrays {
+        let dictionary = array
+            .as_any()
+            .downcast_ref::<DictionaryArray<K>>()
+            .ok_or_else(|| RecordBatchesError::invalid("Array does not match its field type"))?;
+        let values = dictionary.values();
+        let values = values
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .ok_or_else(|| RecordBatchesError::invalid("Array does not match its field type"))?;
+
+        let remap: Vec<Option<i32>> = (0..values.len())
+            .map(|i| {
+                if values.is_null(i) {
+                    return None;
+                }
+                let level = values.value(i);
+                let code = *positions.entry(level.to_string()).or_insert_with(|| {
+                    levels.push(level.to_string());
+                    levels.len() as i32
+                });
+                Some(code)
+            })
+            .collect();
+        for key in dictionary.keys() {
+            let code = key
+                .and_then(|k| k.to_usize())
+                .and_then(|k| remap.get(k).copied().flatten());
+            codes.push(code);
+        }
+    }
+    Ok((levels, codes))
+}
+
+/// Sets attributes on an R object, in order
+pub(crate) fn set_attributes<S: AsRef<str>>(robj: Robj, attributes: &[(S, Robj)]) -> Result<Robj> {
+    let mut robj = robj;
+    for (name, value) in attributes {
+        robj = robj
+            .set_attrib(name.as_ref(), value.clone())
+            .map_err(|_| RecordBatchesError::invalid("Error setting R attributes"))?;
+    }
+    Ok(robj)
+}
+
+/// Builds a data frame from its column names and columns
+fn new_data_frame(names: Vec<String>, columns: Vec<Robj>, num_rows: usize) -> Result<Robj> {
+    let row_names: Vec<i32> = (1..=num_rows as i32).collect();
+    let set_attributes = || -> extendr_api::Result<Robj> {
+        Robj::from(List(columns))
+            .set_attrib(names_symbol(), names)?
+            .set_attrib(row_names_symbol(), row_names)?
+            .set_attrib(class_symbol(), "data.frame")
+    };
+    set_attributes().map_err(|_| RecordBatchesError::invalid("Error building data frame"))
+}
+
+#[allow(unused_imports)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_struct_nulls_are_set_in_sliced_children() {
+        let a = Int32Array::from(vec![Some(1), None, Some(3), Some(4)]);
+        let b = StringArray::from(vec![Some("w"), Some("x"), None, Some("z")]);
+        let mut validity = BooleanBufferBuilder::new(4);
+        validity.append_slice(&[true, true, false, true]);
+        let data = ArrayData::builder(DataType::Struct(vec![
+            Field::new("a", DataType::Int32, true),
+            Field::new("b", DataType::Utf8, true),
+        ]))
+        .len(4)
+        .null_bit_buffer(validity.finish())
+        .child_data(vec![a.data(), b.data()])
+        .build();
+        let structs = make_array(data).slice(1, 3);
+        let structs = structs.as_any().downcast_ref::<StructArray>().unwrap();
+
+        for j in 0..2 {
+            let child = make_array(structs.data_ref().child_data()[j].clone())
+                .slice(structs.offset(), structs.len());
+            let child = with_struct_nulls(child, structs);
+            let valid: Vec<bool> = (0..child.len()).map(|i| child.is_valid(i)).collect();
+            assert_eq!(valid, vec![j == 1, false, true]);
+        }
+    }
+
+    #[test]
+    fn test_factor_codes_combine_dictionaries() {
+        let first = dictionary_array(
+            &Int32Array::from(vec![Some(1), None, Some(0)]),
+            &StringArray::from(vec!["low", "high"]),
+        );
+        let second = dictionary_array(
+            &Int32Array::from(vec![Some(0), Some(1)]),
+            &StringArray::from(vec!["high", "medium"]),
+        );
+        let (levels, codes) = factor_codes::<Int32Type>(&[&first, &second]).unwrap();
+        assert_eq!(levels, vec!["low", "high", "medium"]);
+        assert_eq!(codes, vec![Some(2), None, Some(1), Some(2), Some(3)]);
+    }
+
+    #[test]
+    fn test_seconds_of_every_time_unit() {
+        let cases: Vec<(DataType, ArrayRef)> = vec![
+            (
+                DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".to_string())),
+                Arc::new(TimestampMillisecondArray::from_opt_vec(
+                    vec![Some(1500), None],
+                    Some("UTC".to_string()),
+                )),
+            ),
+            (
+                DataType::Time32(TimeUnit::Second),
+                Arc::new(Time32SecondArray::from(vec![Some(1), None])),
+            ),
+            (
+                DataType::Time64(TimeUnit::Nanosecond),
+                Arc::new(Time64NanosecondArray::from(vec![Some(2_500_000_000), None])),
+            ),
+            (
+                DataType::Duration(TimeUnit::Microsecond),
+                Arc::new(DurationMicrosecondArray::from(vec![Some(-250_000), None])),
+            ),
+        ];
+        let expected = [1.5, 1.0, 2.5, -0.25];
+        for ((data_type, array), expected) in cases.into_iter().zip(expected.iter()) {
+            let field = Field::new("x", data_type, true);
+            assert_eq!(
+                seconds(&field, &[&array]).unwrap(),
+                vec![Some(*expected), None]
+            );
+        }
+    }
+
+    #[test]
+    fn test_day_time_seconds() {
+        let value = (1500i64 << 32) | 2;
+        assert_eq!(day_time_seconds(value), 2.0 * SECONDS_PER_DAY + 1.5);
+        let value = -500i64 << 32;
+        assert_eq!(day_time_seconds(value), -0.5);
+    }
+
+    #[test]
+    fn test_large_variants_are_chosen_when_offsets_would_overflow() {
+        let strings = vec![Some("abc"), None, Some("de")];
+        assert_eq!(
+            string_array(strings.clone(), 5).data_type(),
+            &DataType::Utf8
+        );
+        let large = string_array(strings, 4);
+        assert_eq!(large.data_type(), &DataType::LargeUtf8);
+        let large = large.as_any().downcast_ref::<LargeStringArray>().unwrap();
+        assert_eq!(large.value(2), "de");
+        assert!(large.is_null(1));
+
+        let raws: Vec<Option<&[u8]>> = vec![Some(&[1, 2]), None, Some(&[3])];
+        assert_eq!(binary_array(raws.clone(), 3).data_type(), &DataType::Binary);
+        assert_eq!(binary_array(raws, 2).data_type(), &DataType::LargeBinary);
+    }
+
+    /// Builds (and writes, and reads back) a column with more than 2^31 bytes
+    /// of strings. Needs several GB of memory, so only runs with
+    /// `cargo test --features slow-tests`.
+    #[cfg(feature = "slow-tests")]
+    #[test]
+    fn test_strings_over_two_gigabytes_round_trip() {
+        use crate::structs::RecordBatchStreamSink;
+        use arrow::ipc::reader::StreamReader;
+
+        let chunk = "x".repeat(1 << 20);
+        let n = (MAX_SMALL_OFFSET >> 20) + 2;
+        let strings: Vec<Option<&str>> = (0..n).map(|_| Some(chunk.as_str())).collect();
+        let array = string_array(strings, MAX_SMALL_OFFSET);
+        assert_eq!(array.data_type(), &DataType::LargeUtf8);
+
+        let schema = Arc::new(Schema::new(vec![Field::new(
+            "s",
+            DataType::LargeUtf8,
+            true,
+        )]));
+        let batch = RecordBatch::try_new(schema.clone(), vec![array]).unwrap();
+        let mut sink = RecordBatchStreamSink::try_new(Vec::new(), schema).unwrap();
+        sink.write(&batch).unwrap();
+        drop(batch);
+        let buffer = sink.into_inner().unwrap();
+        assert!(buffer.len() > MAX_SMALL_OFFSET);
+
+        let read = StreamReader::try_new(&buffer[..])
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        let strings = read
+            .column(0)
+            .as_any()
+            .downcast_ref::<LargeStringArray>()
+            .unwrap();
+        assert_eq!(strings.len(), n);
+        assert_eq!(strings.value(n - 1), chunk);
+    }
+
+    #[test]
+    fn test_values_rejects_mismatched_arrays() {
+        let ints: ArrayRef = Arc::new(Int32Array::from(vec![Some(1), None]));
+        assert_eq!(
+            values::<Int32Array, _>(&[&ints], |a, i| a.value(i)).unwrap(),
+            vec![Some(1), None]
+        );
+        assert!(values::<Float64Array, _>(&[&ints], |a, i| a.value(i)).is_err());
+    }
+}
@@ -0,0 +1,343 @@
+//! This module handles dictionaries (the values of dictionary-encoded columns,
+//! like the levels of an R factor) that change from one RecordBatch to the
+//! next. The IPC format allows two kinds of change:
+//!
+//! - a delta, which appends values to the dictionary, so the keys of earlier
+//!   RecordBatches stay valid
+//! - a replacement, which sends a whole new dictionary. This is only allowed
+//!   in the IPC stream format.
+//!
+//! Arrow (v3.0.0) can't tell the two apart on the write side, and can't read
+//! deltas at all. On the write side, [DictionaryTracker] decides which kind of
+//! DictionaryBatch (if any) each RecordBatch needs. On the read side,
+//! [DictionaryDeltas] rewrites each delta as a replacement holding the whole
+//! dictionary, which arrow's `StreamReader` can read (see
+//! [rewrite_ipc_messages](crate::structs::rewrite_ipc_messages)).
+
+use crate::structs::{dictionary_batch_to_bytes, RecordBatchesError};
+use arrow::{
+    array::{make_array, Array, ArrayRef},
+    compute::concat,
+    datatypes::{DataType, Field, Schema},
+    ipc::{reader::read_record_batch, writer::EncodedData, Message},
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+type Result<T> = std::result::Result<T, RecordBatchesError>;
+
+/// How the dictionary of a column differs from the last one written for the
+/// same dictionary id
+#[derive(Debug)]
+pub enum DictionaryUpdate {
+    /// The same dictionary was already written, nothing needs to be sent
+    Unchanged,
+    /// No dictionary was written for this id yet
+    New,
+    /// Values were appended to the dictionary, holds only the new values
+    Delta(ArrayRef),
+    /// The dictionary changed in any other way, and must be sent in full
+    Replacement,
+}
+
+/// Tracks the last dictionary written for each dictionary id. Replaces
+/// `arrow::ipc::writer::DictionaryTracker`, which doesn't detect deltas.
+#[derive(Debug)]
+pub struct DictionaryTracker {
+    written: HashMap<i64, ArrayRef>,
+    error_on_replacement: bool,
+}
+
+impl DictionaryTracker {
+    /// Creates a new tracker. The IPC file format doesn't allow replacements,
+    /// so file writers should set `error_on_replacement`.
+    pub fn new(error_on_replacement: bool) -> Self {
+        DictionaryTracker {
+            written: HashMap::new(),
+            error_on_replacement,
+        }
+    }
+
+    /// Records the dictionary of a dictionary-encoded `column`, returning how
+    /// it differs from the last one written for `dict_id`
+    pub fn insert(&mut self, dict_id: i64, column: &ArrayRef) -> Result<DictionaryUpdate> {
+        let values = make_array(column.data_ref().child_data()[0].clone());
+        let update = match self.written.get(&dict_id) {
+            None => DictionaryUpdate::New,
+            Some(previous) if previous.as_ref() == values.as_ref() => {
+                return Ok(DictionaryUpdate::Unchanged)
+            }
+            Some(previous) if is_prefix(previous, &values) => {
+                DictionaryUpdate::Delta(values.slice(previous.len(), values.len() - previous.len()))
+            }
+            Some(_) if self.error_on_replacement => {
+                return Err(RecordBatchesError::invalid(
+                    "Dictionary replacement is not supported in the IPC file format, values can only be appended",
+                ))
+            }
+            Some(_) => DictionaryUpdate::Replacement,
+        };
+        self.written.insert(dict_id, values);
+        Ok(update)
+    }
+}
+
+/// Whether `values` starts with all of `previous`, followed by more values
+fn is_prefix(previous: &ArrayRef, values: &ArrayRef) -> bool {
+    values.len() > previous.len()
+        && values.data_type() == previous.data_type()
+        && values.slice(0, previous.len()).as_ref() == previous.as_ref()
+}
+
+/// Rewrites delta DictionaryBatch messages as replacements holding the whole
+/// dictionary, tracking the dictionaries of an IPC stream as it is read
+#[derive(Debug, Default)]
+pub struct DictionaryDeltas {
+    value_fields: HashMap<i64, Field>,
+    dictionaries: HashMap<i64, ArrayRef>,
+}
+
+impl DictionaryDeltas {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records the value type of each dictionary from the schema of a stream,
+    /// including dictionaries nested in struct and list fields
+    pub fn read_schema(&mut self, schema: &Schema) {
+        for field in schema.fields() {
+            self.read_field(field);
+        }
+    }
+
+    fn read_field(&mut self, field: &Field) {
+        match field.data_type() {
+            DataType::Dictionary(_, value_type) => {
+                let value_field = Field::new(field.name(), (**value_type).clone(), true);
+                if let Some(dict_id) = field.dict_id() {
+                    self.value_fields.insert(dict_id, value_field.clone());
+                }
+                self.read_field(&value_field);
+            }
+            DataType::List(child)
+            | DataType::LargeList(child)
+            | DataType::FixedSizeList(child, _) => self.read_field(child),
+            DataType::Struct(children) | DataType::Union(children) => {
+                for child in children {
+                    self.read_field(child);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Records the dictionary in an (uncompressed) DictionaryBatch message.
+    /// If it is a delta, returns a replacement message holding the previous
+    /// dictionary with the delta appended.
+    ///
+    /// A dictionary with an id that isn't in the schema can't be decoded, so
+    /// it is passed through untouched (and arrow reports the error, if any)
+    /// unless it is a delta.
+    pub fn resolve(&mut self, message: &Message, body: &[u8]) -> Result<Option<EncodedData>> {
+        let batch = match message.header_as_dictionary_batch() {
+            Some(batch) => batch,
+            None => return Ok(None),
+        };
+        let dict_id = batch.id();
+        let field = match self.value_fields.get(&dict_id) {
+            Some(field) => field,
+            None if batch.isDelta() => {
+                return Err(RecordBatchesError::invalid(
+                    "Delta DictionaryBatch has an unknown dictionary id",
+                ))
+            }
+            None => return Ok(None),
+        };
+        let data = batch
+            .data()
+            .ok_or_else(|| RecordBatchesError::invalid("DictionaryBatch has no data"))?;
+        let schema = Arc::new(Schema::new(vec![field.clone()]));
+        let values = read_record_batch(body, data, schema, &[])
+            .map_err(|e| {
+                RecordBatchesError::arrow("Error reading DictionaryBatch")(e)
+                    .for_field(field.name().as_str())
+            })?
+            .column(0)
+            .clone();
+
+        if !batch.isDelta() {
+            self.dictionaries.insert(dict_id, values);
+            return Ok(None);
+        }
+        let previous = self.dictionaries.get(&dict_id).ok_or_else(|| {
+            RecordBatchesError::invalid("Delta DictionaryBatch arrived before its dictionary")
+                .for_field(field.name().as_str())
+        })?;
+        let combined = concat(&[previous.as_ref(), values.as_ref()]).map_err(|e| {
+            RecordBatchesError::arrow("Error appending delta to dictionary")(e)
+                .for_field(field.name().as_str())
+        })?;
+        let encoded = dictionary_batch_to_bytes(dict_id, &combined.data(), false, None)?;
+        self.dictionaries.insert(dict_id, combined);
+        Ok(Some(encoded))
+    }
+}
+
+#[allow(unused_imports)]
+mod test {
+    use super::*;
+    use crate::structs::{RecordBatchFileSink, RecordBatchStreamSink, RecordBatches};
+    use arrow::array::{DictionaryArray, StringArray};
+    use arrow::datatypes::Int32Type;
+    use arrow::ipc::MessageHeader;
+    use arrow::record_batch::RecordBatch;
+
+    #[allow(dead_code)]
+    fn factor_batch(values: Vec<&str>) -> RecordBatch {
+        let data_type = DataType::Dictionary(Box::new(DataType::Int32), Box::new(DataType::Utf8));
+        let schema = Schema::new(vec![Field::new_dict("f", data_type, true, 0, false)]);
+        let array: DictionaryArray<Int32Type> = values.into_iter().collect();
+        RecordBatch::try_new(Arc::new(schema), vec![Arc::new(array)]).unwrap()
+    }
+
+    #[allow(dead_code)]
+    fn levels(batch: &RecordBatch) -> Vec<String> {
+        let array = batch
+            .column(0)
+            .as_any()
+            .downcast_ref::<DictionaryArray<Int32Type>>()
+            .unwrap();
+        let values = array.values();
+        let values = values.as_any().downcast_ref::<StringArray>().unwrap();
+        (0..values.len())
+            .map(|i| values.value(i).to_string())
+            .collect()
+    }
+
+    /// Whether each DictionaryBatch message in an IPC stream is a delta
+    #[allow(dead_code)]
+    fn delta_flags(stream: &[u8]) -> Vec<bool> {
+        let mut flags = Vec::new();
+        let mut position = 0;
+        while position + 8 <= stream.len() {
+            let len = i32::from_le_bytes([
+                stream[position + 4],
+                stream[position + 5],
+                stream[position + 6],
+                stream[position + 7],
+            ]) as usize;
+            if len == 0 {
+                break;
+            }
+            let message =
+                arrow::ipc::root_as_message(&stream[position + 8..position + 8 + len]).unwrap();
+            if message.header_type() == MessageHeader::DictionaryBatch {
+                flags.push(message.header_as_dictionary_batch().unwrap().isDelta());
+            }
+            position += 8 + len + message.bodyLength() as usize;
+        }
+        flags
+    }
+
+    #[test]
+    fn test_tracker_detects_deltas_and_replacements() {
+        let mut tracker = DictionaryTracker::new(false);
+        let first = factor_batch(vec!["a", "b"]);
+        let grown = factor_batch(vec!["a", "b", "c"]);
+        let replaced = factor_batch(vec!["z"]);
+
+        assert!(matches!(
+            tracker.insert(0, first.column(0)).unwrap(),
+            DictionaryUpdate::New
+        ));
+        assert!(matches!(
+            tracker.insert(0, first.column(0)).unwrap(),
+            DictionaryUpdate::Unchanged
+        ));
+        match tracker.insert(0, grown.column(0)).unwrap() {
+            DictionaryUpdate::Delta(values) => assert_eq!(values.len(), 1),
+            update => panic!("Expected a delta, found {:?}", update),
+        }
+        assert!(matches!(
+            tracker.insert(0, replaced.column(0)).unwrap(),
+            DictionaryUpdate::Replacement
+        ));
+
+        let mut tracker = DictionaryTracker::new(true);
+        tracker.insert(0, first.column(0)).unwrap();
+        assert!(tracker.insert(0, grown.column(0)).is_ok());
+        assert!(tracker.insert(0, replaced.column(0)).is_err());
+    }
+
+    #[test]
+    fn test_stream_with_deltas_and_replacements_round_trip() {
+        let batches = [
+            factor_batch(vec!["a", "b", "a"]),
+            factor_batch(vec!["a", "b", "c"]),
+            factor_batch(vec!["a", "b", "c", "d"]),
+            factor_batch(vec!["z"]),
+        ];
+        let mut sink = RecordBatchStreamSink::try_new(Vec::new(), batches[0].schema()).unwrap();
+        for batch in batches.iter() {
+            sink.write(batch).unwrap();
+        }
+        let buffer = sink.into_inner().unwrap();
+        assert_eq!(delta_flags(&buffer), vec![false, true, true, false]);
+
+        let read: Vec<_> = RecordBatches::from_bytes(&buffer)
+            .unwrap()
+            .into_iter()
+            .collect();
+        assert_eq!(read.len(), 4);
+        assert_eq!(levels(&read[0]), vec!["a", "b"]);
+        assert_eq!(levels(&read[1]), vec!["a", "b", "c"]);
+        assert_eq!(levels(&read[2]), vec!["a", "b", "c", "d"]);
+        assert_eq!(levels(&read[3]), vec!["z"]);
+    }
+
+    #[test]
+    fn test_file_with_deltas_round_trip() {
+        let batches = [factor_batch(vec!["a"]), factor_batch(vec!["a", "b"])];
+        let mut sink = RecordBatchFileSink::try_new(Vec::new(), batches[0].schema()).unwrap();
+        for batch in batches.iter() {
+            sink.write(batch).unwrap();
+        }
+        let buffer = sink.into_inner().unwrap();
+
+        let read: Vec<_> = RecordBatches::from_bytes(&buffer)
+            .unwrap()
+            .into_iter()
+            .collect();
+        assert_eq!(levels(&read[1]), vec!["a", "b"]);
+
+        let mut sink = RecordBatchFileSink::try_new(Vec::new(), batches[0].schema()).unwrap();
+        sink.write(&batches[1]).unwrap();
+        let err = sink.write(&batches[0]).unwrap_err();
+        assert_eq!(err.field(), Some("f"));
+        assert_eq!(err.message_index(), Some(1));
+    }
+
+    #[test]
+    fn test_deltas_resolve_nested_and_pass_unknown_ids() {
+        let data_type = DataType::Dictionary(Box::new(DataType::Int32), Box::new(DataType::Utf8));
+        let nested = Field::new_dict("f", data_type, true, 7, false);
+        let schema = Schema::new(vec![Field::new("s", DataType::Struct(vec![nested]), true)]);
+        let mut deltas = DictionaryDeltas::new();
+        deltas.read_schema(&schema);
+
+        let resolve = |deltas: &mut DictionaryDeltas, dict_id, values: Vec<&str>, is_delta| {
+            let values = StringArray::from(values);
+            let encoded =
+                dictionary_batch_to_bytes(dict_id, &values.data(), is_delta, None).unwrap();
+            let message = arrow::ipc::root_as_message(&encoded.ipc_message).unwrap();
+            deltas.resolve(&message, &encoded.arrow_data)
+        };
+        assert!(resolve(&mut deltas, 7, vec!["a"], false).unwrap().is_none());
+        assert!(resolve(&mut deltas, 7, vec!["b"], true).unwrap().is_some());
+        assert_eq!(deltas.dictionaries[&7].len(), 2);
+
+        assert!(resolve(&mut deltas, 8, vec!["a"], false).unwrap().is_none());
+        assert!(!deltas.dictionaries.contains_key(&8));
+        assert!(resolve(&mut deltas, 8, vec!["b"], true).is_err());
+    }
+}
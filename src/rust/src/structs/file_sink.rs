@@ -0,0 +1,236 @@
+//! This module provides [RecordBatchFileSink], which writes RecordBatches in
+//! the Arrow IPC file format (also known as Feather v2). The file format is the
+//! stream format wrapped in 'magic' bytes, followed by a footer that records
+//! the schema and the location of every dictionary and record batch message,
+//! allowing readers to jump straight to any RecordBatch in the file.
+//!
+//! Dictionaries may grow between RecordBatches (and are written as deltas),
+//! but can't be replaced, see [DictionaryTracker].
+
+use crate::structs::{
+    append_record_batch_to_stream, pad_output_stream, schema_mismatch, schema_to_bytes,
+    write_continuation_marker, write_encoded_data_to_stream, DictionaryTracker, IpcCompression,
+    RecordBatchesError,
+};
+use arrow::{datatypes::SchemaRef, record_batch::RecordBatch};
+use flatbuffers::FlatBufferBuilder;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+
+/// The bytes at the start and end of an Arrow IPC file
+pub const ARROW_MAGIC: [u8; 6] = [b'A', b'R', b'R', b'O', b'W', b'1'];
+
+type Result<T> = std::result::Result<T, RecordBatchesError>;
+
+/// Writes RecordBatches to `W` in the Arrow IPC file format. Like
+/// [RecordBatchStreamSink](crate::structs::RecordBatchStreamSink), batches are
+/// written as they are received, but nothing can read the file until
+/// [finish](RecordBatchFileSink::finish) (or [into_inner](RecordBatchFileSink::into_inner))
+/// has written the footer. A sink dropped without being finished gets no footer.
+pub struct RecordBatchFileSink<W: Write> {
+    writer: Option<BufWriter<W>>,
+    schema: SchemaRef,
+    dictionary_tracker: DictionaryTracker,
+    compression: Option<IpcCompression>,
+    dictionary_blocks: Vec<arrow::ipc::Block>,
+    record_blocks: Vec<arrow::ipc::Block>,
+    position: usize,
+    finished: bool,
+}
+
+impl<W: Write> RecordBatchFileSink<W> {
+    /// Creates a new sink, writing the leading magic bytes and the schema
+    /// message to `writer`
+    pub fn try_new(writer: W, schema: SchemaRef) -> Result<Self> {
+        let mut writer = BufWriter::new(writer);
+        writer
+            .write_all(&ARROW_MAGIC)
+            .map_err(RecordBatchesError::io("Could not write magic bytes."))?;
+        pad_output_stream(&mut writer, 2)?;
+        let (meta, body) = write_encoded_data_to_stream(&mut writer, schema_to_bytes(&schema))?;
+        Ok(RecordBatchFileSink {
+            writer: Some(writer),
+            schema,
+            dictionary_tracker: DictionaryTracker::new(true),
+            compression: None,
+            dictionary_blocks: Vec::new(),
+            record_blocks: Vec::new(),
+            position: ARROW_MAGIC.len() + 2 + meta + body,
+            finished: false,
+        })
+    }
+
+    /// Compresses the body of every RecordBatch (and dictionary) written from
+    /// now on with `compression`, or not at all if `None`
+    pub fn with_compression(mut self, compression: Option<IpcCompression>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Encodes a RecordBatch, writes it to the file, and records the location
+    /// of each message written in the block index
+    pub fn write(&mut self, batch: &RecordBatch) -> Result<()> {
+        if self.finished {
+            return Err(RecordBatchesError::invalid(
+                "Cannot write to a finished RecordBatchFileSink",
+            ));
+        }
+        let index = self.record_blocks.len();
+        if batch.schema() != self.schema {
+            return Err(schema_mismatch(
+                "RecordBatch schema does not match the file schema",
+                &self.schema,
+                &batch.schema(),
+            )
+            .at_message(index));
+        }
+        let writer = self
+            .writer
+            .as_mut()
+            .ok_or_else(|| RecordBatchesError::invalid("Writer already taken"))?;
+        let written = append_record_batch_to_stream(
+            &mut *writer,
+            &mut self.dictionary_tracker,
+            batch,
+            self.compression,
+        )
+        .map_err(|e| e.at_message(index))?;
+        for (meta, body) in written.dictionaries {
+            self.dictionary_blocks
+                .push(block(self.position, meta, body));
+            self.position += meta + body;
+        }
+        let (meta, body) = written.record_batch;
+        self.record_blocks.push(block(self.position, meta, body));
+        self.position += meta + body;
+        Ok(())
+    }
+
+    /// Writes the end-of-stream marker, the footer, and the trailing magic
+    /// bytes. Calling `finish` more than once is a no-op.
+    pub fn finish(&mut self) -> Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        let writer = self
+            .writer
+            .as_mut()
+            .ok_or_else(|| RecordBatchesError::invalid("Writer already taken"))?;
+        write_continuation_marker(&mut *writer, 0)?;
+
+        let mut fbb = FlatBufferBuilder::new();
+        let dictionaries = fbb.create_vector(&self.dictionary_blocks);
+        let record_batches = fbb.create_vector(&self.record_blocks);
+        let schema = arrow::ipc::convert::schema_to_fb_offset(&mut fbb, &self.schema);
+        let root = {
+            let mut footer_builder = arrow::ipc::FooterBuilder::new(&mut fbb);
+            footer_builder.add_version(arrow::ipc::MetadataVersion::V5);
+            footer_builder.add_schema(schema);
+            footer_builder.add_dictionaries(dictionaries);
+            footer_builder.add_recordBatches(record_batches);
+            footer_builder.finish()
+        };
+        fbb.finish(root, None);
+        let footer_data = fbb.finished_data();
+
+        writer
+            .write_all(footer_data)
+            .map_err(RecordBatchesError::io("Could not write footer."))?;
+        writer
+            .write_all(&(footer_data.len() as i32).to_le_bytes())
+            .map_err(RecordBatchesError::io("Could not write footer length."))?;
+        writer
+            .write_all(&ARROW_MAGIC)
+            .map_err(RecordBatchesError::io("Could not write magic bytes."))?;
+        writer
+            .flush()
+            .map_err(RecordBatchesError::io("Could not flush buffer."))?;
+        self.finished = true;
+        Ok(())
+    }
+
+    /// Finishes the file (if needed) and returns the underlying writer
+    pub fn into_inner(mut self) -> Result<W> {
+        self.finish()?;
+        let writer = self
+            .writer
+            .take()
+            .ok_or_else(|| RecordBatchesError::invalid("Writer already taken"))?;
+        writer.into_inner().map_err(|e| {
+            RecordBatchesError::io("Could not retrieve writer from BufWriter")(e.into_error())
+        })
+    }
+}
+
+/// Checks whether `reader` starts with the IPC file magic bytes, leaving the
+/// reader positioned at the start either way
+pub fn is_ipc_file<R: Read + Seek>(reader: &mut R) -> Result<bool> {
+    let mut magic = [0u8; 6];
+    let is_file = match reader.read_exact(&mut magic) {
+        Ok(()) => magic == ARROW_MAGIC,
+        Err(_) => false,
+    };
+    reader
+        .seek(SeekFrom::Start(0))
+        .map_err(RecordBatchesError::io("Could not seek to start of input."))?;
+    Ok(is_file)
+}
+
+fn block(offset: usize, meta: usize, body: usize) -> arrow::ipc::Block {
+    arrow::ipc::Block::new(offset as i64, meta as i32, body as i64)
+}
+
+#[allow(unused_imports)]
+mod test {
+    use super::*;
+    use arrow::array::{Int32Array, StringArray};
+    use arrow::datatypes::{DataType, Field, Schema};
+    use arrow::ipc::reader::FileReader;
+    use std::io::Cursor;
+    use std::sync::Arc;
+
+    #[allow(dead_code)]
+    fn sample_batch(offset: i32) -> RecordBatch {
+        let schema = Schema::new(vec![
+            Field::new("id", DataType::Int32, false),
+            Field::new("label", DataType::Utf8, true),
+        ]);
+        RecordBatch::try_new(
+            Arc::new(schema),
+            vec![
+                Arc::new(Int32Array::from(vec![offset, offset + 1])),
+                Arc::new(StringArray::from(vec![Some("a"), None])),
+            ],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_file_sink_random_access() {
+        let batches: Vec<_> = (0..3).map(|i| sample_batch(i * 10)).collect();
+        let mut sink = RecordBatchFileSink::try_new(Vec::new(), batches[0].schema()).unwrap();
+        for batch in batches.iter() {
+            sink.write(batch).unwrap();
+        }
+        let buffer = sink.into_inner().unwrap();
+        assert_eq!(&buffer[..6], &ARROW_MAGIC);
+        assert_eq!(&buffer[buffer.len() - 6..], &ARROW_MAGIC);
+
+        let mut reader = FileReader::try_new(Cursor::new(buffer)).unwrap();
+        assert_eq!(reader.num_batches(), 3);
+        reader.set_index(2).unwrap();
+        let found = reader.next().unwrap().unwrap();
+        assert_eq!(found.column(0).data(), batches[2].column(0).data());
+    }
+
+    #[test]
+    fn test_is_ipc_file() {
+        let batch = sample_batch(0);
+        let sink = RecordBatchFileSink::try_new(Vec::new(), batch.schema()).unwrap();
+        let mut file = Cursor::new(sink.into_inner().unwrap());
+        assert!(is_ipc_file(&mut file).unwrap());
+        assert_eq!(file.position(), 0);
+
+        let mut stream = Cursor::new(vec![0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0]);
+        assert!(!is_ipc_file(&mut stream).unwrap());
+    }
+}
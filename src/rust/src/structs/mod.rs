@@ -1,5 +1,29 @@
-mod char_vec;
+mod compression;
+mod data_frame;
+mod dictionaries;
+mod file_sink;
+mod na_vec;
+mod named_record_batches;
+mod r_array;
+mod r_metadata;
+mod r_slice;
+mod record_batch_stream;
 mod record_batches;
+mod record_batches_error;
+mod schema;
+mod stream_sink;
 
-pub use char_vec::*;
+pub use compression::*;
+pub use data_frame::*;
+pub use dictionaries::*;
+pub use file_sink::*;
+pub use na_vec::*;
+pub use named_record_batches::*;
+pub use r_array::*;
+pub use r_metadata::*;
+pub use r_slice::*;
+pub use record_batch_stream::*;
 pub use record_batches::*;
+pub use record_batches_error::*;
+pub use schema::*;
+pub use stream_sink::*;
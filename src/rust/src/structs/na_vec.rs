@@ -0,0 +1,304 @@
+//! This module implements [NaVec], a newtype wrapping a Vec<Option<T>>, for
+//! passing atomic R vectors that may contain NA's in and out of Rust functions
+//! annotated with #[extendr]. extendr_api (v0.2.0) reads NA's as the plain
+//! values they are stored as (`i32::MIN` for integers and logicals, a NaN
+//! with a special payload for doubles), and doesn't read NA_character_ as
+//! anything but the string "NA". `NaVec` represents every NA as `None`, so
+//! they survive a round trip.
+//!
+//! The element types are those implementing [RNative]:
+//!
+//! | R type    | Rust type          | NA                      |
+//! |-----------|--------------------|-------------------------|
+//! | integer   | `NaVec<i32>`       | NA_integer_             |
+//! | double    | `NaVec<f64>`       | NA_real_, but not NaN   |
+//! | logical   | `NaVec<bool>`      | NA                      |
+//! | raw       | `NaVec<u8>`        | never, raws can't be NA |
+//! | character | `NaVec<String>`    | NA_character_           |
+//! | complex   | `NaVec<Complex64>` | NA_real_ in either part |
+//!
+//! Doubles keep NA_real_ and NaN apart: NA_real_ is read as `None`, while NaN
+//! is read as `Some(f64::NAN)`, so `is.nan()` still holds on the way back.
+//! The same goes for each part of a complex number. [IntVec], [CharVec], and
+//! [ComplexVec] are the integer, character, and complex versions.
+
+use extendr_api::{
+    new_owned,
+    prelude::{na_str, FromRobj, RType, Raw, Robj},
+};
+use num_complex::Complex64;
+use std::iter::FromIterator;
+
+/// The bit pattern R uses for `NA_real_`, a NaN with 1954 in its low word
+const NA_REAL_PAYLOAD: u32 = 1954;
+
+/// Rust type that wraps a Vec<Option<T>>, where `None` represents an NA from R
+#[derive(Debug, Clone, PartialEq)]
+pub struct NaVec<T: RNative>(Vec<Option<T>>);
+
+/// An integer vector, where `None` represents an NA_integer_
+pub type IntVec = NaVec<i32>;
+
+/// A character vector, where `None` represents an NA_character_
+pub type CharVec = NaVec<String>;
+
+/// A complex vector, where `None` represents an NA_complex_
+pub type ComplexVec = NaVec<Complex64>;
+
+/// Element types of the atomic R vectors, for [NaVec]
+pub trait RNative: Sized {
+    /// Error for an `Robj` that isn't a vector of this type
+    const TYPE_ERROR: &'static str;
+
+    /// Reads the elements of an R vector of this type, with NA's as `None`.
+    /// Returns `None` if `robj` is not a vector of this type.
+    fn from_r_vector(robj: &Robj) -> Option<Vec<Option<Self>>>;
+
+    /// Creates an R vector of this type, with `None` written as NA
+    fn into_r_vector(values: Vec<Option<Self>>) -> Robj;
+}
+
+impl RNative for i32 {
+    const TYPE_ERROR: &'static str = "Input must be an integer vector.";
+
+    fn from_r_vector(robj: &Robj) -> Option<Vec<Option<Self>>> {
+        let v = robj.as_integer_slice()?;
+        Some(v.iter().copied().map(parse_rint).collect())
+    }
+
+    fn into_r_vector(values: Vec<Option<Self>>) -> Robj {
+        Robj::from(values)
+    }
+}
+
+impl RNative for f64 {
+    const TYPE_ERROR: &'static str = "Input must be a double vector.";
+
+    fn from_r_vector(robj: &Robj) -> Option<Vec<Option<Self>>> {
+        let v = robj.as_real_slice()?;
+        Some(v.iter().copied().map(parse_rreal).collect())
+    }
+
+    fn into_r_vector(values: Vec<Option<Self>>) -> Robj {
+        Robj::from(values)
+    }
+}
+
+impl RNative for bool {
+    const TYPE_ERROR: &'static str = "Input must be a logical vector.";
+
+    fn from_r_vector(robj: &Robj) -> Option<Vec<Option<Self>>> {
+        let v = robj.as_logical_iter()?;
+        Some(
+            v.map(|b| match (b.is_true(), b.is_false()) {
+                (true, _) => Some(true),
+                (_, true) => Some(false),
+                _ => None,
+            })
+            .collect(),
+        )
+    }
+
+    fn into_r_vector(values: Vec<Option<Self>>) -> Robj {
+        Robj::from(values)
+    }
+}
+
+/// R has no NA for raws, so every element is read as `Some`, and `None` is
+/// written as `00`
+impl RNative for u8 {
+    const TYPE_ERROR: &'static str = "Input must be a raw vector.";
+
+    fn from_r_vector(robj: &Robj) -> Option<Vec<Option<Self>>> {
+        let v = robj.as_raw_slice()?;
+        Some(v.iter().copied().map(Some).collect())
+    }
+
+    fn into_r_vector(values: Vec<Option<Self>>) -> Robj {
+        let bytes: Vec<u8> = values.into_iter().map(|x| x.unwrap_or(0)).collect();
+        Robj::from(Raw(&bytes))
+    }
+}
+
+impl RNative for String {
+    const TYPE_ERROR: &'static str = "Input must be a character vector.";
+
+    fn from_r_vector(robj: &Robj) -> Option<Vec<Option<Self>>> {
+        if robj.is_na() {
+            // A single NA_character_ is represented by a Vec containing a single None
+            Some(vec![None])
+        } else {
+            let v = robj.as_string_vector()?;
+            Some(v.into_iter().map(parse_rchar).collect())
+        }
+    }
+
+    fn into_r_vector(values: Vec<Option<Self>>) -> Robj {
+        Robj::from(values)
+    }
+}
+
+/// R stores complex numbers as a pair of doubles. Any number with an NA_real_
+/// in either part is read as `None`, like `is.na()` in R, and `None` is
+/// written as NA_complex_, with NA_real_ in both parts.
+impl RNative for Complex64 {
+    const TYPE_ERROR: &'static str = "Input must be a complex vector.";
+
+    fn from_r_vector(robj: &Robj) -> Option<Vec<Option<Self>>> {
+        if robj.rtype() != RType::Complex {
+            return None;
+        }
+        let v = match robj.len() {
+            // R may not give a valid pointer for an empty vector
+            0 => &[],
+            len => unsafe { std::slice::from_raw_parts(libR_sys::COMPLEX(robj.get()), len) },
+        };
+        Some(v.iter().map(|z| parse_rcomplex(z.r, z.i)).collect())
+    }
+
+    fn into_r_vector(values: Vec<Option<Self>>) -> Robj {
+        let len = values.len();
+        unsafe {
+            let robj = new_owned(libR_sys::Rf_allocVector(
+                libR_sys::CPLXSXP,
+                len as libR_sys::R_xlen_t,
+            ));
+            if len > 0 {
+                let data = std::slice::from_raw_parts_mut(libR_sys::COMPLEX(robj.get()), len);
+                for (z, value) in data.iter_mut().zip(values) {
+                    let value = value
+                        .unwrap_or_else(|| Complex64::new(libR_sys::R_NaReal, libR_sys::R_NaReal));
+                    z.r = value.re;
+                    z.i = value.im;
+                }
+            }
+            robj
+        }
+    }
+}
+
+// Helper functions
+/// Whether a double from R is `NA_real_` (as opposed to any other NaN)
+pub(crate) fn is_na_real(x: f64) -> bool {
+    x.is_nan() && x.to_bits() as u32 == NA_REAL_PAYLOAD
+}
+
+#[rustfmt::skip]
+pub(crate) fn parse_rint(rint: i32) -> Option<i32> {
+    if rint == i32::MIN { None } else { Some(rint) }
+}
+
+#[rustfmt::skip]
+pub(crate) fn parse_rreal(rreal: f64) -> Option<f64> {
+    if is_na_real(rreal) { None } else { Some(rreal) }
+}
+
+#[rustfmt::skip]
+pub(crate) fn parse_rcomplex(re: f64, im: f64) -> Option<Complex64> {
+    if is_na_real(re) || is_na_real(im) { None } else { Some(Complex64::new(re, im)) }
+}
+
+#[rustfmt::skip]
+pub(crate) fn parse_rchar(rchar: String) -> Option<String> {
+    if rchar == na_str() { None } else { Some(rchar) }
+}
+
+impl<T: RNative> NaVec<T> {
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Iterates over the elements by reference, `None` for NA
+    pub fn iter(&self) -> std::slice::Iter<'_, Option<T>> {
+        self.0.iter()
+    }
+}
+
+/// For converting `NaVec` to `Robj`
+impl<T: RNative> From<NaVec<T>> for Robj {
+    fn from(v: NaVec<T>) -> Robj {
+        T::into_r_vector(v.0)
+    }
+}
+
+/// For converting `Robj` to `NaVec`
+impl<'a, T: RNative> FromRobj<'a> for NaVec<T> {
+    fn from_robj(robj: &'a Robj) -> std::result::Result<Self, &'static str> {
+        T::from_r_vector(robj).map(NaVec).ok_or(T::TYPE_ERROR)
+    }
+}
+
+/// Allow for iterating over the Vec<Option<T>> wrapped by NaVec
+impl<T: RNative> IntoIterator for NaVec<T> {
+    type Item = Option<T>;
+    type IntoIter = std::vec::IntoIter<Option<T>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+/// Allows for collecting a NaVec from any iterator that yields <Option<T>>
+impl<T: RNative> FromIterator<Option<T>> for NaVec<T> {
+    fn from_iter<I: IntoIterator<Item = Option<T>>>(iter: I) -> Self {
+        NaVec(iter.into_iter().collect())
+    }
+}
+
+impl<T: RNative> From<Vec<Option<T>>> for NaVec<T> {
+    fn from(v: Vec<Option<T>>) -> Self {
+        NaVec(v)
+    }
+}
+
+#[allow(unused_imports)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_na_real_distinguishes_nan() {
+        let na_real = f64::from_bits(0x7FF0_0000_0000_07A2);
+        assert!(is_na_real(na_real));
+        assert!(!is_na_real(f64::NAN));
+        assert!(!is_na_real(1.0));
+        assert_eq!(parse_rreal(na_real), None);
+        assert!(parse_rreal(f64::NAN).unwrap().is_nan());
+        assert_eq!(parse_rreal(1.5), Some(1.5));
+    }
+
+    #[test]
+    fn test_parse_rcomplex() {
+        let na_real = f64::from_bits(0x7FF0_0000_0000_07A2);
+        assert_eq!(parse_rcomplex(na_real, 1.0), None);
+        assert_eq!(parse_rcomplex(1.0, na_real), None);
+        assert_eq!(parse_rcomplex(1.0, -2.0), Some(Complex64::new(1.0, -2.0)));
+        assert!(parse_rcomplex(f64::NAN, 0.0).unwrap().re.is_nan());
+    }
+
+    #[test]
+    fn test_parse_rint() {
+        assert_eq!(parse_rint(i32::MIN), None);
+        assert_eq!(parse_rint(-1), Some(-1));
+    }
+
+    #[test]
+    fn test_int_vec_keeps_na_integer() {
+        let v: IntVec = [5, i32::MIN, -7].iter().copied().map(parse_rint).collect();
+        assert_eq!(v, IntVec::from(vec![Some(5), None, Some(-7)]));
+        let tenfold: IntVec = v.into_iter().map(|x| x.map(|x| x * 10)).collect();
+        assert_eq!(tenfold.iter().filter(|x| x.is_none()).count(), 1);
+        assert_eq!(tenfold, IntVec::from(vec![Some(50), None, Some(-70)]));
+    }
+
+    #[test]
+    fn test_collect_and_iterate() {
+        let v: NaVec<f64> = vec![Some(1.0), None].into_iter().collect();
+        assert_eq!(v.len(), 2);
+        let doubled: NaVec<f64> = v.into_iter().map(|x| x.map(|x| x * 2.0)).collect();
+        assert_eq!(doubled, NaVec::from(vec![Some(2.0), None]));
+    }
+}
@@ -0,0 +1,202 @@
+//! This module defines [NamedRecordBatches], for passing several tables, each
+//! with its own schema, between R and Rust in a single call. On the R side,
+//! the tables are a named list, e.g. the rows that did and didn't match a
+//! filter:
+//!
+//! ```r
+//! list(
+//!   matched = <raw vector>,
+//!   unmatched = <raw vector>
+//! )
+//! ```
+//!
+//! Each element of the list can be anything [RecordBatches] accepts: a raw
+//! vector or file path holding an IPC stream or file, a data frame, or Arrow
+//! C Data Interface pointers. Tables are returned as a named list of raw
+//! vectors in the IPC stream format, or of data frames.
+//!
+//! Like [RecordBatches], there is no `From<NamedRecordBatches> for Robj`:
+//! writing a table as an IPC stream or data frame can fail (e.g. on a column
+//! type R has no equivalent for), and `From` has no way to report that, so
+//! the conversions return a [Result] naming the table that failed instead.
+
+use crate::structs::{RecordBatches, RecordBatchesError};
+use extendr_api::prelude::*;
+
+type Result<T> = std::result::Result<T, RecordBatchesError>;
+
+/// Newtype wrapping named tables of RecordBatches, kept in order. Names are
+/// unique.
+#[derive(Debug, Default)]
+pub struct NamedRecordBatches(Vec<(String, RecordBatches)>);
+
+impl NamedRecordBatches {
+    /// Creates an empty set of tables
+    pub fn new() -> Self {
+        NamedRecordBatches(Vec::new())
+    }
+
+    /// Adds a table, replacing any table with the same name
+    pub fn insert<S: Into<String>>(&mut self, name: S, batches: RecordBatches) {
+        let name = name.into();
+        match self.0.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, table)) => *table = batches,
+            None => self.0.push((name, batches)),
+        }
+    }
+
+    /// Fetches a table by name
+    pub fn get(&self, name: &str) -> Option<&RecordBatches> {
+        self.0
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, table)| table)
+    }
+
+    /// Removes a table by name, returning it
+    pub fn remove(&mut self, name: &str) -> Option<RecordBatches> {
+        let index = self.0.iter().position(|(n, _)| n == name)?;
+        Some(self.0.remove(index).1)
+    }
+
+    /// Removes a table that must be present, for Rust functions that require
+    /// certain tables in their input
+    pub fn take_required(&mut self, name: &str) -> Result<RecordBatches> {
+        self.remove(name)
+            .ok_or_else(|| RecordBatchesError::invalid("Missing table").for_table(name))
+    }
+
+    /// Converts the tables into a named list of raw vectors, each holding
+    /// one table in the IPC stream format, see
+    /// [into_robj](RecordBatches::into_robj)
+    pub fn into_robj(self) -> Result<Robj> {
+        self.into_named_list(|batches| batches.into_robj())
+    }
+
+    /// Converts the tables into a named list of data frames, see
+    /// [into_data_frame](RecordBatches::into_data_frame)
+    pub fn into_data_frames(self) -> Result<Robj> {
+        self.into_named_list(|batches| batches.into_data_frame())
+    }
+
+    fn into_named_list<F>(self, convert: F) -> Result<Robj>
+    where
+        F: Fn(RecordBatches) -> Result<Robj>,
+    {
+        let mut names = Vec::with_capacity(self.0.len());
+        let mut values = Vec::with_capacity(self.0.len());
+        for (name, batches) in self.0 {
+            values.push(convert(batches).map_err(|e| e.for_table(&name))?);
+            names.push(name);
+        }
+        let robj = Robj::from(List(values));
+        robj.set_attrib(names_symbol(), names)
+            .map_err(|_| RecordBatchesError::invalid("Error setting names of list of tables"))
+    }
+
+    /// For converting `Robj` to `NamedRecordBatches`. Expects a named list,
+    /// whose elements are each converted with
+    /// [try_from_robj](RecordBatches::try_from_robj). Every element must have
+    /// a distinct, non-empty name.
+    pub fn try_from_robj(robj: &Robj) -> Result<Self> {
+        let expected_list = || RecordBatchesError::invalid("Expected a named list of tables.");
+        if robj.inherits("data.frame") {
+            return Err(expected_list());
+        }
+        let items = robj.as_list_iter().ok_or_else(expected_list)?;
+        let names: Vec<String> = match robj.names() {
+            Some(names) => names.map(String::from).collect(),
+            None if robj.len() == 0 => Vec::new(),
+            None => return Err(expected_list()),
+        };
+
+        let mut tables = NamedRecordBatches::new();
+        for (name, item) in names.into_iter().zip(items) {
+            if name.is_empty() || name == "NA" {
+                return Err(RecordBatchesError::invalid(
+                    "Every table in the list must be named.",
+                ));
+            }
+            if tables.get(&name).is_some() {
+                return Err(RecordBatchesError::invalid("Duplicate table name").for_table(name));
+            }
+            let batches = RecordBatches::try_from_robj(&item).map_err(|e| e.for_table(&name))?;
+            tables.0.push((name, batches));
+        }
+        Ok(tables)
+    }
+}
+
+impl<'a> FromRobj<'a> for NamedRecordBatches {
+    /// For converting `Robj` to `NamedRecordBatches`, see
+    /// [try_from_robj](NamedRecordBatches::try_from_robj). Only a short
+    /// description of any error can be reported this way.
+    fn from_robj(robj: &'a Robj) -> std::result::Result<Self, &'static str> {
+        NamedRecordBatches::try_from_robj(robj).map_err(|e| e.context())
+    }
+}
+
+/// Allow for iterating over the (name, table) pairs in order
+impl IntoIterator for NamedRecordBatches {
+    type Item = (String, RecordBatches);
+    type IntoIter = std::vec::IntoIter<(String, RecordBatches)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+#[allow(unused_imports)]
+mod test {
+    use super::*;
+    use arrow::{
+        array::{ArrayRef, Float64Array, Int32Array},
+        datatypes::{DataType, Field, Schema},
+        record_batch::RecordBatch,
+    };
+    use std::sync::Arc;
+
+    #[allow(dead_code)]
+    fn table(name: &str, array: ArrayRef) -> RecordBatches {
+        let schema = Schema::new(vec![Field::new(name, array.data_type().clone(), true)]);
+        vec![RecordBatch::try_new(Arc::new(schema), vec![array]).unwrap()]
+            .into_iter()
+            .collect()
+    }
+
+    #[test]
+    fn test_tables_keep_order_and_schemas() {
+        let mut tables = NamedRecordBatches::new();
+        tables.insert(
+            "predictions",
+            table("fitted", Arc::new(Float64Array::from(vec![1.5]))),
+        );
+        tables.insert(
+            "diagnostics",
+            table("n", Arc::new(Int32Array::from(vec![10]))),
+        );
+        let schema = tables.get("diagnostics").unwrap().schema().unwrap();
+        assert_eq!(schema.field(0).data_type(), &DataType::Int32);
+        let names: Vec<String> = tables.into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["predictions", "diagnostics"]);
+    }
+
+    #[test]
+    fn test_insert_replaces_table_with_same_name() {
+        let mut tables = NamedRecordBatches::new();
+        tables.insert("a", table("x", Arc::new(Int32Array::from(vec![1]))));
+        tables.insert("a", table("y", Arc::new(Int32Array::from(vec![2]))));
+        let tables: Vec<_> = tables.into_iter().collect();
+        assert_eq!(tables.len(), 1);
+        assert_eq!(tables[0].0, "a");
+        assert_eq!(tables[0].1.schema().unwrap().field(0).name(), "y");
+    }
+
+    #[test]
+    fn test_take_required_reports_missing_table() {
+        let mut tables = NamedRecordBatches::new();
+        let err = tables.take_required("train").unwrap_err();
+        assert_eq!(err.context(), "Missing table");
+        assert_eq!(err.table(), Some("train"));
+    }
+}
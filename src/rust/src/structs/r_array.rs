@@ -0,0 +1,444 @@
+//! This module implements [RArray] and [RMatrix], for passing R arrays and
+//! matrices in and out of Rust functions annotated with #[extendr] without
+//! losing their shape. A plain slice like `&[f64]` only sees the values of a
+//! matrix, not its `dim` and `dimnames` attributes.
+//!
+//! The values are kept the way R stores them, in column-major order: the
+//! first index varies fastest, so element `[i, j]` of an `nrow` by `ncol`
+//! matrix is at `i + j * nrow`. Indices are 0-based in Rust. The element
+//! types are those of [RSlice] (doubles, integers, and
+//! [RLogical](crate::structs::RLogical)s), and NA's are left as R stores
+//! them.
+//!
+//! Both types are returned to R with [into_robj](RArray::into_robj), which
+//! restores the `dim` and `dimnames` attributes. Names on the `dimnames` list
+//! itself (as in `dimnames(x) <- list(rows = ..., cols = ...)`) aren't kept.
+//!
+//! With the `ndarray` feature, arrays and matrices can also be converted to
+//! and from `ndarray::ArrayD` and `ndarray::Array2`.
+
+use crate::structs::{attribute, RSlice, RSliceElement};
+use extendr_api::{
+    new_owned,
+    prelude::{FromRobj, List, Robj},
+};
+
+type Result<T> = std::result::Result<T, &'static str>;
+
+/// The names along each dimension of an array, `None` for a dimension
+/// without names
+pub type DimNames = Vec<Option<Vec<String>>>;
+
+/// An R array with any number of dimensions
+#[derive(Debug, Clone, PartialEq)]
+pub struct RArray<T> {
+    data: Vec<T>,
+    dim: Vec<usize>,
+    dimnames: Option<DimNames>,
+}
+
+#[allow(dead_code)]
+impl<T: RSliceElement> RArray<T> {
+    /// Creates an array from its values in column-major order
+    pub fn new(data: Vec<T>, dim: Vec<usize>) -> Result<Self> {
+        if dim.iter().product::<usize>() != data.len() {
+            return Err("The dimensions don't match the number of values.");
+        }
+        Ok(RArray {
+            data,
+            dim,
+            dimnames: None,
+        })
+    }
+
+    /// Sets the names along each dimension
+    pub fn with_dimnames(mut self, dimnames: DimNames) -> Result<Self> {
+        let matches = dimnames.len() == self.dim.len()
+            && dimnames
+                .iter()
+                .zip(&self.dim)
+                .all(|(names, &n)| names.as_ref().map_or(true, |x| x.len() == n));
+        if !matches {
+            return Err("The dimnames don't match the dimensions.");
+        }
+        self.dimnames = Some(dimnames);
+        Ok(self)
+    }
+
+    pub fn dim(&self) -> &[usize] {
+        &self.dim
+    }
+
+    pub fn dimnames(&self) -> Option<&DimNames> {
+        self.dimnames.as_ref()
+    }
+
+    /// The values, in column-major order
+    pub fn data(&self) -> &[T] {
+        &self.data
+    }
+
+    pub fn data_mut(&mut self) -> &mut [T] {
+        &mut self.data
+    }
+
+    /// The position in [data](RArray::data) of the element at `index`, or
+    /// `None` if `index` is out of bounds
+    pub fn offset(&self, index: &[usize]) -> Option<usize> {
+        if index.len() != self.dim.len() {
+            return None;
+        }
+        let mut offset = 0;
+        let mut stride = 1;
+        for (&i, &n) in index.iter().zip(&self.dim) {
+            if i >= n {
+                return None;
+            }
+            offset += i * stride;
+            stride *= n;
+        }
+        Some(offset)
+    }
+
+    /// The element at `index`, or `None` if `index` is out of bounds
+    pub fn get(&self, index: &[usize]) -> Option<T> {
+        self.offset(index).map(|i| self.data[i])
+    }
+
+    /// For converting `Robj` to `RArray`. Expects a vector of type `T` with a
+    /// `dim` attribute, copying its values.
+    pub fn try_from_robj(robj: &Robj) -> Result<Self> {
+        let data = RSlice::<T>::from_robj_ref(robj)
+            .ok_or(T::TYPE_ERROR)?
+            .to_vec();
+        let dim: Vec<usize> = attribute(robj, "dim")
+            .ok_or("Input must be an array, with a dim attribute.")?
+            .as_integer_slice()
+            .ok_or("Invalid dim attribute.")?
+            .iter()
+            .map(|&n| n as usize)
+            .collect();
+        let array = RArray::new(data, dim)?;
+        match attribute(robj, "dimnames") {
+            Some(dimnames) => array.with_dimnames(read_dimnames(&dimnames)?),
+            None => Ok(array),
+        }
+    }
+
+    /// Converts the array into an R vector of type `T`, with its `dim` and
+    /// `dimnames` attributes
+    pub fn into_robj(self) -> Result<Robj> {
+        let len = self.data.len();
+        let robj = unsafe {
+            let robj = new_owned(libR_sys::Rf_allocVector(
+                T::SEXPTYPE,
+                len as libR_sys::R_xlen_t,
+            ));
+            if len > 0 {
+                std::ptr::copy_nonoverlapping(self.data.as_ptr(), T::data_ptr(&robj), len);
+            }
+            robj
+        };
+        let dim: Vec<i32> = self.dim.iter().map(|&n| n as i32).collect();
+        let robj = robj
+            .set_attrib("dim", dim)
+            .map_err(|_| "Error setting dim attribute")?;
+        match self.dimnames {
+            Some(dimnames) => robj
+                .set_attrib("dimnames", write_dimnames(dimnames))
+                .map_err(|_| "Error setting dimnames attribute"),
+            None => Ok(robj),
+        }
+    }
+}
+
+/// For converting `Robj` to `RArray`, see
+/// [try_from_robj](RArray::try_from_robj)
+impl<'a, T: RSliceElement> FromRobj<'a> for RArray<T> {
+    fn from_robj(robj: &'a Robj) -> Result<Self> {
+        RArray::try_from_robj(robj)
+    }
+}
+
+/// Reads a `dimnames` attribute, a list with a character vector or `NULL`
+/// per dimension
+fn read_dimnames(robj: &Robj) -> Result<DimNames> {
+    let invalid = "Invalid dimnames attribute.";
+    robj.as_list_iter()
+        .ok_or(invalid)?
+        .map(|names| match names.is_null() {
+            true => Ok(None),
+            false => names.as_string_vector().map(Some).ok_or(invalid),
+        })
+        .collect()
+}
+
+fn write_dimnames(dimnames: DimNames) -> Robj {
+    let names: Vec<Robj> = dimnames
+        .into_iter()
+        .map(|names| match names {
+            Some(names) => Robj::from(names),
+            None => Robj::from(()),
+        })
+        .collect();
+    Robj::from(List(names))
+}
+
+/// An R matrix, an [RArray] with two dimensions
+#[derive(Debug, Clone, PartialEq)]
+pub struct RMatrix<T>(RArray<T>);
+
+#[allow(dead_code)]
+impl<T: RSliceElement> RMatrix<T> {
+    /// Creates an `nrow` by `ncol` matrix from its values in column-major
+    /// order
+    pub fn new(data: Vec<T>, nrow: usize, ncol: usize) -> Result<Self> {
+        RArray::new(data, vec![nrow, ncol]).map(RMatrix)
+    }
+
+    /// Sets the row and column names
+    pub fn with_dimnames(
+        self,
+        rownames: Option<Vec<String>>,
+        colnames: Option<Vec<String>>,
+    ) -> Result<Self> {
+        self.0.with_dimnames(vec![rownames, colnames]).map(RMatrix)
+    }
+
+    pub fn nrow(&self) -> usize {
+        self.0.dim[0]
+    }
+
+    pub fn ncol(&self) -> usize {
+        self.0.dim[1]
+    }
+
+    pub fn rownames(&self) -> Option<&[String]> {
+        self.names_along(0)
+    }
+
+    pub fn colnames(&self) -> Option<&[String]> {
+        self.names_along(1)
+    }
+
+    fn names_along(&self, axis: usize) -> Option<&[String]> {
+        self.0.dimnames.as_ref()?[axis].as_deref()
+    }
+
+    /// The element in row `row` and column `col`, or `None` if out of bounds
+    pub fn get(&self, row: usize, col: usize) -> Option<T> {
+        self.0.get(&[row, col])
+    }
+
+    /// The values, in column-major order
+    pub fn data(&self) -> &[T] {
+        self.0.data()
+    }
+
+    pub fn data_mut(&mut self) -> &mut [T] {
+        self.0.data_mut()
+    }
+
+    /// The transpose of the matrix, with the row and column names swapped
+    pub fn transpose(&self) -> Self {
+        let (nrow, ncol) = (self.nrow(), self.ncol());
+        let mut data = Vec::with_capacity(self.0.data.len());
+        for row in 0..nrow {
+            for col in 0..ncol {
+                data.push(self.0.data[row + col * nrow]);
+            }
+        }
+        RMatrix(RArray {
+            data,
+            dim: vec![ncol, nrow],
+            dimnames: self
+                .0
+                .dimnames
+                .as_ref()
+                .map(|x| vec![x[1].clone(), x[0].clone()]),
+        })
+    }
+
+    /// For converting `Robj` to `RMatrix`. Expects a vector of type `T` with a
+    /// `dim` attribute of length 2.
+    pub fn try_from_robj(robj: &Robj) -> Result<Self> {
+        let array = RArray::try_from_robj(robj)?;
+        match array.dim.len() {
+            2 => Ok(RMatrix(array)),
+            _ => Err("Input must be a matrix."),
+        }
+    }
+
+    /// Converts the matrix into an R matrix, see
+    /// [into_robj](RArray::into_robj)
+    pub fn into_robj(self) -> Result<Robj> {
+        self.0.into_robj()
+    }
+}
+
+impl RMatrix<f64> {
+    /// The matrix product `self %*% other`, with the row names of `self` and
+    /// the column names of `other`. NA's and NaN's propagate as in R.
+    pub fn matmul(&self, other: &RMatrix<f64>) -> std::result::Result<Self, String> {
+        if self.ncol() != other.nrow() {
+            return Err(format!(
+                "Non-conformable matrices: {}x{} and {}x{}",
+                self.nrow(),
+                self.ncol(),
+                other.nrow(),
+                other.ncol()
+            ));
+        }
+        let (n, k, m) = (self.nrow(), self.ncol(), other.ncol());
+        let mut data = vec![0.0; n * m];
+        for col in 0..m {
+            for inner in 0..k {
+                let b = other.0.data[inner + col * k];
+                for row in 0..n {
+                    data[row + col * n] += self.0.data[row + inner * n] * b;
+                }
+            }
+        }
+        let dimnames = match (self.rownames(), other.colnames()) {
+            (None, None) => None,
+            (rownames, colnames) => Some(vec![
+                rownames.map(|x| x.to_vec()),
+                colnames.map(|x| x.to_vec()),
+            ]),
+        };
+        Ok(RMatrix(RArray {
+            data,
+            dim: vec![n, m],
+            dimnames,
+        }))
+    }
+}
+
+/// For converting `Robj` to `RMatrix`, see
+/// [try_from_robj](RMatrix::try_from_robj)
+impl<'a, T: RSliceElement> FromRobj<'a> for RMatrix<T> {
+    fn from_robj(robj: &'a Robj) -> Result<Self> {
+        RMatrix::try_from_robj(robj)
+    }
+}
+
+impl<T> From<RMatrix<T>> for RArray<T> {
+    fn from(matrix: RMatrix<T>) -> Self {
+        matrix.0
+    }
+}
+
+#[cfg(feature = "ndarray")]
+#[allow(dead_code)]
+impl<T: RSliceElement> RArray<T> {
+    /// Copies the array into an `ndarray::ArrayD`, with the same column-major
+    /// (Fortran) layout as R
+    pub fn to_ndarray(&self) -> ndarray::ArrayD<T> {
+        use ndarray::ShapeBuilder;
+        ndarray::ArrayD::from_shape_vec(ndarray::IxDyn(&self.dim).f(), self.data.clone())
+            .expect("The dimensions match the number of values")
+    }
+
+    /// Copies an `ndarray` array of any layout into an array without dimnames
+    pub fn from_ndarray<S, D>(array: &ndarray::ArrayBase<S, D>) -> Self
+    where
+        S: ndarray::Data<Elem = T>,
+        D: ndarray::Dimension,
+    {
+        RArray {
+            // Iterating over the transpose visits the first index fastest
+            data: array.t().iter().copied().collect(),
+            dim: array.shape().to_vec(),
+            dimnames: None,
+        }
+    }
+}
+
+#[cfg(feature = "ndarray")]
+#[allow(dead_code)]
+impl<T: RSliceElement> RMatrix<T> {
+    /// Copies the matrix into an `ndarray::Array2`, see
+    /// [to_ndarray](RArray::to_ndarray)
+    pub fn to_ndarray(&self) -> ndarray::Array2<T> {
+        use ndarray::ShapeBuilder;
+        ndarray::Array2::from_shape_vec((self.nrow(), self.ncol()).f(), self.0.data.clone())
+            .expect("The dimensions match the number of values")
+    }
+
+    /// Copies an `ndarray` matrix into a matrix without dimnames
+    pub fn from_ndarray<S>(array: &ndarray::ArrayBase<S, ndarray::Ix2>) -> Self
+    where
+        S: ndarray::Data<Elem = T>,
+    {
+        RMatrix(RArray::from_ndarray(array))
+    }
+}
+
+#[allow(unused_imports)]
+mod test {
+    use super::*;
+
+    #[allow(dead_code)]
+    fn names(x: &[&str]) -> Option<Vec<String>> {
+        Some(x.iter().map(|x| x.to_string()).collect())
+    }
+
+    #[test]
+    fn test_column_major_indexing() {
+        let array = RArray::new((0..24).collect::<Vec<i32>>(), vec![2, 3, 4]).unwrap();
+        assert_eq!(array.get(&[1, 0, 0]), Some(1));
+        assert_eq!(array.get(&[0, 1, 0]), Some(2));
+        assert_eq!(array.get(&[1, 2, 3]), Some(23));
+        assert_eq!(array.get(&[2, 0, 0]), None);
+        assert_eq!(array.get(&[0, 0]), None);
+        assert!(RArray::new(vec![1.0, 2.0], vec![3]).is_err());
+    }
+
+    #[test]
+    fn test_transpose_swaps_dimnames() {
+        let matrix = RMatrix::new(vec![1, 2, 3, 4, 5, 6], 2, 3)
+            .unwrap()
+            .with_dimnames(names(&["a", "b"]), None)
+            .unwrap();
+        let t = matrix.transpose();
+        assert_eq!((t.nrow(), t.ncol()), (3, 2));
+        assert_eq!(t.data(), &[1, 3, 5, 2, 4, 6]);
+        assert_eq!(t.rownames(), None);
+        assert_eq!(t.colnames(), Some(&["a".to_string(), "b".to_string()][..]));
+    }
+
+    #[test]
+    fn test_matmul() {
+        // [1 3 5]   [1 4]   [22 49]
+        // [2 4 6] x [2 5] = [28 64]
+        //           [3 6]
+        let x = RMatrix::new(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0], 2, 3).unwrap();
+        let y = RMatrix::new(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0], 3, 2)
+            .unwrap()
+            .with_dimnames(None, names(&["p", "q"]))
+            .unwrap();
+        let product = x.matmul(&y).unwrap();
+        assert_eq!(product.data(), &[22.0, 28.0, 49.0, 64.0]);
+        assert_eq!(product.colnames().unwrap()[1], "q");
+        assert!(x.matmul(&x).is_err());
+    }
+
+    #[test]
+    fn test_dimnames_must_match_dimensions() {
+        let matrix = RMatrix::new(vec![1.0, 2.0], 1, 2).unwrap();
+        assert!(matrix.with_dimnames(names(&["a", "b"]), None).is_err());
+    }
+
+    #[cfg(feature = "ndarray")]
+    #[test]
+    fn test_ndarray_round_trip() {
+        let matrix = RMatrix::new(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0], 2, 3).unwrap();
+        let nd = matrix.to_ndarray();
+        assert_eq!(nd[[1, 2]], 6.0);
+        assert_eq!(RMatrix::from_ndarray(&nd), matrix);
+        assert_eq!(
+            RMatrix::from_ndarray(&nd.t().as_standard_layout()),
+            matrix.transpose()
+        );
+    }
+}
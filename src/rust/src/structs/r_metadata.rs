@@ -0,0 +1,225 @@
+//! This module carries R attributes that an Arrow schema can't represent (row
+//! names, units, labels, or the class of a tibble) through RecordBatches. Like
+//! R's arrow package, they are kept in the schema metadata under the `r` key,
+//! as an ASCII `serialize()`d list:
+//!
+//! ```r
+//! list(
+//!   attributes = list(class = "data.frame", row.names = c("a", "b")),
+//!   columns = list(x = NULL, y = list(attributes = list(units = "cm"), columns = NULL))
+//! )
+//! ```
+//!
+//! Attributes already represented by a column's Arrow type (like the levels
+//! of a factor, the time zone of a POSIXct, or the units of a difftime) are
+//! left out, and so are automatic row names. Since the format matches R's
+//! arrow package, metadata written by either side is restored by the other.
+
+use crate::structs::{set_attributes, RecordBatchesError};
+use extendr_api::prelude::*;
+
+type Result<T> = std::result::Result<T, RecordBatchesError>;
+
+/// The schema metadata key holding R attributes
+pub const R_METADATA_KEY: &str = "r";
+
+/// R attributes of a data frame and of each of its columns, by name
+#[derive(Debug)]
+pub struct RMetadata {
+    attributes: Vec<(String, Robj)>,
+    columns: Vec<(String, Vec<(String, Robj)>)>,
+}
+
+impl RMetadata {
+    /// Collects the attributes of a data frame and its columns that a
+    /// RecordBatch would otherwise lose, or `None` if there aren't any
+    pub fn from_data_frame(df: &Robj) -> Result<Option<Self>> {
+        let attributes: Vec<(String, Robj)> = attributes_of(df)?
+            .into_iter()
+            .filter(|(name, value)| match name.as_str() {
+                "names" => false,
+                "row.names" => !is_automatic_row_names(value),
+                _ => true,
+            })
+            .collect();
+
+        let names: Vec<String> = df
+            .names()
+            .map(|names| names.map(String::from).collect())
+            .unwrap_or_default();
+        let mut columns = Vec::with_capacity(names.len());
+        for (name, column) in names
+            .into_iter()
+            .zip(df.as_list_iter().into_iter().flatten())
+        {
+            let typed = typed_attributes(&column);
+            let column_attributes: Vec<(String, Robj)> = attributes_of(&column)?
+                .into_iter()
+                .filter(|(name, _)| !typed.contains(&name.as_str()))
+                .collect();
+            columns.push((name, column_attributes));
+        }
+
+        let only_class = attributes.len() == 1 && attributes[0].0 == "class";
+        let plain_class = only_class && attributes[0].1.as_str() == Some("data.frame");
+        if (attributes.is_empty() || plain_class) && columns.iter().all(|(_, x)| x.is_empty()) {
+            return Ok(None);
+        }
+        Ok(Some(RMetadata {
+            attributes,
+            columns,
+        }))
+    }
+
+    /// Serializes the attributes with R's `serialize()`, as text that can be
+    /// stored in the schema metadata
+    pub fn serialize(&self) -> Result<String> {
+        let columns: Vec<(String, Robj)> = self
+            .columns
+            .iter()
+            .map(|(name, attributes)| {
+                let column = if attributes.is_empty() {
+                    Ok(Robj::from(()))
+                } else {
+                    named_list(attributes).map(|x| list!(attributes = x, columns = ()))
+                };
+                column.map(|x| (name.clone(), x))
+            })
+            .collect::<Result<_>>()?;
+        let metadata = list!(
+            attributes = named_list(&self.attributes)?,
+            columns = named_list(&columns)?
+        );
+
+        let serialize_error = || RecordBatchesError::invalid("Error serializing R attributes");
+        let serialized = call!("serialize", metadata, (), true).map_err(|_| serialize_error())?;
+        let bytes = serialized.as_raw_slice().ok_or_else(serialize_error)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| serialize_error())
+    }
+
+    /// Reads attributes written by [serialize](RMetadata::serialize) or by R's
+    /// arrow package. Metadata that can't be read is ignored, like R's arrow
+    /// package does, so `None` is returned instead of an error.
+    pub fn unserialize(metadata: &str) -> Option<Self> {
+        if !metadata.starts_with("A\n") {
+            return None;
+        }
+        let robj = call!("unserialize", Robj::from(Raw(metadata.as_bytes()))).ok()?;
+        let elements = named_elements(&robj);
+        let element = |key: &str| {
+            elements
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| named_elements(value))
+                .unwrap_or_default()
+        };
+        let columns = element("columns")
+            .into_iter()
+            .map(|(name, column)| {
+                let attributes = named_elements(&column)
+                    .into_iter()
+                    .find(|(name, _)| name == "attributes")
+                    .map(|(_, value)| named_elements(&value))
+                    .unwrap_or_default();
+                (name, attributes)
+            })
+            .collect();
+        Some(RMetadata {
+            attributes: element("attributes"),
+            columns,
+        })
+    }
+
+    /// Drops the row names, for RecordBatches whose rows were reordered or
+    /// filtered, so they no longer match. Returns `None` if nothing is left.
+    pub fn without_row_names(self) -> Option<Self> {
+        let attributes: Vec<(String, Robj)> = self
+            .attributes
+            .into_iter()
+            .filter(|(name, _)| name != "row.names")
+            .collect();
+        let only_class = attributes.len() == 1 && attributes[0].0 == "class";
+        let plain_class = only_class && attributes[0].1.as_str() == Some("data.frame");
+        if (attributes.is_empty() || plain_class) && self.columns.iter().all(|(_, x)| x.is_empty())
+        {
+            return None;
+        }
+        Some(RMetadata {
+            attributes,
+            columns: self.columns,
+        })
+    }
+
+    /// Sets the attributes of each column, found by name
+    pub fn restore_columns(&self, names: &[String], columns: Vec<Robj>) -> Result<Vec<Robj>> {
+        let mut columns = columns;
+        for (name, attributes) in &self.columns {
+            if let Some(i) = names.iter().position(|x| x == name) {
+                columns[i] = set_attributes(columns[i].clone(), attributes)
+                    .map_err(|e| e.for_field(name.as_str()))?;
+            }
+        }
+        Ok(columns)
+    }
+
+    /// Sets the attributes of the data frame itself. Row names are skipped if
+    /// the number of rows has changed.
+    pub fn restore_data_frame(&self, df: Robj, num_rows: usize) -> Result<Robj> {
+        let attributes: Vec<(String, Robj)> = self
+            .attributes
+            .iter()
+            .filter(|(name, value)| {
+                name != "names" && (name != "row.names" || value.len() == num_rows)
+            })
+            .cloned()
+            .collect();
+        set_attributes(df, &attributes)
+    }
+}
+
+/// The attributes of an Robj, in order
+fn attributes_of(robj: &Robj) -> Result<Vec<(String, Robj)>> {
+    let attributes = call!("attributes", robj.clone())
+        .map_err(|_| RecordBatchesError::invalid("Error reading R attributes"))?;
+    Ok(named_elements(&attributes))
+}
+
+/// The attributes of a column that its Arrow type already represents
+fn typed_attributes(column: &Robj) -> &'static [&'static str] {
+    if column.inherits("factor") {
+        &["class", "levels"]
+    } else if column.inherits("POSIXct") {
+        &["class", "tzone"]
+    } else if column.inherits("Date") {
+        &["class"]
+    } else if column.inherits("difftime") {
+        &["class", "units"]
+    } else if column.inherits("data.frame") {
+        &["class", "names", "row.names"]
+    } else {
+        &[]
+    }
+}
+
+/// Whether row names are the automatic `1:n`, which don't need to be kept
+fn is_automatic_row_names(row_names: &Robj) -> bool {
+    match row_names.as_integer_slice() {
+        Some(v) => v.iter().copied().eq(1..=v.len() as i32),
+        None => false,
+    }
+}
+
+/// The elements of a named list, or nothing if `robj` isn't one
+fn named_elements(robj: &Robj) -> Vec<(String, Robj)> {
+    match (robj.names(), robj.as_list_iter()) {
+        (Some(names), Some(values)) => names.map(String::from).zip(values).collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn named_list(pairs: &[(String, Robj)]) -> Result<Robj> {
+    let (names, values): (Vec<String>, Vec<Robj>) = pairs.iter().cloned().unzip();
+    Robj::from(List(values))
+        .set_attrib(names_symbol(), names)
+        .map_err(|_| RecordBatchesError::invalid("Error building list of R attributes"))
+}
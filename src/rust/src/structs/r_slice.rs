@@ -0,0 +1,205 @@
+//! This module implements [RSlice] and [RSliceMut], views of the memory of
+//! double, integer, and logical vectors from R (`REAL()`, `INTEGER()`, and
+//! `LOGICAL()`), for passing large vectors in and out of Rust functions
+//! annotated with #[extendr] without copying them.
+//!
+//! An `RSlice` borrows the argument R passed in, so it is read-only: R
+//! vectors are shared, and writing to one would change every R variable
+//! bound to it. To change values in place, first make a fresh copy of the
+//! vector with [duplicate](RSlice::duplicate), which returns an `RSliceMut`
+//! that owns the copy and converts into the `Robj` to return. That's one copy
+//! of the vector, rather than collecting it into a `Vec` and copying it again
+//! into a new R vector.
+//!
+//! NA's are left as R stores them: `i32::MIN` for integers (see
+//! [parse_rint](crate::structs::parse_rint)), a NaN for doubles (see
+//! [is_na_real](crate::structs::is_na_real)), and [RLogical::NA] for
+//! logicals. Arithmetic on a double NA gives an NA back, like in R.
+
+use extendr_api::{
+    new_owned,
+    prelude::{FromRobj, RType, Robj},
+};
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+
+/// A logical value as R stores it, a 32-bit integer that is 0 for `FALSE`,
+/// `NA_LOGICAL` (`i32::MIN`) for `NA`, and anything else for `TRUE`
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RLogical(i32);
+
+#[allow(dead_code)]
+impl RLogical {
+    pub const TRUE: RLogical = RLogical(1);
+    pub const FALSE: RLogical = RLogical(0);
+    pub const NA: RLogical = RLogical(i32::MIN);
+
+    pub fn is_na(self) -> bool {
+        self == RLogical::NA
+    }
+
+    /// The value as a bool, `None` for NA
+    pub fn to_option(self) -> Option<bool> {
+        match self {
+            RLogical::NA => None,
+            RLogical(x) => Some(x != 0),
+        }
+    }
+}
+
+impl From<Option<bool>> for RLogical {
+    fn from(b: Option<bool>) -> Self {
+        match b {
+            Some(true) => RLogical::TRUE,
+            Some(false) => RLogical::FALSE,
+            None => RLogical::NA,
+        }
+    }
+}
+
+/// Element types of the R vectors that can be viewed in place
+pub trait RSliceElement: Copy {
+    /// The type of R vectors holding this type
+    const RTYPE: RType;
+
+    /// The `SEXPTYPE` for allocating R vectors of this type
+    const SEXPTYPE: u32;
+
+    /// Error for an `Robj` that isn't a vector of this type
+    const TYPE_ERROR: &'static str;
+
+    /// Pointer to the first element of `robj`, which must be of type `RTYPE`
+    ///
+    /// # Safety
+    /// `robj` must be a vector of type `RTYPE`
+    unsafe fn data_ptr(robj: &Robj) -> *mut Self;
+}
+
+impl RSliceElement for f64 {
+    const RTYPE: RType = RType::Real;
+    const SEXPTYPE: u32 = libR_sys::REALSXP;
+    const TYPE_ERROR: &'static str = "Input must be a double vector.";
+
+    unsafe fn data_ptr(robj: &Robj) -> *mut Self {
+        libR_sys::REAL(robj.get())
+    }
+}
+
+impl RSliceElement for i32 {
+    const RTYPE: RType = RType::Integer;
+    const SEXPTYPE: u32 = libR_sys::INTSXP;
+    const TYPE_ERROR: &'static str = "Input must be an integer vector.";
+
+    unsafe fn data_ptr(robj: &Robj) -> *mut Self {
+        libR_sys::INTEGER(robj.get())
+    }
+}
+
+impl RSliceElement for RLogical {
+    const RTYPE: RType = RType::Logical;
+    const SEXPTYPE: u32 = libR_sys::LGLSXP;
+    const TYPE_ERROR: &'static str = "Input must be a logical vector.";
+
+    unsafe fn data_ptr(robj: &Robj) -> *mut Self {
+        libR_sys::LOGICAL(robj.get()) as *mut RLogical
+    }
+}
+
+/// Read-only view of the memory of an R vector passed to Rust
+#[derive(Debug, Clone, Copy)]
+pub struct RSlice<'a, T: RSliceElement> {
+    robj: &'a Robj,
+    data: &'a [T],
+}
+
+impl<'a, T: RSliceElement> RSlice<'a, T> {
+    /// Views `robj`, or returns `None` if it's not a vector of type `T`
+    pub fn from_robj_ref(robj: &'a Robj) -> Option<Self> {
+        if robj.rtype() != T::RTYPE {
+            return None;
+        }
+        let data = match robj.len() {
+            // R may not give a valid pointer for an empty vector
+            0 => &[],
+            len => unsafe { std::slice::from_raw_parts(T::data_ptr(robj), len) },
+        };
+        Some(RSlice { robj, data })
+    }
+
+    /// Copies the R vector (with its attributes), giving a view of the copy
+    /// that can be changed in place and then returned to R
+    pub fn duplicate(&self) -> RSliceMut<'a, T> {
+        let robj = unsafe { new_owned(libR_sys::Rf_duplicate(self.robj.get())) };
+        RSliceMut {
+            robj,
+            len: self.data.len(),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<'a, T: RSliceElement> Deref for RSlice<'a, T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        self.data
+    }
+}
+
+/// For viewing an `Robj` as an `RSlice`, without copying it
+impl<'a, T: RSliceElement> FromRobj<'a> for RSlice<'a, T> {
+    fn from_robj(robj: &'a Robj) -> std::result::Result<Self, &'static str> {
+        RSlice::from_robj_ref(robj).ok_or(T::TYPE_ERROR)
+    }
+}
+
+/// Mutable view of a fresh copy of an R vector, made by
+/// [duplicate](RSlice::duplicate). The copy stays protected from R's garbage
+/// collector until the `RSliceMut` is dropped or converted into an `Robj`.
+#[derive(Debug)]
+pub struct RSliceMut<'a, T: RSliceElement> {
+    robj: Robj,
+    len: usize,
+    marker: PhantomData<&'a mut T>,
+}
+
+impl<'a, T: RSliceElement> Deref for RSliceMut<'a, T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        match self.len {
+            0 => &[],
+            len => unsafe { std::slice::from_raw_parts(T::data_ptr(&self.robj), len) },
+        }
+    }
+}
+
+impl<'a, T: RSliceElement> DerefMut for RSliceMut<'a, T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        match self.len {
+            0 => &mut [],
+            len => unsafe { std::slice::from_raw_parts_mut(T::data_ptr(&self.robj), len) },
+        }
+    }
+}
+
+/// For returning the changed copy to R
+impl<'a, T: RSliceElement> From<RSliceMut<'a, T>> for Robj {
+    fn from(slice: RSliceMut<'a, T>) -> Robj {
+        slice.robj
+    }
+}
+
+#[allow(unused_imports)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_logical_options() {
+        assert_eq!(RLogical::from(Some(true)).to_option(), Some(true));
+        assert_eq!(RLogical::from(Some(false)).to_option(), Some(false));
+        assert!(RLogical::from(None).is_na());
+        assert_eq!(RLogical(-5).to_option(), Some(true));
+    }
+}
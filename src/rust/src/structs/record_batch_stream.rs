@@ -0,0 +1,253 @@
+//! This module provides [RecordBatchStream], which reads RecordBatches lazily,
+//! one at a time, from an IPC stream or file in a raw vector or a file.
+//! [RecordBatches](crate::structs::RecordBatches) reads every RecordBatch into
+//! memory before any work starts; a RecordBatchStream only holds the
+//! RecordBatch being processed. Together with a
+//! [RecordBatchStreamSink](crate::structs::RecordBatchStreamSink) (see
+//! [map_into](RecordBatchStream::map_into)), a Rust function can process
+//! inputs larger than memory, writing each result as soon as it is ready.
+//!
+//! Compressed messages and dictionary deltas are rewritten as they are read,
+//! see [RewritingReader](crate::structs::RewritingReader). Raw vectors are read
+//! in place, without copying them first.
+
+use crate::structs::{
+    is_ipc_file, IpcCompression, RecordBatchStreamSink, RecordBatchesError, RewritingReader,
+    ARROW_MAGIC,
+};
+use arrow::{datatypes::SchemaRef, ipc::reader::StreamReader, record_batch::RecordBatch};
+use extendr_api::prelude::*;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+
+type Result<T> = std::result::Result<T, RecordBatchesError>;
+
+/// A lazy iterator over the RecordBatches of an IPC stream or file
+pub struct RecordBatchStream {
+    reader: StreamReader<Box<dyn Read>>,
+    index: usize,
+}
+
+impl RecordBatchStream {
+    /// Reads RecordBatches lazily from the IPC stream format. Only the schema
+    /// is read until the stream is iterated.
+    pub fn from_ipc_stream<R: Read + 'static>(reader: R) -> Result<Self> {
+        let reader: Box<dyn Read> = Box::new(RewritingReader::new(BufReader::new(reader)));
+        let reader = StreamReader::try_new(reader)
+            .map_err(RecordBatchesError::arrow("Error creating StreamReader"))?;
+        Ok(RecordBatchStream { reader, index: 0 })
+    }
+
+    /// Reads RecordBatches lazily from either the IPC stream or the IPC file
+    /// format, detected from the leading magic bytes. The messages of an IPC
+    /// file are read in order; its footer is only used to find where they end.
+    pub fn from_reader<R: Read + Seek + 'static>(mut reader: R) -> Result<Self> {
+        if !is_ipc_file(&mut reader)? {
+            return Self::from_ipc_stream(reader);
+        }
+        let messages = ipc_file_messages(&mut reader)?;
+        reader
+            .seek(SeekFrom::Start(messages.start))
+            .map_err(RecordBatchesError::io(
+                "Could not seek to IPC file messages",
+            ))?;
+        Self::from_ipc_stream(reader.take(messages.end - messages.start))
+    }
+
+    /// For converting `Robj` to a `RecordBatchStream`. Expects a raw vector,
+    /// which is read in place, or a file path, in the IPC stream or IPC file
+    /// format.
+    pub fn try_from_robj(robj: &Robj) -> Result<Self> {
+        if robj.as_raw_slice().is_some() {
+            Self::from_reader(Cursor::new(RawBytes(robj.clone())))
+        } else if let Some(path) = robj.as_str() {
+            let file = File::open(path).map_err(RecordBatchesError::io("Could not open file"))?;
+            Self::from_reader(file)
+        } else {
+            Err(RecordBatchesError::invalid(
+                "Expected a raw vector or a file path.",
+            ))
+        }
+    }
+
+    /// The schema of every RecordBatch in the stream
+    pub fn schema(&self) -> SchemaRef {
+        self.reader.schema()
+    }
+
+    /// Applies `f` to each RecordBatch as it is read, writing each result to
+    /// `writer` as an IPC stream before the next RecordBatch is read. Every
+    /// result must have the given `schema`, which is written first, so a
+    /// stream without RecordBatches still gives a (schema-only) IPC stream.
+    /// Returns the writer once every result has been written.
+    pub fn map_into<W, F>(
+        self,
+        writer: W,
+        schema: SchemaRef,
+        compression: Option<IpcCompression>,
+        mut f: F,
+    ) -> Result<W>
+    where
+        W: Write,
+        F: FnMut(RecordBatch) -> Result<RecordBatch>,
+    {
+        let mut sink =
+            RecordBatchStreamSink::try_new(writer, schema)?.with_compression(compression);
+        for (i, batch) in self.enumerate() {
+            let output = f(batch?).map_err(|e| e.at_message(i))?;
+            sink.write(&output).map_err(|e| e.at_message(i))?;
+        }
+        sink.into_inner()
+    }
+}
+
+impl Iterator for RecordBatchStream {
+    type Item = Result<RecordBatch>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let batch = self.reader.next()?;
+        let index = self.index;
+        self.index += 1;
+        Some(batch.map_err(|e| {
+            RecordBatchesError::arrow("Error reading RecordBatch")(e).at_message(index)
+        }))
+    }
+}
+
+/// The bytes of an R raw vector, borrowed for as long as the vector is kept
+struct RawBytes(Robj);
+
+impl AsRef<[u8]> for RawBytes {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_raw_slice().unwrap_or(&[])
+    }
+}
+
+/// The byte range of the messages of an IPC file, between the leading magic
+/// bytes and the footer
+fn ipc_file_messages<R: Read + Seek>(reader: &mut R) -> Result<Range<u64>> {
+    let magic = ARROW_MAGIC.len() as u64;
+    let footer_end = reader
+        .seek(SeekFrom::End(-(magic as i64) - 4))
+        .map_err(RecordBatchesError::io("Could not read IPC file footer"))?;
+    let mut footer_len = [0u8; 4];
+    reader
+        .read_exact(&mut footer_len)
+        .map_err(RecordBatchesError::io("Could not read IPC file footer"))?;
+    let footer_len = i32::from_le_bytes(footer_len);
+    u64::try_from(footer_len)
+        .ok()
+        .and_then(|footer_len| footer_end.checked_sub(footer_len))
+        .filter(|&start| start >= magic + 2)
+        .map(|start| magic + 2..start)
+        .ok_or_else(|| RecordBatchesError::invalid("IPC file footer is out of bounds"))
+}
+
+#[allow(unused_imports)]
+mod test {
+    use super::*;
+    use crate::structs::{RecordBatchFileSink, RecordBatches};
+    use arrow::array::{DictionaryArray, Float64Array};
+    use arrow::datatypes::{DataType, Field, Int32Type, Schema};
+    use std::sync::Arc;
+
+    #[allow(dead_code)]
+    fn batches() -> Vec<RecordBatch> {
+        let dictionary = DataType::Dictionary(Box::new(DataType::Int32), Box::new(DataType::Utf8));
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("x", DataType::Float64, true),
+            Field::new_dict("f", dictionary, true, 0, false),
+        ]));
+        let batch = |x: Vec<f64>, f: Vec<&str>| {
+            let f: DictionaryArray<Int32Type> = f.into_iter().collect();
+            RecordBatch::try_new(
+                schema.clone(),
+                vec![Arc::new(Float64Array::from(x)), Arc::new(f)],
+            )
+            .unwrap()
+        };
+        vec![
+            batch(vec![1.0, 2.0], vec!["a", "b"]),
+            batch(vec![3.0, 4.0, 5.0], vec!["a", "b", "c"]),
+            batch(vec![4.0, 5.0, 6.0], vec!["a", "d", "c"]),
+        ]
+    }
+
+    #[test]
+    fn test_stream_reads_compressed_dictionaries_lazily() {
+        let input = batches();
+        let mut sink = RecordBatchStreamSink::try_new(Vec::new(), input[0].schema())
+            .unwrap()
+            .with_compression(Some(IpcCompression::Zstd));
+        for batch in &input {
+            sink.write(batch).unwrap();
+        }
+        let buffer = sink.into_inner().unwrap();
+
+        let mut stream = RecordBatchStream::from_ipc_stream(Cursor::new(buffer)).unwrap();
+        assert_eq!(stream.schema().fields().len(), 2);
+        let first = stream.next().unwrap().unwrap();
+        assert_eq!(first.num_rows(), 2);
+        let rest: Vec<RecordBatch> = stream.map(|x| x.unwrap()).collect();
+        assert_eq!(rest.len(), 2);
+        assert_eq!(rest[1].column(1).data_ref().child_data()[0].len(), 3);
+    }
+
+    #[test]
+    fn test_stream_reads_ipc_files() {
+        let input = batches();
+        let mut sink = RecordBatchFileSink::try_new(Vec::new(), input[0].schema()).unwrap();
+        for batch in &input[..2] {
+            sink.write(batch).unwrap();
+        }
+        let buffer = sink.into_inner().unwrap();
+
+        let stream = RecordBatchStream::from_reader(Cursor::new(buffer)).unwrap();
+        let rows: Vec<usize> = stream.map(|x| x.unwrap().num_rows()).collect();
+        assert_eq!(rows, vec![2, 3]);
+    }
+
+    #[test]
+    fn test_map_into_writes_each_result() {
+        let input: RecordBatches = batches().into_iter().collect();
+        let buffer = input.into_ipc_stream(None).unwrap();
+        let stream = RecordBatchStream::from_ipc_stream(Cursor::new(buffer)).unwrap();
+        let schema = Arc::new(Schema::new(vec![stream.schema().field(0).clone()]));
+
+        let output = stream
+            .map_into(Vec::new(), schema.clone(), None, |batch| {
+                let columns = vec![batch.column(0).clone()];
+                RecordBatch::try_new(schema.clone(), columns)
+                    .map_err(RecordBatchesError::arrow("Error selecting column"))
+            })
+            .unwrap();
+        let output: Vec<RecordBatch> = RecordBatches::from_bytes(&output)
+            .unwrap()
+            .into_iter()
+            .collect();
+        assert_eq!(output.len(), 3);
+        assert_eq!(output[2].num_columns(), 1);
+        assert_eq!(output[2].num_rows(), 3);
+    }
+
+    #[test]
+    fn test_map_into_writes_the_schema_of_empty_streams() {
+        let input = RecordBatchStreamSink::try_new(Vec::new(), batches()[0].schema())
+            .unwrap()
+            .into_inner()
+            .unwrap();
+        let stream = RecordBatchStream::from_ipc_stream(Cursor::new(input)).unwrap();
+        let schema = Arc::new(Schema::new(vec![stream.schema().field(0).clone()]));
+
+        let output = stream
+            .map_into(Vec::new(), schema, None, |_| {
+                panic!("There are no RecordBatches to map")
+            })
+            .unwrap();
+        let mut output = RecordBatchStream::from_ipc_stream(Cursor::new(output)).unwrap();
+        assert_eq!(output.schema().fields().len(), 1);
+        assert!(output.next().is_none());
+    }
+}
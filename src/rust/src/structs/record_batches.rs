@@ -2,23 +2,49 @@
 //! serialized RecordBatch (in IPC format) from R, transform it to a usable
 //! RecordBatch, serialize back to IPC format, and send the serialized
 //! RecordBatch back to R.
-
+//!
+//! Serializing to IPC means every buffer is copied at least twice on the way
+//! in and again on the way out. For large data frames, RecordBatches can also
+//! be exchanged through the [Arrow C Data Interface](https://arrow.apache.org/docs/format/CDataInterface.html),
+//! where R and Rust hand each other `ArrowArray`/`ArrowSchema` structs and the
+//! underlying buffers are shared instead of copied. See
+//! [CDataRecordBatches](crate::structs::CDataRecordBatches).
+//!
+//! Both the IPC stream format and the IPC file format (Feather v2) can be
+//! read, from either a raw vector or a file path. The format is detected from
+//! the leading magic bytes. Use [IpcFileRecordBatches] to send RecordBatches
+//! back to R in the file format.
+//!
+//! RecordBatches can also be built from a plain R data frame, and returned to R
+//! as one, without the R arrow package, see
+//! [record_batch_from_data_frame](crate::structs::record_batch_from_data_frame).
+//!
+//! Message bodies compressed with LZ4 or ZSTD (as written by
+//! `arrow::write_feather()`) are decompressed on the way in, see
+//! [compression](crate::structs::IpcCompression).
+
+use crate::structs::{
+    check_expected_schema, data_frame_from_record_batches, is_ipc_file,
+    record_batch_from_data_frame, rewrite_ipc_messages, schema_mismatch, IpcCompression,
+    RecordBatchFileSink, RecordBatchStream, RecordBatchStreamSink, RecordBatchesError,
+};
 use arrow::{
-    array::ArrayDataRef,
-    buffer::Buffer,
-    datatypes::{DataType, Schema, SchemaRef},
-    ipc::reader::StreamReader,
-    ipc::writer::{DictionaryTracker, EncodedData},
+    array::{make_array_from_raw, ArrayRef},
+    datatypes::{DataType, Field, Schema, SchemaRef},
+    ffi::{ArrowArray, FFI_ArrowArray, FFI_ArrowSchema},
+    ipc::reader::{FileReader, StreamReader},
     record_batch::RecordBatch,
 };
-use extendr_api::prelude::{FromRobj, Raw, Robj};
-use flatbuffers::FlatBufferBuilder;
-use std::io::{BufWriter, Write};
-
-pub const PREFIX_SIZE: usize = 8; // Bytes in buffer prefix
-pub const BLOCK_SIZE: usize = 8;
+use extendr_api::prelude::*;
+use std::collections::BTreeMap;
+use std::ffi::c_void;
+use std::fs::File;
+use std::io::{BufReader, Cursor, Read, Seek};
+use std::iter::FromIterator;
+use std::os::raw::c_char;
+use std::sync::Arc;
 
-type Result<T> = std::result::Result<T, &'static str>;
+type Result<T> = std::result::Result<T, RecordBatchesError>;
 
 /// Newtype wrapping a Vec<RecordBatch>
 #[derive(Debug)]
@@ -27,65 +53,207 @@ pub struct RecordBatches(Vec<RecordBatch>);
 impl RecordBatches {
     /// Fetches the schema from the first RecordBatch in RecordBatches
     pub fn schema(&self) -> Option<SchemaRef> {
-        self.0.get(0).map(|x| x.schema())
+        self.0.first().map(|x| x.schema())
+    }
+
+    /// Checks that every RecordBatch shares the schema of the first, returning
+    /// that schema (or `None` if there are no RecordBatches)
+    pub fn validate_schema(&self) -> Result<Option<SchemaRef>> {
+        let schema = match self.schema() {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        for (i, batch) in self.0.iter().enumerate().skip(1) {
+            if batch.schema() != schema {
+                return Err(schema_mismatch(
+                    "RecordBatch schema does not match the first RecordBatch",
+                    &schema,
+                    &batch.schema(),
+                )
+                .at_message(i));
+            }
+        }
+        Ok(Some(schema))
+    }
+
+    /// Checks that every RecordBatch provides the fields in `expected`, see
+    /// [check_expected_schema]. A Rust function can use this to declare the
+    /// schema it needs, so mismatched input is rejected before processing:
+    ///
+    /// ```ignore
+    /// let expected = Schema::new(vec![Field::new("mpg", DataType::Float64, true)]);
+    /// let batches = RecordBatches::try_from_robj(&robj)?.expect_schema(&expected)?;
+    /// ```
+    pub fn expect_schema(self, expected: &Schema) -> Result<Self> {
+        for (i, batch) in self.0.iter().enumerate() {
+            check_expected_schema(expected, &batch.schema()).map_err(|e| e.at_message(i))?;
+        }
+        Ok(self)
     }
 
     /// Converts this RecordBatches into an Robj representing a the wrapped
-    /// Vec<RecordBatch> as a serialized raw vector. The contents of this function,
-    /// as well as most everything under the 'Utility Functions' section, will
-    /// need to be updated with Arrow 4.0.0 and the addition of the
-    /// `StreamWriter.into_inner()` function.
+    /// Vec<RecordBatch> as a serialized raw vector, written using a
+    /// [RecordBatchStreamSink](crate::structs::RecordBatchStreamSink).
     pub fn into_robj(self) -> Result<Robj> {
-        // TODO: Update when Arrow 4.0.0 is released to crates.io
-        let out: Vec<u8> = Vec::new();
+        let buffer = self.into_ipc_stream(None)?;
+        Ok(Robj::from(Raw(&buffer)))
+    }
+
+    /// Serializes this RecordBatches to a Vec<u8> in the IPC stream format,
+    /// compressing the body of each message with `compression` if given. An
+    /// empty RecordBatches has no schema and is serialized as an empty Vec.
+    pub fn into_ipc_stream(self, compression: Option<IpcCompression>) -> Result<Vec<u8>> {
         let schema = match self.schema() {
             Some(x) => x,
-            None => return Ok(Robj::from(out)),
+            None => return Ok(Vec::new()),
         };
-        let mut dictionary_tracker = DictionaryTracker::new(false);
-        let mut writer = BufWriter::new(out);
-        let schema_bytes = schema_to_bytes(&schema);
-        write_encoded_data_to_stream(&mut writer, schema_bytes)?;
+        let mut sink =
+            RecordBatchStreamSink::try_new(Vec::new(), schema)?.with_compression(compression);
+        for batch in self {
+            sink.write(&batch)?;
+        }
+        sink.into_inner()
+    }
+
+    /// Converts an R data frame into RecordBatches holding a single
+    /// RecordBatch, see [record_batch_from_data_frame]
+    pub fn from_data_frame(df: &Robj) -> Result<Self> {
+        Ok(RecordBatches(vec![record_batch_from_data_frame(df)?]))
+    }
+
+    /// Converts this RecordBatches into a single R data frame, see
+    /// [data_frame_from_record_batches]. Every RecordBatch must share the
+    /// schema of the first.
+    pub fn into_data_frame(self) -> Result<Robj> {
+        self.validate_schema()?;
+        data_frame_from_record_batches(&self.0)
+    }
 
+    /// Serializes this RecordBatches to a Vec<u8> in the IPC file format
+    pub fn into_ipc_file(self) -> Result<Vec<u8>> {
+        let schema = match self.schema() {
+            Some(x) => x,
+            None => {
+                return Err(RecordBatchesError::invalid(
+                    "Cannot write an empty set of RecordBatches to an IPC file",
+                ))
+            }
+        };
+        let mut sink = RecordBatchFileSink::try_new(Vec::new(), schema)?;
         for batch in self {
-            append_record_batch_to_stream(&mut writer, &mut dictionary_tracker, &batch)?;
+            sink.write(&batch)?;
+        }
+        sink.into_inner()
+    }
+
+    /// Reads RecordBatches from an in-memory IPC stream or IPC file. Unlike
+    /// [from_reader](RecordBatches::from_reader), message bodies compressed
+    /// with LZ4 or ZSTD and dictionary deltas are supported, see
+    /// [rewrite_ipc_messages].
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        match rewrite_ipc_messages(bytes)? {
+            Some(stream) => Self::from_ipc_stream(Cursor::new(stream)),
+            None => Self::from_reader(Cursor::new(bytes)),
         }
+    }
 
-        // Mark the stream as 'done'
-        write_continuation_marker(&mut writer, 0)?;
-        let buffer = writer
-            .into_inner()
-            .map_err(|_| "Could not retrieve writer from BufWriter")?;
-        let raw = Raw(&buffer);
-        Ok(Robj::from(raw))
+    /// Reads RecordBatches from a file in either the IPC stream or the IPC
+    /// file format. The file is read through a buffer rather than into memory
+    /// first, and compressed messages and dictionary deltas are rewritten as
+    /// they are read, as for a [RecordBatchStream].
+    pub fn from_path(path: &str) -> Result<Self> {
+        let file = File::open(path).map_err(RecordBatchesError::io("Could not open file"))?;
+        RecordBatchStream::from_reader(file)?.collect()
     }
-}
 
-impl<'a> FromRobj<'a> for RecordBatches {
-    /// For converting `Robj` to `RecordBatches`. Expects a raw vector input and
-    /// uses a [StreamReader](arrow::ipc::reader::StreamReader) to read that raw
-    /// (byte) vector into a set of RecordBatches.
-    fn from_robj(robj: &'a Robj) -> Result<Self> {
+    /// Reads RecordBatches from either the IPC stream or the IPC file format,
+    /// detected from the leading magic bytes
+    pub fn from_reader<R: Read + Seek>(mut reader: R) -> Result<Self> {
+        if is_ipc_file(&mut reader)? {
+            Self::from_ipc_file(reader)
+        } else {
+            Self::from_ipc_stream(reader)
+        }
+    }
+
+    /// Reads RecordBatches from the IPC stream format using a
+    /// [StreamReader](arrow::ipc::reader::StreamReader)
+    pub fn from_ipc_stream<R: Read>(reader: R) -> Result<Self> {
+        let reader = StreamReader::try_new(reader)
+            .map_err(RecordBatchesError::arrow("Error creating StreamReader"))?;
+        let mut v = Vec::new();
+        for (i, rb) in reader.enumerate() {
+            let rb = rb.map_err(|e| {
+                RecordBatchesError::arrow("Error reading RecordBatch")(e).at_message(i)
+            })?;
+            v.push(rb);
+        }
+        Ok(RecordBatches(v))
+    }
+
+    /// Reads RecordBatches from the IPC file format using a
+    /// [FileReader](arrow::ipc::reader::FileReader)
+    pub fn from_ipc_file<R: Read + Seek>(reader: R) -> Result<Self> {
+        let reader = file_reader(reader)?;
+        let mut v = Vec::new();
+        for (i, rb) in reader.enumerate() {
+            let rb = rb.map_err(|e| {
+                RecordBatchesError::arrow("Error reading RecordBatch")(e).at_message(i)
+            })?;
+            v.push(rb);
+        }
+        Ok(RecordBatches(v))
+    }
+
+    /// For converting `Robj` to `RecordBatches`. Expects either a raw vector
+    /// input or a file path, in the IPC stream or IPC file format, a data frame
+    /// (see [from_data_frame](RecordBatches::from_data_frame)), C Data
+    /// Interface addresses (see [import_c_data]), which are imported without
+    /// copying the underlying buffers, or a list of data frames and C Data
+    /// Interface addresses, one per RecordBatch. Unlike the `FromRobj`
+    /// implementation, the full [RecordBatchesError] is returned on failure.
+    pub fn try_from_robj(robj: &Robj) -> Result<Self> {
         if let Some(slice) = robj.as_raw_slice() {
+            RecordBatches::from_bytes(slice)
+        } else if let Some(path) = robj.as_str() {
+            RecordBatches::from_path(path)
+        } else if robj.inherits("data.frame") {
+            RecordBatches::from_data_frame(robj)
+        } else if robj.inherits(C_DATA_CLASS) {
+            Ok(RecordBatches(vec![import_c_data(robj)?]))
+        } else if robj.is_list() {
+            // An unclassed list of data frames or C Data Interface RecordBatches
             let mut v = Vec::new();
-            let reader = StreamReader::try_new(slice).map_err(|_| "Error creating StreamReader")?;
-            for rb in reader {
-                let rb = rb.map_err(|_| "Error reading RecordBatch")?;
-                v.push(rb);
+            let items = robj
+                .as_list_iter()
+                .ok_or_else(|| RecordBatchesError::invalid("Expected a list."))?;
+            for (i, item) in items.enumerate() {
+                let batch = if item.inherits("data.frame") {
+                    record_batch_from_data_frame(&item)
+                } else if item.inherits(C_DATA_CLASS) {
+                    import_c_data(&item)
+                } else {
+                    Err(RecordBatchesError::invalid(
+                        "Expected a list of data frames or `rustbind_arrow_c_data` objects.",
+                    ))
+                };
+                v.push(batch.map_err(|e| e.at_message(i))?);
             }
             Ok(RecordBatches(v))
         } else {
-            Err("Expected a raw buffer type.")
+            Err(RecordBatchesError::invalid(
+                "Expected a raw buffer type, a data frame, or Arrow C Data Interface pointers.",
+            ))
         }
     }
 }
 
-/// For converting `RecordBatches` to `Robj`
-impl From<RecordBatches> for Robj {
-    fn from(batches: RecordBatches) -> Robj {
-        batches
-            .into_robj()
-            .expect("Error converting RecordBatches to Raw/Robj")
+impl<'a> FromRobj<'a> for RecordBatches {
+    /// For converting `Robj` to `RecordBatches`, see
+    /// [try_from_robj](RecordBatches::try_from_robj). Only a short description
+    /// of any error can be reported this way.
+    fn from_robj(robj: &'a Robj) -> std::result::Result<Self, &'static str> {
+        RecordBatches::try_from_robj(robj).map_err(|e| e.context())
     }
 }
 
@@ -99,335 +267,516 @@ impl IntoIterator for RecordBatches {
     }
 }
 
+/// Allows for collecting a RecordBatches from any iterator that yields RecordBatch
+impl FromIterator<RecordBatch> for RecordBatches {
+    fn from_iter<I: IntoIterator<Item = RecordBatch>>(iter: I) -> Self {
+        RecordBatches(iter.into_iter().collect())
+    }
+}
+
 // -=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-
-// Utility Functions for RecordBatches -----------------------------------------
+// Arrow IPC File Format -------------------------------------------------------
 // -=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-
 
-// Most of what's in this section comes from inner methods in arrow::ipc::writer::StreamWrtier
-// and arrow::ipc::writer::IpcDataGenerator. ALL of this will be replaced with the
-// release of Arrow 4.0.0 and the `StreamWriter.into_inner()` function. Feel free
-// to peruse these, but 90% of this code is lightly refactored from the
-// [arrow::ipc::writer](https://docs.rs/arrow/3.0.0/arrow/ipc/writer/index.html)
-// source.
-
-/// Encode a RecordBatch and append its data to the end of the stream
-pub fn append_record_batch_to_stream<W: Write>(
-    mut stream_writer: W,
-    mut dictionary_tracker: &mut DictionaryTracker,
-    batch: &RecordBatch,
-) -> Result<()> {
-    let (encoded_dictionaries, encoded_message) = encode_batch(&batch, &mut dictionary_tracker)
-        .map_err(|_| "Could not encode record batch")?;
-    for encoded_dictionary in encoded_dictionaries {
-        write_encoded_data_to_stream(&mut stream_writer, encoded_dictionary)
-            .map_err(|_| "Cound not write message")?;
-    }
-    write_encoded_data_to_stream(&mut stream_writer, encoded_message)
-        .map_err(|_| "Could not write message")?;
-    Ok(())
-}
-
-pub fn encode_batch(
-    batch: &RecordBatch,
-    dictionary_tracker: &mut DictionaryTracker,
-) -> Result<(Vec<EncodedData>, EncodedData)> {
-    let schema = batch.schema();
-    let mut encoded_dictionaries = Vec::with_capacity(schema.fields().len());
+/// Newtype wrapping RecordBatches that are returned to R as a raw vector in
+/// the IPC file format (Feather v2) instead of the IPC stream format
+#[derive(Debug)]
+pub struct IpcFileRecordBatches(pub RecordBatches);
 
-    for (i, field) in schema.fields().iter().enumerate() {
-        let column = batch.column(i);
+impl IpcFileRecordBatches {
+    /// Serializes the RecordBatches to a raw vector in the IPC file format
+    pub fn into_robj(self) -> Result<Robj> {
+        let buffer = self.0.into_ipc_file()?;
+        Ok(Robj::from(Raw(&buffer)))
+    }
+}
 
-        if let DataType::Dictionary(_key_type, _value_type) = column.data_type() {
-            let dict_id = field
-                .dict_id()
-                .expect("All Dictionary types have `dict_id`");
-            let dict_data = column.data();
-            let dict_values = &dict_data.child_data()[0];
+/// An IPC file from R, either a raw vector or a path to a file on disk. Unlike
+/// [RecordBatches], nothing is read on conversion, so a single RecordBatch can
+/// be read from a large file without reading the rest.
+pub enum IpcFileSource<'a> {
+    Raw(&'a [u8]),
+    Path(String),
+}
 
-            let emit = dictionary_tracker
-                .insert(dict_id, column)
-                .map_err(|_| "Could not insert dict column into dictionary_tracker")?;
+impl<'a> FromRobj<'a> for IpcFileSource<'a> {
+    fn from_robj(robj: &'a Robj) -> std::result::Result<Self, &'static str> {
+        if let Some(slice) = robj.as_raw_slice() {
+            Ok(IpcFileSource::Raw(slice))
+        } else if let Some(path) = robj.as_str() {
+            Ok(IpcFileSource::Path(path.to_string()))
+        } else {
+            Err("Expected a raw vector or a file path.")
+        }
+    }
+}
 
-            if emit {
-                encoded_dictionaries.push(dictionary_batch_to_bytes(dict_id, dict_values));
-            }
+impl<'a> IpcFileSource<'a> {
+    /// The number of RecordBatches in the file, read from the footer
+    pub fn num_batches(&self) -> Result<usize> {
+        match self {
+            IpcFileSource::Raw(slice) => Ok(file_reader(Cursor::new(*slice))?.num_batches()),
+            IpcFileSource::Path(path) => Ok(file_reader(open_ipc_path(path)?)?.num_batches()),
         }
     }
 
-    let encoded_message = record_batch_to_bytes(batch);
+    /// Reads only the RecordBatch at `index` (zero-based), using the block
+    /// index in the footer to skip over the others
+    pub fn read_batch(&self, index: usize) -> Result<RecordBatch> {
+        match self {
+            IpcFileSource::Raw(slice) => read_batch_at(file_reader(Cursor::new(*slice))?, index),
+            IpcFileSource::Path(path) => read_batch_at(file_reader(open_ipc_path(path)?)?, index),
+        }
+    }
+}
 
-    Ok((encoded_dictionaries, encoded_message))
+fn file_reader<R: Read + Seek>(reader: R) -> Result<FileReader<R>> {
+    FileReader::try_new(reader).map_err(RecordBatchesError::arrow("Error creating FileReader"))
 }
 
-fn dictionary_batch_to_bytes(dict_id: i64, array_data: &ArrayDataRef) -> EncodedData {
-    let mut fbb = FlatBufferBuilder::new();
-    let mut nodes: Vec<arrow::ipc::FieldNode> = vec![];
-    let mut buffers: Vec<arrow::ipc::Buffer> = vec![];
-    let mut arrow_data: Vec<u8> = vec![];
-
-    write_array_data(
-        &array_data,
-        &mut buffers,
-        &mut arrow_data,
-        &mut nodes,
-        0,
-        array_data.len(),
-        array_data.null_count(),
-    );
+fn read_batch_at<R: Read + Seek>(mut reader: FileReader<R>, index: usize) -> Result<RecordBatch> {
+    let out_of_bounds =
+        || RecordBatchesError::invalid("RecordBatch index out of bounds").at_message(index);
+    if index >= reader.num_batches() {
+        return Err(out_of_bounds());
+    }
+    reader.set_index(index).map_err(|e| {
+        RecordBatchesError::arrow("Error seeking to RecordBatch")(e).at_message(index)
+    })?;
+    match reader.next() {
+        Some(rb) => rb.map_err(|e| {
+            RecordBatchesError::arrow("Error reading RecordBatch")(e).at_message(index)
+        }),
+        None => Err(out_of_bounds()),
+    }
+}
 
-    // write data
-    let buffers = fbb.create_vector(&buffers);
-    let nodes = fbb.create_vector(&nodes);
+fn open_ipc_path(path: &str) -> Result<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(RecordBatchesError::io("Could not open file"))
+}
 
-    let root = {
-        let mut batch_builder = arrow::ipc::RecordBatchBuilder::new(&mut fbb);
-        batch_builder.add_length(array_data.len() as i64);
-        batch_builder.add_nodes(nodes);
-        batch_builder.add_buffers(buffers);
-        batch_builder.finish()
-    };
+// -=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-
+// Arrow C Data Interface ------------------------------------------------------
+// -=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-
 
-    let root = {
-        let mut batch_builder = arrow::ipc::DictionaryBatchBuilder::new(&mut fbb);
-        batch_builder.add_id(dict_id);
-        batch_builder.add_data(root);
-        batch_builder.finish().as_union_value()
-    };
+// The C Data Interface passes two C structs per column, an `ArrowArray` that
+// holds pointers to the buffers and an `ArrowSchema` that describes them. R's
+// arrow package can export a column into structs it allocates with
+// `allocate_arrow_array()`/`allocate_arrow_schema()` and import a column from
+// structs at a given address with `Array$import_from_c()`. Addresses are
+// passed back and forth as doubles (as the R arrow package does) or as
+// external pointers. Each RecordBatch is represented on the R side by a list
+// with class `rustbind_arrow_c_data` and the elements:
+//
+// - `names`: a character vector of column names
+// - `array`: the addresses of each column's `ArrowArray` struct
+// - `schema`: the addresses of each column's `ArrowSchema` struct
+
+/// Class attribute of the R list describing a RecordBatch by its C Data
+/// Interface addresses
+pub const C_DATA_CLASS: &str = "rustbind_arrow_c_data";
+
+/// Newtype wrapping RecordBatches that are returned to R through the C Data
+/// Interface instead of being serialized to an IPC buffer. The R side takes
+/// ownership of the exported buffers with `Array$import_from_c()`, and must
+/// then hand the addresses back to `release_arrow_c_data()` so the (now empty)
+/// structs allocated by Rust are freed.
+#[derive(Debug)]
+pub struct CDataRecordBatches(pub RecordBatches);
 
-    let root = {
-        let mut message_builder = arrow::ipc::MessageBuilder::new(&mut fbb);
-        message_builder.add_version(arrow::ipc::MetadataVersion::V5);
-        message_builder.add_header_type(arrow::ipc::MessageHeader::DictionaryBatch);
-        message_builder.add_bodyLength(arrow_data.len() as i64);
-        message_builder.add_header(root);
-        message_builder.finish()
-    };
+impl CDataRecordBatches {
+    /// Exports each column of each RecordBatch through the C Data Interface
+    /// and returns a list of `rustbind_arrow_c_data` objects, one per batch.
+    pub fn into_robj(self) -> Result<Robj> {
+        let mut out = Vec::new();
+        for batch in self.0 {
+            out.push(export_c_data(&batch)?);
+        }
+        Ok(Robj::from(List(out)))
+    }
+}
 
-    fbb.finish(root, None);
-    let finished_data = fbb.finished_data();
+/// Imports a single RecordBatch from a `rustbind_arrow_c_data` list. The
+/// contents of each `ArrowArray`/`ArrowSchema` struct are moved into structs
+/// owned by Rust, leaving the R-allocated structs in a released state, so R
+/// is free to delete them as soon as this returns.
+pub fn import_c_data(robj: &Robj) -> Result<RecordBatch> {
+    let names = list_element(robj, "names")
+        .and_then(|x| x.as_string_vector())
+        .ok_or_else(|| {
+            RecordBatchesError::invalid("Expected a character vector of column `names`.")
+        })?;
+    let arrays = c_data_addresses(&required_element(robj, "array")?)?;
+    let schemas = c_data_addresses(&required_element(robj, "schema")?)?;
+    if names.len() != arrays.len() || names.len() != schemas.len() {
+        return Err(RecordBatchesError::invalid(
+            "Expected one `array` and one `schema` pointer per column.",
+        ));
+    }
 
-    EncodedData {
-        ipc_message: finished_data.to_vec(),
-        arrow_data,
+    let mut fields = Vec::with_capacity(names.len());
+    let mut columns = Vec::with_capacity(names.len());
+    for ((name, array_addr), schema_addr) in names.into_iter().zip(arrays).zip(schemas) {
+        let (column, field) = unsafe {
+            let field = c_field_properties(schema_addr).map_err(|e| e.for_field(&name))?;
+            let column =
+                import_c_column(array_addr, schema_addr).map_err(|e| e.for_field(&name))?;
+            (column, field)
+        };
+        fields.push(field.into_field(&name, column.data_type(), fields.len() as i64));
+        columns.push(column);
     }
+
+    RecordBatch::try_new(Arc::new(Schema::new(fields)), columns).map_err(RecordBatchesError::arrow(
+        "Error creating RecordBatch from C Data Interface arrays",
+    ))
 }
 
-fn record_batch_to_bytes(batch: &RecordBatch) -> EncodedData {
-    let mut fbb = FlatBufferBuilder::new();
-    let mut nodes: Vec<arrow::ipc::FieldNode> = vec![];
-    let mut buffers: Vec<arrow::ipc::Buffer> = vec![];
-    let mut arrow_data: Vec<u8> = vec![];
-    let mut offset = 0;
-    for array in batch.columns() {
-        let array_data = array.data();
-        offset = write_array_data(
-            &array_data,
-            &mut buffers,
-            &mut arrow_data,
-            &mut nodes,
-            offset,
-            array.len(),
-            array.null_count(),
-        );
+/// Exports a single RecordBatch as a `rustbind_arrow_c_data` list. The
+/// exported structs are allocated by Rust and must be released with
+/// [release_c_data] once R has imported them.
+pub fn export_c_data(batch: &RecordBatch) -> Result<Robj> {
+    let schema = batch.schema();
+    let names: Vec<_> = schema.fields().iter().map(|f| f.name().clone()).collect();
+    let mut arrays = Vec::with_capacity(batch.num_columns());
+    let mut schemas = Vec::with_capacity(batch.num_columns());
+    for (column, name) in batch.columns().iter().zip(names.iter()) {
+        let (array_ptr, schema_ptr) = column.to_raw().map_err(|e| {
+            RecordBatchesError::arrow("Error exporting column through the C Data Interface")(e)
+                .for_field(name)
+        })?;
+        arrays.push(array_ptr as usize as f64);
+        schemas.push(schema_ptr as usize as f64);
     }
 
-    // write data
-    let buffers = fbb.create_vector(&buffers);
-    let nodes = fbb.create_vector(&nodes);
+    let robj = list!(names = names, array = arrays, schema = schemas);
+    robj.set_attrib(class_symbol(), C_DATA_CLASS)
+        .map_err(|_| RecordBatchesError::invalid("Error setting class of C Data Interface list"))
+}
 
-    let root = {
-        let mut batch_builder = arrow::ipc::RecordBatchBuilder::new(&mut fbb);
-        batch_builder.add_length(batch.num_rows() as i64);
-        batch_builder.add_nodes(nodes);
-        batch_builder.add_buffers(buffers);
-        let b = batch_builder.finish();
-        b.as_union_value()
+/// Frees the structs allocated by [export_c_data]. If R has already imported
+/// a column, its struct is marked as released and only the struct itself is
+/// freed. Otherwise, the exported buffers are released as well.
+///
+/// The addresses in `robj` are then set to zero, so the same structs can't be
+/// freed (or imported) again. Nothing is freed if any address is already zero.
+pub fn release_c_data(robj: &Robj) -> Result<()> {
+    let items: Vec<Robj> = if robj.inherits(C_DATA_CLASS) {
+        vec![robj.clone()]
+    } else {
+        robj.as_list_iter()
+            .ok_or_else(|| RecordBatchesError::invalid("Expected a list."))?
+            .collect()
     };
-    // create an ipc::Message
-    let mut message = arrow::ipc::MessageBuilder::new(&mut fbb);
-    message.add_version(arrow::ipc::MetadataVersion::V5);
-    message.add_header_type(arrow::ipc::MessageHeader::RecordBatch);
-    message.add_bodyLength(arrow_data.len() as i64);
-    message.add_header(root);
-    let root = message.finish();
-    fbb.finish(root, None);
-    let finished_data = fbb.finished_data();
-
-    EncodedData {
-        ipc_message: finished_data.to_vec(),
-        arrow_data,
+    let mut released = Vec::with_capacity(items.len());
+    for item in &items {
+        let arrays = required_element(item, "array")?;
+        let schemas = required_element(item, "schema")?;
+        let addrs: Vec<_> = c_data_addresses(&arrays)?
+            .into_iter()
+            .zip(c_data_addresses(&schemas)?)
+            .collect();
+        if addrs
+            .iter()
+            .any(|&(array, schema)| array == 0 || schema == 0)
+        {
+            return Err(RecordBatchesError::invalid(
+                "C Data Interface structs were already released.",
+            ));
+        }
+        released.push((addrs, arrays, schemas));
+    }
+    for (addrs, arrays, schemas) in released {
+        for (array_addr, schema_addr) in addrs {
+            let owned = unsafe {
+                ArrowArray::try_from_raw(
+                    array_addr as *const FFI_ArrowArray,
+                    schema_addr as *const FFI_ArrowSchema,
+                )
+            };
+            drop(owned);
+        }
+        unsafe {
+            clear_c_data_addresses(&arrays);
+            clear_c_data_addresses(&schemas);
+        }
     }
+    Ok(())
 }
 
-fn schema_to_bytes(schema: &Schema) -> EncodedData {
-    let mut fbb = FlatBufferBuilder::new();
-    let schema = {
-        let fb = arrow::ipc::convert::schema_to_fb_offset(&mut fbb, schema);
-        fb.as_union_value()
-    };
+/// The layout of the `ArrowSchema` C struct. Arrow's `FFI_ArrowSchema` has
+/// the same layout but keeps its members private, and arrow (v3.0.0) drops
+/// the flags and metadata when importing a column.
+#[repr(C)]
+struct CArrowSchema {
+    _format: *const c_char,
+    _name: *const c_char,
+    metadata: *const c_char,
+    flags: i64,
+    _n_children: i64,
+    _children: *mut *mut CArrowSchema,
+    _dictionary: *mut CArrowSchema,
+    release: Option<unsafe extern "C" fn(*mut CArrowSchema)>,
+    _private_data: *mut c_void,
+}
 
-    let mut message = arrow::ipc::MessageBuilder::new(&mut fbb);
-    message.add_version(arrow::ipc::MetadataVersion::V5);
-    message.add_header_type(arrow::ipc::MessageHeader::Schema);
-    message.add_bodyLength(0);
-    message.add_header(schema);
-    let data = message.finish();
-    fbb.finish(data, None);
+const ARROW_FLAG_DICTIONARY_ORDERED: i64 = 1;
+const ARROW_FLAG_NULLABLE: i64 = 2;
 
-    let data = fbb.finished_data();
-    EncodedData {
-        ipc_message: data.to_vec(),
-        arrow_data: vec![],
-    }
+/// The properties of a column's field that are read from its `ArrowSchema`
+/// struct rather than from the imported array
+struct CFieldProperties {
+    nullable: bool,
+    dict_is_ordered: bool,
+    metadata: BTreeMap<String, String>,
 }
 
-fn write_array_data(
-    array_data: &ArrayDataRef,
-    mut buffers: &mut Vec<arrow::ipc::Buffer>,
-    mut arrow_data: &mut Vec<u8>,
-    mut nodes: &mut Vec<arrow::ipc::FieldNode>,
-    offset: i64,
-    num_rows: usize,
-    null_count: usize,
-) -> i64 {
-    let mut offset = offset;
-    nodes.push(arrow::ipc::FieldNode::new(
-        num_rows as i64,
-        null_count as i64,
-    ));
-    // NullArray does not have any buffers, thus the null buffer is not generated
-    if array_data.data_type() != &DataType::Null {
-        // write null buffer if exists
-        let null_buffer = match array_data.null_buffer() {
-            None => {
-                // create a buffer and fill it with valid bits
-                let num_bytes = arrow::util::bit_util::ceil(num_rows, 8);
-                let buffer = arrow::buffer::MutableBuffer::new(num_bytes);
-                let buffer = buffer.with_bitset(num_bytes, true);
-                buffer.into()
-            }
-            Some(buffer) => buffer.clone(),
+impl CFieldProperties {
+    fn into_field(self, name: &str, data_type: &DataType, dict_id: i64) -> Field {
+        let mut field = match data_type {
+            DataType::Dictionary(_, _) => Field::new_dict(
+                name,
+                data_type.clone(),
+                self.nullable,
+                dict_id,
+                self.dict_is_ordered,
+            ),
+            _ => Field::new(name, data_type.clone(), self.nullable),
         };
-
-        offset = write_buffer(&null_buffer, &mut buffers, &mut arrow_data, offset);
+        if !self.metadata.is_empty() {
+            field.set_metadata(Some(self.metadata));
+        }
+        field
     }
+}
 
-    array_data.buffers().iter().for_each(|buffer| {
-        offset = write_buffer(buffer, &mut buffers, &mut arrow_data, offset);
-    });
+/// Reads the flags and metadata of the `ArrowSchema` struct at `schema_addr`,
+/// which must not have been released yet
+unsafe fn c_field_properties(schema_addr: usize) -> Result<CFieldProperties> {
+    if schema_addr == 0 {
+        return Err(RecordBatchesError::invalid(
+            "Received a null C Data Interface pointer.",
+        ));
+    }
+    let schema = &*(schema_addr as *const CArrowSchema);
+    if schema.release.is_none() {
+        return Err(RecordBatchesError::invalid(
+            "C Data Interface structs were already released.",
+        ));
+    }
+    Ok(CFieldProperties {
+        nullable: schema.flags & ARROW_FLAG_NULLABLE != 0,
+        dict_is_ordered: schema.flags & ARROW_FLAG_DICTIONARY_ORDERED != 0,
+        metadata: read_c_metadata(schema.metadata)?,
+    })
+}
 
-    if !matches!(array_data.data_type(), DataType::Dictionary(_, _)) {
-        // recursively write out nested structures
-        array_data.child_data().iter().for_each(|data_ref| {
-            // write the nested data (e.g list data)
-            offset = write_array_data(
-                data_ref,
-                &mut buffers,
-                &mut arrow_data,
-                &mut nodes,
-                offset,
-                data_ref.len(),
-                data_ref.null_count(),
-            );
-        });
+/// Decodes the metadata of an `ArrowSchema` struct: an int32 number of pairs,
+/// then each key and value as an int32 length followed by that many bytes
+unsafe fn read_c_metadata(metadata: *const c_char) -> Result<BTreeMap<String, String>> {
+    let mut out = BTreeMap::new();
+    if metadata.is_null() {
+        return Ok(out);
     }
+    let mut position = metadata as *const u8;
+    let pairs = read_c_i32(&mut position);
+    for _ in 0..pairs {
+        let key = read_c_string(&mut position)?;
+        let value = read_c_string(&mut position)?;
+        out.insert(key, value);
+    }
+    Ok(out)
+}
 
-    offset
+unsafe fn read_c_i32(position: &mut *const u8) -> i32 {
+    let value = std::ptr::read_unaligned(*position as *const i32);
+    *position = position.add(4);
+    value
 }
 
-/// Write a buffer to a vector of bytes, and add its ipc::Buffer to a vector
-fn write_buffer(
-    buffer: &Buffer,
-    buffers: &mut Vec<arrow::ipc::Buffer>,
-    arrow_data: &mut Vec<u8>,
-    offset: i64,
-) -> i64 {
-    let len = buffer.len();
-    let pad_len = pad_to_8(len);
-    let total_len: i64 = (len + pad_len) as i64;
-    buffers.push(arrow::ipc::Buffer::new(offset, total_len));
-    arrow_data.extend_from_slice(buffer.as_slice());
-    arrow_data.extend_from_slice(&vec![0u8; pad_len][..]);
-    offset + total_len
+unsafe fn read_c_string(position: &mut *const u8) -> Result<String> {
+    let len = read_c_i32(position);
+    if len < 0 {
+        return Err(RecordBatchesError::invalid(
+            "C Data Interface metadata has a negative length.",
+        ));
+    }
+    let bytes = std::slice::from_raw_parts(*position, len as usize);
+    *position = position.add(len as usize);
+    String::from_utf8(bytes.to_vec())
+        .map_err(|_| RecordBatchesError::invalid("C Data Interface metadata is not UTF-8."))
 }
 
-/// Write the contents of an EncodedData struct to a stream, including the IPC
-/// message and RecordBatch data
-pub fn write_encoded_data_to_stream<W: Write>(
-    mut writer: W,
-    encoded: EncodedData,
-) -> Result<(usize, usize)> {
-    let arrow_data_len = encoded.arrow_data.len();
-    if arrow_data_len % 8 != 0 {
-        return Err("Arrow data not aligned");
+/// Moves one column out of the C structs at the given addresses and into an
+/// ArrayRef. The buffers themselves are not copied.
+unsafe fn import_c_column(array_addr: usize, schema_addr: usize) -> Result<ArrayRef> {
+    if array_addr == 0 || schema_addr == 0 {
+        return Err(RecordBatchesError::invalid(
+            "Received a null C Data Interface pointer.",
+        ));
     }
+    let (array_ptr, schema_ptr) = ArrowArray::into_raw(ArrowArray::empty());
+    move_c_struct(
+        array_addr as *mut FFI_ArrowArray,
+        array_ptr as *mut FFI_ArrowArray,
+    );
+    move_c_struct(
+        schema_addr as *mut FFI_ArrowSchema,
+        schema_ptr as *mut FFI_ArrowSchema,
+    );
+    make_array_from_raw(array_ptr, schema_ptr).map_err(RecordBatchesError::arrow(
+        "Error importing column through the C Data Interface",
+    ))
+}
 
-    let a = BLOCK_SIZE - 1;
-    let buffer = encoded.ipc_message;
-    let flatbuf_size = buffer.len();
-    let aligned_size = (flatbuf_size + PREFIX_SIZE + a) & !a;
-    let padding_bytes = aligned_size - flatbuf_size - PREFIX_SIZE;
+/// Moves a C Data Interface struct from `src` to `dst` as described by the
+/// specification: the struct is copied bit-for-bit and the source is marked
+/// as released by zeroing it (which sets its `release` callback to NULL).
+unsafe fn move_c_struct<T>(src: *mut T, dst: *mut T) {
+    std::ptr::copy_nonoverlapping(src, dst, 1);
+    std::ptr::write_bytes(src, 0, 1);
+}
 
-    write_continuation_marker(&mut writer, (aligned_size - PREFIX_SIZE) as i32)?;
+/// Reads a vector of addresses, given either as a double vector or as a list
+/// of external pointers (or doubles)
+fn c_data_addresses(robj: &Robj) -> Result<Vec<usize>> {
+    if let Some(addrs) = robj.as_real_slice() {
+        Ok(addrs.iter().map(|x| *x as usize).collect())
+    } else if let Some(items) = robj.as_list_iter() {
+        items.map(|item| c_data_address(&item)).collect()
+    } else {
+        c_data_address(robj).map(|x| vec![x])
+    }
+}
 
-    // write the flatbuf
-    if flatbuf_size > 0 {
-        writer
-            .write_all(&buffer)
-            .map_err(|_| "Could not write buffer.")?;
+/// Sets addresses read by [c_data_addresses] to zero, in place, so every R
+/// variable holding them sees that the structs are gone
+unsafe fn clear_c_data_addresses(robj: &Robj) {
+    if robj.rtype() == RType::Real {
+        for i in 0..robj.len() {
+            *libR_sys::REAL(robj.get()).add(i) = 0.0;
+        }
+    } else if robj.rtype() == RType::ExternalPtr {
+        libR_sys::R_ClearExternalPtr(robj.get());
+    } else if let Some(items) = robj.as_list_iter() {
+        for item in items {
+            clear_c_data_addresses(&item);
+        }
     }
-    pad_output_stream(&mut writer, padding_bytes)?;
+}
 
-    // write arrow data
-    let body_len = if arrow_data_len > 0 {
-        write_bytes_to_stream(&mut writer, &encoded.arrow_data)?
+fn c_data_address(robj: &Robj) -> Result<usize> {
+    if let Some(addr) = robj.as_real() {
+        Ok(addr as usize)
+    } else if robj.rtype() == RType::ExternalPtr {
+        Ok(unsafe { libR_sys::R_ExternalPtrAddr(robj.get()) } as usize)
     } else {
-        0
-    };
+        Err(RecordBatchesError::invalid(
+            "Expected C Data Interface addresses as doubles or external pointers.",
+        ))
+    }
+}
 
-    Ok((aligned_size, body_len))
+/// Fetches a named element from an R list, which must be present
+fn required_element(robj: &Robj, name: &'static str) -> Result<Robj> {
+    list_element(robj, name).ok_or_else(|| {
+        RecordBatchesError::invalid("Missing C Data Interface pointers.").for_field(name)
+    })
 }
 
-/// Write a 'continuation' marker and the length of the record batch to a
-/// stream. This is written prior to the contents of the record batch.
-fn write_continuation_marker<W: Write>(mut writer: W, total_len: i32) -> Result<()> {
-    writer
-        .write_all(&[0xff; 4])
-        .map_err(|_| "Could not write continuation marker to buffer.")?;
-    writer
-        .write_all(&total_len.to_le_bytes()[..])
-        .map_err(|_| "Could not write length to buffer.")?;
-    writer.flush().map_err(|_| "Could not flush buffer.")?;
-    Ok(())
+/// Fetches a named element from an R list
+fn list_element(robj: &Robj, name: &str) -> Option<Robj> {
+    let names = robj.names()?;
+    let items = robj.as_list_iter()?;
+    names
+        .zip(items)
+        .find(|(n, _)| *n == name)
+        .map(|(_, item)| item)
 }
 
-/// Write bytes to a stream, padding up to the nearest multliple of 8
-fn write_bytes_to_stream<W: Write>(mut writer: W, data: &[u8]) -> Result<usize> {
-    let len = data.len();
-    let pad_len = pad_to_8(len);
-    let total_len = len + pad_len;
+#[allow(unused_imports)]
+mod test {
+    use super::*;
+    use arrow::array::DictionaryArray;
+    use arrow::datatypes::Int32Type;
 
-    writer
-        .write_all(data)
-        .map_err(|_| "Could not write data to buffer.")?;
-    if pad_len > 0 {
-        pad_output_stream(&mut writer, pad_len)?;
+    #[allow(dead_code)]
+    unsafe extern "C" fn release_nothing(schema: *mut CArrowSchema) {
+        (*schema).release = None;
     }
 
-    writer.flush().map_err(|_| "Could not flush buffer.")?;
-    Ok(total_len)
-}
+    #[allow(dead_code)]
+    fn c_schema(flags: i64, metadata: *const c_char) -> CArrowSchema {
+        CArrowSchema {
+            _format: b"u\0".as_ptr() as *const c_char,
+            _name: std::ptr::null(),
+            metadata,
+            flags,
+            _n_children: 0,
+            _children: std::ptr::null_mut(),
+            _dictionary: std::ptr::null_mut(),
+            release: Some(release_nothing),
+            _private_data: std::ptr::null_mut(),
+        }
+    }
 
-/// Add 0's to the end of a stream up to the nearest multiple of 8 bytes
-fn pad_output_stream<W: Write>(mut writer: W, pad_len: usize) -> Result<()> {
-    writer
-        .write_all(&vec![0u8; pad_len][..])
-        .map_err(|_| "Could not write padding to buffer.")?;
-    Ok(())
-}
+    #[test]
+    fn test_paths_are_read_with_compression_and_deltas() {
+        let data_type = DataType::Dictionary(Box::new(DataType::Int32), Box::new(DataType::Utf8));
+        let schema = Arc::new(Schema::new(vec![Field::new_dict(
+            "f", data_type, true, 0, false,
+        )]));
+        let batch = |values: Vec<&str>| {
+            let array: DictionaryArray<Int32Type> = values.into_iter().collect();
+            RecordBatch::try_new(schema.clone(), vec![Arc::new(array)]).unwrap()
+        };
+        let mut sink = RecordBatchStreamSink::try_new(Vec::new(), schema.clone())
+            .unwrap()
+            .with_compression(Some(IpcCompression::Zstd));
+        sink.write(&batch(vec!["a"])).unwrap();
+        sink.write(&batch(vec!["a", "b"])).unwrap();
+        let path = std::env::temp_dir().join("rustbind-test-paths-are-read.arrows");
+        std::fs::write(&path, sink.into_inner().unwrap()).unwrap();
+
+        let read = RecordBatches::from_path(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let read: Vec<_> = read.into_iter().collect();
+        assert_eq!(read.len(), 2);
+        assert_eq!(read[1].column(0).data_ref().child_data()[0].len(), 2);
+        assert!(RecordBatches::from_path("/nonexistent/file.arrows").is_err());
+    }
 
-/// Rounds a u32 up to a multiple of 8
-#[inline]
-fn pad_to_8(len: usize) -> usize {
-    (((len + 7) & !7) - len) as usize
+    #[test]
+    fn test_c_field_properties_keep_flags_and_metadata() {
+        let mut metadata = Vec::new();
+        metadata.extend_from_slice(&1i32.to_ne_bytes());
+        metadata.extend_from_slice(&4i32.to_ne_bytes());
+        metadata.extend_from_slice(b"unit");
+        metadata.extend_from_slice(&2i32.to_ne_bytes());
+        metadata.extend_from_slice(b"kg");
+        let schema = c_schema(ARROW_FLAG_DICTIONARY_ORDERED, metadata.as_ptr() as _);
+
+        let properties = unsafe { c_field_properties(&schema as *const _ as usize) }.unwrap();
+        let dictionary = DataType::Dictionary(Box::new(DataType::Int32), Box::new(DataType::Utf8));
+        let field = properties.into_field("f", &dictionary, 3);
+        assert!(!field.is_nullable());
+        assert_eq!(field.dict_is_ordered(), Some(true));
+        assert_eq!(field.dict_id(), Some(3));
+        assert_eq!(
+            field.metadata().as_ref().unwrap().get("unit"),
+            Some(&"kg".to_string())
+        );
+
+        let schema = c_schema(ARROW_FLAG_NULLABLE, std::ptr::null());
+        let properties = unsafe { c_field_properties(&schema as *const _ as usize) }.unwrap();
+        let field = properties.into_field("x", &DataType::Utf8, 0);
+        assert!(field.is_nullable());
+        assert!(field.metadata().is_none());
+
+        let mut schema = c_schema(ARROW_FLAG_NULLABLE, std::ptr::null());
+        schema.release = None;
+        assert!(unsafe { c_field_properties(&schema as *const _ as usize) }.is_err());
+    }
 }
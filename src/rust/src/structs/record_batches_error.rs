@@ -0,0 +1,300 @@
+//! This module defines [RecordBatchesError], the error type for everything that
+//! can go wrong while moving RecordBatches between R and Rust. Unlike a plain
+//! `&'static str`, it keeps the underlying `ArrowError` (or I/O error) along
+//! with where the failure happened: which RecordBatch was being read or
+//! written, and which field (column) and table were involved, if known.
+//!
+//! On the R side, a RecordBatchesError is signalled as a classed condition
+//! (`rustbind_arrow_error`), so those details can be inspected with
+//! `tryCatch()`:
+//!
+//! ```r
+//! tryCatch(
+//!   pass_arrow_record_batch(as.raw(1:10)),
+//!   rustbind_arrow_error = function(e) e$message_index
+//! )
+//! ```
+
+use arrow::error::ArrowError;
+use parquet::errors::ParquetError;
+use std::fmt;
+
+/// Class vector of the R condition signalled for a RecordBatchesError
+pub const ARROW_ERROR_CLASS: [&str; 4] = [
+    "rustbind_arrow_error",
+    "rustbind_error",
+    "error",
+    "condition",
+];
+
+#[derive(Debug)]
+pub enum RecordBatchesError {
+    /// An error raised by arrow while reading, writing, or converting data
+    Arrow {
+        context: &'static str,
+        source: ArrowError,
+        message_index: Option<usize>,
+        field: Option<String>,
+        table: Option<String>,
+    },
+    /// An error raised by the parquet crate while reading or writing a file
+    Parquet {
+        context: &'static str,
+        source: ParquetError,
+        field: Option<String>,
+        table: Option<String>,
+    },
+    /// An error reading from or writing to a file or buffer
+    Io {
+        context: &'static str,
+        source: std::io::Error,
+        table: Option<String>,
+    },
+    /// Input that was read successfully, but can't be used
+    Invalid {
+        message: &'static str,
+        message_index: Option<usize>,
+        field: Option<String>,
+        table: Option<String>,
+    },
+    /// A RecordBatch whose schema differs from the one expected
+    Schema {
+        message: &'static str,
+        expected: Option<String>,
+        found: Option<String>,
+        message_index: Option<usize>,
+        field: Option<String>,
+        table: Option<String>,
+    },
+}
+
+impl RecordBatchesError {
+    /// For use with `map_err`, wraps an ArrowError with a description of what
+    /// was being attempted
+    pub fn arrow(context: &'static str) -> impl FnOnce(ArrowError) -> Self {
+        move |source| RecordBatchesError::Arrow {
+            context,
+            source,
+            message_index: None,
+            field: None,
+            table: None,
+        }
+    }
+
+    /// For use with `map_err`, wraps a ParquetError with a description of what
+    /// was being attempted
+    pub fn parquet(context: &'static str) -> impl FnOnce(ParquetError) -> Self {
+        move |source| RecordBatchesError::Parquet {
+            context,
+            source,
+            field: None,
+            table: None,
+        }
+    }
+
+    /// For use with `map_err`, wraps an io::Error with a description of what
+    /// was being attempted
+    pub fn io(context: &'static str) -> impl FnOnce(std::io::Error) -> Self {
+        move |source| RecordBatchesError::Io {
+            context,
+            source,
+            table: None,
+        }
+    }
+
+    /// An error for input that can't be used
+    pub fn invalid(message: &'static str) -> Self {
+        RecordBatchesError::Invalid {
+            message,
+            message_index: None,
+            field: None,
+            table: None,
+        }
+    }
+
+    /// An error for a schema (or field) that differs from the one expected,
+    /// with descriptions of what was expected and what was found, if any
+    pub fn schema(message: &'static str, expected: Option<String>, found: Option<String>) -> Self {
+        RecordBatchesError::Schema {
+            message,
+            expected,
+            found,
+            message_index: None,
+            field: None,
+            table: None,
+        }
+    }
+
+    /// Records the (zero-based) index of the RecordBatch being read or written
+    /// when the error occurred. Reported to R as a one-based index.
+    pub fn at_message(mut self, index: usize) -> Self {
+        match &mut self {
+            RecordBatchesError::Arrow { message_index, .. }
+            | RecordBatchesError::Invalid { message_index, .. }
+            | RecordBatchesError::Schema { message_index, .. } => *message_index = Some(index),
+            _ => (),
+        }
+        self
+    }
+
+    /// Records the name of the field (column) involved in the error
+    pub fn for_field<S: Into<String>>(mut self, name: S) -> Self {
+        match &mut self {
+            RecordBatchesError::Arrow { field, .. }
+            | RecordBatchesError::Parquet { field, .. }
+            | RecordBatchesError::Invalid { field, .. }
+            | RecordBatchesError::Schema { field, .. } => *field = Some(name.into()),
+            _ => (),
+        }
+        self
+    }
+
+    /// Records the name of the table involved in the error, when several are
+    /// passed at once (see [NamedRecordBatches](crate::structs::NamedRecordBatches))
+    pub fn for_table<S: Into<String>>(mut self, name: S) -> Self {
+        match &mut self {
+            RecordBatchesError::Arrow { table, .. }
+            | RecordBatchesError::Parquet { table, .. }
+            | RecordBatchesError::Io { table, .. }
+            | RecordBatchesError::Invalid { table, .. }
+            | RecordBatchesError::Schema { table, .. } => *table = Some(name.into()),
+        }
+        self
+    }
+
+    /// The (zero-based) index of the RecordBatch involved, if known
+    pub fn message_index(&self) -> Option<usize> {
+        match self {
+            RecordBatchesError::Arrow { message_index, .. }
+            | RecordBatchesError::Invalid { message_index, .. }
+            | RecordBatchesError::Schema { message_index, .. } => *message_index,
+            _ => None,
+        }
+    }
+
+    /// The name of the field (column) involved, if known
+    pub fn field(&self) -> Option<&str> {
+        match self {
+            RecordBatchesError::Arrow { field, .. }
+            | RecordBatchesError::Parquet { field, .. }
+            | RecordBatchesError::Invalid { field, .. }
+            | RecordBatchesError::Schema { field, .. } => field.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// The name of the table involved, if known
+    pub fn table(&self) -> Option<&str> {
+        match self {
+            RecordBatchesError::Arrow { table, .. }
+            | RecordBatchesError::Parquet { table, .. }
+            | RecordBatchesError::Io { table, .. }
+            | RecordBatchesError::Invalid { table, .. }
+            | RecordBatchesError::Schema { table, .. } => table.as_deref(),
+        }
+    }
+
+    /// A short, static description of the error, for APIs (like `FromRobj`)
+    /// that can only report a `&'static str`
+    pub fn context(&self) -> &'static str {
+        match self {
+            RecordBatchesError::Arrow { context, .. }
+            | RecordBatchesError::Parquet { context, .. }
+            | RecordBatchesError::Io { context, .. } => context,
+            RecordBatchesError::Invalid { message, .. }
+            | RecordBatchesError::Schema { message, .. } => message,
+        }
+    }
+
+    /// The message of the underlying error, if there is one
+    pub fn cause(&self) -> Option<String> {
+        match self {
+            RecordBatchesError::Arrow { source, .. } => Some(source.to_string()),
+            RecordBatchesError::Parquet { source, .. } => Some(source.to_string()),
+            RecordBatchesError::Io { source, .. } => Some(source.to_string()),
+            RecordBatchesError::Invalid { .. } => None,
+            RecordBatchesError::Schema {
+                expected, found, ..
+            } => match (expected, found) {
+                (Some(expected), Some(found)) => {
+                    Some(format!("expected {}, found {}", expected, found))
+                }
+                (Some(expected), None) => Some(format!("expected {}", expected)),
+                (None, Some(found)) => Some(format!("found {}", found)),
+                (None, None) => None,
+            },
+        }
+    }
+}
+
+impl fmt::Display for RecordBatchesError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.context())?;
+        if let Some(table) = self.table() {
+            write!(f, " (table '{}')", table)?;
+        }
+        if let Some(index) = self.message_index() {
+            write!(f, " (RecordBatch {})", index + 1)?;
+        }
+        if let Some(field) = self.field() {
+            write!(f, " (field '{}')", field)?;
+        }
+        if let Some(cause) = self.cause() {
+            write!(f, ": {}", cause)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RecordBatchesError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RecordBatchesError::Arrow { source, .. } => Some(source),
+            RecordBatchesError::Parquet { source, .. } => Some(source),
+            RecordBatchesError::Io { source, .. } => Some(source),
+            RecordBatchesError::Invalid { .. } | RecordBatchesError::Schema { .. } => None,
+        }
+    }
+}
+
+#[allow(unused_imports)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_error_display_includes_details() {
+        let err = RecordBatchesError::arrow("Error reading RecordBatch")(ArrowError::IoError(
+            "unexpected end of file".to_string(),
+        ))
+        .at_message(2)
+        .for_field("mpg");
+        let message = err.to_string();
+        assert!(message.starts_with("Error reading RecordBatch (RecordBatch 3) (field 'mpg')"));
+        assert!(message.contains("unexpected end of file"));
+        assert_eq!(err.message_index(), Some(2));
+        assert_eq!(err.field(), Some("mpg"));
+    }
+
+    #[test]
+    fn test_io_errors_have_no_message_index() {
+        let err = RecordBatchesError::io("Could not open file")(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "missing",
+        ))
+        .at_message(1);
+        assert_eq!(err.message_index(), None);
+        assert_eq!(err.context(), "Could not open file");
+    }
+
+    #[test]
+    fn test_error_display_includes_table() {
+        let err = RecordBatchesError::invalid("Unknown column")
+            .for_field("mpg")
+            .for_table("predictions");
+        assert_eq!(
+            err.to_string(),
+            "Unknown column (table 'predictions') (field 'mpg')"
+        );
+        assert_eq!(err.table(), Some("predictions"));
+    }
+}
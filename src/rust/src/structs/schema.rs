@@ -0,0 +1,511 @@
+//! This module inspects and compares the schemas of RecordBatches. A schema can
+//! be read from an IPC stream or file without reading any RecordBatches (see
+//! [read_ipc_schema]) and described to R as a data frame (see
+//! [schema_to_robj]). Schemas are compared in two ways:
+//!
+//! - strictly, with [schema_mismatch], for RecordBatches that must all share
+//!   one schema, like the batches of an IPC stream
+//! - loosely, with [check_expected_schema], for a Rust function that declares
+//!   the schema it expects. Fields must have the expected names, types, and
+//!   order, but a non-nullable field is accepted where a nullable one is
+//!   expected, and metadata is ignored.
+
+use crate::structs::{is_ipc_file, RecordBatchesError};
+use arrow::{
+    datatypes::{DataType, Field, Schema, SchemaRef},
+    ipc::{
+        reader::{FileReader, StreamReader},
+        Message, Type,
+    },
+};
+use extendr_api::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufReader, Cursor, Read, Seek};
+
+type Result<T> = std::result::Result<T, RecordBatchesError>;
+
+/// Reads only the schema of an IPC stream or file, from a raw vector or a file
+/// path. Unlike [RecordBatches::schema](crate::structs::RecordBatches::schema),
+/// this works for streams with no RecordBatches.
+pub fn read_ipc_schema(robj: &Robj) -> Result<SchemaRef> {
+    if let Some(slice) = robj.as_raw_slice() {
+        schema_from_reader(Cursor::new(slice))
+    } else if let Some(path) = robj.as_str() {
+        let file = File::open(path).map_err(RecordBatchesError::io("Could not open file"))?;
+        schema_from_reader(BufReader::new(file))
+    } else {
+        Err(RecordBatchesError::invalid(
+            "Expected a raw vector or a file path.",
+        ))
+    }
+}
+
+fn schema_from_reader<R: Read + Seek>(mut reader: R) -> Result<SchemaRef> {
+    if is_ipc_file(&mut reader)? {
+        FileReader::try_new(reader)
+            .map(|r| r.schema())
+            .map_err(RecordBatchesError::arrow("Error creating FileReader"))
+    } else {
+        StreamReader::try_new(reader)
+            .map(|r| r.schema())
+            .map_err(RecordBatchesError::arrow("Error creating StreamReader"))
+    }
+}
+
+/// Reads the schema of a Schema message. Arrow (v3.0.0) has no `Map` type, so
+/// a `Map` field is read as a `List` of its key/value entries, which has the
+/// same layout. Also returns whether there were any, in which case the message
+/// must be re-encoded before arrow can read it.
+pub(crate) fn schema_from_message(message: &Message) -> Option<(Schema, bool)> {
+    let schema = message.header_as_schema()?;
+    let mut has_maps = false;
+    let fields = schema
+        .fields()
+        .map(|fields| fields.iter().map(|f| fb_field(f, &mut has_maps)).collect())
+        .unwrap_or_default();
+    let metadata: HashMap<String, String> = schema
+        .custom_metadata()
+        .map(|pairs| {
+            pairs
+                .iter()
+                .filter_map(|kv| Some((kv.key()?.to_string(), kv.value()?.to_string())))
+                .collect()
+        })
+        .unwrap_or_default();
+    Some((Schema::new_with_metadata(fields, metadata), has_maps))
+}
+
+/// Converts a field of a Schema message. Fields with a `Map` at any depth are
+/// rebuilt here, keeping their metadata; all other fields are left to arrow,
+/// which keeps their dictionary encoding and metadata as well.
+fn fb_field(field: arrow::ipc::Field, has_maps: &mut bool) -> Field {
+    if !contains_map(&field) {
+        return field.into();
+    }
+    *has_maps = true;
+    let children: Vec<Field> = field
+        .children()
+        .map(|children| children.iter().map(|c| fb_field(c, has_maps)).collect())
+        .unwrap_or_default();
+    let item = || children.first().cloned().map(Box::new);
+    let data_type = match field.type_type() {
+        Type::Map | Type::List => item().map(DataType::List),
+        Type::LargeList => item().map(DataType::LargeList),
+        Type::FixedSizeList => item().and_then(|item| {
+            let size = field.type_as_fixed_size_list()?.listSize();
+            Some(DataType::FixedSizeList(item, size))
+        }),
+        Type::Struct_ => Some(DataType::Struct(children.clone())),
+        _ => None,
+    };
+    let data_type = match data_type {
+        Some(data_type) => data_type,
+        None => return field.into(),
+    };
+    let mut out = Field::new(
+        field.name().unwrap_or_default(),
+        data_type,
+        field.nullable(),
+    );
+    let metadata: BTreeMap<String, String> = field
+        .custom_metadata()
+        .map(|pairs| {
+            pairs
+                .iter()
+                .filter_map(|kv| Some((kv.key()?.to_string(), kv.value()?.to_string())))
+                .collect()
+        })
+        .unwrap_or_default();
+    if !metadata.is_empty() {
+        out.set_metadata(Some(metadata));
+    }
+    out
+}
+
+/// Whether a field of a Schema message is a `Map`, or has one at any depth
+fn contains_map(field: &arrow::ipc::Field) -> bool {
+    field.type_type() == Type::Map
+        || field
+            .children()
+            .map_or(false, |children| children.iter().any(|c| contains_map(&c)))
+}
+
+/// Describes a schema to R as a data frame with one row per field and the
+/// columns `name`, `type` (the Arrow data type), `nullable`, and `metadata` (a
+/// list of named character vectors, or `NULL` for fields without metadata).
+/// The schema's own metadata is attached as the `metadata` attribute.
+pub fn schema_to_robj(schema: &Schema) -> Result<Robj> {
+    let fields = schema.fields();
+    let names: Vec<String> = fields.iter().map(|f| f.name().clone()).collect();
+    let types: Vec<String> = fields.iter().map(data_type_name).collect();
+    let nullable: Vec<bool> = fields.iter().map(|f| f.is_nullable()).collect();
+    let metadata: Vec<Robj> = fields
+        .iter()
+        .map(|f| match f.metadata() {
+            Some(metadata) => named_strings(metadata.iter()),
+            None => Ok(Robj::from(())),
+        })
+        .collect::<Result<_>>()?;
+
+    let columns = vec![
+        Robj::from(names),
+        Robj::from(types),
+        Robj::from(nullable),
+        Robj::from(List(metadata)),
+    ];
+    let row_names: Vec<i32> = (1..=fields.len() as i32).collect();
+    let sorted: BTreeMap<_, _> = schema.metadata().iter().collect();
+    let schema_metadata = named_strings(sorted.into_iter())?;
+
+    let set_attributes = || -> extendr_api::Result<Robj> {
+        Robj::from(List(columns))
+            .set_attrib(names_symbol(), vec!["name", "type", "nullable", "metadata"])?
+            .set_attrib(row_names_symbol(), row_names)?
+            .set_attrib(class_symbol(), "data.frame")?
+            .set_attrib("metadata", schema_metadata)
+    };
+    set_attributes().map_err(|_| RecordBatchesError::invalid("Error building schema data frame"))
+}
+
+/// A named character vector from key/value pairs
+fn named_strings<'a, I>(pairs: I) -> Result<Robj>
+where
+    I: Iterator<Item = (&'a String, &'a String)>,
+{
+    let (keys, values): (Vec<String>, Vec<String>) =
+        pairs.map(|(k, v)| (k.clone(), v.clone())).unzip();
+    Robj::from(values)
+        .set_attrib(names_symbol(), keys)
+        .map_err(|_| RecordBatchesError::invalid("Error setting names of metadata"))
+}
+
+/// The name of a field's Arrow data type, as shown to R
+fn data_type_name(field: &Field) -> String {
+    format!("{:?}", field.data_type())
+}
+
+/// A description of a field for error messages, e.g. `Float64 not null`
+fn describe_field(field: &Field) -> String {
+    if field.is_nullable() {
+        data_type_name(field)
+    } else {
+        format!("{} not null", data_type_name(field))
+    }
+}
+
+/// An error for a RecordBatch whose schema differs from the one expected,
+/// naming the first field that differs
+pub(crate) fn schema_mismatch(
+    message: &'static str,
+    expected: &Schema,
+    found: &Schema,
+) -> RecordBatchesError {
+    let expected_fields = expected.fields();
+    let found_fields = found.fields();
+    for i in 0..expected_fields.len().max(found_fields.len()) {
+        let (a, b) = (expected_fields.get(i), found_fields.get(i));
+        if a == b {
+            continue;
+        }
+        let name = a.or(b).map(|f| f.name().as_str()).unwrap_or_default();
+        return RecordBatchesError::schema(message, a.map(describe_field), b.map(describe_field))
+            .for_field(name);
+    }
+    RecordBatchesError::schema(message, None, None)
+}
+
+/// Checks that a schema provides the fields a Rust function expects (see the
+/// module documentation for the rules), naming the first field that doesn't
+pub fn check_expected_schema(expected: &Schema, found: &Schema) -> Result<()> {
+    let positions: HashMap<&str, usize> = found
+        .fields()
+        .iter()
+        .enumerate()
+        .map(|(i, f)| (f.name().as_str(), i))
+        .collect();
+
+    for (i, want) in expected.fields().iter().enumerate() {
+        let name = want.name().as_str();
+        let (position, have) = match positions.get(name) {
+            Some(&position) => (position, &found.fields()[position]),
+            None => {
+                return Err(RecordBatchesError::schema(
+                    "Missing expected field",
+                    Some(describe_field(want)),
+                    None,
+                )
+                .for_field(name))
+            }
+        };
+        if have.data_type() != want.data_type() {
+            return Err(RecordBatchesError::schema(
+                "Field has an unexpected type",
+                Some(data_type_name(want)),
+                Some(data_type_name(have)),
+            )
+            .for_field(name));
+        }
+        if have.is_nullable() && !want.is_nullable() {
+            return Err(RecordBatchesError::schema(
+                "Field is nullable, but a non-nullable field was expected",
+                Some(describe_field(want)),
+                Some(describe_field(have)),
+            )
+            .for_field(name));
+        }
+        if position != i {
+            return Err(RecordBatchesError::schema(
+                "Field is not in the expected position",
+                Some(format!("position {}", i + 1)),
+                Some(format!("position {}", position + 1)),
+            )
+            .for_field(name));
+        }
+    }
+
+    if let Some(extra) = found.fields().get(expected.fields().len()) {
+        return Err(RecordBatchesError::schema(
+            "Unexpected field",
+            None,
+            Some(describe_field(extra)),
+        )
+        .for_field(extra.name().as_str()));
+    }
+    Ok(())
+}
+
+#[allow(unused_imports)]
+mod test {
+    use super::*;
+    use flatbuffers::{FlatBufferBuilder, UnionWIPOffset, WIPOffset};
+
+    #[allow(dead_code)]
+    fn expected() -> Schema {
+        Schema::new(vec![
+            Field::new("mpg", DataType::Float64, true),
+            Field::new("cyl", DataType::Int32, false),
+        ])
+    }
+
+    #[allow(dead_code)]
+    fn fb_field<'a>(
+        fbb: &mut FlatBufferBuilder<'a>,
+        name: &str,
+        type_type: Type,
+        type_: WIPOffset<UnionWIPOffset>,
+        children: &[WIPOffset<arrow::ipc::Field<'a>>],
+    ) -> WIPOffset<arrow::ipc::Field<'a>> {
+        let name = fbb.create_string(name);
+        let children = fbb.create_vector(children);
+        let mut field = arrow::ipc::FieldBuilder::new(fbb);
+        field.add_name(name);
+        field.add_nullable(true);
+        field.add_type_type(type_type);
+        field.add_type_(type_);
+        field.add_children(children);
+        field.finish()
+    }
+
+    /// A Schema message with a `Map(Utf8, Int32)` field in a struct
+    #[allow(dead_code)]
+    fn map_schema_message() -> Vec<u8> {
+        let mut fbb = FlatBufferBuilder::new();
+        let utf8 = arrow::ipc::Utf8Builder::new(&mut fbb)
+            .finish()
+            .as_union_value();
+        let key = fb_field(&mut fbb, "key", Type::Utf8, utf8, &[]);
+        let mut int = arrow::ipc::IntBuilder::new(&mut fbb);
+        int.add_bitWidth(32);
+        int.add_is_signed(true);
+        let int = int.finish().as_union_value();
+        let value = fb_field(&mut fbb, "value", Type::Int, int, &[]);
+        let entries_type = arrow::ipc::Struct_Builder::new(&mut fbb)
+            .finish()
+            .as_union_value();
+        let entries = fb_field(
+            &mut fbb,
+            "entries",
+            Type::Struct_,
+            entries_type,
+            &[key, value],
+        );
+        let map_type = arrow::ipc::MapBuilder::new(&mut fbb)
+            .finish()
+            .as_union_value();
+        let map = fb_field(&mut fbb, "m", Type::Map, map_type, &[entries]);
+        let struct_type = arrow::ipc::Struct_Builder::new(&mut fbb)
+            .finish()
+            .as_union_value();
+        let outer = fb_field(&mut fbb, "s", Type::Struct_, struct_type, &[map]);
+
+        let fields = fbb.create_vector(&[outer]);
+        let mut schema = arrow::ipc::SchemaBuilder::new(&mut fbb);
+        schema.add_fields(fields);
+        let schema = schema.finish().as_union_value();
+        let mut message = arrow::ipc::MessageBuilder::new(&mut fbb);
+        message.add_version(arrow::ipc::MetadataVersion::V5);
+        message.add_header_type(arrow::ipc::MessageHeader::Schema);
+        message.add_header(schema);
+        let message = message.finish();
+        fbb.finish(message, None);
+        fbb.finished_data().to_vec()
+    }
+
+    #[test]
+    fn test_map_fields_are_read_as_lists() {
+        let bytes = map_schema_message();
+        let message = arrow::ipc::root_as_message(&bytes).unwrap();
+        let (schema, has_maps) = schema_from_message(&message).unwrap();
+        assert!(has_maps);
+        let entries = DataType::Struct(vec![
+            Field::new("key", DataType::Utf8, true),
+            Field::new("value", DataType::Int32, true),
+        ]);
+        let map = Field::new(
+            "m",
+            DataType::List(Box::new(Field::new("entries", entries, true))),
+            true,
+        );
+        assert_eq!(schema.field(0).data_type(), &DataType::Struct(vec![map]));
+
+        let encoded = crate::structs::schema_to_bytes(&expected());
+        let message = arrow::ipc::root_as_message(&encoded.ipc_message).unwrap();
+        let (schema, has_maps) = schema_from_message(&message).unwrap();
+        assert!(!has_maps);
+        assert_eq!(schema, expected());
+    }
+
+    /// A Schema message with a dictionary-encoded field in a struct, both with
+    /// metadata. Arrow (v3.0.0) can't write dictionaries or metadata of nested
+    /// fields, so the message is built by hand.
+    #[allow(dead_code)]
+    fn nested_dictionary_schema_message() -> Vec<u8> {
+        let mut fbb = FlatBufferBuilder::new();
+        let mut int = arrow::ipc::IntBuilder::new(&mut fbb);
+        int.add_bitWidth(32);
+        int.add_is_signed(true);
+        let index_type = int.finish();
+        let mut dictionary = arrow::ipc::DictionaryEncodingBuilder::new(&mut fbb);
+        dictionary.add_id(4);
+        dictionary.add_indexType(index_type);
+        dictionary.add_isOrdered(true);
+        let dictionary = dictionary.finish();
+        let key = fbb.create_string("unit");
+        let value = fbb.create_string("kg");
+        let mut pair = arrow::ipc::KeyValueBuilder::new(&mut fbb);
+        pair.add_key(key);
+        pair.add_value(value);
+        let pair = pair.finish();
+        let metadata = fbb.create_vector(&[pair]);
+        let utf8 = arrow::ipc::Utf8Builder::new(&mut fbb)
+            .finish()
+            .as_union_value();
+        let name = fbb.create_string("f");
+        let mut child = arrow::ipc::FieldBuilder::new(&mut fbb);
+        child.add_name(name);
+        child.add_nullable(true);
+        child.add_type_type(Type::Utf8);
+        child.add_type_(utf8);
+        child.add_dictionary(dictionary);
+        child.add_custom_metadata(metadata);
+        let child = child.finish();
+        let struct_type = arrow::ipc::Struct_Builder::new(&mut fbb)
+            .finish()
+            .as_union_value();
+        let name = fbb.create_string("s");
+        let children = fbb.create_vector(&[child]);
+        let mut outer = arrow::ipc::FieldBuilder::new(&mut fbb);
+        outer.add_name(name);
+        outer.add_nullable(true);
+        outer.add_type_type(Type::Struct_);
+        outer.add_type_(struct_type);
+        outer.add_children(children);
+        outer.add_custom_metadata(metadata);
+        let outer = outer.finish();
+
+        let fields = fbb.create_vector(&[outer]);
+        let mut schema = arrow::ipc::SchemaBuilder::new(&mut fbb);
+        schema.add_fields(fields);
+        let schema = schema.finish().as_union_value();
+        let mut message = arrow::ipc::MessageBuilder::new(&mut fbb);
+        message.add_version(arrow::ipc::MetadataVersion::V5);
+        message.add_header_type(arrow::ipc::MessageHeader::Schema);
+        message.add_header(schema);
+        let message = message.finish();
+        fbb.finish(message, None);
+        fbb.finished_data().to_vec()
+    }
+
+    #[test]
+    fn test_fields_without_maps_keep_dictionaries_and_metadata() {
+        let bytes = nested_dictionary_schema_message();
+        let message = arrow::ipc::root_as_message(&bytes).unwrap();
+        let (schema, has_maps) = schema_from_message(&message).unwrap();
+        assert!(!has_maps);
+        assert_eq!(
+            schema.field(0).metadata().as_ref().unwrap().get("unit"),
+            Some(&"kg".to_string())
+        );
+        let child = match schema.field(0).data_type() {
+            DataType::Struct(children) => children[0].clone(),
+            data_type => panic!("Expected a struct, found {:?}", data_type),
+        };
+        assert_eq!(
+            child.data_type(),
+            &DataType::Dictionary(Box::new(DataType::Int32), Box::new(DataType::Utf8))
+        );
+        assert_eq!(child.dict_id(), Some(4));
+        assert_eq!(child.dict_is_ordered(), Some(true));
+        assert_eq!(
+            child.metadata().as_ref().unwrap().get("unit"),
+            Some(&"kg".to_string())
+        );
+    }
+
+    #[test]
+    fn test_expected_schema_accepts_non_nullable_fields() {
+        let found = Schema::new(vec![
+            Field::new("mpg", DataType::Float64, false),
+            Field::new("cyl", DataType::Int32, false),
+        ]);
+        assert!(check_expected_schema(&expected(), &found).is_ok());
+    }
+
+    #[test]
+    fn test_expected_schema_reports_field_and_types() {
+        let found = Schema::new(vec![
+            Field::new("mpg", DataType::Float64, true),
+            Field::new("cyl", DataType::Float64, false),
+        ]);
+        let err = check_expected_schema(&expected(), &found).unwrap_err();
+        assert_eq!(err.field(), Some("cyl"));
+        assert_eq!(err.cause().unwrap(), "expected Int32, found Float64");
+
+        let found = Schema::new(vec![Field::new("mpg", DataType::Float64, true)]);
+        let err = check_expected_schema(&expected(), &found).unwrap_err();
+        assert_eq!(err.context(), "Missing expected field");
+        assert_eq!(err.field(), Some("cyl"));
+    }
+
+    #[test]
+    fn test_expected_schema_rejects_extra_and_reordered_fields() {
+        let mut fields = expected().fields().clone();
+        fields.push(Field::new("hp", DataType::Float64, true));
+        let err = check_expected_schema(&expected(), &Schema::new(fields)).unwrap_err();
+        assert_eq!(err.field(), Some("hp"));
+
+        let mut fields = expected().fields().clone();
+        fields.reverse();
+        let err = check_expected_schema(&expected(), &Schema::new(fields)).unwrap_err();
+        assert_eq!(err.context(), "Field is not in the expected position");
+    }
+
+    #[test]
+    fn test_schema_mismatch_names_first_differing_field() {
+        let found = Schema::new(vec![Field::new("mpg", DataType::Float64, true)]);
+        let err = schema_mismatch("Schemas differ", &expected(), &found);
+        assert_eq!(err.field(), Some("cyl"));
+        assert_eq!(err.cause().unwrap(), "expected Int32 not null");
+    }
+}
@@ -0,0 +1,1093 @@
+//! This module provides [RecordBatchStreamSink], which writes RecordBatches in
+//! the Arrow IPC stream format to any type that implements [Write](std::io::Write).
+//! Batches are written (and flushed) as they are received, so a Rust function
+//! can emit its results incrementally to a file, a pipe, or an in-memory
+//! `Vec<u8>` instead of buffering the whole result first.
+//!
+//! Sliced arrays (with a non-zero offset) are written from their offset, so a
+//! Rust function can return a slice of its input without copying it first.
+
+use crate::structs::{
+    has_dictionary, schema_mismatch, DictionaryTracker, DictionaryUpdate, IpcCompression,
+    RecordBatchesError,
+};
+use arrow::{
+    array::{ArrayData, ArrayDataRef},
+    datatypes::{DataType, IntervalUnit, Schema, SchemaRef},
+    ipc::writer::EncodedData,
+    record_batch::RecordBatch,
+};
+use flatbuffers::FlatBufferBuilder;
+use std::borrow::Cow;
+use std::io::{BufWriter, Write};
+
+pub const PREFIX_SIZE: usize = 8; // Bytes in buffer prefix
+pub const BLOCK_SIZE: usize = 8;
+
+type Result<T> = std::result::Result<T, RecordBatchesError>;
+
+/// Writes RecordBatches to `W` in the Arrow IPC stream format. The schema
+/// message is written on creation, each RecordBatch (and any dictionaries it
+/// needs) is written and flushed by [write](RecordBatchStreamSink::write), and
+/// the end-of-stream marker is written by [finish](RecordBatchStreamSink::finish)
+/// or [into_inner](RecordBatchStreamSink::into_inner). A sink that is dropped
+/// without being finished (say, because a RecordBatch failed) leaves the
+/// stream unterminated, so readers can tell it is incomplete.
+pub struct RecordBatchStreamSink<W: Write> {
+    writer: Option<BufWriter<W>>,
+    schema: SchemaRef,
+    dictionary_tracker: DictionaryTracker,
+    compression: Option<IpcCompression>,
+    batches_written: usize,
+    finished: bool,
+}
+
+impl<W: Write> RecordBatchStreamSink<W> {
+    /// Creates a new sink, writing the schema message to `writer`
+    pub fn try_new(writer: W, schema: SchemaRef) -> Result<Self> {
+        let mut writer = BufWriter::new(writer);
+        write_encoded_data_to_stream(&mut writer, schema_to_bytes(&schema))?;
+        Ok(RecordBatchStreamSink {
+            writer: Some(writer),
+            schema,
+            dictionary_tracker: DictionaryTracker::new(false),
+            compression: None,
+            batches_written: 0,
+            finished: false,
+        })
+    }
+
+    /// Compresses the body of every RecordBatch (and dictionary) written from
+    /// now on with `compression`, or not at all if `None`
+    pub fn with_compression(mut self, compression: Option<IpcCompression>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Encodes a RecordBatch, writes it to the stream, and flushes the writer
+    pub fn write(&mut self, batch: &RecordBatch) -> Result<()> {
+        if self.finished {
+            return Err(RecordBatchesError::invalid(
+                "Cannot write to a finished RecordBatchStreamSink",
+            ));
+        }
+        let index = self.batches_written;
+        if batch.schema() != self.schema {
+            return Err(schema_mismatch(
+                "RecordBatch schema does not match the stream schema",
+                &self.schema,
+                &batch.schema(),
+            )
+            .at_message(index));
+        }
+        let writer = self
+            .writer
+            .as_mut()
+            .ok_or_else(|| RecordBatchesError::invalid("Writer already taken"))?;
+        append_record_batch_to_stream(
+            &mut *writer,
+            &mut self.dictionary_tracker,
+            batch,
+            self.compression,
+        )
+        .map_err(|e| e.at_message(index))?;
+        writer
+            .flush()
+            .map_err(RecordBatchesError::io("Could not flush buffer."))?;
+        self.batches_written += 1;
+        Ok(())
+    }
+
+    /// Writes the end-of-stream marker. Nothing may be written afterwards.
+    /// Calling `finish` more than once is a no-op.
+    pub fn finish(&mut self) -> Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        let writer = self
+            .writer
+            .as_mut()
+            .ok_or_else(|| RecordBatchesError::invalid("Writer already taken"))?;
+        write_continuation_marker(&mut *writer, 0)?;
+        writer
+            .flush()
+            .map_err(RecordBatchesError::io("Could not flush buffer."))?;
+        self.finished = true;
+        Ok(())
+    }
+
+    /// Finishes the stream (if needed) and returns the underlying writer
+    pub fn into_inner(mut self) -> Result<W> {
+        self.finish()?;
+        let writer = self
+            .writer
+            .take()
+            .ok_or_else(|| RecordBatchesError::invalid("Writer already taken"))?;
+        writer.into_inner().map_err(|e| {
+            RecordBatchesError::io("Could not retrieve writer from BufWriter")(e.into_error())
+        })
+    }
+}
+
+// -=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-
+// Utility Functions for RecordBatchStreamSink ---------------------------------
+// -=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-=+=-
+
+// Most of what's in this section comes from inner methods in arrow::ipc::writer::StreamWrtier
+// and arrow::ipc::writer::IpcDataGenerator. Feel free to peruse these, but 90% of
+// this code is lightly refactored from the
+// [arrow::ipc::writer](https://docs.rs/arrow/3.0.0/arrow/ipc/writer/index.html)
+// source.
+
+/// The (metadata, body) sizes in bytes of each message written for one
+/// RecordBatch, needed to build the block index of an IPC file
+pub struct WrittenMessages {
+    pub dictionaries: Vec<(usize, usize)>,
+    pub record_batch: (usize, usize),
+}
+
+/// Encode a RecordBatch and append its data to the end of the stream
+pub fn append_record_batch_to_stream<W: Write>(
+    mut stream_writer: W,
+    dictionary_tracker: &mut DictionaryTracker,
+    batch: &RecordBatch,
+    compression: Option<IpcCompression>,
+) -> Result<WrittenMessages> {
+    let (encoded_dictionaries, encoded_message) =
+        encode_batch(batch, dictionary_tracker, compression)?;
+    let mut dictionaries = Vec::with_capacity(encoded_dictionaries.len());
+    for encoded_dictionary in encoded_dictionaries {
+        let sizes = write_encoded_data_to_stream(&mut stream_writer, encoded_dictionary)?;
+        dictionaries.push(sizes);
+    }
+    let record_batch = write_encoded_data_to_stream(&mut stream_writer, encoded_message)?;
+    Ok(WrittenMessages {
+        dictionaries,
+        record_batch,
+    })
+}
+
+/// Encodes a RecordBatch, along with a DictionaryBatch for each dictionary
+/// that changed since the last one. Only the dictionaries of top-level
+/// columns are written, so a dictionary nested in a List or Struct column is
+/// an error rather than being written without its DictionaryBatch.
+pub fn encode_batch(
+    batch: &RecordBatch,
+    dictionary_tracker: &mut DictionaryTracker,
+    compression: Option<IpcCompression>,
+) -> Result<(Vec<EncodedData>, EncodedData)> {
+    let schema = batch.schema();
+    let mut encoded_dictionaries = Vec::with_capacity(schema.fields().len());
+
+    for (i, field) in schema.fields().iter().enumerate() {
+        let column = batch.column(i);
+
+        if !matches!(column.data_type(), DataType::Dictionary(_, _))
+            && has_dictionary(column.data_type())
+        {
+            return Err(
+                RecordBatchesError::invalid("Nested dictionaries are not supported")
+                    .for_field(field.name().as_str()),
+            );
+        }
+        if let DataType::Dictionary(_key_type, _value_type) = column.data_type() {
+            let dict_id = field.dict_id().ok_or_else(|| {
+                RecordBatchesError::invalid("Dictionary field is missing a `dict_id`")
+                    .for_field(field.name().as_str())
+            })?;
+            let dict_data = column.data();
+            let dict_values = &dict_data.child_data()[0];
+
+            let update = dictionary_tracker
+                .insert(dict_id, column)
+                .map_err(|e| e.for_field(field.name().as_str()))?;
+            let encoded = match update {
+                DictionaryUpdate::Unchanged => continue,
+                DictionaryUpdate::New | DictionaryUpdate::Replacement => {
+                    dictionary_batch_to_bytes(dict_id, dict_values, false, compression)
+                }
+                DictionaryUpdate::Delta(appended) => {
+                    dictionary_batch_to_bytes(dict_id, &appended.data(), true, compression)
+                }
+            };
+            encoded_dictionaries.push(encoded.map_err(|e| e.for_field(field.name().as_str()))?);
+        }
+    }
+
+    let encoded_message = record_batch_to_bytes(batch, compression)?;
+
+    Ok((encoded_dictionaries, encoded_message))
+}
+
+/// Encodes a DictionaryBatch message holding the values of a dictionary, or
+/// only the values appended to it if `is_delta`
+pub(crate) fn dictionary_batch_to_bytes(
+    dict_id: i64,
+    array_data: &ArrayDataRef,
+    is_delta: bool,
+    compression: Option<IpcCompression>,
+) -> Result<EncodedData> {
+    let mut fbb = FlatBufferBuilder::new();
+    let mut nodes: Vec<arrow::ipc::FieldNode> = vec![];
+    let mut buffers: Vec<arrow::ipc::Buffer> = vec![];
+    let mut arrow_data: Vec<u8> = vec![];
+
+    write_array_data(
+        array_data,
+        &mut buffers,
+        &mut arrow_data,
+        &mut nodes,
+        0,
+        array_data.offset(),
+        array_data.len(),
+        compression,
+    )?;
+
+    // write data
+    let buffers = fbb.create_vector(&buffers);
+    let nodes = fbb.create_vector(&nodes);
+    let body_compression = compression.map(|c| body_compression_to_fb(&mut fbb, c));
+
+    let root = {
+        let mut batch_builder = arrow::ipc::RecordBatchBuilder::new(&mut fbb);
+        batch_builder.add_length(array_data.len() as i64);
+        batch_builder.add_nodes(nodes);
+        batch_builder.add_buffers(buffers);
+        if let Some(body_compression) = body_compression {
+            batch_builder.add_compression(body_compression);
+        }
+        batch_builder.finish()
+    };
+
+    let root = {
+        let mut batch_builder = arrow::ipc::DictionaryBatchBuilder::new(&mut fbb);
+        batch_builder.add_id(dict_id);
+        batch_builder.add_data(root);
+        batch_builder.add_isDelta(is_delta);
+        batch_builder.finish().as_union_value()
+    };
+
+    let root = {
+        let mut message_builder = arrow::ipc::MessageBuilder::new(&mut fbb);
+        message_builder.add_version(arrow::ipc::MetadataVersion::V5);
+        message_builder.add_header_type(arrow::ipc::MessageHeader::DictionaryBatch);
+        message_builder.add_bodyLength(arrow_data.len() as i64);
+        message_builder.add_header(root);
+        message_builder.finish()
+    };
+
+    fbb.finish(root, None);
+    let finished_data = fbb.finished_data();
+
+    Ok(EncodedData {
+        ipc_message: finished_data.to_vec(),
+        arrow_data,
+    })
+}
+
+fn record_batch_to_bytes(
+    batch: &RecordBatch,
+    compression: Option<IpcCompression>,
+) -> Result<EncodedData> {
+    let mut fbb = FlatBufferBuilder::new();
+    let mut nodes: Vec<arrow::ipc::FieldNode> = vec![];
+    let mut buffers: Vec<arrow::ipc::Buffer> = vec![];
+    let mut arrow_data: Vec<u8> = vec![];
+    let mut offset = 0;
+    for (array, field) in batch.columns().iter().zip(batch.schema().fields()) {
+        let array_data = array.data();
+        offset = write_array_data(
+            &array_data,
+            &mut buffers,
+            &mut arrow_data,
+            &mut nodes,
+            offset,
+            array_data.offset(),
+            array_data.len(),
+            compression,
+        )
+        .map_err(|e| e.for_field(field.name().as_str()))?;
+    }
+
+    // write data
+    let buffers = fbb.create_vector(&buffers);
+    let nodes = fbb.create_vector(&nodes);
+    let body_compression = compression.map(|c| body_compression_to_fb(&mut fbb, c));
+
+    let root = {
+        let mut batch_builder = arrow::ipc::RecordBatchBuilder::new(&mut fbb);
+        batch_builder.add_length(batch.num_rows() as i64);
+        batch_builder.add_nodes(nodes);
+        batch_builder.add_buffers(buffers);
+        if let Some(body_compression) = body_compression {
+            batch_builder.add_compression(body_compression);
+        }
+        let b = batch_builder.finish();
+        b.as_union_value()
+    };
+    // create an ipc::Message
+    let mut message = arrow::ipc::MessageBuilder::new(&mut fbb);
+    message.add_version(arrow::ipc::MetadataVersion::V5);
+    message.add_header_type(arrow::ipc::MessageHeader::RecordBatch);
+    message.add_bodyLength(arrow_data.len() as i64);
+    message.add_header(root);
+    let root = message.finish();
+    fbb.finish(root, None);
+    let finished_data = fbb.finished_data();
+
+    Ok(EncodedData {
+        ipc_message: finished_data.to_vec(),
+        arrow_data,
+    })
+}
+
+/// Builds the `BodyCompression` table recording that each buffer in a message
+/// body was compressed separately with `compression`
+fn body_compression_to_fb<'a>(
+    fbb: &mut FlatBufferBuilder<'a>,
+    compression: IpcCompression,
+) -> flatbuffers::WIPOffset<arrow::ipc::BodyCompression<'a>> {
+    let mut builder = arrow::ipc::BodyCompressionBuilder::new(fbb);
+    builder.add_codec(compression.to_fb());
+    builder.add_method(arrow::ipc::BodyCompressionMethod::BUFFER);
+    builder.finish()
+}
+
+/// Encodes a schema message. The schema metadata is written as the Schema
+/// table's `custom_metadata` (not the Message's), which is where R attributes
+/// are kept under the `r` key (see [RMetadata](crate::structs::RMetadata)), so
+/// they survive a round trip.
+pub(crate) fn schema_to_bytes(schema: &Schema) -> EncodedData {
+    let mut fbb = FlatBufferBuilder::new();
+    let schema = {
+        let fb = arrow::ipc::convert::schema_to_fb_offset(&mut fbb, schema);
+        fb.as_union_value()
+    };
+
+    let mut message = arrow::ipc::MessageBuilder::new(&mut fbb);
+    message.add_version(arrow::ipc::MetadataVersion::V5);
+    message.add_header_type(arrow::ipc::MessageHeader::Schema);
+    message.add_bodyLength(0);
+    message.add_header(schema);
+    let data = message.finish();
+    fbb.finish(data, None);
+
+    let data = fbb.finished_data();
+    EncodedData {
+        ipc_message: data.to_vec(),
+        arrow_data: vec![],
+    }
+}
+
+/// Writes the `len` rows of an array starting at `start` (which includes the
+/// array's own offset), and recursively the rows of its children that they
+/// refer to. Slices are written without copying their buffers where
+/// possible: only validity bitmaps that don't start on a byte boundary, and
+/// offsets that don't start at zero, are copied (and rebased).
+#[allow(clippy::too_many_arguments)]
+fn write_array_data(
+    array_data: &ArrayData,
+    buffers: &mut Vec<arrow::ipc::Buffer>,
+    arrow_data: &mut Vec<u8>,
+    nodes: &mut Vec<arrow::ipc::FieldNode>,
+    offset: i64,
+    start: usize,
+    len: usize,
+    compression: Option<IpcCompression>,
+) -> Result<i64> {
+    let mut offset = offset;
+    let null_count = match array_data.null_buffer() {
+        Some(buffer) => len - buffer.count_set_bits_offset(start, len),
+        None => 0,
+    };
+    nodes.push(arrow::ipc::FieldNode::new(len as i64, null_count as i64));
+    // NullArray does not have any buffers, thus the null buffer is not generated
+    if array_data.data_type() == &DataType::Null {
+        return Ok(offset);
+    }
+
+    let num_bytes = arrow::util::bit_util::ceil(len, 8);
+    let null_buffer = match array_data.null_buffer() {
+        Some(buffer) => bitmap_window(buffer.as_slice(), start, len),
+        // create a buffer and fill it with valid bits
+        None => Cow::Owned(vec![0xff; num_bytes]),
+    };
+    offset = write_buffer(&null_buffer, buffers, arrow_data, offset, compression)?;
+
+    let buffer = |i: usize| buffer_bytes(array_data, i);
+    // The rows of each child to write, for nested types
+    let mut children = Vec::new();
+    match array_data.data_type() {
+        DataType::Boolean => {
+            let values = bitmap_window(buffer(0)?, start, len);
+            offset = write_buffer(&values, buffers, arrow_data, offset, compression)?;
+        }
+        DataType::Utf8 | DataType::Binary | DataType::LargeUtf8 | DataType::LargeBinary => {
+            let width = offset_width(array_data.data_type());
+            let (offsets, first, last) = offsets_window(buffer(0)?, width, start, len)?;
+            let values = buffer(1)?
+                .get(first..last)
+                .ok_or_else(|| RecordBatchesError::invalid("Array offsets out of bounds"))?;
+            offset = write_buffer(&offsets, buffers, arrow_data, offset, compression)?;
+            offset = write_buffer(values, buffers, arrow_data, offset, compression)?;
+        }
+        DataType::List(_) | DataType::LargeList(_) => {
+            let width = offset_width(array_data.data_type());
+            let (offsets, first, last) = offsets_window(buffer(0)?, width, start, len)?;
+            offset = write_buffer(&offsets, buffers, arrow_data, offset, compression)?;
+            children.push((first, last - first));
+        }
+        DataType::FixedSizeList(_, size) => {
+            let size = *size as usize;
+            children.push((start * size, len * size));
+        }
+        DataType::Struct(_) => {
+            children = vec![(start, len); array_data.child_data().len()];
+        }
+        data_type => {
+            let width = byte_width(data_type).ok_or_else(|| {
+                RecordBatchesError::schema(
+                    "Unsupported data type for IPC",
+                    None,
+                    Some(format!("{:?}", data_type)),
+                )
+            })?;
+            let values = buffer(0)?
+                .get(start * width..(start + len) * width)
+                .ok_or_else(|| RecordBatchesError::invalid("Array values out of bounds"))?;
+            offset = write_buffer(values, buffers, arrow_data, offset, compression)?;
+        }
+    }
+
+    // recursively write out nested structures, which have offsets of their own
+    for (child, (child_start, child_len)) in array_data.child_data().iter().zip(children) {
+        offset = write_array_data(
+            child,
+            buffers,
+            arrow_data,
+            nodes,
+            offset,
+            child.offset() + child_start,
+            child_len,
+            compression,
+        )?;
+    }
+
+    Ok(offset)
+}
+
+/// The bytes of the `i`th buffer of an array
+fn buffer_bytes(array_data: &ArrayData, i: usize) -> Result<&[u8]> {
+    array_data
+        .buffers()
+        .get(i)
+        .map(|buffer| buffer.as_slice())
+        .ok_or_else(|| RecordBatchesError::invalid("Array is missing a buffer"))
+}
+
+/// The `len` bits of a bitmap starting at bit `start`. Bitmaps that start on a
+/// byte boundary are borrowed, others are copied so they start at bit 0.
+fn bitmap_window(bitmap: &[u8], start: usize, len: usize) -> Cow<'_, [u8]> {
+    let num_bytes = arrow::util::bit_util::ceil(len, 8);
+    if start % 8 == 0 {
+        if let Some(bytes) = bitmap.get(start / 8..start / 8 + num_bytes) {
+            return Cow::Borrowed(bytes);
+        }
+    }
+    let mut copy = vec![0u8; num_bytes];
+    for i in 0..len {
+        if arrow::util::bit_util::get_bit(bitmap, start + i) {
+            arrow::util::bit_util::set_bit(&mut copy, i);
+        }
+    }
+    Cow::Owned(copy)
+}
+
+/// The `len + 1` offsets (of `width` bytes each) of a variable-width or list
+/// array starting at row `start`, rebased to start at zero, along with the
+/// range of values (or child rows) they refer to. Offsets that already start
+/// at zero are borrowed.
+fn offsets_window(
+    offsets: &[u8],
+    width: usize,
+    start: usize,
+    len: usize,
+) -> Result<(Cow<'_, [u8]>, usize, usize)> {
+    if len == 0 {
+        return Ok((Cow::Owned(vec![0u8; width]), 0, 0));
+    }
+    let bytes = offsets
+        .get(start * width..(start + len + 1) * width)
+        .ok_or_else(|| RecordBatchesError::invalid("Array offsets out of bounds"))?;
+    let values: Vec<i64> = bytes
+        .chunks_exact(width)
+        .map(|x| match width {
+            4 => i32::from_le_bytes([x[0], x[1], x[2], x[3]]) as i64,
+            _ => i64::from_le_bytes([x[0], x[1], x[2], x[3], x[4], x[5], x[6], x[7]]),
+        })
+        .collect();
+    let (first, last) = (values[0], values[len]);
+    if first < 0 || last < first {
+        return Err(RecordBatchesError::invalid("Array offsets are invalid"));
+    }
+    let offsets = if first == 0 {
+        Cow::Borrowed(bytes)
+    } else {
+        let rebased = values.iter().map(|x| x - first);
+        Cow::Owned(match width {
+            4 => rebased
+                .flat_map(|x| (x as i32).to_le_bytes().to_vec())
+                .collect(),
+            _ => rebased.flat_map(|x| x.to_le_bytes().to_vec()).collect(),
+        })
+    };
+    Ok((offsets, first as usize, last as usize))
+}
+
+/// The width in bytes of the offsets of a variable-width or list type
+fn offset_width(data_type: &DataType) -> usize {
+    match data_type {
+        DataType::LargeUtf8 | DataType::LargeBinary | DataType::LargeList(_) => 8,
+        _ => 4,
+    }
+}
+
+/// The width in bytes of each value of a fixed-width type. Dictionary arrays
+/// hold only their keys; the values are written in a DictionaryBatch.
+fn byte_width(data_type: &DataType) -> Option<usize> {
+    match data_type {
+        DataType::Int8 | DataType::UInt8 => Some(1),
+        DataType::Int16 | DataType::UInt16 | DataType::Float16 => Some(2),
+        DataType::Int32
+        | DataType::UInt32
+        | DataType::Float32
+        | DataType::Date32(_)
+        | DataType::Time32(_)
+        | DataType::Interval(IntervalUnit::YearMonth) => Some(4),
+        DataType::Int64
+        | DataType::UInt64
+        | DataType::Float64
+        | DataType::Date64(_)
+        | DataType::Time64(_)
+        | DataType::Timestamp(_, _)
+        | DataType::Duration(_)
+        | DataType::Interval(IntervalUnit::DayTime) => Some(8),
+        DataType::Decimal(_, _) => Some(16),
+        DataType::FixedSizeBinary(width) => Some(*width as usize),
+        DataType::Dictionary(key_type, _) => byte_width(key_type),
+        _ => None,
+    }
+}
+
+/// Write a buffer to a vector of bytes, and add its ipc::Buffer to a vector.
+/// Non-empty buffers are compressed first if `compression` is given.
+fn write_buffer(
+    buffer: &[u8],
+    buffers: &mut Vec<arrow::ipc::Buffer>,
+    arrow_data: &mut Vec<u8>,
+    offset: i64,
+    compression: Option<IpcCompression>,
+) -> Result<i64> {
+    let compressed = match compression {
+        Some(codec) if !buffer.is_empty() => Some(codec.compress(buffer)?),
+        _ => None,
+    };
+    let data = compressed.as_deref().unwrap_or(buffer);
+    let len = data.len();
+    let pad_len = pad_to_8(len);
+    let total_len: i64 = (len + pad_len) as i64;
+    // A compressed buffer's length must exclude the padding, or the padding
+    // would be handed to the decompressor
+    let buffer_len = if compressed.is_some() {
+        len as i64
+    } else {
+        total_len
+    };
+    buffers.push(arrow::ipc::Buffer::new(offset, buffer_len));
+    arrow_data.extend_from_slice(data);
+    arrow_data.extend_from_slice(&vec![0u8; pad_len][..]);
+    Ok(offset + total_len)
+}
+
+/// Write the contents of an EncodedData struct to a stream, including the IPC
+/// message and RecordBatch data
+pub fn write_encoded_data_to_stream<W: Write>(
+    mut writer: W,
+    encoded: EncodedData,
+) -> Result<(usize, usize)> {
+    let arrow_data_len = encoded.arrow_data.len();
+    if arrow_data_len % 8 != 0 {
+        return Err(RecordBatchesError::invalid("Arrow data not aligned"));
+    }
+
+    let a = BLOCK_SIZE - 1;
+    let buffer = encoded.ipc_message;
+    let flatbuf_size = buffer.len();
+    let aligned_size = (flatbuf_size + PREFIX_SIZE + a) & !a;
+    let padding_bytes = aligned_size - flatbuf_size - PREFIX_SIZE;
+
+    write_continuation_marker(&mut writer, (aligned_size - PREFIX_SIZE) as i32)?;
+
+    // write the flatbuf
+    if flatbuf_size > 0 {
+        writer
+            .write_all(&buffer)
+            .map_err(RecordBatchesError::io("Could not write buffer."))?;
+    }
+    pad_output_stream(&mut writer, padding_bytes)?;
+
+    // write arrow data
+    let body_len = if arrow_data_len > 0 {
+        write_bytes_to_stream(&mut writer, &encoded.arrow_data)?
+    } else {
+        0
+    };
+
+    Ok((aligned_size, body_len))
+}
+
+/// Write a 'continuation' marker and the length of the record batch to a
+/// stream. This is written prior to the contents of the record batch.
+pub(crate) fn write_continuation_marker<W: Write>(mut writer: W, total_len: i32) -> Result<()> {
+    writer
+        .write_all(&[0xff; 4])
+        .map_err(RecordBatchesError::io(
+            "Could not write continuation marker to buffer.",
+        ))?;
+    writer
+        .write_all(&total_len.to_le_bytes()[..])
+        .map_err(RecordBatchesError::io("Could not write length to buffer."))?;
+    Ok(())
+}
+
+/// Write bytes to a stream, padding up to the nearest multliple of 8
+fn write_bytes_to_stream<W: Write>(mut writer: W, data: &[u8]) -> Result<usize> {
+    let len = data.len();
+    let pad_len = pad_to_8(len);
+    let total_len = len + pad_len;
+
+    writer
+        .write_all(data)
+        .map_err(RecordBatchesError::io("Could not write data to buffer."))?;
+    if pad_len > 0 {
+        pad_output_stream(&mut writer, pad_len)?;
+    }
+
+    Ok(total_len)
+}
+
+/// Add 0's to the end of a stream up to the nearest multiple of 8 bytes
+pub(crate) fn pad_output_stream<W: Write>(mut writer: W, pad_len: usize) -> Result<()> {
+    writer
+        .write_all(&vec![0u8; pad_len][..])
+        .map_err(RecordBatchesError::io("Could not write padding to buffer."))?;
+    Ok(())
+}
+
+/// Rounds a u32 up to a multiple of 8
+#[inline]
+fn pad_to_8(len: usize) -> usize {
+    ((len + 7) & !7) - len
+}
+
+#[allow(unused_imports)]
+mod test {
+    use super::*;
+    use arrow::array::{make_array, ArrayData};
+    use arrow::array::{
+        Array, ArrayRef, BooleanArray, Date32Array, Date64Array, DecimalArray, DecimalBuilder,
+        DictionaryArray, DurationMicrosecondArray, DurationMillisecondArray,
+        DurationNanosecondArray, DurationSecondArray, FixedSizeListBuilder, Float64Array,
+        Float64Builder, Int32Array, Int32Builder, IntervalDayTimeArray, IntervalYearMonthArray,
+        LargeListBuilder, LargeStringArray, ListBuilder, StringArray, StringBuilder, StructArray,
+        Time32MillisecondArray, Time32SecondArray, Time64MicrosecondArray, Time64NanosecondArray,
+        TimestampMicrosecondArray, TimestampMillisecondArray, TimestampNanosecondArray,
+        TimestampSecondArray, UInt32Array,
+    };
+    use arrow::buffer::Buffer;
+    use arrow::datatypes::{Field, Int32Type, TimeUnit};
+    use arrow::ipc::reader::StreamReader;
+    use std::sync::Arc;
+
+    #[allow(dead_code)]
+    fn sample_batch(offset: i32) -> RecordBatch {
+        let schema = Schema::new(vec![
+            Field::new("id", DataType::Int32, false),
+            Field::new("value", DataType::Float64, true),
+            Field::new("label", DataType::Utf8, true),
+        ]);
+        RecordBatch::try_new(
+            Arc::new(schema),
+            vec![
+                Arc::new(Int32Array::from(vec![offset, offset + 1, offset + 2])),
+                Arc::new(Float64Array::from(vec![Some(1.5), None, Some(3.5)])),
+                Arc::new(StringArray::from(vec![Some("a"), Some("bb"), None])),
+            ],
+        )
+        .unwrap()
+    }
+
+    /// A batch with a column of each nested type, with nulls at each level
+    #[allow(dead_code)]
+    fn nested_batch() -> RecordBatch {
+        let mut list = ListBuilder::new(Int32Builder::new(4));
+        list.values().append_value(1).unwrap();
+        list.values().append_null().unwrap();
+        list.append(true).unwrap();
+        list.append(false).unwrap();
+        list.values().append_value(3).unwrap();
+        list.append(true).unwrap();
+        list.append(true).unwrap();
+
+        let mut large_list = LargeListBuilder::new(StringBuilder::new(4));
+        large_list.append(false).unwrap();
+        large_list.values().append_value("a").unwrap();
+        large_list.values().append_null().unwrap();
+        large_list.append(true).unwrap();
+        large_list.values().append_value("bc").unwrap();
+        large_list.append(true).unwrap();
+        large_list.append(true).unwrap();
+
+        let mut fixed = FixedSizeListBuilder::new(Float64Builder::new(8), 2);
+        for (i, valid) in [true, true, false, true].iter().enumerate() {
+            fixed.values().append_value(i as f64).unwrap();
+            fixed.values().append_null().unwrap();
+            fixed.append(*valid).unwrap();
+        }
+
+        let structs = StructArray::from((
+            vec![
+                (
+                    Field::new("a", DataType::Int32, true),
+                    Arc::new(Int32Array::from(vec![Some(1), None, Some(3), Some(4)])) as ArrayRef,
+                ),
+                (
+                    Field::new("b", DataType::Utf8, true),
+                    Arc::new(StringArray::from(vec![
+                        Some("w"),
+                        Some("x"),
+                        None,
+                        Some("z"),
+                    ])) as ArrayRef,
+                ),
+            ],
+            Buffer::from(&[0b0000_1011u8]),
+        ));
+
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(list.finish()),
+            Arc::new(large_list.finish()),
+            Arc::new(fixed.finish()),
+            Arc::new(structs),
+        ];
+        let fields = ["list", "large_list", "fixed_size_list", "struct"]
+            .iter()
+            .zip(columns.iter())
+            .map(|(name, column)| Field::new(name, column.data_type().clone(), true))
+            .collect();
+        RecordBatch::try_new(Arc::new(Schema::new(fields)), columns).unwrap()
+    }
+
+    /// A batch with a column of each decimal and temporal type, with a null in
+    /// each column
+    #[allow(dead_code)]
+    fn temporal_batch() -> RecordBatch {
+        let mut decimals = DecimalBuilder::new(3, 12, 2);
+        decimals.append_value(123_456_789).unwrap();
+        decimals.append_null().unwrap();
+        decimals.append_value(-5).unwrap();
+
+        let tz = || Some("America/New_York".to_string());
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(decimals.finish()),
+            Arc::new(Date32Array::from(vec![Some(18628), None, Some(-1)])),
+            Arc::new(Date64Array::from(vec![
+                Some(1_609_459_200_000),
+                None,
+                Some(0),
+            ])),
+            Arc::new(TimestampSecondArray::from_opt_vec(
+                vec![Some(1), None, Some(-1)],
+                tz(),
+            )),
+            Arc::new(TimestampMillisecondArray::from_opt_vec(
+                vec![Some(1), None, Some(2)],
+                tz(),
+            )),
+            Arc::new(TimestampMicrosecondArray::from_opt_vec(
+                vec![Some(1), None, Some(2)],
+                None,
+            )),
+            Arc::new(TimestampNanosecondArray::from_opt_vec(
+                vec![Some(1), None, Some(2)],
+                tz(),
+            )),
+            Arc::new(Time32SecondArray::from(vec![Some(3600), None, Some(86399)])),
+            Arc::new(Time32MillisecondArray::from(vec![Some(1), None, Some(2)])),
+            Arc::new(Time64MicrosecondArray::from(vec![Some(1), None, Some(2)])),
+            Arc::new(Time64NanosecondArray::from(vec![Some(1), None, Some(2)])),
+            Arc::new(DurationSecondArray::from(vec![Some(-1), None, Some(2)])),
+            Arc::new(DurationMillisecondArray::from(vec![Some(1), None, Some(2)])),
+            Arc::new(DurationMicrosecondArray::from(vec![Some(1), None, Some(2)])),
+            Arc::new(DurationNanosecondArray::from(vec![Some(1), None, Some(2)])),
+            Arc::new(IntervalYearMonthArray::from(vec![Some(14), None, Some(-2)])),
+            Arc::new(IntervalDayTimeArray::from(vec![
+                Some((500 << 32) | 3),
+                None,
+                Some(1),
+            ])),
+        ];
+        let fields = columns
+            .iter()
+            .enumerate()
+            .map(|(i, column)| Field::new(&format!("c{}", i), column.data_type().clone(), true))
+            .collect();
+        RecordBatch::try_new(Arc::new(Schema::new(fields)), columns).unwrap()
+    }
+
+    #[allow(dead_code)]
+    fn stream_round_trip(batch: &RecordBatch) -> RecordBatch {
+        let mut sink = RecordBatchStreamSink::try_new(Vec::new(), batch.schema()).unwrap();
+        sink.write(batch).unwrap();
+        let buffer = sink.into_inner().unwrap();
+        let mut reader = StreamReader::try_new(&buffer[..]).unwrap();
+        reader.next().unwrap().unwrap()
+    }
+
+    /// Compares an array read back with the (possibly sliced) array written.
+    /// arrow's `==` ignores the offset of validity bitmaps, so the written
+    /// array is first copied to offset 0 with `take`. There is no `take` for
+    /// decimals, so those are compared by value, and structs are compared
+    /// field by field.
+    #[allow(dead_code)]
+    fn assert_same_values(read: &ArrayRef, written: &ArrayRef) {
+        assert_eq!(read.null_count(), written.null_count());
+        let validity = |array: &ArrayRef| -> Vec<bool> {
+            (0..array.len()).map(|i| array.is_valid(i)).collect()
+        };
+        assert_eq!(validity(read), validity(written));
+        if let DataType::Decimal(_, _) = written.data_type() {
+            let values = |array: &ArrayRef| {
+                let array = array.as_any().downcast_ref::<DecimalArray>().unwrap();
+                (0..array.len())
+                    .map(|i| Some(array.value(i)).filter(|_| array.is_valid(i)))
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(values(read), values(written));
+        } else if let DataType::Struct(_) = written.data_type() {
+            let read = read.as_any().downcast_ref::<StructArray>().unwrap();
+            let written = written.as_any().downcast_ref::<StructArray>().unwrap();
+            for (read, written) in read.columns().into_iter().zip(written.columns()) {
+                assert_same_values(read, written);
+            }
+        } else {
+            let indices = UInt32Array::from((0..written.len() as u32).collect::<Vec<_>>());
+            let taken = arrow::compute::take(written.as_ref(), &indices, None).unwrap();
+            // `take` drops the time zone of timestamps
+            let data = taken.data();
+            let copy = make_array(Arc::new(ArrayData::new(
+                written.data_type().clone(),
+                data.len(),
+                None,
+                data.null_buffer().cloned(),
+                0,
+                data.buffers().to_vec(),
+                data.child_data().to_vec(),
+            )));
+            assert_eq!(read, &copy);
+        }
+    }
+
+    #[test]
+    fn test_nested_columns_round_trip() {
+        let batch = nested_batch();
+        let read = stream_round_trip(&batch);
+        assert_eq!(read.schema(), batch.schema());
+        for i in 0..batch.num_columns() {
+            assert_eq!(read.column(i).as_ref(), batch.column(i).as_ref());
+            assert_eq!(read.column(i).null_count(), 1);
+        }
+    }
+
+    #[test]
+    fn test_temporal_columns_round_trip() {
+        let batch = temporal_batch();
+        let read = stream_round_trip(&batch);
+        assert_eq!(read.schema(), batch.schema());
+        assert_eq!(
+            read.schema().field(3).data_type(),
+            &DataType::Timestamp(TimeUnit::Second, Some("America/New_York".to_string()))
+        );
+        assert_eq!(
+            read.schema().field(0).data_type(),
+            &DataType::Decimal(12, 2)
+        );
+        for i in 0..batch.num_columns() {
+            assert_eq!(read.column(i).as_ref(), batch.column(i).as_ref());
+            assert_eq!(read.column(i).null_count(), 1);
+        }
+
+        let columns: Vec<ArrayRef> = batch.columns().iter().map(|c| c.slice(1, 2)).collect();
+        let sliced = RecordBatch::try_new(batch.schema(), columns).unwrap();
+        let read = stream_round_trip(&sliced);
+        for i in 0..sliced.num_columns() {
+            assert_same_values(read.column(i), sliced.column(i));
+        }
+    }
+
+    #[test]
+    fn test_sliced_nested_columns_round_trip() {
+        let batch = nested_batch();
+        let columns: Vec<ArrayRef> = batch.columns().iter().map(|c| c.slice(1, 2)).collect();
+        let batch = RecordBatch::try_new(batch.schema(), columns).unwrap();
+        let read = stream_round_trip(&batch);
+        assert_eq!(read.num_rows(), 2);
+        for i in 0..batch.num_columns() {
+            assert_same_values(read.column(i), batch.column(i));
+            assert_eq!(read.column(i).offset(), 0);
+        }
+    }
+
+    #[test]
+    fn test_sliced_columns_are_written_from_their_offset() {
+        let n = 20;
+        let ints = Int32Array::from(
+            (0..n)
+                .map(|i| if i % 3 == 0 { None } else { Some(i) })
+                .collect::<Vec<_>>(),
+        );
+        let bools: BooleanArray = (0..n).map(|i| Some(i % 2 == 0)).collect();
+        let strings: Vec<Option<String>> = (0..n)
+            .map(|i| {
+                if i % 4 == 0 {
+                    None
+                } else {
+                    Some("x".repeat(i as usize))
+                }
+            })
+            .collect();
+        let strings = StringArray::from(strings.iter().map(|x| x.as_deref()).collect::<Vec<_>>());
+        let large: Vec<String> = (0..n).map(|i| i.to_string()).collect();
+        let large = LargeStringArray::from(large.iter().map(|x| x.as_str()).collect::<Vec<_>>());
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("ints", DataType::Int32, true),
+            Field::new("bools", DataType::Boolean, true),
+            Field::new("strings", DataType::Utf8, true),
+            Field::new("large", DataType::LargeUtf8, true),
+        ]));
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(ints),
+            Arc::new(bools),
+            Arc::new(strings),
+            Arc::new(large),
+        ];
+        let batch = RecordBatch::try_new(schema.clone(), columns).unwrap();
+        let full_len = record_batch_to_bytes(&batch, None)
+            .unwrap()
+            .arrow_data
+            .len();
+
+        // Both on and off a byte boundary
+        for &(start, len) in &[(3, 7), (8, 9), (19, 1), (5, 0)] {
+            let columns = batch
+                .columns()
+                .iter()
+                .map(|c| c.slice(start, len))
+                .collect();
+            let sliced = RecordBatch::try_new(schema.clone(), columns).unwrap();
+            let encoded = record_batch_to_bytes(&sliced, None).unwrap();
+            assert!(encoded.arrow_data.len() < full_len);
+
+            let read = stream_round_trip(&sliced);
+            assert_eq!(read.num_rows(), len);
+            for i in 0..sliced.num_columns() {
+                assert_same_values(read.column(i), sliced.column(i));
+            }
+        }
+    }
+
+    #[test]
+    fn test_schema_metadata_is_encoded() {
+        let mut metadata = std::collections::HashMap::new();
+        metadata.insert("r".to_string(), "A\n3\n".to_string());
+        let schema = Schema::new_with_metadata(sample_batch(0).schema().fields().clone(), metadata);
+        let encoded = schema_to_bytes(&schema);
+        let message = arrow::ipc::root_as_message(&encoded.ipc_message).unwrap();
+        let decoded = arrow::ipc::convert::fb_to_schema(message.header_as_schema().unwrap());
+        assert_eq!(
+            decoded.metadata().get("r").map(|x| x.as_str()),
+            Some("A\n3\n")
+        );
+    }
+
+    #[test]
+    fn test_stream_sink_round_trip() {
+        let batches = [sample_batch(0), sample_batch(3)];
+        let mut sink = RecordBatchStreamSink::try_new(Vec::new(), batches[0].schema()).unwrap();
+        for batch in batches.iter() {
+            sink.write(batch).unwrap();
+        }
+        let buffer = sink.into_inner().unwrap();
+
+        let reader = StreamReader::try_new(&buffer[..]).unwrap();
+        let read: Vec<_> = reader.map(|rb| rb.unwrap()).collect();
+        assert_eq!(read.len(), 2);
+        for (expected, found) in batches.iter().zip(read.iter()) {
+            assert_eq!(expected.schema(), found.schema());
+            for i in 0..expected.num_columns() {
+                assert_eq!(expected.column(i).data(), found.column(i).data());
+            }
+        }
+    }
+
+    #[test]
+    fn test_stream_sink_ends_with_eos_marker() {
+        let batch = sample_batch(0);
+        let mut sink = RecordBatchStreamSink::try_new(Vec::new(), batch.schema()).unwrap();
+        sink.write(&batch).unwrap();
+        sink.finish().unwrap();
+        sink.finish().unwrap(); // Finishing twice doesn't write a second marker
+        assert!(sink.write(&batch).is_err());
+        let buffer = sink.into_inner().unwrap();
+        assert_eq!(
+            &buffer[buffer.len() - 8..],
+            &[0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0]
+        );
+
+        // An abandoned stream is left without a marker
+        let mut abandoned = Vec::new();
+        let mut sink = RecordBatchStreamSink::try_new(&mut abandoned, batch.schema()).unwrap();
+        sink.write(&batch).unwrap();
+        drop(sink);
+        assert_eq!(abandoned[..], buffer[..buffer.len() - 8]);
+    }
+
+    #[test]
+    fn test_nested_dictionaries_are_rejected() {
+        let factor: DictionaryArray<Int32Type> = vec!["a", "b", "a"].into_iter().collect();
+        let field = Field::new("factor", factor.data_type().clone(), true);
+        let column = StructArray::from(vec![(field, Arc::new(factor) as ArrayRef)]);
+        let schema = Schema::new(vec![Field::new("s", column.data_type().clone(), true)]);
+        let batch = RecordBatch::try_new(Arc::new(schema), vec![Arc::new(column)]).unwrap();
+
+        let mut sink = RecordBatchStreamSink::try_new(Vec::new(), batch.schema()).unwrap();
+        let err = sink.write(&batch).unwrap_err();
+        assert_eq!(err.context(), "Nested dictionaries are not supported");
+        assert_eq!(err.field(), Some("s"));
+    }
+
+    #[test]
+    fn test_stream_sink_rejects_mismatched_schema() {
+        let batch = sample_batch(0);
+        let other = RecordBatch::try_new(
+            Arc::new(Schema::new(vec![Field::new("id", DataType::Int32, false)])),
+            vec![Arc::new(Int32Array::from(vec![1]))],
+        )
+        .unwrap();
+        let mut sink = RecordBatchStreamSink::try_new(Vec::new(), batch.schema()).unwrap();
+        sink.write(&batch).unwrap();
+        let err = sink.write(&other).unwrap_err();
+        assert_eq!(err.message_index(), Some(1));
+        assert_eq!(err.field(), Some("value"));
+    }
+}
@@ -1,11 +1,85 @@
 use extendr_api::prelude::*;
 
-pub(crate) fn flip(b: Bool) -> Bool {
-    if b.is_true() {
-        Bool::from(false)
-    } else if b.is_false() {
-        Bool::from(true)
+/// Reads an optional character vector argument, where `NULL` means `None`
+pub(crate) fn optional_strings(
+    robj: &Robj,
+) -> std::result::Result<Option<Vec<String>>, &'static str> {
+    if robj.is_null() {
+        Ok(None)
     } else {
-        b
+        robj.as_string_vector()
+            .map(Some)
+            .ok_or("Expected a character vector or NULL.")
     }
 }
+
+/// Reads an optional vector of one-based R indices as zero-based Rust indices,
+/// where `NULL` means `None`. Accepts integer or double vectors.
+pub(crate) fn optional_indices(
+    robj: &Robj,
+) -> std::result::Result<Option<Vec<usize>>, &'static str> {
+    if robj.is_null() {
+        return Ok(None);
+    }
+    let indices: Vec<f64> = if let Some(v) = robj.as_integer_vector() {
+        v.into_iter().map(|x| x as f64).collect()
+    } else if let Some(v) = robj.as_real_vector() {
+        v
+    } else {
+        return Err("Expected a numeric vector of indices or NULL.");
+    };
+    if indices.iter().any(|x| x.is_nan() || *x < 1.0) {
+        return Err("Indices must be positive, non-missing numbers.");
+    }
+    Ok(Some(indices.into_iter().map(|x| x as usize - 1).collect()))
+}
+
+/// Reads a logical vector argument recycled to `len` values, the way R
+/// recycles arguments. NA's aren't allowed.
+pub(crate) fn recycled_logicals(
+    robj: &Robj,
+    len: usize,
+) -> std::result::Result<Vec<bool>, &'static str> {
+    let values: Vec<Option<bool>> = robj
+        .as_logical_iter()
+        .ok_or("Expected a logical vector.")?
+        .map(|x| {
+            if x.is_true() {
+                Some(true)
+            } else if x.is_false() {
+                Some(false)
+            } else {
+                None
+            }
+        })
+        .collect();
+    if values.is_empty() || values.iter().any(Option::is_none) {
+        return Err("Expected TRUE or FALSE, without NA's.");
+    }
+    Ok((0..len)
+        .map(|i| values[i % values.len()] == Some(true))
+        .collect())
+}
+
+/// Reads a vector of one-based R indices as zero-based Rust indices, where
+/// NA's become `None`. Accepts integer or double vectors.
+pub(crate) fn indices_with_na(
+    robj: &Robj,
+) -> std::result::Result<Vec<Option<usize>>, &'static str> {
+    let indices: Vec<Option<f64>> = if let Some(v) = robj.as_integer_slice() {
+        v.iter()
+            .map(|&x| Some(x as f64).filter(|_| x != i32::MIN))
+            .collect()
+    } else if let Some(v) = robj.as_real_slice() {
+        v.iter().map(|&x| Some(x).filter(|x| !x.is_nan())).collect()
+    } else {
+        return Err("Expected a numeric vector of indices.");
+    };
+    if indices.iter().flatten().any(|x| *x < 1.0) {
+        return Err("Indices must be positive numbers or NA.");
+    }
+    Ok(indices
+        .into_iter()
+        .map(|x| x.map(|x| x as usize - 1))
+        .collect())
+}